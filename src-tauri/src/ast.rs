@@ -1,13 +1,35 @@
 use serde::{Deserialize, Serialize};
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
 use std::fmt;
 
+mod cst;
+mod envelope;
+mod format;
+mod identity;
+mod incremental;
+mod infer;
+mod layout;
 mod lexer;
+mod lint;
+mod names;
 mod parser;
+mod patch;
+mod pitfalls;
 mod render;
+mod symbols;
+mod visit;
+mod warnings;
 
 #[cfg(test)]
 mod tests;
 
+pub use envelope::{decode_ir, encode_ir};
+pub use format::format_python;
+pub use lint::{lint_python, Linter};
+pub use pitfalls::error_pitfall;
+pub use visit::{IrVisitor, IrVisitorMut, Visitor, VisitorMut};
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Position {
     pub line: usize,
@@ -58,7 +80,7 @@ impl Default for TokenRange {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct NodeId(pub u64);
 
 impl Default for NodeId {
@@ -79,6 +101,17 @@ pub struct NodeMeta {
     pub leading_trivia: Vec<Trivia>,
     #[serde(default)]
     pub trailing_trivia: Vec<Trivia>,
+    /// Comment ending the line of a statement or a decorator, or, on a
+    /// block, the line of the header opening it. Holds the text after the
+    /// `#`.
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// Set by editors on nodes they change. Rendering with
+    /// `RenderConfig::reuse_token_ranges` copies clean nodes from the original
+    /// tokens and re-renders only dirty ones and their ancestors. Nodes with
+    /// an empty span count as dirty.
+    #[serde(default)]
+    pub dirty: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -101,6 +134,35 @@ pub enum BlankSource {
     Generated,
 }
 
+/// Line break sequence a source file uses, taken from its first line break.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+/// Encoding details of the original source file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct SourceFormat {
+    pub line_ending: LineEnding,
+    /// The file started with a UTF-8 byte order mark.
+    pub bom: bool,
+    /// Encoding declared by a `# -*- coding: ... -*-` comment.
+    pub coding: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Program {
     #[serde(default)]
@@ -115,6 +177,8 @@ pub struct Program {
     pub raw_tokens: Vec<LexToken>,
     #[serde(default)]
     pub dirty: bool,
+    #[serde(default)]
+    pub source_format: SourceFormat,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -133,6 +197,7 @@ pub enum Stmt {
     Break(BreakStmt),
     Continue(ContinueStmt),
     Empty(EmptyStmt),
+    Comment(CommentStmt),
     Import(ImportStmt),
     Try(TryStmt),
     ClassDef(ClassDefStmt),
@@ -317,6 +382,15 @@ pub struct EmptyStmt {
     pub source: BlankSource,
 }
 
+/// A line holding only a comment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommentStmt {
+    #[serde(default)]
+    pub meta: NodeMeta,
+    /// Text after the `#`.
+    pub text: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImportStmt {
     #[serde(default)]
@@ -714,13 +788,14 @@ pub enum BinaryOp {
 impl BinaryOp {
     fn precedence(&self) -> u8 {
         match self {
-            BinaryOp::BitOr => 5,
-            BinaryOp::BitXor => 6,
-            BinaryOp::BitAnd => 7,
-            BinaryOp::LeftShift | BinaryOp::RightShift => 8,
-            BinaryOp::Add | BinaryOp::Sub => 9,
-            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod | BinaryOp::FloorDiv => 10,
-            BinaryOp::Power => 11,
+            BinaryOp::BitOr => 7,
+            BinaryOp::BitXor => 8,
+            BinaryOp::BitAnd => 9,
+            BinaryOp::LeftShift | BinaryOp::RightShift => 10,
+            BinaryOp::Add | BinaryOp::Sub => 11,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod | BinaryOp::FloorDiv => 12,
+            // Binds tighter than unary operators, which come in at 13.
+            BinaryOp::Power => 14,
         }
     }
 }
@@ -790,7 +865,7 @@ pub enum FStringTokenPart {
     ExprText(String),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum QuoteStyle {
     Single,
@@ -829,6 +904,9 @@ pub struct IrProgram {
     pub token_store: Option<TokenStore>,
     #[serde(default)]
     pub dirty: bool,
+    /// How the source was written, which rendering keeps.
+    #[serde(default)]
+    pub source_format: SourceFormat,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -847,6 +925,7 @@ pub enum IrStmt {
     Break(IrBreakStmt),
     Continue(IrContinueStmt),
     Empty(IrEmptyStmt),
+    Comment(IrCommentStmt),
     Import(IrImportStmt),
     Try(IrTryStmt),
     ClassDef(IrClassDefStmt),
@@ -1017,6 +1096,13 @@ pub struct IrEmptyStmt {
     pub source: BlankSource,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IrCommentStmt {
+    #[serde(default)]
+    pub meta: NodeMeta,
+    pub text: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IrImportStmt {
     #[serde(default)]
@@ -1401,6 +1487,496 @@ pub struct TokenStore {
     pub raw_tokens: Vec<LexToken>,
 }
 
+/// An edit to an `IrProgram`, addressing nodes by their `NodeId`.
+/// `IrProgram::apply` returns the operation undoing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data")]
+pub enum IrOperation {
+    /// Inserts `stmt` at `index` of the statements of `block`, or of the
+    /// program when `block` is `None`. Nodes of `stmt` with id 0 get new ids.
+    InsertStmt {
+        block: Option<NodeId>,
+        index: usize,
+        stmt: Box<IrStmt>,
+    },
+    /// Removes a statement.
+    DeleteNode { id: NodeId },
+    /// Moves a statement to `index` of the statements of `block`, counted
+    /// without the statement itself.
+    MoveNode {
+        id: NodeId,
+        block: Option<NodeId>,
+        index: usize,
+    },
+    /// Replaces an expression. Nodes of `expr` with id 0 get new ids.
+    ReplaceExpr { id: NodeId, expr: Box<IrExpr> },
+    /// Sets a field of a node that holds no nodes itself, such as a name,
+    /// an operator or a literal.
+    SetField {
+        id: NodeId,
+        field: String,
+        value: serde_json::Value,
+    },
+}
+
+/// Version of the IR that `encode_ir` writes. `decode_ir` migrates
+/// envelopes of older versions, starting from version 1, the IR before
+/// comment lines became statements.
+pub const IR_VERSION: u32 = 2;
+
+/// An `IrProgram` on its way to or from the webview.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IrEnvelope {
+    pub version: u32,
+    #[serde(default)]
+    pub encoding: IrEncoding,
+    /// Handle of the program's token store when it was kept in
+    /// `TokenStores` rather than sent along.
+    #[serde(default)]
+    pub token_handle: Option<TokenHandle>,
+    pub program: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum IrEncoding {
+    /// The program as serde writes it.
+    #[default]
+    Json,
+    /// Enums keyed by their variant name alone, spans and token ranges as
+    /// arrays of numbers, and node metadata without its default fields.
+    Compact,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+pub struct IrEncodeOptions {
+    #[serde(default)]
+    pub encoding: IrEncoding,
+    /// Keeps the token store server-side and sends its handle instead.
+    #[serde(default)]
+    pub detach_tokens: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TokenHandle(pub u64);
+
+/// Token stores detached from the programs a document encoded. The document
+/// owns them and drops them when its source changes or it closes; an
+/// envelope referring to a store it no longer has does not decode.
+#[derive(Debug, Default)]
+pub struct TokenStores {
+    stores: Vec<(TokenHandle, TokenStore)>,
+}
+
+/// Concrete syntax tree of a parsed program. Every raw token, whitespace
+/// and comments included, belongs to exactly one node, so printing the
+/// tree gives back the source byte for byte.
+///
+/// A token belongs to the innermost node whose source it falls in.
+/// Statements take their whole lines: the indentation before them and the
+/// rest of their last line, trailing comment and line break included.
+/// Blocks run from the start of their first statement to the end of their
+/// last, so the header line stays with the statement opening the block.
+#[derive(Debug, Clone)]
+pub struct SyntaxTree {
+    /// Typed view of the tree. Its nodes have the ids of their syntax nodes.
+    pub program: Program,
+    pub root: SyntaxNode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyntaxNode {
+    pub id: NodeId,
+    pub kind: SyntaxKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data")]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(LexToken),
+}
+
+/// Kind of AST node a `SyntaxNode` stands for. Nodes without source of
+/// their own, such as embedded f-string expressions, have no syntax node;
+/// their tokens belong to the node around them.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SyntaxKind {
+    Program,
+    Block,
+    Stmt,
+    Expr,
+    Pattern,
+    ComprehensionFor,
+}
+
+/// Names of a program resolved to the scopes they live in, from
+/// `Program::symbols`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct SymbolTable {
+    /// Scopes in the order they open; the module scope comes first.
+    pub scopes: Vec<Scope>,
+    pub symbols: Vec<Symbol>,
+    /// Every place a name occurs, in the order Python evaluates them.
+    pub references: Vec<Reference>,
+    /// Symbol of the name each node binds or uses. A node with several
+    /// names, such as an `import` of two modules or a function with its
+    /// parameters, maps to the first.
+    pub nodes: BTreeMap<NodeId, SymbolId>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScopeId(pub usize);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SymbolId(pub usize);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scope {
+    pub kind: ScopeKind,
+    /// The node opening the scope: the program, a `def`, a `class`, a
+    /// lambda or a comprehension.
+    pub node: NodeId,
+    pub parent: Option<ScopeId>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ScopeKind {
+    Module,
+    Function,
+    Class,
+    Comprehension,
+    Lambda,
+}
+
+/// A variable of a scope. Names used but bound nowhere, such as builtins
+/// and misspellings, get a symbol of the module scope without definitions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub scope: ScopeId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reference {
+    /// The identifier, or the node binding the name when the name has no
+    /// node of its own, like the name of a `def` or a parameter.
+    pub node: NodeId,
+    pub span: Span,
+    pub kind: ReferenceKind,
+    pub symbol: SymbolId,
+    /// Scope the name occurs in, which for `global` and `nonlocal` names
+    /// and for names bound by `:=` in a comprehension is not the scope of
+    /// the symbol.
+    pub scope: ScopeId,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ReferenceKind {
+    /// Binds the name: an assignment target, a `def` or `class`, an import,
+    /// a `for`, `with` or `except` target, a `:=` or a case pattern.
+    Definition,
+    Use,
+    Global,
+    Nonlocal,
+    Parameter,
+    /// Makes the name a variable of the scope without assigning it: a bare
+    /// annotation such as `x: int`.
+    Declaration,
+    /// Unbinds the name: a `del` target.
+    Deletion,
+}
+
+/// A read of a name that would raise `NameError`, from
+/// `Program::check_names`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NameDiagnostic {
+    pub kind: NameDiagnosticKind,
+    pub name: String,
+    /// The identifier read.
+    pub node: NodeId,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum NameDiagnosticKind {
+    /// Bound in no scope around the read, and not a builtin.
+    Undefined,
+    /// A variable of the function or the module, read where no assignment
+    /// to it has run on any path.
+    UsedBeforeAssignment,
+}
+
+impl NameDiagnostic {
+    pub fn message(&self) -> String {
+        match self.kind {
+            NameDiagnosticKind::Undefined => format!("name '{}' is not defined", self.name),
+            NameDiagnosticKind::UsedBeforeAssignment => {
+                format!("'{}' is read before it is assigned", self.name)
+            }
+        }
+    }
+}
+
+/// Code that runs but has no effect, from `Program::warnings`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub severity: Severity,
+    /// The unused name; `None` for unreachable code.
+    pub name: Option<String>,
+    /// The node binding the name, or the first statement that cannot run.
+    pub node: NodeId,
+    pub span: Span,
+    pub fix: QuickFix,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum WarningKind {
+    UnusedImport,
+    /// A variable of a function assigned but never read.
+    UnusedVariable,
+    UnusedParameter,
+    /// Statements after a `return`, `break`, `continue` or `raise` in the
+    /// same block.
+    UnreachableCode,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+/// An edit resolving a warning, applied with `IrProgram::apply` in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuickFix {
+    pub title: String,
+    pub operations: Vec<IrOperation>,
+}
+
+/// Turns rules of the lint engine on or off and changes their severity.
+/// Rules not listed keep their defaults.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct LintConfig {
+    /// Settings keyed by rule id.
+    pub rules: BTreeMap<String, RuleConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct RuleConfig {
+    pub enabled: Option<bool>,
+    pub severity: Option<Severity>,
+}
+
+/// What a lint rule finds, before the engine gives it the rule's id and
+/// severity.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub message: String,
+    pub node: Option<NodeId>,
+    pub span: Span,
+    pub fix: Option<QuickFix>,
+}
+
+/// A finding of a lint rule, from `Linter::lint`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LintDiagnostic {
+    pub rule: String,
+    pub severity: Severity,
+    pub message: String,
+    pub node: Option<NodeId>,
+    pub span: Span,
+    pub fix: Option<QuickFix>,
+}
+
+/// A check of the lint engine.
+pub trait LintRule: Send + Sync {
+    /// Names the rule in a `LintConfig` and in `# noqa` comments.
+    fn id(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    fn severity(&self) -> Severity {
+        Severity::Warning
+    }
+
+    fn enabled_by_default(&self) -> bool {
+        true
+    }
+
+    fn check(&self, context: &LintContext) -> Vec<Finding>;
+}
+
+/// A program with its names resolved, as the rules see it. The name checks
+/// and warnings several rules share run once, when first asked for.
+pub struct LintContext<'a> {
+    pub program: &'a Program,
+    pub symbols: &'a SymbolTable,
+    names: OnceCell<Vec<NameDiagnostic>>,
+    warnings: OnceCell<Vec<Warning>>,
+}
+
+/// Code learners often write by mistake, from `Program::pitfalls` or, for
+/// source that does not parse, `error_pitfall`. The editor shows each with
+/// the hint its translations have under `hint_` and the kind.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pitfall {
+    pub kind: PitfallKind,
+    /// Values of the hint's placeholders, such as `{name}`, as code.
+    pub args: BTreeMap<String, String>,
+    /// `None` for a pitfall in source that does not parse.
+    pub node: Option<NodeId>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PitfallKind {
+    /// `if x = 1:`, or `if (x := 1):`, where `==` was meant.
+    AssignmentInCondition,
+    /// `x == 1` on its own line, where `=` was meant.
+    ComparisonStatement,
+    /// `for i in range(len(items))` where `items` could be looped over.
+    RangeLenLoop,
+    /// A list, dict or set as the default of a parameter, which every call
+    /// shares.
+    MutableDefault,
+    /// `== None` or `!= None` rather than `is None` or `is not None`.
+    NoneComparison,
+    /// `while True` with no `break`, `return` or `raise` in it, outside a
+    /// generator, whose loops can stop at each `yield`.
+    EndlessLoop,
+    /// A builtin such as `list` or `input` assigned or defined.
+    ShadowedBuiltin,
+}
+
+/// A rule of the lint engine as the editor lists it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LintRuleInfo {
+    pub id: String,
+    pub description: String,
+    pub severity: Severity,
+    pub enabled: bool,
+}
+
+impl Warning {
+    pub fn message(&self) -> String {
+        let name = self.name.as_deref().unwrap_or_default();
+        match self.kind {
+            WarningKind::UnusedImport => format!("'{name}' is imported but never used"),
+            WarningKind::UnusedVariable => format!("'{name}' is assigned but never read"),
+            WarningKind::UnusedParameter => format!("parameter '{name}' is never used"),
+            WarningKind::UnreachableCode => "this code can never run".to_string(),
+        }
+    }
+}
+
+/// Types of a program's expressions, from `Program::infer_types`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TypeTable {
+    /// Type of each expression whose type is known.
+    pub types: BTreeMap<NodeId, Type>,
+    pub errors: Vec<TypeDiagnostic>,
+}
+
+/// What the value of an expression is known to be.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+    Str,
+    None,
+    List(Box<Type>),
+    Tuple(Vec<Type>),
+    Dict(Box<Type>, Box<Type>),
+    Set(Box<Type>),
+    Range,
+    /// Anything; the inference could not tell.
+    Unknown,
+}
+
+impl Type {
+    /// The class of the values, as Python names it in its errors.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Type::Int => "int",
+            Type::Float => "float",
+            Type::Bool => "bool",
+            Type::Str => "str",
+            Type::None => "NoneType",
+            Type::List(_) => "list",
+            Type::Tuple(_) => "tuple",
+            Type::Dict(..) => "dict",
+            Type::Set(_) => "set",
+            Type::Range => "range",
+            Type::Unknown => "object",
+        }
+    }
+}
+
+/// The type as an annotation would write it, like `list[int]`.
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::None => write!(f, "None"),
+            Type::Unknown => write!(f, "Any"),
+            Type::List(element) => write!(f, "list[{element}]"),
+            Type::Set(element) => write!(f, "set[{element}]"),
+            Type::Dict(key, value) => write!(f, "dict[{key}, {value}]"),
+            Type::Tuple(elements) if elements.is_empty() => write!(f, "tuple[()]"),
+            Type::Tuple(elements) => {
+                let elements: Vec<String> = elements.iter().map(Type::to_string).collect();
+                write!(f, "tuple[{}]", elements.join(", "))
+            }
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// An operator applied to values of types Python refuses for it, from
+/// `Program::infer_types`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TypeDiagnostic {
+    pub kind: TypeDiagnosticKind,
+    /// The operator, as code.
+    pub op: String,
+    pub left: Type,
+    pub right: Type,
+    /// The binary or comparison expression.
+    pub node: NodeId,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TypeDiagnosticKind {
+    /// An arithmetic or bitwise operator, like `"1" + 1`.
+    Operands,
+    /// An ordering comparison, like `"1" < 1`.
+    Comparison,
+}
+
+impl TypeDiagnostic {
+    pub fn message(&self) -> String {
+        let (left, right) = (self.left.name(), self.right.name());
+        match self.kind {
+            TypeDiagnosticKind::Operands => format!(
+                "unsupported operand type(s) for {}: '{left}' and '{right}'",
+                self.op
+            ),
+            TypeDiagnosticKind::Comparison => format!(
+                "'{}' not supported between instances of '{left}' and '{right}'",
+                self.op
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LexToken {
     pub kind: TokenKind,
@@ -1559,10 +2135,44 @@ pub enum RenderMode {
     Pretty,
 }
 
+/// What pretty rendering indents blocks with.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum IndentStyle {
+    /// The program's `indent_width` in spaces per level.
+    #[default]
+    Spaces,
+    /// One tab per level.
+    Tabs,
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
 pub struct RenderConfig {
     pub mode: RenderMode,
+    /// Copies nodes that are not dirty verbatim from the program's raw tokens
+    /// instead of re-rendering them.
     pub reuse_token_ranges: bool,
+    /// Column limit that pretty rendering breaks long expressions to meet.
+    pub max_line_length: usize,
+    pub indent_style: IndentStyle,
+    /// Quotes rendered strings are put in unless that takes more escapes.
+    /// `None` keeps the quotes each string was written with.
+    pub quote_style: Option<QuoteStyle>,
+    /// Blank lines around top-level functions and classes, which take the
+    /// comments right above them along. `None` keeps the program's.
+    pub top_level_blank_lines: Option<usize>,
+    /// Puts spaces around binary operators and symbolic comparisons, and
+    /// around the colons of slices with bounds other than names and
+    /// literals.
+    pub spaces_around_operators: bool,
+    /// Leaves the spaces out around `**` between names, numbers and
+    /// attributes of names, which may be negated.
+    pub hug_power_operands: bool,
+    /// Puts a space after commas that separate elements.
+    pub space_after_comma: bool,
+    /// Drops parentheses that change neither how an expression parses nor
+    /// what it means, such as those around names, calls and conditions.
+    pub remove_redundant_parens: bool,
 }
 
 impl Default for RenderConfig {
@@ -1570,10 +2180,46 @@ impl Default for RenderConfig {
         Self {
             mode: RenderMode::Lossless,
             reuse_token_ranges: false,
+            max_line_length: 79,
+            indent_style: IndentStyle::Spaces,
+            quote_style: None,
+            top_level_blank_lines: None,
+            spaces_around_operators: true,
+            hug_power_operands: false,
+            space_after_comma: true,
+            remove_redundant_parens: false,
         }
     }
 }
 
+/// Python rendered from a program, with where each node ended up in it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RenderedPython {
+    pub source: String,
+    /// Span of the text rendered for each node. Statements span their
+    /// lines up to and including the line break, and expressions that had
+    /// to be put in parentheses span the parentheses too.
+    pub source_map: BTreeMap<NodeId, Span>,
+}
+
+/// Top-level statements that re-parsing a program after an edit replaced:
+/// the old body's statements `start..old_end` became the new body's
+/// `start..new_end`, and the statements after them only moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReparsedRange {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+/// Replaces the source bytes `start..end` with `text`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TextEdit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct ParseError {
     pub message: String,
@@ -1605,6 +2251,32 @@ impl fmt::Display for ConvertError {
 
 impl std::error::Error for ConvertError {}
 
+#[derive(Debug, Clone)]
+pub struct PatchError {
+    pub message: String,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}", self.message)
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+#[derive(Debug, Clone)]
+pub struct DecodeError {
+    pub message: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}", self.message)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum TokenTag {
     Identifier,
@@ -1677,6 +2349,7 @@ impl Program {
             tokens: Vec::new(),
             raw_tokens: Vec::new(),
             dirty: false,
+            source_format: SourceFormat::default(),
         }
     }
 }
@@ -1694,6 +2367,7 @@ pub fn python_to_ir(program: &Program) -> IrProgram {
             })
         },
         dirty: program.dirty,
+        source_format: program.source_format.clone(),
     }
 }
 
@@ -1707,6 +2381,7 @@ pub fn ir_to_python(ir: &IrProgram, features: &FeatureSet) -> Result<Program, Co
     }
     let mut program = Program::empty(ir.indent_width);
     program.meta = ir.meta.clone();
+    program.source_format = ir.source_format.clone();
     program.body = ir
         .body
         .iter()
@@ -1717,49 +2392,118 @@ pub fn ir_to_python(ir: &IrProgram, features: &FeatureSet) -> Result<Program, Co
         .as_ref()
         .map(|store| store.raw_tokens.clone())
         .unwrap_or_default();
-    program.dirty = ir.dirty || program.raw_tokens.is_empty();
+    program.dirty =
+        ir.dirty || program.raw_tokens.is_empty() || program.body.iter().any(stmt_is_dirty);
     Ok(program)
 }
 
-fn ir_contains_match(stmt: &IrStmt) -> bool {
-    match stmt {
-        IrStmt::Match(_) => true,
-        IrStmt::If(stmt) => {
-            block_has_match(&stmt.body)
-                || stmt.elifs.iter().any(|elif| block_has_match(&elif.body))
-                || stmt
-                    .else_body
-                    .as_ref()
-                    .map(block_has_match)
-                    .unwrap_or(false)
-        }
-        IrStmt::While(stmt) => block_has_match(&stmt.body)
-            || stmt.else_body.as_ref().map(block_has_match).unwrap_or(false),
-        IrStmt::For(stmt) => block_has_match(&stmt.body)
-            || stmt.else_body.as_ref().map(block_has_match).unwrap_or(false),
-        IrStmt::FunctionDef(stmt) => block_has_match(&stmt.body),
-        IrStmt::ClassDef(stmt) => block_has_match(&stmt.body),
-        IrStmt::Try(stmt) => {
-            block_has_match(&stmt.body)
-                || stmt.handlers.iter().any(|h| block_has_match(&h.body))
-                || stmt
-                    .else_body
-                    .as_ref()
-                    .map(block_has_match)
-                    .unwrap_or(false)
-                || stmt
-                    .finally_body
-                    .as_ref()
-                    .map(block_has_match)
-                    .unwrap_or(false)
+/// Parses `source` as a single statement, to be put into a program.
+/// Comment lines around it are left out, since the IR holds them as
+/// statements of their own.
+///
+/// The nodes of the statement are dirty and have no ids yet, since their
+/// spans point into `source` rather than into that program.
+pub fn parse_stmt_to_ir(source: &str, config: ParserConfig) -> Result<IrStmt, ParseError> {
+    let (mut stmt, _) = parse_snippet(source, config)?;
+    stmt_metas_mut(&mut stmt, &mut |meta| {
+        meta.id = NodeId(0);
+        meta.dirty = true;
+    });
+    Ok(stmt_to_ir(&stmt))
+}
+
+/// Parses `source` as a single expression, to be put into a program, with
+/// nodes marked like those `parse_stmt_to_ir` returns. Fails on comments
+/// outside brackets, which an expression has nowhere to keep.
+pub fn parse_expr_to_ir(source: &str, config: ParserConfig) -> Result<IrExpr, ParseError> {
+    let (stmt, comments) = parse_snippet(source, config)?;
+    let mut expr = match stmt {
+        Stmt::Expr(stmt) => match comments.first() {
+            Some(comment) => return Err(comment_in_expr(render::stmt_meta(comment).span)),
+            None if stmt.meta.comment.is_some() => return Err(comment_in_expr(stmt.meta.span)),
+            None => stmt.expr,
+        },
+        stmt => {
+            return Err(ParseError {
+                message: "expected an expression".to_string(),
+                span: render::stmt_meta(&stmt).span,
+            })
         }
-        IrStmt::Assign(_) | IrStmt::AugAssign(_) | IrStmt::Expr(_) | IrStmt::Pass(_) | IrStmt::Return(_) | IrStmt::Break(_) | IrStmt::Continue(_) | IrStmt::Empty(_) | IrStmt::Import(_) | IrStmt::Assert(_) | IrStmt::Raise(_) | IrStmt::Del(_) | IrStmt::Global(_) | IrStmt::Nonlocal(_) | IrStmt::AnnAssign(_) => false,
-        IrStmt::With(stmt) => block_has_match(&stmt.body),
+    };
+    expr_metas_mut(&mut expr, &mut |meta| {
+        meta.id = NodeId(0);
+        meta.dirty = true;
+    });
+    Ok(expr_to_ir(&expr))
+}
+
+fn comment_in_expr(span: Span) -> ParseError {
+    ParseError {
+        message: "an expression cannot hold a comment".to_string(),
+        span,
+    }
+}
+
+/// The only statement of `source`, apart from blank lines and comment
+/// lines, with the comment lines.
+fn parse_snippet(source: &str, config: ParserConfig) -> Result<(Stmt, Vec<Stmt>), ParseError> {
+    let program = Program::parse(source.to_string(), config)?;
+    let (comments, statements): (Vec<_>, Vec<_>) = program
+        .body
+        .into_iter()
+        .filter(|stmt| !matches!(stmt, Stmt::Empty(_)))
+        .partition(|stmt| matches!(stmt, Stmt::Comment(_)));
+    let mut statements = statements.into_iter();
+    let Some(stmt) = statements.next() else {
+        return Err(ParseError {
+            message: "expected a statement".to_string(),
+            span: Span::default(),
+        });
+    };
+    if let Some(extra) = statements.next() {
+        return Err(ParseError {
+            message: "expected a single statement".to_string(),
+            span: render::stmt_meta(&extra).span,
+        });
     }
+    Ok((stmt, comments))
 }
 
-fn block_has_match(block: &IrBlock) -> bool {
-    block.statements.iter().any(ir_contains_match)
+/// Converts a statement from the IR, such as one that `parse_stmt_to_ir`
+/// returned or a program holds, as `ir_to_python` would.
+pub fn ir_stmt_to_python(stmt: &IrStmt, features: &FeatureSet) -> Result<Stmt, ConvertError> {
+    if !features.match_stmt && ir_contains_match(stmt) {
+        return Err(ConvertError {
+            message: "match is disabled".to_string(),
+        });
+    }
+    Ok(stmt_from_ir_with_indent(stmt, 0))
+}
+
+pub fn ir_expr_to_python(expr: &IrExpr) -> Expr {
+    expr_from_ir(expr)
+}
+
+/// Converts a statement of a program to the IR, as `python_to_ir` would.
+pub fn python_stmt_to_ir(stmt: &Stmt) -> IrStmt {
+    stmt_to_ir(stmt)
+}
+
+fn ir_contains_match(stmt: &IrStmt) -> bool {
+    struct FindMatch(bool);
+
+    impl IrVisitor for FindMatch {
+        fn visit_stmt(&mut self, stmt: &IrStmt) {
+            match stmt {
+                IrStmt::Match(_) => self.0 = true,
+                stmt => self.walk_stmt(stmt),
+            }
+        }
+    }
+
+    let mut find = FindMatch(false);
+    find.visit_stmt(stmt);
+    find.0
 }
 
 fn stmt_to_ir(stmt: &Stmt) -> IrStmt {
@@ -1864,6 +2608,10 @@ fn stmt_to_ir(stmt: &Stmt) -> IrStmt {
             meta: stmt.meta.clone(),
             source: stmt.source.clone(),
         }),
+        Stmt::Comment(stmt) => IrStmt::Comment(IrCommentStmt {
+            meta: stmt.meta.clone(),
+            text: stmt.text.clone(),
+        }),
         Stmt::Import(stmt) => IrStmt::Import(IrImportStmt {
             meta: stmt.meta.clone(),
             module: stmt.module.clone(),
@@ -2033,6 +2781,10 @@ fn stmt_from_ir_with_indent(stmt: &IrStmt, indent_level: usize) -> Stmt {
             meta: stmt.meta.clone(),
             source: stmt.source.clone(),
         }),
+        IrStmt::Comment(stmt) => Stmt::Comment(CommentStmt {
+            meta: stmt.meta.clone(),
+            text: stmt.text.clone(),
+        }),
         IrStmt::Import(stmt) => Stmt::Import(ImportStmt {
             meta: stmt.meta.clone(),
             module: stmt.module.clone(),
@@ -2286,7 +3038,7 @@ fn expr_to_ir(expr: &Expr) -> IrExpr {
                 FStringPart::Literal(s) => IrFStringPart::Literal(s.clone()),
                 FStringPart::Expr(e) => IrFStringPart::Expr(expr_to_ir(e)),
             }).collect(),
-            quote: expr.quote.clone(),
+            quote: expr.quote,
         }),
         Expr::NamedExpr(expr) => IrExpr::NamedExpr(IrNamedExprData {
             meta: expr.meta.clone(),
@@ -2473,7 +3225,7 @@ fn expr_from_ir(expr: &IrExpr) -> Expr {
                 IrFStringPart::Literal(s) => FStringPart::Literal(s.clone()),
                 IrFStringPart::Expr(e) => FStringPart::Expr(expr_from_ir(e)),
             }).collect(),
-            quote: expr.quote.clone(),
+            quote: expr.quote,
         }),
         IrExpr::NamedExpr(expr) => Expr::NamedExpr(NamedExprData {
             meta: expr.meta.clone(),
@@ -2510,3 +3262,119 @@ fn pattern_from_ir(pattern: &IrPattern) -> Pattern {
         IrPattern::Literal(pattern) => Pattern::Literal(pattern.clone()),
     }
 }
+
+/// Calls its function with the metadata of every node it visits.
+struct MetasMut<'a>(&'a mut dyn FnMut(&mut NodeMeta));
+
+impl VisitorMut for MetasMut<'_> {
+    fn visit_meta(&mut self, meta: &mut NodeMeta) {
+        (self.0)(meta);
+    }
+}
+
+fn stmt_metas_mut(stmt: &mut Stmt, f: &mut dyn FnMut(&mut NodeMeta)) {
+    MetasMut(f).visit_stmt(stmt);
+}
+
+fn expr_metas_mut(expr: &mut Expr, f: &mut dyn FnMut(&mut NodeMeta)) {
+    MetasMut(f).visit_expr(expr);
+}
+
+/// Whether a node needs rendering rather than copying from its source:
+/// it is marked dirty or, having no span, did not come from the source.
+fn meta_is_dirty(meta: &NodeMeta) -> bool {
+    meta.dirty || meta.span.start.offset >= meta.span.end.offset
+}
+
+/// Looks for a dirty node among those it visits.
+struct FindDirty(bool);
+
+impl Visitor for FindDirty {
+    fn visit_meta(&mut self, meta: &NodeMeta) {
+        self.0 |= meta_is_dirty(meta);
+    }
+}
+
+/// Whether `stmt` or any node inside it is dirty.
+fn stmt_is_dirty(stmt: &Stmt) -> bool {
+    let mut find = FindDirty(false);
+    find.visit_stmt(stmt);
+    find.0
+}
+
+/// Whether `expr` or any node inside it is dirty.
+fn expr_is_dirty(expr: &Expr) -> bool {
+    let mut find = FindDirty(false);
+    find.visit_expr(expr);
+    find.0
+}
+
+fn expr_meta(expr: &Expr) -> &NodeMeta {
+    match expr {
+        Expr::Identifier(expr) => &expr.meta,
+        Expr::Literal(expr) => &expr.meta,
+        Expr::Binary(expr) => &expr.meta,
+        Expr::Unary(expr) => &expr.meta,
+        Expr::BoolOp(expr) => &expr.meta,
+        Expr::Compare(expr) => &expr.meta,
+        Expr::Lambda(expr) => &expr.meta,
+        Expr::IfExpr(expr) => &expr.meta,
+        Expr::Call(expr) => &expr.meta,
+        Expr::Tuple(expr) => &expr.meta,
+        Expr::Attribute(expr) => &expr.meta,
+        Expr::Subscript(expr) => &expr.meta,
+        Expr::Slice(expr) => &expr.meta,
+        Expr::Grouped(expr) => &expr.meta,
+        Expr::List(expr) => &expr.meta,
+        Expr::Dict(expr) => &expr.meta,
+        Expr::Set(expr) => &expr.meta,
+        Expr::Comprehension(ComprehensionExpr::List(expr)) => &expr.meta,
+        Expr::Comprehension(ComprehensionExpr::Set(expr)) => &expr.meta,
+        Expr::Comprehension(ComprehensionExpr::Generator(expr)) => &expr.meta,
+        Expr::Comprehension(ComprehensionExpr::Dict(expr)) => &expr.meta,
+        Expr::FString(expr) => &expr.meta,
+        Expr::NamedExpr(expr) => &expr.meta,
+        Expr::Yield(expr) => &expr.meta,
+        Expr::YieldFrom(expr) => &expr.meta,
+        Expr::Await(expr) => &expr.meta,
+    }
+}
+
+fn expr_meta_mut(expr: &mut Expr) -> &mut NodeMeta {
+    match expr {
+        Expr::Identifier(expr) => &mut expr.meta,
+        Expr::Literal(expr) => &mut expr.meta,
+        Expr::Binary(expr) => &mut expr.meta,
+        Expr::Unary(expr) => &mut expr.meta,
+        Expr::BoolOp(expr) => &mut expr.meta,
+        Expr::Compare(expr) => &mut expr.meta,
+        Expr::Lambda(expr) => &mut expr.meta,
+        Expr::IfExpr(expr) => &mut expr.meta,
+        Expr::Call(expr) => &mut expr.meta,
+        Expr::Tuple(expr) => &mut expr.meta,
+        Expr::Attribute(expr) => &mut expr.meta,
+        Expr::Subscript(expr) => &mut expr.meta,
+        Expr::Slice(expr) => &mut expr.meta,
+        Expr::Grouped(expr) => &mut expr.meta,
+        Expr::List(expr) => &mut expr.meta,
+        Expr::Dict(expr) => &mut expr.meta,
+        Expr::Set(expr) => &mut expr.meta,
+        Expr::Comprehension(ComprehensionExpr::List(expr)) => &mut expr.meta,
+        Expr::Comprehension(ComprehensionExpr::Set(expr)) => &mut expr.meta,
+        Expr::Comprehension(ComprehensionExpr::Generator(expr)) => &mut expr.meta,
+        Expr::Comprehension(ComprehensionExpr::Dict(expr)) => &mut expr.meta,
+        Expr::FString(expr) => &mut expr.meta,
+        Expr::NamedExpr(expr) => &mut expr.meta,
+        Expr::Yield(expr) => &mut expr.meta,
+        Expr::YieldFrom(expr) => &mut expr.meta,
+        Expr::Await(expr) => &mut expr.meta,
+    }
+}
+
+fn pattern_meta(pattern: &Pattern) -> &NodeMeta {
+    match pattern {
+        Pattern::Wildcard(meta) => meta,
+        Pattern::Identifier(pattern) => &pattern.meta,
+        Pattern::Literal(pattern) => &pattern.meta,
+    }
+}
@@ -0,0 +1,317 @@
+use super::render::stmt_meta;
+use super::*;
+use std::cmp::Reverse;
+
+impl SyntaxTree {
+    /// Builds the syntax tree of a parsed program from its raw tokens. A
+    /// program built from the IR has no raw tokens and gives an empty tree.
+    pub fn new(program: Program) -> Self {
+        let mut extents = Extents {
+            tokens: &program.raw_tokens,
+            depth: 0,
+            found: Vec::new(),
+        };
+        extents.visit_program(&program);
+        let mut found = extents.found;
+        found.sort_by_key(|extent| (extent.start, Reverse(extent.end), extent.depth));
+        let root = build(program.meta.id, &found, &program.raw_tokens);
+        SyntaxTree { program, root }
+    }
+
+    pub fn text(&self) -> String {
+        self.root.text()
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&SyntaxNode> {
+        self.root.find(id)
+    }
+
+    /// Replaces the source of the node `id` with `text` and returns the edit
+    /// this made to the source. The whitespace and comments around the
+    /// node's code stay, and so does the rest of the tree; statements
+    /// outside the edited lines keep their ids. Fails without change when
+    /// the node does not exist or the new source does not parse.
+    pub fn replace(
+        &mut self,
+        id: NodeId,
+        text: &str,
+        config: ParserConfig,
+    ) -> Result<TextEdit, ParseError> {
+        let node = self.node(id).ok_or_else(|| ParseError {
+            message: format!("no syntax node with id {}", id.0),
+            span: Span::default(),
+        })?;
+        let tokens = node.tokens();
+        let spans = |comments: bool| -> Vec<Span> {
+            tokens
+                .iter()
+                .filter(|token| {
+                    !token.raw.is_empty()
+                        && match &token.kind {
+                            TokenKind::Comment(_) => comments,
+                            kind => !kind.is_trivia() && *kind != TokenKind::Newline,
+                        }
+                })
+                .map(|token| token.span)
+                .collect()
+        };
+        // A node of comments alone, such as a comment statement, has them
+        // replaced, and a blank line gets the text before its line break.
+        let mut code = spans(false);
+        if code.is_empty() {
+            code = spans(true);
+        }
+        let (start, end) = match (code.first(), code.last()) {
+            (Some(first), Some(last)) => (first.start.offset, last.end.offset),
+            _ => {
+                let at = tokens
+                    .iter()
+                    .find(|token| token.kind == TokenKind::Newline)
+                    .map(|token| token.span.start.offset)
+                    .or_else(|| tokens.last().map(|token| token.span.end.offset))
+                    .unwrap_or(0);
+                (at, at)
+            }
+        };
+        let edit = TextEdit {
+            start,
+            end,
+            text: text.to_string(),
+        };
+        let program = self.program.reparse(&edit, config)?;
+        *self = SyntaxTree::new(program);
+        Ok(edit)
+    }
+}
+
+impl SyntaxNode {
+    pub fn text(&self) -> String {
+        self.tokens()
+            .into_iter()
+            .map(|token| token.raw.as_str())
+            .collect()
+    }
+
+    /// Raw tokens of the node and its descendants, in source order.
+    pub fn tokens(&self) -> Vec<&LexToken> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    pub fn find(&self, id: NodeId) -> Option<&SyntaxNode> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| match child {
+            SyntaxElement::Node(node) => node.find(id),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a LexToken>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+}
+
+/// Source offsets a node owns, and how deep it is in the AST.
+struct Extent {
+    id: NodeId,
+    kind: SyntaxKind,
+    start: usize,
+    end: usize,
+    depth: usize,
+}
+
+/// Finds the extent of every node that gets a syntax node.
+struct Extents<'a> {
+    tokens: &'a [LexToken],
+    depth: usize,
+    found: Vec<Extent>,
+}
+
+impl Extents<'_> {
+    fn add(&mut self, meta: &NodeMeta, kind: SyntaxKind, (start, end): (usize, usize)) {
+        if start < end {
+            self.found.push(Extent {
+                id: meta.id,
+                kind,
+                start,
+                end,
+                depth: self.depth,
+            });
+        }
+    }
+
+    fn nested(&mut self, walk: impl FnOnce(&mut Self)) {
+        self.depth += 1;
+        walk(self);
+        self.depth -= 1;
+    }
+
+    /// From the start of the statement's first line, decorators included,
+    /// to the line break after its last token.
+    fn stmt_extent(&self, stmt: &Stmt) -> (usize, usize) {
+        let span = stmt_meta(stmt).span;
+        let tokens = self.tokens;
+        let line = match stmt {
+            Stmt::FunctionDef(FunctionDefStmt { decorators, .. })
+            | Stmt::ClassDef(ClassDefStmt { decorators, .. }) => {
+                decorators.first().map_or(span.start.line, |decorator| {
+                    expr_meta(decorator).span.start.line
+                })
+            }
+            _ => span.start.line,
+        };
+        let first = tokens.partition_point(|token| token.span.start.line < line);
+        let start = tokens
+            .get(first)
+            .map_or(span.start.offset, |token| token.span.start.offset);
+        // Spans of statements with blocks reach into the indentation of the
+        // line after them, so the end is found from the last token that is
+        // not whitespace.
+        let mut last = tokens.partition_point(|token| token.span.end.offset <= span.end.offset);
+        while last > 0 && is_filler(&tokens[last - 1]) {
+            last -= 1;
+        }
+        if last > 0 && tokens[last - 1].kind != TokenKind::Newline {
+            while let Some(token) = tokens.get(last) {
+                match token.kind {
+                    TokenKind::Newline => {
+                        last += 1;
+                        break;
+                    }
+                    TokenKind::Comment(_) | TokenKind::RawWhitespace(_) | TokenKind::Blank(_) => {
+                        last += 1
+                    }
+                    _ => break,
+                }
+            }
+        }
+        let end = last
+            .checked_sub(1)
+            .and_then(|index| tokens.get(index))
+            .map_or(span.end.offset, |token| token.span.end.offset);
+        (start, end.max(start))
+    }
+}
+
+impl Visitor for Extents<'_> {
+    fn visit_block(&mut self, block: &Block) {
+        if let (Some(first), Some(last)) = (block.statements.first(), block.statements.last()) {
+            let extent = (self.stmt_extent(first).0, self.stmt_extent(last).1);
+            self.add(&block.meta, SyntaxKind::Block, extent);
+        }
+        self.nested(|extents| extents.walk_block(block));
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        self.add(stmt_meta(stmt), SyntaxKind::Stmt, self.stmt_extent(stmt));
+        self.nested(|extents| extents.walk_stmt(stmt));
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        let span = expr_meta(expr).span;
+        self.add(
+            expr_meta(expr),
+            SyntaxKind::Expr,
+            (span.start.offset, span.end.offset),
+        );
+        // Expressions embedded in an f-string share the span of its token.
+        if !matches!(expr, Expr::FString(_)) {
+            self.nested(|extents| extents.walk_expr(expr));
+        }
+    }
+
+    fn visit_pattern(&mut self, pattern: &Pattern) {
+        let meta = pattern_meta(pattern);
+        let span = meta.span;
+        self.add(
+            meta,
+            SyntaxKind::Pattern,
+            (span.start.offset, span.end.offset),
+        );
+    }
+
+    fn visit_comprehension_for(&mut self, comprehension: &ComprehensionFor) {
+        let span = comprehension.meta.span;
+        self.add(
+            &comprehension.meta,
+            SyntaxKind::ComprehensionFor,
+            (span.start.offset, span.end.offset),
+        );
+        self.nested(|extents| extents.walk_comprehension_for(comprehension));
+    }
+}
+
+/// Whitespace or a token without text.
+fn is_filler(token: &LexToken) -> bool {
+    token.raw.is_empty()
+        || matches!(
+            token.kind,
+            TokenKind::RawWhitespace(_) | TokenKind::Indentation(_)
+        )
+}
+
+/// Hands each token to the innermost extent it starts in. `extents` are
+/// sorted outermost first; an extent that does not start at a token or
+/// does not fit in the node around it gets no node, and its tokens go to
+/// that node.
+fn build(id: NodeId, extents: &[Extent], tokens: &[LexToken]) -> SyntaxNode {
+    let mut tree = Builder {
+        root: SyntaxNode {
+            id,
+            kind: SyntaxKind::Program,
+            children: Vec::new(),
+        },
+        open: Vec::new(),
+    };
+    let mut pending = extents.iter().peekable();
+    for token in tokens {
+        let offset = token.span.start.offset;
+        tree.close(offset);
+        while let Some(extent) = pending.next_if(|extent| extent.start <= offset) {
+            let end = tree.open.last().map_or(usize::MAX, |(_, end)| *end);
+            if extent.start == offset && extent.end <= end {
+                let node = SyntaxNode {
+                    id: extent.id,
+                    kind: extent.kind,
+                    children: Vec::new(),
+                };
+                tree.open.push((node, extent.end));
+            }
+        }
+        tree.innermost()
+            .children
+            .push(SyntaxElement::Token(token.clone()));
+    }
+    tree.close(usize::MAX);
+    tree.root
+}
+
+struct Builder {
+    root: SyntaxNode,
+    /// Nodes still taking tokens, with the offset they end at.
+    open: Vec<(SyntaxNode, usize)>,
+}
+
+impl Builder {
+    fn innermost(&mut self) -> &mut SyntaxNode {
+        match self.open.last_mut() {
+            Some((node, _)) => node,
+            None => &mut self.root,
+        }
+    }
+
+    /// Finishes the nodes ending at or before `offset`.
+    fn close(&mut self, offset: usize) {
+        while let Some((node, _)) = self.open.pop_if(|(_, end)| *end <= offset) {
+            self.innermost().children.push(SyntaxElement::Node(node));
+        }
+    }
+}
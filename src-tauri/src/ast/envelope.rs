@@ -0,0 +1,215 @@
+use super::*;
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Last handle given to a token store. Handles are unique across
+/// documents, so one document never decodes with another's tokens.
+static LAST_TOKEN_HANDLE: AtomicU64 = AtomicU64::new(0);
+
+/// Migrations from each IR version to the next, starting with version 1.
+const MIGRATIONS: [fn(Value) -> Result<Value, DecodeError>; IR_VERSION as usize - 1] =
+    [own_comments];
+
+/// Position fields of a span, in the order a compact span lists them.
+const SPAN_FIELDS: [&[&str]; 6] = [
+    &["start", "line"],
+    &["start", "column"],
+    &["start", "offset"],
+    &["end", "line"],
+    &["end", "column"],
+    &["end", "offset"],
+];
+
+const TOKEN_RANGE_FIELDS: [&[&str]; 2] = [&["start"], &["end"]];
+
+impl TokenStores {
+    pub const fn new() -> Self {
+        Self { stores: Vec::new() }
+    }
+
+    pub fn insert(&mut self, store: TokenStore) -> TokenHandle {
+        let handle = TokenHandle(LAST_TOKEN_HANDLE.fetch_add(1, Ordering::Relaxed) + 1);
+        self.stores.push((handle, store));
+        handle
+    }
+
+    pub fn get(&self, handle: TokenHandle) -> Option<&TokenStore> {
+        self.stores
+            .iter()
+            .find(|(kept, _)| *kept == handle)
+            .map(|(_, store)| store)
+    }
+
+    /// Drops every store, once the source they were taken from is gone.
+    pub fn clear(&mut self) {
+        self.stores.clear();
+    }
+}
+
+/// Wraps `program` in an envelope of the current version, keeping its token
+/// store in `stores` if `options` detach it.
+pub fn encode_ir(
+    mut program: IrProgram,
+    options: IrEncodeOptions,
+    stores: &mut TokenStores,
+) -> IrEnvelope {
+    let token_handle = if options.detach_tokens {
+        program.token_store.take().map(|store| stores.insert(store))
+    } else {
+        None
+    };
+    let mut value = serde_json::to_value(&program).expect("the IR serializes to JSON");
+    if options.encoding == IrEncoding::Compact {
+        compact(&mut value);
+    }
+    IrEnvelope {
+        version: IR_VERSION,
+        encoding: options.encoding,
+        token_handle,
+        program: value,
+    }
+}
+
+/// Reads the program out of `envelope`, taking a detached token store back
+/// from `stores` and migrating it from older IR versions.
+pub fn decode_ir(envelope: IrEnvelope, stores: &TokenStores) -> Result<IrProgram, DecodeError> {
+    if envelope.version == 0 || envelope.version > IR_VERSION {
+        return Err(DecodeError {
+            message: format!("unsupported IR version {}", envelope.version),
+        });
+    }
+    let mut value = envelope.program;
+    if envelope.encoding == IrEncoding::Compact {
+        expand(&mut value);
+    }
+    if let Some(handle) = envelope.token_handle {
+        let store = stores.get(handle).ok_or_else(|| DecodeError {
+            message: format!(
+                "token store {} is not kept; its document changed or closed",
+                handle.0
+            ),
+        })?;
+        value["token_store"] = serde_json::to_value(store).map_err(decode_error)?;
+    }
+    for migration in &MIGRATIONS[envelope.version as usize - 1..] {
+        value = migration(value)?;
+    }
+    serde_json::from_value(value).map_err(decode_error)
+}
+
+fn decode_error(error: serde_json::Error) -> DecodeError {
+    DecodeError {
+        message: error.to_string(),
+    }
+}
+
+/// Version 1 kept comments as trivia only, with blank statements standing in
+/// for comment lines. The token store still has them; without one they were
+/// lost already.
+fn own_comments(value: Value) -> Result<Value, DecodeError> {
+    let ir: IrProgram = serde_json::from_value(value).map_err(decode_error)?;
+    if ir.token_store.is_none() {
+        return serde_json::to_value(&ir).map_err(decode_error);
+    }
+    let features = FeatureSet::from_version(PythonVersion::Py310);
+    let mut program = ir_to_python(&ir, &features).map_err(|error| DecodeError {
+        message: error.message,
+    })?;
+    program.own_comments();
+    program.dirty = ir.dirty;
+    serde_json::to_value(python_to_ir(&program)).map_err(decode_error)
+}
+
+fn compact(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(compact),
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                compact(field);
+                let fields: &[&[&str]] = match key.as_str() {
+                    "span" => &SPAN_FIELDS,
+                    "token_range" => &TOKEN_RANGE_FIELDS,
+                    _ => continue,
+                };
+                if let Some(numbers) = fields
+                    .iter()
+                    .map(|path| path.iter().try_fold(&*field, |value, key| value.get(key)))
+                    .collect::<Option<Vec<_>>>()
+                {
+                    *field = Value::Array(numbers.into_iter().cloned().collect());
+                }
+            }
+            if map.contains_key("id") && map.contains_key("token_range") {
+                // Node metadata; serde fills the fields back in.
+                map.retain(|key, field| match key.as_str() {
+                    "leading_trivia" | "trailing_trivia" => field != &Value::Array(Vec::new()),
+                    "comment" => !field.is_null(),
+                    "dirty" => field != &Value::Bool(false),
+                    _ => true,
+                });
+            }
+            let tag = match map.get("kind") {
+                Some(Value::String(tag))
+                    if map.keys().all(|key| key == "kind" || key == "data") =>
+                {
+                    tag.clone()
+                }
+                _ => return,
+            };
+            let data = map.remove("data").unwrap_or(Value::Null);
+            map.clear();
+            map.insert(tag, data);
+        }
+        _ => {}
+    }
+}
+
+fn expand(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(expand),
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                expand(field);
+                let fields: &[&[&str]] = match key.as_str() {
+                    "span" => &SPAN_FIELDS,
+                    "token_range" => &TOKEN_RANGE_FIELDS,
+                    _ => continue,
+                };
+                let Value::Array(numbers) = field else {
+                    continue;
+                };
+                if numbers.len() != fields.len() {
+                    continue;
+                }
+                let mut nested = Value::Null;
+                for (path, number) in fields.iter().zip(numbers.drain(..)) {
+                    let mut target = &mut nested;
+                    for key in path.iter() {
+                        target = &mut target[*key];
+                    }
+                    *target = number;
+                }
+                *field = nested;
+            }
+            // Struct fields are snake case, so a single capitalized key is
+            // the variant of an enum.
+            if map.len() != 1 {
+                return;
+            }
+            let Some(tag) = map
+                .keys()
+                .next()
+                .filter(|key| key.starts_with(|first: char| first.is_ascii_uppercase()))
+                .cloned()
+            else {
+                return;
+            };
+            let data = map.remove(&tag).unwrap_or(Value::Null);
+            map.insert("kind".to_string(), Value::String(tag));
+            if !data.is_null() {
+                map.insert("data".to_string(), data);
+            }
+        }
+        _ => {}
+    }
+}
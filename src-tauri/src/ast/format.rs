@@ -0,0 +1,263 @@
+use super::lexer::Lexer;
+use super::render::{blocks_mut, hoist_comments, render_lossless};
+use super::*;
+use std::collections::VecDeque;
+
+/// Line length Black formats to.
+const BLACK_LINE_LENGTH: usize = 88;
+
+/// Formats `source` the way Black does: strings get double quotes where
+/// that takes no more escapes, definitions get two blank lines around them
+/// at the top level and one inside blocks, blocks are indented by four
+/// spaces, brackets that end with a comma in the source keep one element
+/// per line, and parentheses around whole conditions and values go.
+pub fn format_python(source: String, config: ParserConfig) -> Result<String, ParseError> {
+    let mut lexed = Lexer::new(source).lex()?;
+    for token in &mut lexed.tokens {
+        if let TokenKind::Comment(_) = token.kind {
+            token.raw = normalize_comment(&token.raw);
+        }
+    }
+    let mut program = Program::parse(render_lossless(&lexed.tokens), config)?;
+    RedundantParens.visit_program(&mut program);
+    let mut blank_lines = BlankLines {
+        previous: None,
+        definitions: Vec::new(),
+    };
+    let (body, _) = blank_lines.block(std::mem::take(&mut program.body), 0);
+    program.body = body;
+    program.indent_width = 4;
+    Ok(program.to_python(RenderConfig {
+        mode: RenderMode::Pretty,
+        max_line_length: BLACK_LINE_LENGTH,
+        quote_style: Some(QuoteStyle::Double),
+        hug_power_operands: true,
+        ..RenderConfig::default()
+    }))
+}
+
+/// Starts the text of a comment with a space, except for shebangs, type
+/// comments of documentation tools and commented-out comments.
+fn normalize_comment(raw: &str) -> String {
+    let raw = raw.trim_end();
+    let text = raw.strip_prefix('#').unwrap_or(raw);
+    if text.is_empty() || text.starts_with([' ', '!', ':', '#', '\'']) {
+        raw.to_string()
+    } else {
+        format!("# {text}")
+    }
+}
+
+/// Drops the parentheses around the conditions, iterables, returned and
+/// assigned values of statements, which Black makes invisible and only
+/// puts back to split a clause that does not fit on its line.
+struct RedundantParens;
+
+impl VisitorMut for RedundantParens {
+    fn visit_stmt(&mut self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::If(stmt) => {
+                unwrap_parens(&mut stmt.condition);
+                for elif in &mut stmt.elifs {
+                    unwrap_parens(&mut elif.condition);
+                }
+            }
+            Stmt::While(stmt) => unwrap_parens(&mut stmt.condition),
+            Stmt::For(stmt) => unwrap_parens(&mut stmt.iterable),
+            Stmt::Return(ReturnStmt {
+                value: Some(value), ..
+            }) => unwrap_parens(value),
+            Stmt::Assign(stmt) => unwrap_parens(&mut stmt.value),
+            Stmt::AugAssign(stmt) => unwrap_parens(&mut stmt.value),
+            _ => {}
+        }
+        self.walk_stmt(stmt);
+    }
+}
+
+/// Takes `expr` out of its parentheses, unless they make a tuple, a
+/// `yield` or an assignment expression valid or hold comments.
+fn unwrap_parens(expr: &mut Expr) {
+    while let Expr::Grouped(grouped) = expr {
+        if matches!(
+            *grouped.expr,
+            Expr::Tuple(_) | Expr::Yield(_) | Expr::YieldFrom(_) | Expr::NamedExpr(_)
+        ) {
+            return;
+        }
+        let mut comments = false;
+        expr_metas_mut(&mut grouped.expr.clone(), &mut |meta| {
+            comments |= meta
+                .leading_trivia
+                .iter()
+                .chain(&meta.trailing_trivia)
+                .any(|trivia| matches!(trivia.kind, TriviaKind::Comment(_)));
+        });
+        if comments {
+            return;
+        }
+        let inner = (*grouped.expr).clone();
+        *expr = inner;
+    }
+}
+
+/// What a line is to Black's blank line rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    /// The header of a function or class, or its first decorator.
+    Definition,
+    Import,
+    Comment,
+    /// The header of any other statement with a block.
+    Opener,
+    /// A header continuing a statement, such as `else:`.
+    Clause,
+    Other,
+}
+
+/// Replaces the blank lines between statements with the ones Black keeps
+/// or adds, going through the lines of the program in order.
+struct BlankLines {
+    /// Kind and depth of the last line laid out.
+    previous: Option<(LineKind, usize)>,
+    /// Depths of the definitions whose bodies the lines may still be in.
+    definitions: Vec<usize>,
+}
+
+impl BlankLines {
+    /// Lays out `statements` at `depth`, returning them with their blank
+    /// lines replaced and the number of blank lines they ended with.
+    fn block(&mut self, statements: Vec<Stmt>, depth: usize) -> (Vec<Stmt>, usize) {
+        let mut pending = VecDeque::from(statements);
+        let mut laid_out: Vec<(usize, Stmt)> = Vec::new();
+        let mut blanks = 0;
+        while let Some(mut stmt) = pending.pop_front() {
+            let Some(kind) = self.line_kind(&stmt) else {
+                blanks += 1;
+                continue;
+            };
+            for hoisted in hoist_comments(&mut stmt).into_iter().rev() {
+                pending.push_front(hoisted);
+            }
+            let mut before = self.before(kind, depth, blanks);
+            if kind == LineKind::Definition && blanks == 0 {
+                // Comments right above a definition belong to it, so the
+                // blank lines go above the comments.
+                let mut start = laid_out.len();
+                while start > 0 && matches!(laid_out[start - 1].1, Stmt::Comment(_)) {
+                    start -= 1;
+                    if laid_out[start].0 > 0 {
+                        break;
+                    }
+                }
+                if start < laid_out.len() {
+                    if start > 0 {
+                        laid_out[start].0 = before;
+                    }
+                    before = 0;
+                }
+            }
+            self.previous = Some((kind, depth));
+            blanks = self.blocks(&mut stmt, depth);
+            laid_out.push((before, stmt));
+        }
+        let mut output = Vec::new();
+        for (before, stmt) in laid_out {
+            output.extend((0..before).map(|_| blank_line()));
+            output.push(stmt);
+        }
+        (output, blanks)
+    }
+
+    /// Lays out the blocks of `stmt`, whose header is at `depth`, returning
+    /// the number of blank lines the last block ended with.
+    fn blocks(&mut self, stmt: &mut Stmt, depth: usize) -> usize {
+        // Case clauses are a level deeper than the `match` line.
+        let clause_depth = if let Stmt::Match(_) = stmt {
+            depth + 1
+        } else {
+            depth
+        };
+        let mut blocks = blocks_mut(stmt);
+        let mut blanks = 0;
+        for index in 0..blocks.len() {
+            if index > 0 {
+                // Blank lines above a clause stay at the end of the block
+                // before it.
+                let before = self.before(LineKind::Clause, clause_depth, blanks);
+                blocks[index - 1]
+                    .statements
+                    .extend((0..before).map(|_| blank_line()));
+            }
+            self.previous = Some((LineKind::Opener, clause_depth));
+            let statements = std::mem::take(&mut blocks[index].statements);
+            let (statements, trailing) = self.block(statements, clause_depth + 1);
+            blocks[index].statements = statements;
+            blanks = trailing;
+        }
+        blanks
+    }
+
+    /// Number of blank lines to put above a line of `kind` at `depth` that
+    /// has `blanks` blank lines above it in the source.
+    fn before(&mut self, kind: LineKind, depth: usize, blanks: usize) -> usize {
+        let mut before = blanks.min(if depth == 0 { 2 } else { 1 });
+        while self
+            .definitions
+            .last()
+            .is_some_and(|&definition| definition >= depth)
+        {
+            self.definitions.pop();
+            before = if depth > 0 || kind == LineKind::Clause {
+                1
+            } else {
+                2
+            };
+        }
+        if kind == LineKind::Definition {
+            self.definitions.push(depth);
+        }
+        let Some((previous, previous_depth)) = self.previous else {
+            return 0;
+        };
+        if previous == LineKind::Opener && previous_depth < depth {
+            return 0;
+        }
+        match kind {
+            LineKind::Definition if depth == 0 => 2,
+            LineKind::Definition => 1,
+            _ if previous == LineKind::Import && kind != LineKind::Import => {
+                if previous_depth == depth {
+                    before.max(1)
+                } else {
+                    before
+                }
+            }
+            _ => before,
+        }
+    }
+
+    /// Kind of the first line of `stmt`, or `None` for a blank line.
+    fn line_kind(&self, stmt: &Stmt) -> Option<LineKind> {
+        Some(match stmt {
+            Stmt::Comment(_) => LineKind::Comment,
+            Stmt::Empty(_) => return None,
+            Stmt::FunctionDef(_) | Stmt::ClassDef(_) => LineKind::Definition,
+            Stmt::Import(_) => LineKind::Import,
+            Stmt::If(_)
+            | Stmt::While(_)
+            | Stmt::For(_)
+            | Stmt::Match(_)
+            | Stmt::Try(_)
+            | Stmt::With(_) => LineKind::Opener,
+            _ => LineKind::Other,
+        })
+    }
+}
+
+fn blank_line() -> Stmt {
+    Stmt::Empty(EmptyStmt {
+        meta: NodeMeta::default(),
+        source: BlankSource::Generated,
+    })
+}
@@ -0,0 +1,332 @@
+use super::*;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+impl Program {
+    /// Carries `NodeId`s over from `previous`, an earlier parse of the same
+    /// document.
+    ///
+    /// Nodes are matched top-down: children of matched nodes are aligned by
+    /// structure first and then paired by kind and position, and subtrees
+    /// that moved unchanged keep their ids as well. Nodes without a
+    /// counterpart get ids that `previous` never used.
+    pub fn reconcile_ids(&mut self, previous: &Program) {
+        // Give every node a unique temporary id so the tree can be rewritten
+        // whatever ids the program arrived with.
+        let mut temporary = 0;
+        self.for_each_meta_mut(&mut |meta| {
+            temporary += 1;
+            meta.id = NodeId(temporary);
+        });
+        let Some(old) = IdTree::build(previous) else {
+            return;
+        };
+        let Some(new) = IdTree::build(self) else {
+            return;
+        };
+
+        let mut matcher = Matcher {
+            assigned: HashMap::new(),
+            used: HashSet::new(),
+        };
+        matcher.match_nodes(&old, &new);
+        matcher.match_moved(&old, &new);
+
+        let mut next_id = max_node_id(previous);
+        let assigned = matcher.assigned;
+        self.for_each_meta_mut(&mut |meta| {
+            meta.id = match assigned.get(&meta.id.0) {
+                Some(&id) => NodeId(id),
+                None => {
+                    next_id += 1;
+                    NodeId(next_id)
+                }
+            };
+        });
+    }
+
+    fn for_each_meta_mut(&mut self, f: &mut dyn FnMut(&mut NodeMeta)) {
+        MetasMut(f).visit_program(self);
+    }
+}
+
+/// Largest `NodeId` used anywhere in `program`.
+pub(super) fn max_node_id(program: &Program) -> u64 {
+    struct MaxId(u64);
+
+    impl Visitor for MaxId {
+        fn visit_meta(&mut self, meta: &NodeMeta) {
+            self.0 = self.0.max(meta.id.0);
+        }
+    }
+
+    let mut max = MaxId(0);
+    max.visit_program(program);
+    max.0
+}
+
+/// A node of the syntax tree reduced to what identity matching needs.
+struct IdTree {
+    id: u64,
+    /// Variant name, or the field name for nodes that are not enum variants.
+    label: String,
+    /// Hash of the subtree ignoring ids, positions and trivia.
+    fingerprint: u64,
+    /// Child nodes grouped by the field they are stored in, in source order.
+    children: Vec<(String, Vec<IdTree>)>,
+}
+
+impl IdTree {
+    fn build(program: &Program) -> Option<IdTree> {
+        let body = serde_json::to_value(&program.body).ok()?;
+        let mut hasher = DefaultHasher::new();
+        let mut children = Vec::new();
+        collect(&body, "body", None, &mut hasher, &mut children);
+        Some(IdTree {
+            id: program.meta.id.0,
+            label: "Program".to_string(),
+            fingerprint: hasher.finish(),
+            children: group_children(children),
+        })
+    }
+}
+
+/// Walks a serialized value, feeding everything except node metadata into
+/// `hasher` and collecting the nodes (objects with a `meta` field) it contains.
+fn collect(
+    value: &Value,
+    field: &str,
+    kind: Option<&str>,
+    hasher: &mut DefaultHasher,
+    nodes: &mut Vec<(String, IdTree)>,
+) {
+    match value {
+        Value::Object(map) if map.contains_key("meta") => {
+            let id = map
+                .get("meta")
+                .and_then(|meta| meta.get("id"))
+                .and_then(Value::as_u64)
+                .unwrap_or_default();
+            let label = kind.unwrap_or(field).to_string();
+            let mut own_hasher = DefaultHasher::new();
+            label.hash(&mut own_hasher);
+            let mut children = Vec::new();
+            for (key, value) in map {
+                if key == "meta" {
+                    continue;
+                }
+                key.hash(&mut own_hasher);
+                collect(value, key, None, &mut own_hasher, &mut children);
+            }
+            let fingerprint = own_hasher.finish();
+            // Blank lines move in and out of blocks as code around them
+            // changes, so they do not count towards the enclosing structure.
+            if label != "Empty" {
+                fingerprint.hash(hasher);
+            }
+            nodes.push((
+                field.to_string(),
+                IdTree {
+                    id,
+                    label,
+                    fingerprint,
+                    children: group_children(children),
+                },
+            ));
+        }
+        Value::Object(map) => {
+            if let (Some(Value::String(tag)), Some(data)) = (map.get("kind"), map.get("data")) {
+                // Node variants hash their tag as their own label.
+                if data.get("meta").is_none() {
+                    tag.hash(hasher);
+                }
+                collect(data, field, Some(tag), hasher, nodes);
+                return;
+            }
+            for (key, value) in map {
+                key.hash(hasher);
+                collect(value, &format!("{field}.{key}"), None, hasher, nodes);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect(item, field, kind, hasher, nodes);
+            }
+        }
+        scalar => scalar.to_string().hash(hasher),
+    }
+}
+
+fn group_children(children: Vec<(String, IdTree)>) -> Vec<(String, Vec<IdTree>)> {
+    let mut groups: Vec<(String, Vec<IdTree>)> = Vec::new();
+    for (field, child) in children {
+        match groups.iter_mut().find(|(name, _)| *name == field) {
+            Some((_, group)) => group.push(child),
+            None => groups.push((field, vec![child])),
+        }
+    }
+    groups
+}
+
+struct Matcher {
+    /// Temporary id of a new node to the id it inherits.
+    assigned: HashMap<u64, u64>,
+    /// Old ids that have been handed out.
+    used: HashSet<u64>,
+}
+
+impl Matcher {
+    fn match_nodes(&mut self, old: &IdTree, new: &IdTree) {
+        self.assigned.insert(new.id, old.id);
+        self.used.insert(old.id);
+        for (field, new_children) in &new.children {
+            let Some((_, old_children)) = old.children.iter().find(|(name, _)| name == field)
+            else {
+                continue;
+            };
+            for (old_index, new_index) in align(old_children, new_children) {
+                self.match_nodes(&old_children[old_index], &new_children[new_index]);
+            }
+        }
+    }
+
+    /// Gives unmatched new subtrees the ids of identical unmatched old
+    /// subtrees, so that cut-and-paste keeps identities.
+    fn match_moved(&mut self, old: &IdTree, new: &IdTree) {
+        let mut candidates: HashMap<u64, Vec<&IdTree>> = HashMap::new();
+        let mut pending = vec![old];
+        while let Some(node) = pending.pop() {
+            if self.used.contains(&node.id) {
+                for (_, children) in &node.children {
+                    pending.extend(children.iter().rev());
+                }
+            } else {
+                candidates.entry(node.fingerprint).or_default().push(node);
+            }
+        }
+        for list in candidates.values_mut() {
+            list.reverse();
+        }
+        let mut pending = vec![new];
+        while let Some(node) = pending.pop() {
+            if self.assigned.contains_key(&node.id) {
+                for (_, children) in &node.children {
+                    pending.extend(children.iter().rev());
+                }
+                continue;
+            }
+            match candidates.get_mut(&node.fingerprint).and_then(Vec::pop) {
+                Some(source) => self.match_nodes(source, node),
+                None => {
+                    for (_, children) in &node.children {
+                        pending.extend(children.iter().rev());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Pairs up two sibling lists: identical subtrees along their longest common
+/// subsequence, then identical subtrees that changed places, then the
+/// remaining nodes in each gap by kind and order.
+fn align(old: &[IdTree], new: &[IdTree]) -> Vec<(usize, usize)> {
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(old, new)| old.fingerprint == new.fingerprint)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old.fingerprint == new.fingerprint)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut anchors: Vec<(usize, usize)> = (0..prefix).map(|index| (index, index)).collect();
+    for (old_index, new_index) in common_subsequence(old_middle, new_middle) {
+        anchors.push((prefix + old_index, prefix + new_index));
+    }
+    anchors
+        .extend((0..suffix).map(|index| (old.len() - suffix + index, new.len() - suffix + index)));
+
+    let mut old_taken = vec![false; old.len()];
+    let mut new_taken = vec![false; new.len()];
+    for &(old_index, new_index) in &anchors {
+        old_taken[old_index] = true;
+        new_taken[new_index] = true;
+    }
+    let mut pairs = anchors.clone();
+    for (new_index, node) in new.iter().enumerate() {
+        if new_taken[new_index] {
+            continue;
+        }
+        if let Some(old_index) = (0..old.len())
+            .find(|&index| !old_taken[index] && old[index].fingerprint == node.fingerprint)
+        {
+            old_taken[old_index] = true;
+            new_taken[new_index] = true;
+            pairs.push((old_index, new_index));
+        }
+    }
+
+    let mut old_start = 0;
+    let mut new_start = 0;
+    for (old_anchor, new_anchor) in anchors
+        .into_iter()
+        .chain(std::iter::once((old.len(), new.len())))
+    {
+        let mut old_next = old_start;
+        for (new_index, node) in new.iter().enumerate().take(new_anchor).skip(new_start) {
+            if new_taken[new_index] {
+                continue;
+            }
+            if let Some(old_index) = (old_next..old_anchor)
+                .find(|&index| !old_taken[index] && old[index].label == node.label)
+            {
+                old_taken[old_index] = true;
+                pairs.push((old_index, new_index));
+                old_next = old_index + 1;
+            }
+        }
+        old_start = old_anchor + 1;
+        new_start = new_anchor + 1;
+    }
+    pairs
+}
+
+fn common_subsequence(old: &[IdTree], new: &[IdTree]) -> Vec<(usize, usize)> {
+    let width = new.len() + 1;
+    let mut lengths = vec![0usize; (old.len() + 1) * width];
+    for old_index in (0..old.len()).rev() {
+        for new_index in (0..new.len()).rev() {
+            lengths[old_index * width + new_index] =
+                if old[old_index].fingerprint == new[new_index].fingerprint {
+                    lengths[(old_index + 1) * width + new_index + 1] + 1
+                } else {
+                    lengths[(old_index + 1) * width + new_index]
+                        .max(lengths[old_index * width + new_index + 1])
+                };
+        }
+    }
+    let mut pairs = Vec::new();
+    let (mut old_index, mut new_index) = (0, 0);
+    while old_index < old.len() && new_index < new.len() {
+        if old[old_index].fingerprint == new[new_index].fingerprint {
+            pairs.push((old_index, new_index));
+            old_index += 1;
+            new_index += 1;
+        } else if lengths[(old_index + 1) * width + new_index]
+            >= lengths[old_index * width + new_index + 1]
+        {
+            old_index += 1;
+        } else {
+            new_index += 1;
+        }
+    }
+    pairs
+}
@@ -0,0 +1,481 @@
+use super::identity::max_node_id;
+use super::lexer::{attach_trivia, insert_indent_tokens, Lexer};
+use super::parser::Parser;
+use super::render::{render_lossless, stmt_meta};
+use super::*;
+
+impl Program {
+    /// Applies `edit` to the source this program was parsed from.
+    ///
+    /// Only the lines of the top-level statements touched by the edit are
+    /// re-lexed and re-parsed; statements outside that region keep their
+    /// `NodeId`s and have their positions shifted. Falls back to a full parse
+    /// whenever the edit can change how the rest of the file is tokenized.
+    pub fn reparse(&self, edit: &TextEdit, config: ParserConfig) -> Result<Program, ParseError> {
+        self.reparse_with_range(edit, config)
+            .map(|(program, _)| program)
+    }
+
+    /// Applies `edit` like `reparse`, also returning the top-level
+    /// statements it replaced, or `None` if it parsed the whole file again.
+    pub fn reparse_with_range(
+        &self,
+        edit: &TextEdit,
+        config: ParserConfig,
+    ) -> Result<(Program, Option<ReparsedRange>), ParseError> {
+        if !self.dirty && !self.raw_tokens.is_empty() {
+            if let Some(result) = self.reparse_region(edit, config) {
+                return result.map(|(program, range)| (program, Some(range)));
+            }
+        }
+        let source = if self.dirty || self.raw_tokens.is_empty() {
+            self.to_python(RenderConfig::default())
+        } else {
+            render_lossless(&self.raw_tokens)
+        };
+        Program::parse(edit.apply(&source)?, config).map(|program| (program, None))
+    }
+
+    fn reparse_region(
+        &self,
+        edit: &TextEdit,
+        config: ParserConfig,
+    ) -> Option<Result<(Program, ReparsedRange), ParseError>> {
+        if self.statements().is_empty() || self.tokens.is_empty() {
+            return None;
+        }
+        let source_len = self.raw_tokens.last()?.span.end.offset;
+        if edit.start > edit.end || edit.end > source_len {
+            return Some(Err(edit_error("edit out of range", Position::default())));
+        }
+        // First token of every top-level statement, including decorators.
+        let mut starts = Vec::with_capacity(self.statements().len());
+        let mut next = 0;
+        for stmt in self.statements() {
+            starts.push(next);
+            next = stmt_meta(stmt).token_range.end + 1;
+        }
+        let lines = starts
+            .iter()
+            .map(|&index| self.tokens.get(index).map(|token| token.span.start.line))
+            .collect::<Option<Vec<_>>>()?;
+        let start_line = line_at(&self.raw_tokens, edit.start)?;
+        let end_line = line_at(&self.raw_tokens, edit.end)?;
+        // The statement before the edit is included because the edited lines
+        // may now belong to its block.
+        let first = lines
+            .partition_point(|&line| line <= start_line)
+            .saturating_sub(2);
+        let mut last = lines
+            .partition_point(|&line| line <= end_line)
+            .saturating_sub(1)
+            .max(first);
+        loop {
+            match self.reparse_statements(edit, config, &starts, &lines, first, last) {
+                RegionResult::Done(result) => return Some(*result),
+                RegionResult::Extend if last + 1 < self.statements().len() => last += 1,
+                RegionResult::Extend | RegionResult::Fallback => return None,
+            }
+        }
+    }
+
+    /// Re-lexes and re-parses the top-level statements `first..=last`.
+    fn reparse_statements(
+        &self,
+        edit: &TextEdit,
+        config: ParserConfig,
+        starts: &[usize],
+        lines: &[usize],
+        first: usize,
+        last: usize,
+    ) -> RegionResult {
+        let to_eof = last + 1 == self.statements().len();
+        let Some(region_start) = line_start(&self.raw_tokens, lines[first]) else {
+            return RegionResult::Fallback;
+        };
+        let region_end = if to_eof {
+            self.raw_tokens
+                .last()
+                .map_or(0, |token| token.span.end.offset)
+        } else {
+            match line_start(&self.raw_tokens, lines[last + 1]) {
+                Some(offset) => offset,
+                None => return RegionResult::Fallback,
+            }
+        };
+        if edit.start < region_start || edit.end > region_end {
+            return RegionResult::Fallback;
+        }
+        let raw_start = self
+            .raw_tokens
+            .partition_point(|token| token.span.start.offset < region_start);
+        let raw_end = if to_eof {
+            self.raw_tokens.len()
+        } else {
+            self.raw_tokens
+                .partition_point(|token| token.span.start.offset < region_end)
+        };
+        let old_text = render_lossless(&self.raw_tokens[raw_start..raw_end]);
+        let region_position = self.raw_tokens[raw_start].span.start;
+        let local_edit = TextEdit {
+            start: edit.start - region_start,
+            end: edit.end - region_start,
+            text: edit.text.clone(),
+        };
+        let new_text = match splice(&old_text, &local_edit, region_position) {
+            Ok(text) => text,
+            Err(error) => return RegionResult::Done(Box::new(Err(error))),
+        };
+
+        let mut lexer = Lexer::new(new_text.clone());
+        let lexed = match lexer.lex() {
+            Ok(lexed) => lexed,
+            Err(mut error) => {
+                error.span = shift_span(error.span, &Shift::to(region_position));
+                return RegionResult::Done(Box::new(Err(error)));
+            }
+        };
+        if !to_eof && lexed.paren_depth > 0 {
+            return RegionResult::Fallback;
+        }
+        // Only a region starting at the top of the file can change where the
+        // first line break is or whether there is a BOM or coding cookie.
+        let source_format = if first == 0 {
+            lexed.format
+        } else {
+            self.source_format.clone()
+        };
+        let mut segment = lexed.tokens;
+        if !to_eof {
+            segment.pop();
+        }
+        let into_region = Shift::to(region_position);
+        for token in &mut segment {
+            token.span = shift_span(token.span, &into_region);
+        }
+        let after_region = Shift {
+            lines: line_count(&new_text) as isize - line_count(&old_text) as isize,
+            offset: new_text.len() as isize - old_text.len() as isize,
+        };
+        let region_raw_end = raw_start + segment.len();
+        let mut raw_tokens = Vec::with_capacity(self.raw_tokens.len() + segment.len());
+        raw_tokens.extend_from_slice(&self.raw_tokens[..raw_start]);
+        raw_tokens.extend(segment);
+        raw_tokens.extend(self.raw_tokens[raw_end..].iter().map(|token| LexToken {
+            kind: token.kind.clone(),
+            span: shift_span(token.span, &after_region),
+            raw: token.raw.clone(),
+        }));
+        let indent_width = raw_tokens
+            .iter()
+            .find_map(|token| match token.kind {
+                TokenKind::Indentation(width) if width > 0 => Some(width),
+                _ => None,
+            })
+            .unwrap_or(4);
+        if indent_width != self.indent_width {
+            return RegionResult::Fallback;
+        }
+
+        // Trivia gathers on the next significant token, so the tokens are
+        // rebuilt from the start of the region through the first such token
+        // after it; the rest of the file keeps its tokens.
+        let window_end = if to_eof {
+            raw_tokens.len()
+        } else {
+            let significant = raw_tokens[region_raw_end..]
+                .iter()
+                .position(|token| !token.kind.is_trivia() && token.kind != TokenKind::Newline);
+            match significant {
+                Some(index) => region_raw_end + index + 1,
+                None => return RegionResult::Fallback,
+            }
+        };
+        let mut window = match insert_indent_tokens(raw_tokens[raw_start..window_end].to_vec()) {
+            Ok(tokens) => attach_trivia(tokens),
+            Err(error) => return RegionResult::Done(Box::new(Err(error))),
+        };
+        let old_start = starts[first];
+        // Trivia from before the region that waited for a token in it.
+        let carried: Vec<Trivia> = self.tokens[old_start..]
+            .iter()
+            .find(|token| token.kind != TokenKind::Newline)
+            .map(|token| {
+                token
+                    .leading_trivia
+                    .iter()
+                    .filter(|trivia| trivia.span.start.offset < region_start)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(token) = window
+            .iter_mut()
+            .find(|token| token.kind != TokenKind::Newline)
+        {
+            token.leading_trivia.splice(0..0, carried);
+        }
+        let shift_after = |trivia: &Trivia| {
+            if trivia.span.start.offset < region_end {
+                trivia.clone()
+            } else {
+                shift_trivia(trivia, &after_region)
+            }
+        };
+        let (old_end, old_next) = if to_eof {
+            (self.tokens.len(), self.tokens.len())
+        } else {
+            let old_end = starts[last + 1];
+            let Some(significant) = self.tokens[old_end..].iter().position(|token| {
+                !matches!(
+                    token.kind,
+                    TokenKind::Newline | TokenKind::Indent { .. } | TokenKind::Dedent { .. }
+                )
+            }) else {
+                return RegionResult::Fallback;
+            };
+            (old_end, old_end + significant + 1)
+        };
+        let Some(region_len) = window.len().checked_sub(old_next - old_end) else {
+            return RegionResult::Extend;
+        };
+        let new_end = if to_eof {
+            usize::MAX
+        } else {
+            old_start + region_len
+        };
+        // Comments and blank lines at the end of the region lead the next
+        // statement; if they were edited that statement must be re-parsed.
+        let changed = window[region_len..]
+            .iter()
+            .zip(&self.tokens[old_end..old_next])
+            .any(|(new, old)| {
+                new.kind != old.kind
+                    || !old
+                        .leading_trivia
+                        .iter()
+                        .map(shift_after)
+                        .eq(new.leading_trivia.iter().cloned())
+            });
+        if changed {
+            return RegionResult::Extend;
+        }
+        let mut tokens = Vec::with_capacity(self.tokens.len() + window.len());
+        tokens.extend_from_slice(&self.tokens[..old_start]);
+        tokens.extend(window.into_iter().take(region_len));
+        tokens.extend(self.tokens[old_end..].iter().map(|token| Token {
+            kind: token.kind.clone(),
+            span: shift_span(token.span, &after_region),
+            raw: token.raw.clone(),
+            leading_trivia: token.leading_trivia.iter().map(shift_after).collect(),
+            trailing_trivia: token.trailing_trivia.iter().map(shift_after).collect(),
+        }));
+        let token_delta = tokens.len() as isize - self.tokens.len() as isize;
+
+        let mut parser = Parser::resume(
+            tokens,
+            config,
+            indent_width,
+            starts[first],
+            max_node_id(self) + 1,
+        );
+        let region_body = match parser.parse_top_level_until(new_end) {
+            Ok(body) => body,
+            Err(error) => return RegionResult::Done(Box::new(Err(error))),
+        };
+        if !to_eof && parser.index() != new_end {
+            return RegionResult::Extend;
+        }
+        let tokens = parser.into_tokens();
+        let region_count = region_body.len();
+
+        let mut body = Vec::with_capacity(self.body.len() + region_body.len());
+        body.extend_from_slice(&self.body[..first]);
+        body.extend(region_body);
+        for stmt in &self.statements()[last + 1..] {
+            let mut stmt = stmt.clone();
+            stmt_metas_mut(&mut stmt, &mut |meta| {
+                meta.span = shift_span(meta.span, &after_region);
+                meta.token_range.start = (meta.token_range.start as isize + token_delta) as usize;
+                meta.token_range.end = (meta.token_range.end as isize + token_delta) as usize;
+                for trivia in meta
+                    .leading_trivia
+                    .iter_mut()
+                    .chain(meta.trailing_trivia.iter_mut())
+                {
+                    *trivia = shift_trivia(trivia, &after_region);
+                }
+            });
+            body.push(stmt);
+        }
+        let program_end = tokens.len().saturating_sub(2);
+        let meta = NodeMeta {
+            id: self.meta.id,
+            span: Span::join(
+                tokens
+                    .first()
+                    .map(|token| token.span.start)
+                    .unwrap_or_default(),
+                tokens
+                    .get(program_end)
+                    .map(|token| token.span.end)
+                    .unwrap_or_default(),
+            ),
+            token_range: TokenRange {
+                start: 0,
+                end: program_end,
+            },
+            leading_trivia: tokens
+                .first()
+                .map(|token| token.leading_trivia.clone())
+                .unwrap_or_default(),
+            trailing_trivia: tokens
+                .get(program_end)
+                .map(|token| token.trailing_trivia.clone())
+                .unwrap_or_default(),
+            dirty: false,
+            comment: None,
+        };
+        let mut program = Program {
+            meta,
+            indent_width,
+            body,
+            tokens,
+            raw_tokens,
+            dirty: false,
+            source_format,
+        };
+        program.own_comments();
+        let range = if to_eof {
+            ReparsedRange {
+                start: first,
+                old_end: self.body.len(),
+                new_end: program.body.len(),
+            }
+        } else {
+            ReparsedRange {
+                start: first,
+                old_end: last + 1,
+                new_end: first + region_count,
+            }
+        };
+        RegionResult::Done(Box::new(Ok((program, range))))
+    }
+
+    /// The body without the comments after the last statement, which have
+    /// no tokens of their own and are added again by `own_comments`.
+    fn statements(&self) -> &[Stmt] {
+        let eof = self.tokens.len().saturating_sub(1);
+        let end = self
+            .body
+            .iter()
+            .rposition(|stmt| {
+                !matches!(stmt, Stmt::Comment(comment) if comment.meta.token_range.start == eof)
+            })
+            .map_or(0, |index| index + 1);
+        &self.body[..end]
+    }
+}
+
+impl TextEdit {
+    /// Returns `source` with this edit applied.
+    pub fn apply(&self, source: &str) -> Result<String, ParseError> {
+        splice(source, self, Position::default())
+    }
+}
+
+enum RegionResult {
+    Done(Box<Result<(Program, ReparsedRange), ParseError>>),
+    /// The region ends in the middle of something; retry with one more statement.
+    Extend,
+    Fallback,
+}
+
+/// Moves positions by a number of lines and bytes. Regions always start at
+/// the beginning of a line, so columns never change.
+struct Shift {
+    lines: isize,
+    offset: isize,
+}
+
+impl Shift {
+    /// Shift for positions lexed from a string that starts at `base`.
+    fn to(base: Position) -> Self {
+        Self {
+            lines: base.line as isize - 1,
+            offset: base.offset as isize,
+        }
+    }
+}
+
+fn shift_position(position: Position, shift: &Shift) -> Position {
+    Position {
+        line: (position.line as isize + shift.lines) as usize,
+        column: position.column,
+        offset: (position.offset as isize + shift.offset) as usize,
+    }
+}
+
+fn shift_span(span: Span, shift: &Shift) -> Span {
+    Span::join(
+        shift_position(span.start, shift),
+        shift_position(span.end, shift),
+    )
+}
+
+fn shift_trivia(trivia: &Trivia, shift: &Shift) -> Trivia {
+    Trivia {
+        kind: trivia.kind.clone(),
+        span: shift_span(trivia.span, shift),
+    }
+}
+
+fn line_count(text: &str) -> usize {
+    let bytes = text.as_bytes();
+    bytes
+        .iter()
+        .enumerate()
+        .filter(|&(index, &byte)| {
+            byte == b'\n' || (byte == b'\r' && bytes.get(index + 1) != Some(&b'\n'))
+        })
+        .count()
+}
+
+/// Line number of the byte `offset` in the lexed source.
+fn line_at(raw_tokens: &[LexToken], offset: usize) -> Option<usize> {
+    let index = raw_tokens
+        .partition_point(|token| token.span.start.offset <= offset)
+        .checked_sub(1)?;
+    let token = &raw_tokens[index];
+    let within = token.raw.get(..offset - token.span.start.offset)?;
+    Some(token.span.start.line + line_count(within))
+}
+
+/// Byte offset of the start of `line`, if a token begins there.
+fn line_start(raw_tokens: &[LexToken], line: usize) -> Option<usize> {
+    let index = raw_tokens.partition_point(|token| token.span.start.line < line);
+    let token = raw_tokens.get(index)?;
+    (token.span.start.line == line && token.span.start.column == 1)
+        .then_some(token.span.start.offset)
+}
+
+fn splice(source: &str, edit: &TextEdit, base: Position) -> Result<String, ParseError> {
+    if edit.start > edit.end || edit.end > source.len() {
+        return Err(edit_error("edit out of range", base));
+    }
+    if !source.is_char_boundary(edit.start) || !source.is_char_boundary(edit.end) {
+        return Err(edit_error("edit splits a character", base));
+    }
+    let mut output = String::with_capacity(source.len() + edit.text.len());
+    output.push_str(&source[..edit.start]);
+    output.push_str(&edit.text);
+    output.push_str(&source[edit.end..]);
+    Ok(output)
+}
+
+fn edit_error(message: &str, position: Position) -> ParseError {
+    ParseError {
+        message: message.to_string(),
+        span: Span::join(position, position),
+    }
+}
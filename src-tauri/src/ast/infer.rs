@@ -0,0 +1,666 @@
+use super::*;
+use std::collections::{HashMap, HashSet};
+
+/// Passes over the assignments before the types of variables must have
+/// settled; each pass can only carry a type one assignment further.
+const ROUNDS: usize = 8;
+
+impl Program {
+    /// Infers the types of expressions from literals, operators, the
+    /// builtins learners use most and annotations, and of variables from
+    /// what is assigned to them: a variable has a type when all its
+    /// assignments agree on one. Calls of the program's own functions have
+    /// the type of their return annotation. Operators Python would refuse
+    /// for the types found come back as errors. `table` is the program's
+    /// `symbols()`.
+    pub fn infer_types(&self, table: &SymbolTable) -> TypeTable {
+        let mut bindings = HashMap::new();
+        for reference in &table.references {
+            if matches!(
+                reference.kind,
+                ReferenceKind::Definition | ReferenceKind::Parameter | ReferenceKind::Declaration
+            ) {
+                let name = table.symbols[reference.symbol.0].name.as_str();
+                bindings.insert((reference.node, name), reference.symbol);
+            }
+        }
+        let mut sources = Sources {
+            bindings: &bindings,
+            found: Vec::new(),
+            returns: HashMap::new(),
+        };
+        sources.visit_program(self);
+        // Imports, `def`s, `with` targets and the like bind values of types
+        // the inference does not follow.
+        let covered: HashSet<(NodeId, SymbolId)> = sources
+            .found
+            .iter()
+            .map(|source| (source.node, source.symbol))
+            .collect();
+        let opaque: HashSet<SymbolId> = table
+            .references
+            .iter()
+            .filter(|reference| {
+                matches!(
+                    reference.kind,
+                    ReferenceKind::Definition | ReferenceKind::Parameter
+                ) && !covered.contains(&(reference.node, reference.symbol))
+            })
+            .map(|reference| reference.symbol)
+            .collect();
+        let mut infer = Infer {
+            table,
+            bound: bindings.values().copied().collect(),
+            returns: sources.returns,
+            variables: HashMap::new(),
+            settled: false,
+        };
+        for _ in 0..ROUNDS {
+            let mut variables: HashMap<SymbolId, Type> = opaque
+                .iter()
+                .map(|&symbol| (symbol, Type::Unknown))
+                .collect();
+            for source in &sources.found {
+                if opaque.contains(&source.symbol) {
+                    continue;
+                }
+                let Some(found) = infer.value(source) else {
+                    continue;
+                };
+                variables
+                    .entry(source.symbol)
+                    .and_modify(|known| *known = join(known, &found))
+                    .or_insert(found);
+            }
+            if variables == infer.variables {
+                break;
+            }
+            infer.variables = variables;
+        }
+        infer.settled = true;
+        let mut types = Types {
+            infer: &infer,
+            table: TypeTable::default(),
+        };
+        types.visit_program(self);
+        types.table
+    }
+}
+
+/// What an assignment gives a variable.
+enum Value {
+    Expr(Expr),
+    /// An element of the iterable, for `for` targets.
+    Element(Expr),
+    /// The variable's own value and the expression, for `+=` and the like.
+    /// An update Python refuses leaves the variable as it was.
+    Update(BinaryOp, Expr),
+    Annotated(Type),
+}
+
+struct Source {
+    symbol: SymbolId,
+    /// The node binding the name, as in its reference.
+    node: NodeId,
+    value: Value,
+}
+
+/// Collects the assignments whose values the inference follows.
+struct Sources<'a> {
+    bindings: &'a HashMap<(NodeId, &'a str), SymbolId>,
+    found: Vec<Source>,
+    /// The return annotation of each function that has one.
+    returns: HashMap<SymbolId, Type>,
+}
+
+impl Sources<'_> {
+    fn add(&mut self, node: NodeId, name: &str, value: Value) {
+        if let Some(&symbol) = self.bindings.get(&(node, name)) {
+            self.found.push(Source {
+                symbol,
+                node,
+                value,
+            });
+        }
+    }
+
+    fn target(&mut self, target: &Expr, value: impl FnOnce() -> Value) {
+        if let Expr::Identifier(identifier) = target {
+            self.add(identifier.meta.id, &identifier.name, value());
+        }
+    }
+}
+
+impl Visitor for Sources<'_> {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            // `a, b = 1, 2` has the names as its targets.
+            Stmt::Assign(assign) => match (assign.targets.as_slice(), &assign.value) {
+                ([target], value) => self.target(target, || Value::Expr(value.clone())),
+                (targets, Expr::Tuple(values)) if targets.len() == values.elements.len() => {
+                    for (target, value) in targets.iter().zip(&values.elements) {
+                        self.target(target, || Value::Expr(value.clone()));
+                    }
+                }
+                _ => {}
+            },
+            Stmt::AugAssign(assign) => {
+                if let Some(op) = augmented_operator(&assign.op) {
+                    self.target(&assign.target, || Value::Update(op, assign.value.clone()));
+                }
+            }
+            Stmt::AnnAssign(assign) => {
+                let value = Value::Annotated(annotation(&assign.annotation));
+                self.add(assign.meta.id, &assign.target, value);
+            }
+            Stmt::For(stmt) => {
+                self.target(&stmt.target, || Value::Element(stmt.iterable.clone()));
+            }
+            Stmt::FunctionDef(function) => {
+                for param in &function.params {
+                    if let Some(annotated) = &param.annotation {
+                        let value = Value::Annotated(annotation(annotated));
+                        self.add(function.meta.id, &param.name, value);
+                    }
+                }
+                let returns = function.return_type.as_ref().map(annotation);
+                let symbol = self
+                    .bindings
+                    .get(&(function.meta.id, function.name.as_str()));
+                if let (Some(returns), Some(&symbol)) = (returns, symbol) {
+                    self.returns.insert(symbol, returns);
+                }
+            }
+            _ => {}
+        }
+        self.walk_stmt(stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let Expr::NamedExpr(named) = expr {
+            let value = Value::Expr((*named.value).clone());
+            self.add(named.meta.id, &named.name, value);
+        }
+        self.walk_expr(expr);
+    }
+
+    fn visit_comprehension_for(&mut self, comprehension: &ComprehensionFor) {
+        self.target(&comprehension.target, || {
+            Value::Element(comprehension.iter.clone())
+        });
+        self.walk_comprehension_for(comprehension);
+    }
+}
+
+struct Infer<'a> {
+    table: &'a SymbolTable,
+    /// Symbols the program assigns or defines.
+    bound: HashSet<SymbolId>,
+    returns: HashMap<SymbolId, Type>,
+    variables: HashMap<SymbolId, Type>,
+    /// Whether `variables` is final, so that a variable missing from it has
+    /// no known type rather than one yet to be found.
+    settled: bool,
+}
+
+impl Infer<'_> {
+    /// The type of `expr`, or `None` while it depends on a variable whose
+    /// type is yet to be found.
+    fn expr(&self, expr: &Expr) -> Option<Type> {
+        Some(match expr {
+            Expr::Identifier(identifier) => {
+                return match self.table.nodes.get(&identifier.meta.id) {
+                    Some(symbol) if self.bound.contains(symbol) => self.variable(*symbol),
+                    _ => Some(Type::Unknown),
+                };
+            }
+            Expr::Literal(literal) => literal_type(&literal.literal),
+            Expr::FString(_) => Type::Str,
+            Expr::Grouped(grouped) => return self.expr(&grouped.expr),
+            Expr::NamedExpr(named) => return self.expr(&named.value),
+            Expr::Binary(binary) => {
+                let left = self.expr(&binary.left)?;
+                let right = self.expr(&binary.right)?;
+                binary_type(&binary.op, &left, &right).unwrap_or(Type::Unknown)
+            }
+            Expr::Unary(unary) => match (&unary.op, self.expr(&unary.expr)?) {
+                (UnaryOp::Not, _) => Type::Bool,
+                (_, Type::Int | Type::Bool) => Type::Int,
+                (UnaryOp::Neg, Type::Float) => Type::Float,
+                _ => Type::Unknown,
+            },
+            Expr::BoolOp(bool_op) => self.join(&bool_op.values)?,
+            Expr::Compare(_) => Type::Bool,
+            Expr::IfExpr(if_expr) => {
+                join(&self.expr(&if_expr.body)?, &self.expr(&if_expr.else_body)?)
+            }
+            Expr::Call(call) => return self.call(call),
+            Expr::Tuple(tuple) => Type::Tuple(
+                tuple
+                    .elements
+                    .iter()
+                    .map(|element| self.expr(element))
+                    .collect::<Option<_>>()?,
+            ),
+            Expr::List(list) => Type::List(Box::new(self.join(&list.elements)?)),
+            Expr::Set(set) => Type::Set(Box::new(self.join(&set.elements)?)),
+            Expr::Dict(dict) => {
+                let keys: Vec<Expr> = dict.entries.iter().map(|entry| entry.key.clone()).collect();
+                let values: Vec<Expr> = dict
+                    .entries
+                    .iter()
+                    .map(|entry| entry.value.clone())
+                    .collect();
+                Type::Dict(Box::new(self.join(&keys)?), Box::new(self.join(&values)?))
+            }
+            Expr::Comprehension(comprehension) => match comprehension {
+                ComprehensionExpr::List(list) => Type::List(Box::new(self.expr(&list.element)?)),
+                ComprehensionExpr::Set(set) => Type::Set(Box::new(self.expr(&set.element)?)),
+                ComprehensionExpr::Dict(dict) => Type::Dict(
+                    Box::new(self.expr(&dict.key)?),
+                    Box::new(self.expr(&dict.value)?),
+                ),
+                ComprehensionExpr::Generator(_) => Type::Unknown,
+            },
+            Expr::Subscript(subscript) => {
+                let value = self.expr(&subscript.value)?;
+                let slice = matches!(*subscript.index, Expr::Slice(_));
+                match value {
+                    Type::Str => Type::Str,
+                    Type::List(_) if slice => value,
+                    Type::List(element) => *element,
+                    Type::Dict(_, value) => *value,
+                    Type::Range if !slice => Type::Int,
+                    Type::Tuple(elements) if !slice => index(&subscript.index)
+                        .and_then(|index| elements.get(index).cloned())
+                        .unwrap_or(Type::Unknown),
+                    _ => Type::Unknown,
+                }
+            }
+            _ => Type::Unknown,
+        })
+    }
+
+    fn variable(&self, symbol: SymbolId) -> Option<Type> {
+        match self.variables.get(&symbol) {
+            Some(found) => Some(found.clone()),
+            None if self.settled => Some(Type::Unknown),
+            None => None,
+        }
+    }
+
+    /// The type of values that are any of `exprs`.
+    fn join(&self, exprs: &[Expr]) -> Option<Type> {
+        let mut joined: Option<Type> = None;
+        for expr in exprs {
+            let found = self.expr(expr)?;
+            joined = Some(match joined {
+                Some(joined) => join(&joined, &found),
+                None => found,
+            });
+        }
+        Some(joined.unwrap_or(Type::Unknown))
+    }
+
+    fn call(&self, call: &CallExpr) -> Option<Type> {
+        let argument = |index: usize| match call.args.get(index) {
+            Some(argument) => self.expr(argument),
+            None => Some(Type::Unknown),
+        };
+        Some(match &*call.callee {
+            Expr::Identifier(identifier) => {
+                let symbol = self.table.nodes.get(&identifier.meta.id);
+                if let Some(returns) = symbol.and_then(|symbol| self.returns.get(symbol)) {
+                    return Some(returns.clone());
+                }
+                if symbol.is_some_and(|symbol| self.bound.contains(symbol)) {
+                    return Some(Type::Unknown);
+                }
+                match identifier.name.as_str() {
+                    "len" | "int" => Type::Int,
+                    "input" | "str" => Type::Str,
+                    "float" => Type::Float,
+                    "bool" => Type::Bool,
+                    "range" => Type::Range,
+                    "print" => Type::None,
+                    "list" | "sorted" => Type::List(Box::new(element(&argument(0)?))),
+                    "set" => Type::Set(Box::new(element(&argument(0)?))),
+                    "abs" => match argument(0)? {
+                        Type::Int | Type::Bool => Type::Int,
+                        Type::Float => Type::Float,
+                        _ => Type::Unknown,
+                    },
+                    _ => Type::Unknown,
+                }
+            }
+            Expr::Attribute(attribute) => match self.expr(&attribute.value)? {
+                Type::Str => match attribute.attr.as_str() {
+                    "upper" | "lower" | "strip" | "lstrip" | "rstrip" | "title" | "capitalize"
+                    | "replace" | "format" | "join" => Type::Str,
+                    "split" => Type::List(Box::new(Type::Str)),
+                    "find" | "count" | "index" => Type::Int,
+                    "startswith" | "endswith" | "isdigit" | "isalpha" => Type::Bool,
+                    _ => Type::Unknown,
+                },
+                _ => Type::Unknown,
+            },
+            _ => Type::Unknown,
+        })
+    }
+
+    fn value(&self, source: &Source) -> Option<Type> {
+        match &source.value {
+            Value::Expr(expr) => self.expr(expr),
+            Value::Element(iterable) => Some(element(&self.expr(iterable)?)),
+            Value::Update(op, expr) => {
+                let left = self.variable(source.symbol)?;
+                let right = self.expr(expr)?;
+                Some(binary_type(op, &left, &right).unwrap_or(left))
+            }
+            Value::Annotated(annotated) => Some(annotated.clone()),
+        }
+    }
+}
+
+/// Records the type of each expression and the operators Python would
+/// refuse.
+struct Types<'a> {
+    infer: &'a Infer<'a>,
+    table: TypeTable,
+}
+
+impl Types<'_> {
+    fn type_of(&self, expr: &Expr) -> Type {
+        self.infer.expr(expr).unwrap_or(Type::Unknown)
+    }
+
+    fn error(
+        &mut self,
+        kind: TypeDiagnosticKind,
+        op: &str,
+        left: Type,
+        right: Type,
+        meta: &NodeMeta,
+    ) {
+        self.table.errors.push(TypeDiagnostic {
+            kind,
+            op: op.to_string(),
+            left,
+            right,
+            node: meta.id,
+            span: meta.span,
+        });
+    }
+}
+
+impl Visitor for Types<'_> {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        if let Stmt::AugAssign(assign) = stmt {
+            if let Some(op) = augmented_operator(&assign.op) {
+                let left = self.type_of(&assign.target);
+                let right = self.type_of(&assign.value);
+                if binary_type(&op, &left, &right).is_none() {
+                    let op = format!("{}=", binary_operator(&op));
+                    self.error(TypeDiagnosticKind::Operands, &op, left, right, &assign.meta);
+                }
+            }
+        }
+        self.walk_stmt(stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        let found = self.type_of(expr);
+        if found != Type::Unknown {
+            self.table.types.insert(expr_meta(expr).id, found);
+        }
+        match expr {
+            Expr::Binary(binary) => {
+                let left = self.type_of(&binary.left);
+                let right = self.type_of(&binary.right);
+                if binary_type(&binary.op, &left, &right).is_none() {
+                    let op = binary_operator(&binary.op);
+                    self.error(TypeDiagnosticKind::Operands, op, left, right, &binary.meta);
+                }
+            }
+            Expr::Compare(compare) => {
+                let operands: Vec<&Expr> = std::iter::once(compare.left.as_ref())
+                    .chain(&compare.comparators)
+                    .collect();
+                for (index, op) in compare.ops.iter().enumerate() {
+                    let op = match op {
+                        CompareOp::Lt => "<",
+                        CompareOp::LtEq => "<=",
+                        CompareOp::Gt => ">",
+                        CompareOp::GtEq => ">=",
+                        _ => continue,
+                    };
+                    let left = self.type_of(operands[index]);
+                    let right = self.type_of(operands[index + 1]);
+                    if !orderable(&left, &right) {
+                        self.error(
+                            TypeDiagnosticKind::Comparison,
+                            op,
+                            left,
+                            right,
+                            &compare.meta,
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.walk_expr(expr);
+    }
+}
+
+fn literal_type(literal: &Literal) -> Type {
+    match literal {
+        Literal::Number(number) => {
+            let raw = number.raw.to_ascii_lowercase();
+            if raw.ends_with('j') {
+                Type::Unknown
+            } else if raw.starts_with("0x") || raw.starts_with("0o") || raw.starts_with("0b") {
+                Type::Int
+            } else if raw.contains(['.', 'e']) {
+                Type::Float
+            } else {
+                Type::Int
+            }
+        }
+        Literal::String(string) => {
+            let prefix = string.raw.split(['"', '\'']).next().unwrap_or_default();
+            if prefix.contains(['b', 'B']) {
+                Type::Unknown
+            } else {
+                Type::Str
+            }
+        }
+        Literal::Bool(_) => Type::Bool,
+        Literal::None => Type::None,
+    }
+}
+
+fn binary_operator(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Mod => "%",
+        BinaryOp::FloorDiv => "//",
+        BinaryOp::Power => "**",
+        BinaryOp::BitAnd => "&",
+        BinaryOp::BitOr => "|",
+        BinaryOp::BitXor => "^",
+        BinaryOp::LeftShift => "<<",
+        BinaryOp::RightShift => ">>",
+    }
+}
+
+/// The operator `op`, such as `+=`, applies to the variable and the value.
+fn augmented_operator(op: &Operator) -> Option<BinaryOp> {
+    match op {
+        Operator::PlusAssign => Some(BinaryOp::Add),
+        Operator::MinusAssign => Some(BinaryOp::Sub),
+        Operator::StarAssign => Some(BinaryOp::Mul),
+        Operator::SlashAssign => Some(BinaryOp::Div),
+        Operator::PercentAssign => Some(BinaryOp::Mod),
+        _ => None,
+    }
+}
+
+fn numeric(found: &Type) -> bool {
+    matches!(found, Type::Int | Type::Float | Type::Bool)
+}
+
+/// The type of `left op right`, or `None` when Python raises `TypeError`
+/// for it.
+fn binary_type(op: &BinaryOp, left: &Type, right: &Type) -> Option<Type> {
+    if *left == Type::Unknown || *right == Type::Unknown {
+        return Some(Type::Unknown);
+    }
+    if numeric(left) && numeric(right) {
+        let float = *left == Type::Float || *right == Type::Float;
+        return match op {
+            BinaryOp::Div => Some(Type::Float),
+            BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor
+                if *left == Type::Bool && *right == Type::Bool =>
+            {
+                Some(Type::Bool)
+            }
+            BinaryOp::BitAnd
+            | BinaryOp::BitOr
+            | BinaryOp::BitXor
+            | BinaryOp::LeftShift
+            | BinaryOp::RightShift => (!float).then_some(Type::Int),
+            _ if float => Some(Type::Float),
+            _ => Some(Type::Int),
+        };
+    }
+    let count = |found: &Type| matches!(found, Type::Int | Type::Bool);
+    Some(match (op, left, right) {
+        (BinaryOp::Add, Type::Str, Type::Str) => Type::Str,
+        (BinaryOp::Add, Type::List(_), Type::List(_)) => join(left, right),
+        (BinaryOp::Add, Type::Tuple(left), Type::Tuple(right)) => {
+            Type::Tuple(left.iter().chain(right).cloned().collect())
+        }
+        (BinaryOp::Mul, Type::Str | Type::List(_), other) if count(other) => left.clone(),
+        (BinaryOp::Mul, other, Type::Str | Type::List(_)) if count(other) => right.clone(),
+        (BinaryOp::Mul, Type::Tuple(_), other) | (BinaryOp::Mul, other, Type::Tuple(_))
+            if count(other) =>
+        {
+            Type::Unknown
+        }
+        (BinaryOp::Mod, Type::Str, _) => Type::Str,
+        (
+            BinaryOp::Sub | BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor,
+            Type::Set(_),
+            Type::Set(_),
+        ) => join(left, right),
+        (BinaryOp::BitOr, Type::Dict(..), Type::Dict(..)) => join(left, right),
+        _ => return None,
+    })
+}
+
+/// Whether `<` and the like compare values of `left` and `right`.
+fn orderable(left: &Type, right: &Type) -> bool {
+    match (left, right) {
+        (Type::Unknown, _) | (_, Type::Unknown) => true,
+        (Type::Str, Type::Str)
+        | (Type::List(_), Type::List(_))
+        | (Type::Tuple(_), Type::Tuple(_))
+        | (Type::Set(_), Type::Set(_)) => true,
+        _ => numeric(left) && numeric(right),
+    }
+}
+
+/// The type of values that are either `left` or `right`.
+fn join(left: &Type, right: &Type) -> Type {
+    match (left, right) {
+        _ if left == right => left.clone(),
+        (Type::Int | Type::Bool, Type::Int | Type::Bool) => Type::Int,
+        _ if numeric(left) && numeric(right) => Type::Float,
+        (Type::List(left), Type::List(right)) => Type::List(Box::new(join(left, right))),
+        (Type::Set(left), Type::Set(right)) => Type::Set(Box::new(join(left, right))),
+        (Type::Dict(left_key, left_value), Type::Dict(right_key, right_value)) => Type::Dict(
+            Box::new(join(left_key, right_key)),
+            Box::new(join(left_value, right_value)),
+        ),
+        (Type::Tuple(left), Type::Tuple(right)) if left.len() == right.len() => Type::Tuple(
+            left.iter()
+                .zip(right)
+                .map(|(left, right)| join(left, right))
+                .collect(),
+        ),
+        _ => Type::Unknown,
+    }
+}
+
+/// The type of the elements a `for` over a value of `iterable` gets.
+fn element(iterable: &Type) -> Type {
+    match iterable {
+        Type::List(element) | Type::Set(element) | Type::Dict(element, _) => (**element).clone(),
+        Type::Str => Type::Str,
+        Type::Range => Type::Int,
+        Type::Tuple(elements) => elements
+            .iter()
+            .cloned()
+            .reduce(|left, right| join(&left, &right))
+            .unwrap_or(Type::Unknown),
+        _ => Type::Unknown,
+    }
+}
+
+/// The value of an index that is an integer literal.
+fn index(expr: &Expr) -> Option<usize> {
+    match expr {
+        Expr::Literal(LiteralExpr {
+            literal: Literal::Number(number),
+            ..
+        }) => number.raw.replace('_', "").parse().ok(),
+        _ => None,
+    }
+}
+
+/// The type an annotation such as `int` or `list[str]` names.
+fn annotation(expr: &Expr) -> Type {
+    match expr {
+        Expr::Identifier(identifier) => match identifier.name.as_str() {
+            "int" => Type::Int,
+            "float" => Type::Float,
+            "bool" => Type::Bool,
+            "str" => Type::Str,
+            "range" => Type::Range,
+            "list" => Type::List(Box::new(Type::Unknown)),
+            "set" => Type::Set(Box::new(Type::Unknown)),
+            "dict" => Type::Dict(Box::new(Type::Unknown), Box::new(Type::Unknown)),
+            _ => Type::Unknown,
+        },
+        Expr::Literal(LiteralExpr {
+            literal: Literal::None,
+            ..
+        }) => Type::None,
+        Expr::Subscript(subscript) => {
+            let Expr::Identifier(generic) = &*subscript.value else {
+                return Type::Unknown;
+            };
+            let arguments: Vec<Type> = match &*subscript.index {
+                Expr::Tuple(tuple) => tuple.elements.iter().map(annotation).collect(),
+                index => vec![annotation(index)],
+            };
+            match (generic.name.as_str(), arguments.as_slice()) {
+                ("list", [element]) => Type::List(Box::new(element.clone())),
+                ("set", [element]) => Type::Set(Box::new(element.clone())),
+                ("dict", [key, value]) => {
+                    Type::Dict(Box::new(key.clone()), Box::new(value.clone()))
+                }
+                // `tuple[int, ...]` has its `...` come back unknown.
+                ("tuple", elements) if !elements.contains(&Type::Unknown) => {
+                    Type::Tuple(elements.to_vec())
+                }
+                _ => Type::Unknown,
+            }
+        }
+        _ => Type::Unknown,
+    }
+}
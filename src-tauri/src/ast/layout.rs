@@ -0,0 +1,221 @@
+use super::NodeId;
+use std::ops::Range;
+
+/// A document for the line-width-aware printer, in the style of Wadler's
+/// "A prettier printer": groups are laid out on one line when they fit and
+/// have their line breaks taken otherwise.
+#[derive(Debug, Clone)]
+pub(super) enum Doc {
+    /// Text without line breaks, except inside string literals and code
+    /// copied from the source.
+    Text(String),
+    /// A space, or a line break when the enclosing group is broken.
+    Line,
+    /// Nothing, or a line break when the enclosing group is broken.
+    SoftLine,
+    /// A line break that keeps every enclosing group from staying flat.
+    HardLine,
+    /// Prints nothing but keeps every enclosing group from staying flat.
+    BreakParent,
+    /// Text printed only when the enclosing group is broken.
+    IfBreak(String),
+    /// Text printed at the end of the line it is on, such as a comment.
+    LineSuffix(String),
+    /// Indents the line breaks inside by one more unit.
+    Indent(Box<Doc>),
+    Group(Box<Doc>),
+    Concat(Vec<Doc>),
+    /// Prints the inner document and reports where, as the text of a node.
+    Mark(NodeId, Box<Doc>),
+}
+
+/// Unit of hanging indentation of continuation lines, unless indenting
+/// with tabs.
+pub(super) const INDENT: &str = "    ";
+
+/// Columns a tab is counted as.
+const TAB_WIDTH: usize = 4;
+
+impl Doc {
+    pub(super) fn concat(parts: Vec<Doc>) -> Doc {
+        Doc::Concat(parts)
+    }
+
+    pub(super) fn group(doc: Doc) -> Doc {
+        Doc::Group(Box::new(doc))
+    }
+
+    pub(super) fn indent(doc: Doc) -> Doc {
+        Doc::Indent(Box::new(doc))
+    }
+
+    pub(super) fn mark(id: NodeId, doc: Doc) -> Doc {
+        Doc::Mark(id, Box::new(doc))
+    }
+}
+
+impl From<&str> for Doc {
+    fn from(text: &str) -> Doc {
+        Doc::Text(text.to_string())
+    }
+}
+
+impl From<String> for Doc {
+    fn from(text: String) -> Doc {
+        Doc::Text(text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+/// What is left to print: documents with their indentation and mode, and
+/// the ends of marks, by their index in the marks printed.
+enum Command<'d> {
+    Print(usize, Mode, &'d Doc),
+    EndMark(usize),
+}
+
+/// Lays `doc` out in `width` columns, continuing a line that starts with
+/// `prefix`. Lines after the first start with `prefix` as well, followed by
+/// `indent_unit` once per level of indentation.
+pub(super) fn print(doc: &Doc, width: usize, prefix: &str, indent_unit: &str) -> String {
+    print_marked(doc, width, prefix, indent_unit).0
+}
+
+/// Lays `doc` out like `print`, also returning the byte range of the text
+/// of each mark in the output.
+pub(super) fn print_marked(
+    doc: &Doc,
+    width: usize,
+    prefix: &str,
+    indent_unit: &str,
+) -> (String, Vec<(NodeId, Range<usize>)>) {
+    let mut output = String::new();
+    let mut marks: Vec<(NodeId, Range<usize>)> = Vec::new();
+    let mut column = text_width(prefix);
+    let mut suffix = String::new();
+    let mut stack = vec![Command::Print(0, Mode::Break, doc)];
+    while let Some(command) = stack.pop() {
+        let (indent, mode, doc) = match command {
+            Command::Print(indent, mode, doc) => (indent, mode, doc),
+            Command::EndMark(index) => {
+                marks[index].1.end = output.len();
+                continue;
+            }
+        };
+        match doc {
+            Doc::Text(text) => {
+                output.push_str(text);
+                column = match text.rfind('\n') {
+                    Some(index) => text_width(&text[index + 1..]),
+                    None => column + text_width(text),
+                };
+            }
+            Doc::Line if mode == Mode::Flat => {
+                output.push(' ');
+                column += 1;
+            }
+            Doc::SoftLine if mode == Mode::Flat => {}
+            Doc::Line | Doc::SoftLine | Doc::HardLine => {
+                output.push_str(&std::mem::take(&mut suffix));
+                output.push('\n');
+                output.push_str(prefix);
+                output.push_str(&indent_unit.repeat(indent));
+                column = text_width(prefix) + indent * text_width(indent_unit);
+            }
+            Doc::BreakParent => {}
+            Doc::IfBreak(text) => {
+                if mode == Mode::Break {
+                    output.push_str(text);
+                    column += text_width(text);
+                }
+            }
+            Doc::LineSuffix(text) => {
+                suffix.push_str(text);
+                column += text_width(text);
+            }
+            Doc::Indent(inner) => stack.push(Command::Print(indent + 1, mode, inner)),
+            Doc::Group(inner) => {
+                let remaining = isize::try_from(width).unwrap_or(isize::MAX) - column as isize;
+                let mode = if mode == Mode::Flat || fits(remaining, inner, &stack) {
+                    Mode::Flat
+                } else {
+                    Mode::Break
+                };
+                stack.push(Command::Print(indent, mode, inner));
+            }
+            Doc::Concat(parts) => {
+                stack.extend(
+                    parts
+                        .iter()
+                        .rev()
+                        .map(|part| Command::Print(indent, mode, part)),
+                );
+            }
+            Doc::Mark(id, inner) => {
+                marks.push((*id, output.len()..output.len()));
+                stack.push(Command::EndMark(marks.len() - 1));
+                stack.push(Command::Print(indent, mode, inner));
+            }
+        }
+    }
+    output.push_str(&suffix);
+    (output, marks)
+}
+
+/// Whether `doc` laid out flat, followed by what comes after it up to the
+/// next line break, fits in `remaining` columns.
+fn fits(mut remaining: isize, doc: &Doc, rest: &[Command]) -> bool {
+    let mut pending = vec![(Mode::Flat, doc)];
+    let mut rest = rest.iter().rev().filter_map(|command| match command {
+        Command::Print(_, mode, doc) => Some((*mode, *doc)),
+        Command::EndMark(_) => None,
+    });
+    loop {
+        if remaining < 0 {
+            return false;
+        }
+        let (mode, doc) = match pending.pop() {
+            Some(next) => next,
+            None => match rest.next() {
+                Some(next) => next,
+                None => return true,
+            },
+        };
+        match doc {
+            Doc::Text(text) => match text.find('\n') {
+                Some(index) => return text_width(&text[..index]) as isize <= remaining,
+                None => remaining -= text_width(text) as isize,
+            },
+            Doc::Line | Doc::SoftLine if mode == Mode::Break => return true,
+            Doc::Line => remaining -= 1,
+            Doc::SoftLine => {}
+            Doc::HardLine => return mode == Mode::Break,
+            Doc::BreakParent => {
+                if mode == Mode::Flat {
+                    return false;
+                }
+            }
+            Doc::IfBreak(text) => {
+                if mode == Mode::Break {
+                    remaining -= text_width(text) as isize;
+                }
+            }
+            Doc::LineSuffix(text) => remaining -= text_width(text) as isize,
+            Doc::Indent(inner) | Doc::Group(inner) | Doc::Mark(_, inner) => {
+                pending.push((mode, inner))
+            }
+            Doc::Concat(parts) => pending.extend(parts.iter().rev().map(|part| (mode, part))),
+        }
+    }
+}
+
+fn text_width(text: &str) -> usize {
+    text.chars()
+        .map(|ch| if ch == '\t' { TAB_WIDTH } else { 1 })
+        .sum()
+}
@@ -1,5 +1,8 @@
 use super::*;
 
+/// Tabs advance to the next multiple of this many columns, as in CPython.
+const TAB_SIZE: usize = 8;
+
 pub(super) struct Lexer {
     source: Vec<char>,
     source_str: String,
@@ -11,12 +14,17 @@ pub(super) struct Lexer {
     indent_width: Option<usize>,
     at_line_start: bool,
     line_has_content: bool,
+    format: SourceFormat,
+    saw_line_break: bool,
     tokens: Vec<LexToken>,
 }
 
 pub(super) struct LexedSource {
     pub(super) tokens: Vec<LexToken>,
     pub(super) indent_width: usize,
+    pub(super) format: SourceFormat,
+    /// Brackets still open at the end of the source.
+    pub(super) paren_depth: usize,
 }
 
 impl Lexer {
@@ -33,11 +41,22 @@ impl Lexer {
             indent_width: None,
             at_line_start: true,
             line_has_content: false,
+            format: SourceFormat::default(),
+            saw_line_break: false,
             tokens: Vec::new(),
         }
     }
 
     pub(super) fn lex(&mut self) -> Result<LexedSource, ParseError> {
+        if self.peek_char() == Some('\u{feff}') {
+            // The byte order mark is kept for lossless rendering but does not
+            // take up a column.
+            let start = self.current_position();
+            self.advance_char();
+            self.push_token(TokenKind::RawWhitespace(String::new()), start);
+            self.column = 1;
+            self.format.bom = true;
+        }
         while self.peek_char().is_some() {
             if self.at_line_start && self.paren_depth == 0 {
                 self.lex_indent();
@@ -49,11 +68,14 @@ impl Lexer {
             }
             let current = self.peek_char().unwrap();
             match current {
-                '\n' => {
+                '\n' | '\r' => {
                     let start = self.current_position();
-                    self.advance_char();
+                    let line_ending = self.lex_line_break();
                     if self.paren_depth > 0 {
-                        self.push_token(TokenKind::RawWhitespace("\n".to_string()), start);
+                        self.push_token(
+                            TokenKind::RawWhitespace(line_ending.as_str().to_string()),
+                            start,
+                        );
                     } else {
                         if !self.line_has_content {
                             self.tokens.push(LexToken {
@@ -262,6 +284,8 @@ impl Lexer {
         Ok(LexedSource {
             tokens: std::mem::take(&mut self.tokens),
             indent_width: self.indent_width.unwrap_or(4),
+            format: std::mem::take(&mut self.format),
+            paren_depth: self.paren_depth,
         })
     }
 
@@ -276,7 +300,7 @@ impl Lexer {
                 self.advance_char();
             } else if ch == '\t' {
                 raw.push(ch);
-                width += 4;
+                width = (width / TAB_SIZE + 1) * TAB_SIZE;
                 self.advance_char();
             } else {
                 break;
@@ -304,17 +328,49 @@ impl Lexer {
         }
     }
 
+    /// Consumes a `\n`, `\r\n` or `\r` line break.
+    fn lex_line_break(&mut self) -> LineEnding {
+        let line_ending = if self.consume_char('\r') {
+            if self.consume_char('\n') {
+                LineEnding::CrLf
+            } else {
+                LineEnding::Cr
+            }
+        } else {
+            self.advance_char();
+            LineEnding::Lf
+        };
+        if !self.saw_line_break {
+            self.saw_line_break = true;
+            self.format.line_ending = line_ending;
+        }
+        line_ending
+    }
+
     fn lex_comment(&mut self) {
         let start = self.current_position();
         let mut raw = String::new();
         while let Some(ch) = self.peek_char() {
-            if ch == '\n' {
+            if ch == '\n' || ch == '\r' {
                 break;
             }
             raw.push(ch);
             self.advance_char();
         }
         let text = raw.trim_start_matches('#').to_string();
+        // Like CPython, only a comment on its own on one of the first two
+        // lines declares the encoding, and the second line only counts when
+        // the first holds no code.
+        if start.line <= 2 && !self.line_has_content && self.format.coding.is_none() {
+            let after_code = self.tokens.iter().any(|token| {
+                token.span.start.line == 1
+                    && !token.kind.is_trivia()
+                    && token.kind != TokenKind::Newline
+            });
+            if !after_code {
+                self.format.coding = coding_cookie(&text);
+            }
+        }
         self.push_token(TokenKind::Comment(text), start);
     }
 
@@ -398,45 +454,9 @@ impl Lexer {
                 }
             }
         }
-        let kind = match raw.as_str() {
-            "if" => TokenKind::Keyword(Keyword::If),
-            "elif" => TokenKind::Keyword(Keyword::Elif),
-            "else" => TokenKind::Keyword(Keyword::Else),
-            "while" => TokenKind::Keyword(Keyword::While),
-            "for" => TokenKind::Keyword(Keyword::For),
-            "in" => TokenKind::Keyword(Keyword::In),
-            "is" => TokenKind::Keyword(Keyword::Is),
-            "lambda" => TokenKind::Keyword(Keyword::Lambda),
-            "def" => TokenKind::Keyword(Keyword::Def),
-            "match" => TokenKind::Keyword(Keyword::Match),
-            "case" => TokenKind::Keyword(Keyword::Case),
-            "pass" => TokenKind::Keyword(Keyword::Pass),
-            "return" => TokenKind::Keyword(Keyword::Return),
-            "break" => TokenKind::Keyword(Keyword::Break),
-            "continue" => TokenKind::Keyword(Keyword::Continue),
-            "and" => TokenKind::Keyword(Keyword::And),
-            "or" => TokenKind::Keyword(Keyword::Or),
-            "not" => TokenKind::Keyword(Keyword::Not),
-            "True" => TokenKind::Keyword(Keyword::True),
-            "False" => TokenKind::Keyword(Keyword::False),
-            "None" => TokenKind::Keyword(Keyword::None),
-            "import" => TokenKind::Keyword(Keyword::Import),
-            "from" => TokenKind::Keyword(Keyword::From),
-            "as" => TokenKind::Keyword(Keyword::As),
-            "try" => TokenKind::Keyword(Keyword::Try),
-            "except" => TokenKind::Keyword(Keyword::Except),
-            "finally" => TokenKind::Keyword(Keyword::Finally),
-            "class" => TokenKind::Keyword(Keyword::Class),
-            "with" => TokenKind::Keyword(Keyword::With),
-            "assert" => TokenKind::Keyword(Keyword::Assert),
-            "raise" => TokenKind::Keyword(Keyword::Raise),
-            "del" => TokenKind::Keyword(Keyword::Del),
-            "global" => TokenKind::Keyword(Keyword::Global),
-            "nonlocal" => TokenKind::Keyword(Keyword::Nonlocal),
-            "yield" => TokenKind::Keyword(Keyword::Yield),
-            "async" => TokenKind::Keyword(Keyword::Async),
-            "await" => TokenKind::Keyword(Keyword::Await),
-            _ => TokenKind::Identifier(raw),
+        let kind = match keyword(&raw) {
+            Some(keyword) => TokenKind::Keyword(keyword),
+            None => TokenKind::Identifier(raw),
         };
         self.push_token(kind, start);
         Ok(())
@@ -561,7 +581,9 @@ impl Lexer {
         if let Some(&ch) = self.source.get(self.char_index) {
             self.char_index += 1;
             self.offset += ch.len_utf8();
-            if ch == '\n' {
+            let line_break =
+                ch == '\n' || (ch == '\r' && self.source.get(self.char_index) != Some(&'\n'));
+            if line_break {
                 self.line += 1;
                 self.column = 1;
             } else {
@@ -594,9 +616,71 @@ impl Lexer {
     }
 }
 
-pub(super) fn insert_indent_tokens(tokens: Vec<LexToken>) -> Vec<LexToken> {
+/// The keyword spelled `word`, if it is one.
+pub(super) fn keyword(word: &str) -> Option<Keyword> {
+    let keyword = match word {
+        "if" => Keyword::If,
+        "elif" => Keyword::Elif,
+        "else" => Keyword::Else,
+        "while" => Keyword::While,
+        "for" => Keyword::For,
+        "in" => Keyword::In,
+        "is" => Keyword::Is,
+        "lambda" => Keyword::Lambda,
+        "def" => Keyword::Def,
+        "match" => Keyword::Match,
+        "case" => Keyword::Case,
+        "pass" => Keyword::Pass,
+        "return" => Keyword::Return,
+        "break" => Keyword::Break,
+        "continue" => Keyword::Continue,
+        "and" => Keyword::And,
+        "or" => Keyword::Or,
+        "not" => Keyword::Not,
+        "True" => Keyword::True,
+        "False" => Keyword::False,
+        "None" => Keyword::None,
+        "import" => Keyword::Import,
+        "from" => Keyword::From,
+        "as" => Keyword::As,
+        "try" => Keyword::Try,
+        "except" => Keyword::Except,
+        "finally" => Keyword::Finally,
+        "class" => Keyword::Class,
+        "with" => Keyword::With,
+        "assert" => Keyword::Assert,
+        "raise" => Keyword::Raise,
+        "del" => Keyword::Del,
+        "global" => Keyword::Global,
+        "nonlocal" => Keyword::Nonlocal,
+        "yield" => Keyword::Yield,
+        "async" => Keyword::Async,
+        "await" => Keyword::Await,
+        _ => return None,
+    };
+    Some(keyword)
+}
+
+/// Encoding named by a `coding[:=] name` declaration in `comment`.
+fn coding_cookie(comment: &str) -> Option<String> {
+    let (_, rest) = comment.split_once("coding")?;
+    let rest = rest.strip_prefix([':', '='])?;
+    let name: String = rest
+        .trim_start_matches([' ', '\t'])
+        .chars()
+        .take_while(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.'))
+        .collect();
+    (!name.is_empty()).then_some(name)
+}
+
+/// Replaces indentation with `Indent`/`Dedent` tokens.
+///
+/// Like CPython, each indentation is measured twice, with tabs advancing to
+/// the next multiple of 8 and with tabs counting as one column, and the line
+/// is rejected when the two measurements disagree about its block.
+pub(super) fn insert_indent_tokens(tokens: Vec<LexToken>) -> Result<Vec<LexToken>, ParseError> {
     let mut output = Vec::new();
-    let mut indent_stack = vec![0usize];
+    let mut indent_stack = vec![(0usize, 0usize)];
     let mut pending_indent: Option<(usize, Position, Position, String)> = None;
     for token in tokens {
         match token.kind {
@@ -634,16 +718,20 @@ pub(super) fn insert_indent_tokens(tokens: Vec<LexToken>) -> Vec<LexToken> {
             _ => {
                 if let Some((level, start, end, raw)) = pending_indent.take() {
                     if !token.kind.is_trivia() && token.kind != TokenKind::Newline {
-                        let current = *indent_stack.last().unwrap_or(&0);
-                        if level > current && level > 0 {
-                            indent_stack.push(level);
+                        let tab_columns = raw.chars().count();
+                        let (current, current_tabs) = *indent_stack.last().unwrap_or(&(0, 0));
+                        if level > current {
+                            if tab_columns <= current_tabs {
+                                return Err(tab_error(start, end));
+                            }
+                            indent_stack.push((level, tab_columns));
                             output.push(LexToken {
                                 kind: TokenKind::Indent { level },
                                 span: Span::join(start, end),
                                 raw: String::new(),
                             });
-                        } else if level < current {
-                            while let Some(last) = indent_stack.last().copied() {
+                        } else {
+                            while let Some((last, _)) = indent_stack.last().copied() {
                                 if level >= last {
                                     break;
                                 }
@@ -654,6 +742,17 @@ pub(super) fn insert_indent_tokens(tokens: Vec<LexToken>) -> Vec<LexToken> {
                                     raw: String::new(),
                                 });
                             }
+                            let (last, last_tabs) = *indent_stack.last().unwrap_or(&(0, 0));
+                            if level != last {
+                                return Err(ParseError {
+                                    message: "unindent does not match any outer indentation level"
+                                        .to_string(),
+                                    span: Span::join(start, end),
+                                });
+                            }
+                            if tab_columns != last_tabs {
+                                return Err(tab_error(start, end));
+                            }
                         }
                         output.push(LexToken {
                             kind: TokenKind::RawWhitespace("".to_string()),
@@ -672,7 +771,14 @@ pub(super) fn insert_indent_tokens(tokens: Vec<LexToken>) -> Vec<LexToken> {
             }
         }
     }
-    output
+    Ok(output)
+}
+
+fn tab_error(start: Position, end: Position) -> ParseError {
+    ParseError {
+        message: "inconsistent use of tabs and spaces in indentation".to_string(),
+        span: Span::join(start, end),
+    }
 }
 
 pub(super) fn attach_trivia(raw_tokens: Vec<LexToken>) -> Vec<Token> {
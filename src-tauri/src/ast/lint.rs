@@ -0,0 +1,311 @@
+use super::*;
+use std::collections::{HashMap, HashSet};
+
+impl<'a> LintContext<'a> {
+    pub fn new(program: &'a Program, symbols: &'a SymbolTable) -> Self {
+        LintContext {
+            program,
+            symbols,
+            names: OnceCell::new(),
+            warnings: OnceCell::new(),
+        }
+    }
+
+    pub fn names(&self) -> &[NameDiagnostic] {
+        self.names
+            .get_or_init(|| self.program.check_names(self.symbols))
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        self.warnings
+            .get_or_init(|| self.program.warnings(self.symbols))
+    }
+}
+
+/// Runs lint rules over programs.
+pub struct Linter {
+    rules: Vec<Box<dyn LintRule>>,
+}
+
+impl Default for Linter {
+    /// A linter with the built-in rules.
+    fn default() -> Self {
+        let mut linter = Linter::new();
+        for kind in [
+            NameDiagnosticKind::Undefined,
+            NameDiagnosticKind::UsedBeforeAssignment,
+        ] {
+            linter.add_rule(Box::new(NameRule(kind)));
+        }
+        for kind in [
+            WarningKind::UnusedImport,
+            WarningKind::UnusedVariable,
+            WarningKind::UnusedParameter,
+            WarningKind::UnreachableCode,
+        ] {
+            linter.add_rule(Box::new(WarningRule(kind)));
+        }
+        linter.add_rule(Box::new(TypeErrors));
+        linter.add_rule(Box::new(GlobalStatement));
+        linter
+    }
+}
+
+impl Linter {
+    /// A linter without rules.
+    pub fn new() -> Self {
+        Linter { rules: Vec::new() }
+    }
+
+    /// Adds `rule`, replacing a rule with the same id.
+    pub fn add_rule(&mut self, rule: Box<dyn LintRule>) {
+        self.rules.retain(|existing| existing.id() != rule.id());
+        self.rules.push(rule);
+    }
+
+    /// The rules with their settings under `config`.
+    pub fn rules(&self, config: &LintConfig) -> Vec<LintRuleInfo> {
+        self.rules
+            .iter()
+            .map(|rule| {
+                let (enabled, severity) = settings(rule.as_ref(), config);
+                LintRuleInfo {
+                    id: rule.id().to_string(),
+                    description: rule.description().to_string(),
+                    severity,
+                    enabled,
+                }
+            })
+            .collect()
+    }
+
+    /// Runs the rules `config` enables, in source order of what they find.
+    /// A line with a `# noqa` comment gets no diagnostics from it, and one
+    /// with `# noqa: a, b` none from rules `a` and `b`.
+    pub fn lint(&self, program: &Program, config: &LintConfig) -> Vec<LintDiagnostic> {
+        let symbols = program.symbols();
+        let context = LintContext::new(program, &symbols);
+        let suppressions = suppressions(program);
+        let mut diagnostics = Vec::new();
+        for rule in &self.rules {
+            let (enabled, severity) = settings(rule.as_ref(), config);
+            if !enabled {
+                continue;
+            }
+            for finding in rule.check(&context) {
+                let suppressed = match suppressions.get(&finding.span.start.line) {
+                    Some(None) => true,
+                    Some(Some(rules)) => rules.contains(rule.id()),
+                    None => false,
+                };
+                if !suppressed {
+                    diagnostics.push(LintDiagnostic {
+                        rule: rule.id().to_string(),
+                        severity,
+                        message: finding.message,
+                        node: finding.node,
+                        span: finding.span,
+                        fix: finding.fix,
+                    });
+                }
+            }
+        }
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.start.offset);
+        diagnostics
+    }
+}
+
+/// Parses `source` and lints it with the built-in rules.
+pub fn lint_python(
+    source: String,
+    config: &LintConfig,
+    parser: ParserConfig,
+) -> Result<Vec<LintDiagnostic>, ParseError> {
+    let program = Program::parse(source, parser)?;
+    Ok(Linter::default().lint(&program, config))
+}
+
+/// Whether `rule` runs under `config`, and with what severity.
+fn settings(rule: &dyn LintRule, config: &LintConfig) -> (bool, Severity) {
+    let settings = config.rules.get(rule.id()).copied().unwrap_or_default();
+    (
+        settings.enabled.unwrap_or(rule.enabled_by_default()),
+        settings.severity.unwrap_or(rule.severity()),
+    )
+}
+
+/// The lines with a `# noqa` comment, with the rules it names after a `:`,
+/// or `None` when it names none and so silences them all.
+fn suppressions(program: &Program) -> HashMap<usize, Option<HashSet<String>>> {
+    let mut lines = HashMap::new();
+    let trivia = program
+        .tokens
+        .iter()
+        .flat_map(|token| token.leading_trivia.iter().chain(&token.trailing_trivia));
+    for trivia in trivia {
+        let TriviaKind::Comment(text) = &trivia.kind else {
+            continue;
+        };
+        let Some(after) = noqa(text) else {
+            continue;
+        };
+        let rules = after.trim_start().strip_prefix(':').map(|rules| {
+            rules
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|rule| !rule.is_empty())
+                .map(str::to_string)
+                .collect()
+        });
+        lines.insert(trivia.span.start.line, rules);
+    }
+    lines
+}
+
+/// The rest of a comment that opens with the word `noqa`, in any case.
+fn noqa(comment: &str) -> Option<&str> {
+    let body = comment.trim_start();
+    let word = body.get(.."noqa".len())?;
+    let after = &body["noqa".len()..];
+    let whole_word = !after.starts_with(|c: char| c.is_alphanumeric() || c == '_');
+    (word.eq_ignore_ascii_case("noqa") && whole_word).then_some(after)
+}
+
+/// Reports one kind of `Program::check_names`.
+struct NameRule(NameDiagnosticKind);
+
+impl LintRule for NameRule {
+    fn id(&self) -> &'static str {
+        match self.0 {
+            NameDiagnosticKind::Undefined => "undefined-name",
+            NameDiagnosticKind::UsedBeforeAssignment => "used-before-assignment",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self.0 {
+            NameDiagnosticKind::Undefined => "Reads of names bound nowhere",
+            NameDiagnosticKind::UsedBeforeAssignment => {
+                "Reads of variables before any assignment to them"
+            }
+        }
+    }
+
+    fn severity(&self) -> Severity {
+        Severity::Error
+    }
+
+    fn check(&self, context: &LintContext) -> Vec<Finding> {
+        context
+            .names()
+            .iter()
+            .filter(|diagnostic| diagnostic.kind == self.0)
+            .map(|diagnostic| Finding {
+                message: diagnostic.message(),
+                node: Some(diagnostic.node),
+                span: diagnostic.span,
+                fix: None,
+            })
+            .collect()
+    }
+}
+
+/// Reports one kind of `Program::warnings`.
+struct WarningRule(WarningKind);
+
+impl LintRule for WarningRule {
+    fn id(&self) -> &'static str {
+        match self.0 {
+            WarningKind::UnusedImport => "unused-import",
+            WarningKind::UnusedVariable => "unused-variable",
+            WarningKind::UnusedParameter => "unused-parameter",
+            WarningKind::UnreachableCode => "unreachable-code",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self.0 {
+            WarningKind::UnusedImport => "Imports nothing reads",
+            WarningKind::UnusedVariable => "Variables of functions assigned but never read",
+            WarningKind::UnusedParameter => "Parameters the function never reads",
+            WarningKind::UnreachableCode => "Statements after a return, break, continue or raise",
+        }
+    }
+
+    fn check(&self, context: &LintContext) -> Vec<Finding> {
+        context
+            .warnings()
+            .iter()
+            .filter(|warning| warning.kind == self.0)
+            .map(|warning| Finding {
+                message: warning.message(),
+                node: Some(warning.node),
+                span: warning.span,
+                fix: Some(warning.fix.clone()),
+            })
+            .collect()
+    }
+}
+
+/// The errors of `Program::infer_types`.
+struct TypeErrors;
+
+impl LintRule for TypeErrors {
+    fn id(&self) -> &'static str {
+        "type-error"
+    }
+
+    fn description(&self) -> &'static str {
+        "Operators applied to values of types they do not support"
+    }
+
+    fn check(&self, context: &LintContext) -> Vec<Finding> {
+        context
+            .program
+            .infer_types(context.symbols)
+            .errors
+            .into_iter()
+            .map(|error| Finding {
+                message: error.message(),
+                node: Some(error.node),
+                span: error.span,
+                fix: None,
+            })
+            .collect()
+    }
+}
+
+/// `global` statements, for lessons that keep functions to their own
+/// variables.
+struct GlobalStatement;
+
+impl LintRule for GlobalStatement {
+    fn id(&self) -> &'static str {
+        "global-statement"
+    }
+
+    fn description(&self) -> &'static str {
+        "Functions assigning module variables through `global`"
+    }
+
+    fn enabled_by_default(&self) -> bool {
+        false
+    }
+
+    fn check(&self, context: &LintContext) -> Vec<Finding> {
+        let symbols = context.symbols;
+        symbols
+            .references
+            .iter()
+            .filter(|reference| reference.kind == ReferenceKind::Global)
+            .map(|reference| Finding {
+                message: format!(
+                    "'{}' is declared global; pass it in and return it instead",
+                    symbols.symbols[reference.symbol.0].name
+                ),
+                node: Some(reference.node),
+                span: reference.span,
+                fix: None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,472 @@
+use super::render::stmt_meta;
+use super::*;
+use std::collections::HashSet;
+
+/// Names Python provides without an import.
+pub(super) const BUILTINS: &[&str] = &[
+    "__build_class__",
+    "__debug__",
+    "__doc__",
+    "__file__",
+    "__import__",
+    "__name__",
+    "__package__",
+    "__spec__",
+    "abs",
+    "aiter",
+    "all",
+    "anext",
+    "any",
+    "ascii",
+    "bin",
+    "bool",
+    "breakpoint",
+    "bytearray",
+    "bytes",
+    "callable",
+    "chr",
+    "classmethod",
+    "compile",
+    "complex",
+    "copyright",
+    "credits",
+    "delattr",
+    "dict",
+    "dir",
+    "divmod",
+    "enumerate",
+    "eval",
+    "exec",
+    "exit",
+    "filter",
+    "float",
+    "format",
+    "frozenset",
+    "getattr",
+    "globals",
+    "hasattr",
+    "hash",
+    "help",
+    "hex",
+    "id",
+    "input",
+    "int",
+    "isinstance",
+    "issubclass",
+    "iter",
+    "len",
+    "license",
+    "list",
+    "locals",
+    "map",
+    "max",
+    "memoryview",
+    "min",
+    "next",
+    "object",
+    "oct",
+    "open",
+    "ord",
+    "pow",
+    "print",
+    "property",
+    "quit",
+    "range",
+    "repr",
+    "reversed",
+    "round",
+    "set",
+    "setattr",
+    "slice",
+    "sorted",
+    "staticmethod",
+    "str",
+    "sum",
+    "super",
+    "tuple",
+    "type",
+    "vars",
+    "zip",
+    "ArithmeticError",
+    "AssertionError",
+    "AttributeError",
+    "BaseException",
+    "BlockingIOError",
+    "BrokenPipeError",
+    "BufferError",
+    "BytesWarning",
+    "ChildProcessError",
+    "ConnectionAbortedError",
+    "ConnectionError",
+    "ConnectionRefusedError",
+    "ConnectionResetError",
+    "DeprecationWarning",
+    "EOFError",
+    "Ellipsis",
+    "EncodingWarning",
+    "EnvironmentError",
+    "Exception",
+    "False",
+    "FileExistsError",
+    "FileNotFoundError",
+    "FloatingPointError",
+    "FutureWarning",
+    "GeneratorExit",
+    "IOError",
+    "ImportError",
+    "ImportWarning",
+    "IndentationError",
+    "IndexError",
+    "InterruptedError",
+    "IsADirectoryError",
+    "KeyError",
+    "KeyboardInterrupt",
+    "LookupError",
+    "MemoryError",
+    "ModuleNotFoundError",
+    "NameError",
+    "None",
+    "NotADirectoryError",
+    "NotImplemented",
+    "NotImplementedError",
+    "OSError",
+    "OverflowError",
+    "PendingDeprecationWarning",
+    "PermissionError",
+    "ProcessLookupError",
+    "RecursionError",
+    "ReferenceError",
+    "ResourceWarning",
+    "RuntimeError",
+    "RuntimeWarning",
+    "StopAsyncIteration",
+    "StopIteration",
+    "SyntaxError",
+    "SyntaxWarning",
+    "SystemError",
+    "SystemExit",
+    "TabError",
+    "TimeoutError",
+    "True",
+    "TypeError",
+    "UnboundLocalError",
+    "UnicodeDecodeError",
+    "UnicodeEncodeError",
+    "UnicodeError",
+    "UnicodeTranslateError",
+    "UnicodeWarning",
+    "UserWarning",
+    "ValueError",
+    "Warning",
+    "ZeroDivisionError",
+];
+
+impl Program {
+    /// Finds the reads of names that would raise `NameError` when run:
+    /// names bound nowhere, and variables of a function or of the module
+    /// read before any assignment to them, or after a `del`, on every path
+    /// leading to the first time the read runs. Deleting a name counts as
+    /// reading it. Variables also assigned from other scopes
+    /// through `global` or `nonlocal` are not checked for the second.
+    /// `table` is the program's `symbols()`.
+    pub fn check_names(&self, table: &SymbolTable) -> Vec<NameDiagnostic> {
+        let mut defined = HashSet::new();
+        for reference in &table.references {
+            if matches!(
+                reference.kind,
+                ReferenceKind::Definition | ReferenceKind::Parameter | ReferenceKind::Declaration
+            ) {
+                defined.insert(reference.symbol);
+            }
+        }
+        let mut check = Check {
+            table,
+            defined: &defined,
+            diagnostics: Vec::new(),
+        };
+        for reference in &table.references {
+            let name = &table.symbols[reference.symbol.0].name;
+            if matches!(reference.kind, ReferenceKind::Use | ReferenceKind::Deletion)
+                && !defined.contains(&reference.symbol)
+                && !BUILTINS.contains(&name.as_str())
+            {
+                check.report(reference, NameDiagnosticKind::Undefined);
+            }
+        }
+        check.visit_program(self);
+        let mut diagnostics = check.diagnostics;
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.start.offset);
+        diagnostics
+    }
+}
+
+/// Runs the flow of every function and of the module.
+struct Check<'a> {
+    table: &'a SymbolTable,
+    /// Symbols assigned somewhere; reads of the others are undefined.
+    defined: &'a HashSet<SymbolId>,
+    diagnostics: Vec<NameDiagnostic>,
+}
+
+impl Check<'_> {
+    fn report(&mut self, reference: &Reference, kind: NameDiagnosticKind) {
+        self.diagnostics.push(NameDiagnostic {
+            kind,
+            name: self.table.symbols[reference.symbol.0].name.clone(),
+            node: reference.node,
+            span: reference.span,
+        });
+    }
+
+    /// Follows the statements of the scope opened by `node`.
+    fn flow(&mut self, node: NodeId, kind: ScopeKind, body: &[Stmt]) {
+        let table = self.table;
+        let defined = self.defined;
+        let Some(scope) = table
+            .scopes
+            .iter()
+            .position(|scope| scope.node == node && scope.kind == kind)
+            .map(ScopeId)
+        else {
+            return;
+        };
+        let references: Vec<&Reference> = table
+            .references
+            .iter()
+            .filter(|reference| reference.scope == scope)
+            .collect();
+        let assigned_elsewhere: HashSet<SymbolId> = table
+            .references
+            .iter()
+            .filter(|reference| {
+                reference.kind == ReferenceKind::Definition
+                    && reference.scope != table.symbols[reference.symbol.0].scope
+            })
+            .map(|reference| reference.symbol)
+            .collect();
+        let mut flow = Flow {
+            references,
+            checked: |symbol: SymbolId| {
+                table.symbols[symbol.0].scope == scope
+                    && defined.contains(&symbol)
+                    && !assigned_elsewhere.contains(&symbol)
+            },
+            repeated: HashSet::new(),
+            unassigned: Vec::new(),
+        };
+        let mut assigned = flow
+            .references
+            .iter()
+            .filter(|reference| reference.kind == ReferenceKind::Parameter)
+            .map(|reference| reference.symbol)
+            .collect();
+        flow.block(body, &mut assigned);
+        for reference in flow.unassigned {
+            self.report(reference, NameDiagnosticKind::UsedBeforeAssignment);
+        }
+    }
+}
+
+impl Visitor for Check<'_> {
+    fn visit_program(&mut self, program: &Program) {
+        self.flow(program.meta.id, ScopeKind::Module, &program.body);
+        self.walk_program(program);
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        if let Stmt::FunctionDef(function) = stmt {
+            self.flow(
+                function.meta.id,
+                ScopeKind::Function,
+                &function.body.statements,
+            );
+        }
+        self.walk_stmt(stmt);
+    }
+}
+
+/// Symbols that may have been assigned at a point of a scope, on some path
+/// leading there.
+type Assigned = HashSet<SymbolId>;
+
+/// Follows the statements of one scope, in the order they may run.
+struct Flow<'a, F> {
+    /// References of the scope, in the order Python evaluates them.
+    references: Vec<&'a Reference>,
+    /// Whether reads of a symbol are checked.
+    checked: F,
+    /// Symbols the loops around the current statement assign. A branch
+    /// that may be skipped on one pass can run after they were assigned on
+    /// an earlier one.
+    repeated: Assigned,
+    unassigned: Vec<&'a Reference>,
+}
+
+impl<'a, F: Fn(SymbolId) -> bool> Flow<'a, F> {
+    fn block(&mut self, statements: &[Stmt], assigned: &mut Assigned) {
+        for stmt in statements {
+            self.stmt(stmt, assigned);
+        }
+    }
+
+    /// State at the start of a branch that may be skipped.
+    fn branch(&self, entry: &Assigned) -> Assigned {
+        entry.union(&self.repeated).copied().collect()
+    }
+
+    /// Runs the body of a loop, which may not run at all. What it assigns
+    /// counts for its branches on later passes, but not for the statements
+    /// that run on every pass before the assignment.
+    fn repeat(
+        &mut self,
+        span: &Span,
+        body: impl FnOnce(&mut Self, &mut Assigned),
+        assigned: &mut Assigned,
+    ) {
+        let outer = self.repeated.clone();
+        for reference in &self.references {
+            if reference.kind == ReferenceKind::Definition
+                && span.start.offset <= reference.span.start.offset
+                && reference.span.end.offset <= span.end.offset
+            {
+                self.repeated.insert(reference.symbol);
+            }
+        }
+        let mut pass = assigned.clone();
+        body(self, &mut pass);
+        self.repeated = outer;
+        assigned.extend(pass);
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, assigned: &mut Assigned) {
+        match stmt {
+            Stmt::If(stmt) => {
+                self.eval(&expr_meta(&stmt.condition).span, assigned);
+                let entry = assigned.clone();
+                let mut taken = self.branch(&entry);
+                self.block(&stmt.body.statements, &mut taken);
+                for elif in &stmt.elifs {
+                    let mut branch = self.branch(&entry);
+                    self.eval(&expr_meta(&elif.condition).span, &mut branch);
+                    self.block(&elif.body.statements, &mut branch);
+                    taken.extend(branch);
+                }
+                if let Some(body) = &stmt.else_body {
+                    let mut branch = self.branch(&entry);
+                    self.block(&body.statements, &mut branch);
+                    taken.extend(branch);
+                }
+                assigned.extend(taken);
+            }
+            Stmt::While(stmt) => {
+                self.eval(&expr_meta(&stmt.condition).span, assigned);
+                self.repeat(
+                    &stmt.meta.span,
+                    |flow, pass| flow.block(&stmt.body.statements, pass),
+                    assigned,
+                );
+                if let Some(body) = &stmt.else_body {
+                    self.block(&body.statements, assigned);
+                }
+            }
+            Stmt::For(stmt) => {
+                self.eval(&expr_meta(&stmt.iterable).span, assigned);
+                self.repeat(
+                    &stmt.meta.span,
+                    |flow, pass| {
+                        flow.eval(&expr_meta(&stmt.target).span, pass);
+                        flow.block(&stmt.body.statements, pass);
+                    },
+                    assigned,
+                );
+                if let Some(body) = &stmt.else_body {
+                    self.block(&body.statements, assigned);
+                }
+            }
+            Stmt::Match(stmt) => {
+                self.eval(&expr_meta(&stmt.subject).span, assigned);
+                let entry = assigned.clone();
+                for case in &stmt.cases.cases {
+                    let mut branch = self.branch(&entry);
+                    self.eval(&pattern_meta(&case.pattern).span, &mut branch);
+                    self.block(&case.body.statements, &mut branch);
+                    assigned.extend(branch);
+                }
+            }
+            Stmt::Try(stmt) => {
+                self.block(&stmt.body.statements, assigned);
+                let entry = assigned.clone();
+                for handler in &stmt.handlers {
+                    let mut branch = self.branch(&entry);
+                    if let Some(exception_type) = &handler.exception_type {
+                        self.eval(&expr_meta(exception_type).span, &mut branch);
+                    }
+                    self.bind(handler.meta.id, &mut branch);
+                    self.block(&handler.body.statements, &mut branch);
+                    assigned.extend(branch);
+                }
+                if let Some(body) = &stmt.else_body {
+                    let mut branch = self.branch(&entry);
+                    self.block(&body.statements, &mut branch);
+                    assigned.extend(branch);
+                }
+                if let Some(body) = &stmt.finally_body {
+                    self.block(&body.statements, assigned);
+                }
+            }
+            Stmt::With(with) => {
+                for item in &with.items {
+                    self.eval(&expr_meta(&item.context).span, assigned);
+                }
+                self.bind(with.meta.id, assigned);
+                self.block(&with.body.statements, assigned);
+            }
+            // The bodies of functions and classes are scopes of their own,
+            // so only the decorators, defaults, bases and the name bound
+            // are references of this scope.
+            Stmt::FunctionDef(FunctionDefStmt { decorators, .. })
+            | Stmt::ClassDef(ClassDefStmt { decorators, .. }) => {
+                for decorator in decorators {
+                    self.eval(&expr_meta(decorator).span, assigned);
+                }
+                self.eval(&stmt_meta(stmt).span, assigned);
+            }
+            _ => self.eval(&stmt_meta(stmt).span, assigned),
+        }
+    }
+
+    /// Evaluates the references within `span`.
+    fn eval(&mut self, span: &Span, assigned: &mut Assigned) {
+        let within = |reference: &&Reference| {
+            span.start.offset <= reference.span.start.offset
+                && reference.span.end.offset <= span.end.offset
+        };
+        for reference in self.references.iter().copied().filter(within) {
+            match reference.kind {
+                ReferenceKind::Use | ReferenceKind::Deletion
+                    if (self.checked)(reference.symbol)
+                        && !assigned.contains(&reference.symbol) =>
+                {
+                    self.unassigned.push(reference);
+                }
+                ReferenceKind::Definition => {
+                    assigned.insert(reference.symbol);
+                }
+                ReferenceKind::Deletion => {
+                    assigned.remove(&reference.symbol);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Assigns the names bound by the node `node` itself, such as the name
+    /// of an `except` clause.
+    fn bind(&mut self, node: NodeId, assigned: &mut Assigned) {
+        for reference in &self.references {
+            if reference.node == node && reference.kind == ReferenceKind::Definition {
+                assigned.insert(reference.symbol);
+            }
+        }
+    }
+}
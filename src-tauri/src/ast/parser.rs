@@ -1,19 +1,143 @@
 use super::*;
+use super::identity::max_node_id;
 use super::lexer::{attach_trivia, insert_indent_tokens, Lexer};
+use super::render::{blocks_mut, has_block, last_line, stmt_meta, stmt_meta_mut};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 
 impl Program {
     pub fn parse(source: String, config: ParserConfig) -> Result<Self, ParseError> {
         let mut lexer = Lexer::new(source);
         let lexed = lexer.lex()?;
         let raw_tokens = lexed.tokens.clone();
-        let tokens = attach_trivia(insert_indent_tokens(lexed.tokens));
-        let mut parser = Parser::new(tokens.clone(), config, lexed.indent_width);
+        let tokens = attach_trivia(insert_indent_tokens(lexed.tokens)?);
+        let mut parser = Parser::new(tokens, config, lexed.indent_width);
         let mut program = parser.parse_program()?;
-        program.tokens = tokens;
+        program.tokens = parser.into_tokens();
         program.raw_tokens = raw_tokens;
+        program.source_format = lexed.format;
+        program.own_comments();
         Ok(program)
     }
+
+    /// Gives the comments outside brackets to nodes. A comment on a line of
+    /// its own turns the blank statement standing in for that line into a
+    /// `Stmt::Comment`, and a comment ending a line goes to the statement
+    /// or decorator the line ends, or the block a header on it opens.
+    /// Comments after the last statement have no blank statements standing
+    /// in for their lines and are added to the end of the body.
+    pub(super) fn own_comments(&mut self) {
+        let raw_tokens = std::mem::take(&mut self.raw_tokens);
+        let mut comments = statement_comments(&raw_tokens);
+        for stmt in &mut self.body {
+            own_stmt_comments(stmt, &mut comments);
+        }
+        let end = self
+            .body
+            .iter()
+            .map(|stmt| stmt_meta(stmt).span.end.offset)
+            .max()
+            .unwrap_or(0);
+        let eof = self.tokens.len().saturating_sub(1);
+        let mut next_id = max_node_id(self);
+        for token in comments.into_values() {
+            if token.span.start.offset >= end {
+                next_id += 1;
+                self.body.push(Stmt::Comment(CommentStmt {
+                    meta: NodeMeta {
+                        id: NodeId(next_id),
+                        span: token.span,
+                        token_range: TokenRange {
+                            start: eof,
+                            end: eof,
+                        },
+                        ..NodeMeta::default()
+                    },
+                    text: comment_text(token),
+                }));
+            }
+        }
+        self.raw_tokens = raw_tokens;
+    }
+}
+
+/// Comments outside brackets by line. Comments inside brackets are kept
+/// with the elements around them instead.
+pub(super) fn statement_comments(tokens: &[LexToken]) -> BTreeMap<usize, &LexToken> {
+    let mut comments = BTreeMap::new();
+    let mut depth = 0usize;
+    for token in tokens {
+        match &token.kind {
+            TokenKind::LParen | TokenKind::LBracket | TokenKind::LBrace => depth += 1,
+            TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace => {
+                depth = depth.saturating_sub(1)
+            }
+            TokenKind::Comment(_) if depth == 0 => {
+                comments.insert(token.span.start.line, token);
+            }
+            _ => {}
+        }
+    }
+    comments
+}
+
+fn own_stmt_comments(stmt: &mut Stmt, comments: &mut BTreeMap<usize, &LexToken>) {
+    match stmt {
+        Stmt::Empty(empty) => {
+            let line = empty.meta.span.start.line;
+            if let Some(token) = comments.remove(&line) {
+                let mut meta = empty.meta.clone();
+                meta.span.start = token.span.start;
+                *stmt = Stmt::Comment(CommentStmt {
+                    meta,
+                    text: comment_text(token),
+                });
+            }
+            return;
+        }
+        Stmt::Comment(comment) => {
+            comments.remove(&comment.meta.span.start.line);
+            return;
+        }
+        Stmt::FunctionDef(FunctionDefStmt { decorators, .. })
+        | Stmt::ClassDef(ClassDefStmt { decorators, .. }) => {
+            for decorator in decorators {
+                let line = expr_meta(decorator).span.end.line;
+                expr_meta_mut(decorator).comment = comments.remove(&line).map(comment_text);
+            }
+        }
+        Stmt::Match(stmt) => {
+            let line = stmt.cases.meta.span.start.line;
+            stmt.cases.meta.comment = comments.remove(&line).map(comment_text);
+            for case in &mut stmt.cases.cases {
+                own_block_comments(&mut case.body, comments);
+            }
+        }
+        _ => {}
+    }
+    if has_block(stmt) {
+        for block in blocks_mut(stmt) {
+            own_block_comments(block, comments);
+        }
+    } else {
+        let meta = stmt_meta_mut(stmt);
+        meta.comment = comments.remove(&last_line(&meta.span)).map(comment_text);
+    }
+}
+
+fn own_block_comments(block: &mut Block, comments: &mut BTreeMap<usize, &LexToken>) {
+    block.meta.comment = comments
+        .remove(&block.meta.span.start.line)
+        .map(comment_text);
+    for stmt in &mut block.statements {
+        own_stmt_comments(stmt, comments);
+    }
+}
+
+/// Text of a comment token after the `#`.
+fn comment_text(token: &LexToken) -> String {
+    let raw = token.raw.trim_end();
+    raw.strip_prefix('#').unwrap_or(raw).to_string()
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -29,18 +153,22 @@ struct BinaryOpInfo {
     assoc: Associativity,
 }
 
-struct Parser {
+pub(super) struct Parser {
     tokens: Vec<Token>,
     index: usize,
     next_id: u64,
     config: ParserConfig,
     indent_width: usize,
+    /// Number of blocks currently open.
+    depth: usize,
+    /// Start offsets of comments already attached to an element.
+    claimed_comments: HashSet<usize>,
     last_span: Span,
     allow_in_compare: bool,
 }
 
 impl Parser {
-    fn new(tokens: Vec<Token>, config: ParserConfig, indent_width: usize) -> Self {
+    pub(super) fn new(tokens: Vec<Token>, config: ParserConfig, indent_width: usize) -> Self {
         let last_span = tokens.last().map(|token| token.span).unwrap_or_default();
         Self {
             tokens,
@@ -48,15 +176,55 @@ impl Parser {
             next_id: 1,
             config,
             indent_width,
+            depth: 0,
+            claimed_comments: HashSet::new(),
             last_span,
             allow_in_compare: true,
         }
     }
 
+    /// Resumes parsing at `index` of an already tokenized source, handing out
+    /// node ids from `next_id` onwards.
+    pub(super) fn resume(
+        tokens: Vec<Token>,
+        config: ParserConfig,
+        indent_width: usize,
+        index: usize,
+        next_id: u64,
+    ) -> Self {
+        let mut parser = Self::new(tokens, config, indent_width);
+        parser.index = index;
+        parser.next_id = next_id;
+        parser
+    }
+
+    pub(super) fn index(&self) -> usize {
+        self.index
+    }
+
+    pub(super) fn into_tokens(self) -> Vec<Token> {
+        self.tokens
+    }
+
     fn parse_program(&mut self) -> Result<Program, ParseError> {
         let start = self.index;
+        let body = self.parse_top_level_until(usize::MAX)?;
+        let meta = self.node_meta(start, self.index.saturating_sub(1));
+        Ok(Program {
+            meta,
+            indent_width: self.indent_width,
+            body,
+            tokens: Vec::new(),
+            raw_tokens: Vec::new(),
+            dirty: false,
+            source_format: SourceFormat::default(),
+        })
+    }
+
+    /// Parses top-level statements until the token at `end` is reached.
+    pub(super) fn parse_top_level_until(&mut self, end: usize) -> Result<Vec<Stmt>, ParseError> {
         let mut body = Vec::new();
-        while !self.check_tag(TokenTag::Eof) {
+        while self.index < end && !self.check_tag(TokenTag::Eof) {
             if self.check_tag(TokenTag::Newline) {
                 if self.peek_kind_offset(1).tag() == TokenTag::Eof {
                     self.advance();
@@ -73,15 +241,7 @@ impl Parser {
             }
             body.push(self.parse_stmt()?);
         }
-        let meta = self.node_meta(start, self.index.saturating_sub(1));
-        Ok(Program {
-            meta,
-            indent_width: self.indent_width,
-            body,
-            tokens: Vec::new(),
-            raw_tokens: Vec::new(),
-            dirty: false,
-        })
+        Ok(body)
     }
 
     fn parse_stmt(&mut self) -> Result<Stmt, ParseError> {
@@ -274,6 +434,7 @@ impl Parser {
                 value,
             }));
         }
+        let expr = self.parse_tuple_rest(start, expr)?;
         self.expect_line_end()?;
         let meta = self.node_meta(start, self.index.saturating_sub(1));
         Ok(Stmt::Expr(ExprStmt { meta, expr }))
@@ -652,7 +813,7 @@ impl Parser {
             }
             cases.push(self.parse_case_stmt()?);
         }
-        self.expect_tag(TokenTag::Dedent)?;
+        self.expect_dedent()?;
         let meta = self.node_meta(start, self.index.saturating_sub(1));
         Ok(CaseBlock {
             meta,
@@ -722,7 +883,7 @@ impl Parser {
             }
             statements.push(self.parse_stmt()?);
         }
-        self.expect_tag(TokenTag::Dedent)?;
+        self.expect_dedent()?;
         let meta = self.node_meta(start, self.index.saturating_sub(1));
         Ok(Block {
             meta,
@@ -748,12 +909,21 @@ impl Parser {
     fn parse_comma_separated_value(&mut self) -> Result<Expr, ParseError> {
         let start = self.index;
         let first = self.parse_expression_no_generator()?;
+        self.parse_tuple_rest(start, first)
+    }
+
+    /// Parses the items after `first` of a tuple without parentheses that
+    /// starts at token `start`, or returns `first` if no comma follows it.
+    fn parse_tuple_rest(&mut self, start: usize, first: Expr) -> Result<Expr, ParseError> {
         if !self.check_tag(TokenTag::Comma) {
             return Ok(first);
         }
         let mut elements = vec![first];
         while self.match_tag(TokenTag::Comma) {
-            if self.check_tag(TokenTag::Newline) || self.check_tag(TokenTag::Eof) {
+            if self.check_tag(TokenTag::Newline)
+                || self.check_tag(TokenTag::Eof)
+                || self.check_tag(TokenTag::RParen)
+            {
                 break;
             }
             elements.push(self.parse_expression_no_generator()?);
@@ -791,7 +961,9 @@ impl Parser {
                 let meta = self.node_meta(start, self.index.saturating_sub(1));
                 return Ok(Expr::Yield(YieldExprData { meta, value: None }));
             }
+            let value_start = self.index;
             let value = self.parse_expression()?;
+            let value = self.parse_tuple_rest(value_start, value)?;
             let meta = self.node_meta(start, self.index.saturating_sub(1));
             return Ok(Expr::Yield(YieldExprData {
                 meta,
@@ -799,16 +971,6 @@ impl Parser {
             }));
         }
 
-        // await has very low precedence, similar to yield
-        if self.match_keyword(Keyword::Await) {
-            let value = self.parse_expression()?;
-            let meta = self.node_meta(start, self.index.saturating_sub(1));
-            return Ok(Expr::Await(AwaitExprData {
-                meta,
-                value: Box::new(value),
-            }));
-        }
-
         let expr = self.parse_lambda_with_if_expr(allow_if_expr)?;
         if allow_generator && self.match_keyword(Keyword::For) {
             let fors = self.parse_comprehension_fors()?;
@@ -852,7 +1014,7 @@ impl Parser {
                 }
             }
             self.expect_tag(TokenTag::Colon)?;
-            let body = self.parse_expression_with_generator(true, true)?;
+            let body = self.parse_expression_no_generator()?;
             let meta = self.node_meta(start, self.index.saturating_sub(1));
             return Ok(Expr::Lambda(LambdaExpr {
                 meta,
@@ -900,9 +1062,9 @@ impl Parser {
 
     fn parse_bool_and(&mut self) -> Result<Expr, ParseError> {
         let start = self.index;
-        let mut values = vec![self.parse_compare()?];
+        let mut values = vec![self.parse_not()?];
         while self.match_keyword(Keyword::And) {
-            values.push(self.parse_compare()?);
+            values.push(self.parse_not()?);
         }
         if values.len() == 1 {
             return Ok(values.remove(0));
@@ -915,6 +1077,20 @@ impl Parser {
         }))
     }
 
+    fn parse_not(&mut self) -> Result<Expr, ParseError> {
+        let start = self.index;
+        if !self.match_keyword(Keyword::Not) {
+            return self.parse_compare();
+        }
+        let expr = self.parse_not()?;
+        let meta = self.node_meta(start, self.index.saturating_sub(1));
+        Ok(Expr::Unary(UnaryExpr {
+            meta,
+            op: UnaryOp::Not,
+            expr: Box::new(expr),
+        }))
+    }
+
     fn parse_compare(&mut self) -> Result<Expr, ParseError> {
         let start = self.index;
         let left = self.parse_binary(0)?;
@@ -937,6 +1113,7 @@ impl Parser {
     }
 
     fn parse_binary(&mut self, min_prec: u8) -> Result<Expr, ParseError> {
+        let start = self.index;
         let mut left = self.parse_unary()?;
         loop {
             let info = match self.peek_binary_op_info() {
@@ -946,14 +1123,13 @@ impl Parser {
             if info.precedence < min_prec {
                 break;
             }
-            let op_meta_start = self.index;
             self.advance();
             let next_min = match info.assoc {
                 Associativity::Left => info.precedence + 1,
                 Associativity::Right => info.precedence,
             };
             let right = self.parse_binary(next_min)?;
-            let meta = self.node_meta(op_meta_start, self.index.saturating_sub(1));
+            let meta = self.node_meta(start, self.index.saturating_sub(1));
             left = Expr::Binary(BinaryExpr {
                 meta,
                 left: Box::new(left),
@@ -969,7 +1145,8 @@ impl Parser {
             TokenKind::Operator(Operator::Minus) => {
                 let start = self.index;
                 self.advance();
-                let expr = self.parse_unary()?;
+                // `-x ** y` is `-(x ** y)`.
+                let expr = self.parse_binary(BinaryOp::Power.precedence())?;
                 let meta = self.node_meta(start, self.index.saturating_sub(1));
                 Ok(Expr::Unary(UnaryExpr {
                     meta,
@@ -977,26 +1154,27 @@ impl Parser {
                     expr: Box::new(expr),
                 }))
             }
-            TokenKind::Keyword(Keyword::Not) => {
+            TokenKind::Operator(Operator::Tilde) => {
                 let start = self.index;
                 self.advance();
-                let expr = self.parse_unary()?;
+                let expr = self.parse_binary(BinaryOp::Power.precedence())?;
                 let meta = self.node_meta(start, self.index.saturating_sub(1));
                 Ok(Expr::Unary(UnaryExpr {
                     meta,
-                    op: UnaryOp::Not,
+                    op: UnaryOp::BitNot,
                     expr: Box::new(expr),
                 }))
             }
-            TokenKind::Operator(Operator::Tilde) => {
+            // `await` binds tighter than any operator: `await x ** 2` is
+            // `(await x) ** 2`.
+            TokenKind::Keyword(Keyword::Await) => {
                 let start = self.index;
                 self.advance();
-                let expr = self.parse_unary()?;
+                let value = self.parse_postfix()?;
                 let meta = self.node_meta(start, self.index.saturating_sub(1));
-                Ok(Expr::Unary(UnaryExpr {
+                Ok(Expr::Await(AwaitExprData {
                     meta,
-                    op: UnaryOp::BitNot,
-                    expr: Box::new(expr),
+                    value: Box::new(value),
                 }))
             }
             _ => self.parse_postfix(),
@@ -1004,6 +1182,7 @@ impl Parser {
     }
 
     fn parse_postfix(&mut self) -> Result<Expr, ParseError> {
+        let expr_start = self.index;
         let mut expr = self.parse_primary()?;
         loop {
             if self.match_tag(TokenTag::LParen) {
@@ -1036,7 +1215,14 @@ impl Parser {
                     }
                 }
                 self.expect_tag(TokenTag::RParen)?;
-                let meta = self.node_meta(start, self.index.saturating_sub(1));
+                let end = self.index.saturating_sub(1);
+                let elements = args
+                    .iter_mut()
+                    .chain(kwargs.iter_mut().map(|kwarg| &mut kwarg.value))
+                    .map(expr_meta_mut)
+                    .collect();
+                self.attach_comments(start, end, elements);
+                let meta = self.node_meta(expr_start, end);
                 expr = Expr::Call(CallExpr {
                     meta,
                     callee: Box::new(expr),
@@ -1046,9 +1232,8 @@ impl Parser {
                 continue;
             }
             if self.match_tag(TokenTag::Dot) {
-                let start = self.index.saturating_sub(1);
                 let attr = self.expect_identifier()?;
-                let meta = self.node_meta(start, self.index.saturating_sub(1));
+                let meta = self.node_meta(expr_start, self.index.saturating_sub(1));
                 expr = Expr::Attribute(AttributeExpr {
                     meta,
                     value: Box::new(expr),
@@ -1058,9 +1243,11 @@ impl Parser {
             }
             if self.match_tag(TokenTag::LBracket) {
                 let start = self.index.saturating_sub(1);
-                let index = self.parse_slice_or_expr()?;
+                let mut index = self.parse_slice_or_expr()?;
                 self.expect_tag(TokenTag::RBracket)?;
-                let meta = self.node_meta(start, self.index.saturating_sub(1));
+                let end = self.index.saturating_sub(1);
+                self.attach_comments(start, end, vec![expr_meta_mut(&mut index)]);
+                let meta = self.node_meta(expr_start, end);
                 expr = Expr::Subscript(SubscriptExpr {
                     meta,
                     value: Box::new(expr),
@@ -1101,7 +1288,7 @@ impl Parser {
                             parts.push(FStringPart::Literal(text.clone()));
                         }
                         FStringTokenPart::ExprText(text) => {
-                            let expr = self.parse_fstring_expr(text)?;
+                            let expr = self.parse_fstring_expr(text, start)?;
                             parts.push(FStringPart::Expr(expr));
                         }
                     }
@@ -1139,7 +1326,7 @@ impl Parser {
             }
             TokenKind::LParen => {
                 self.advance();
-                let expr = self.parse_expression()?;
+                let mut expr = self.parse_expression()?;
                 if self.match_tag(TokenTag::Comma) {
                     let mut elements = vec![expr];
                     if !self.check_tag(TokenTag::RParen) {
@@ -1155,11 +1342,15 @@ impl Parser {
                         }
                     }
                     self.expect_tag(TokenTag::RParen)?;
-                    let meta = self.node_meta(start, self.index.saturating_sub(1));
+                    let end = self.index.saturating_sub(1);
+                    self.attach_comments(start, end, element_metas(&mut elements));
+                    let meta = self.node_meta(start, end);
                     return Ok(Expr::Tuple(TupleExpr { meta, elements }));
                 }
                 self.expect_tag(TokenTag::RParen)?;
-                let meta = self.node_meta(start, self.index.saturating_sub(1));
+                let end = self.index.saturating_sub(1);
+                self.attach_comments(start, end, vec![expr_meta_mut(&mut expr)]);
+                let meta = self.node_meta(start, end);
                 Ok(Expr::Grouped(GroupedExpr {
                     meta,
                     expr: Box::new(expr),
@@ -1178,11 +1369,11 @@ impl Parser {
                 if self.match_tag(TokenTag::Comma) {
                     if self.check_tag(TokenTag::RBracket) {
                         self.expect_tag(TokenTag::RBracket)?;
-                        let meta = self.node_meta(start, self.index.saturating_sub(1));
-                        return Ok(Expr::List(ListExpr {
-                            meta,
-                            elements: vec![first],
-                        }));
+                        let mut elements = vec![first];
+                        let end = self.index.saturating_sub(1);
+                        self.attach_comments(start, end, element_metas(&mut elements));
+                        let meta = self.node_meta(start, end);
+                        return Ok(Expr::List(ListExpr { meta, elements }));
                     }
                     if self.match_keyword(Keyword::For) {
                         let fors = self.parse_comprehension_fors()?;
@@ -1196,7 +1387,8 @@ impl Parser {
                             },
                         )));
                     }
-                    let mut elements = vec![first];
+                    // The comma after `first` has been consumed already.
+                    let mut elements = vec![first, self.parse_expression_no_generator()?];
                     while self.match_tag(TokenTag::Comma) {
                         if self.check_tag(TokenTag::RBracket) {
                             break;
@@ -1204,7 +1396,9 @@ impl Parser {
                         elements.push(self.parse_expression_no_generator()?);
                     }
                     self.expect_tag(TokenTag::RBracket)?;
-                    let meta = self.node_meta(start, self.index.saturating_sub(1));
+                    let end = self.index.saturating_sub(1);
+                    self.attach_comments(start, end, element_metas(&mut elements));
+                    let meta = self.node_meta(start, end);
                     return Ok(Expr::List(ListExpr { meta, elements }));
                 }
                 if self.match_keyword(Keyword::For) {
@@ -1227,7 +1421,9 @@ impl Parser {
                     elements.push(self.parse_expression_no_generator()?);
                 }
                 self.expect_tag(TokenTag::RBracket)?;
-                let meta = self.node_meta(start, self.index.saturating_sub(1));
+                let end = self.index.saturating_sub(1);
+                self.attach_comments(start, end, element_metas(&mut elements));
+                let meta = self.node_meta(start, end);
                 Ok(Expr::List(ListExpr { meta, elements }))
             }
             TokenKind::LBrace => self.parse_dict_or_set(),
@@ -1245,6 +1441,7 @@ impl Parser {
                 entries: Vec::new(),
             }));
         }
+        let first_start = self.index;
         let first = self.parse_expression_no_generator()?;
         if self.match_tag(TokenTag::Colon) {
             let value = self.parse_expression_no_generator()?;
@@ -1262,7 +1459,7 @@ impl Parser {
                 )));
             }
             let mut entries = vec![DictEntry {
-                meta: self.node_meta(start, self.index.saturating_sub(1)),
+                meta: self.node_meta(first_start, self.index.saturating_sub(1)),
                 key: first,
                 value,
             }];
@@ -1281,7 +1478,10 @@ impl Parser {
                 });
             }
             self.expect_tag(TokenTag::RBrace)?;
-            let meta = self.node_meta(start, self.index.saturating_sub(1));
+            let end = self.index.saturating_sub(1);
+            let metas = entries.iter_mut().map(|entry| &mut entry.meta).collect();
+            self.attach_comments(start, end, metas);
+            let meta = self.node_meta(start, end);
             return Ok(Expr::Dict(DictExpr { meta, entries }));
         }
         if self.match_keyword(Keyword::For) {
@@ -1304,7 +1504,9 @@ impl Parser {
             elements.push(self.parse_expression_no_generator()?);
         }
         self.expect_tag(TokenTag::RBrace)?;
-        let meta = self.node_meta(start, self.index.saturating_sub(1));
+        let end = self.index.saturating_sub(1);
+        self.attach_comments(start, end, element_metas(&mut elements));
+        let meta = self.node_meta(start, end);
         Ok(Expr::Set(SetExpr { meta, elements }))
     }
 
@@ -1484,20 +1686,21 @@ impl Parser {
 
     fn expect_indent(&mut self) -> Result<usize, ParseError> {
         match self.peek_kind() {
-            TokenKind::Indent { level } => {
+            TokenKind::Indent { .. } => {
                 self.advance();
-                if self.indent_width == 0 {
-                    return Ok(level);
-                }
-                if level % self.indent_width != 0 {
-                    return Err(self.error("invalid indent width"));
-                }
-                Ok(level / self.indent_width)
+                self.depth += 1;
+                Ok(self.depth)
             }
             _ => Err(self.error("expected indent")),
         }
     }
 
+    fn expect_dedent(&mut self) -> Result<(), ParseError> {
+        self.expect_tag(TokenTag::Dedent)?;
+        self.depth = self.depth.saturating_sub(1);
+        Ok(())
+    }
+
     fn expect_identifier(&mut self) -> Result<String, ParseError> {
         match self.peek_kind() {
             TokenKind::Identifier(name) => {
@@ -1528,12 +1731,27 @@ impl Parser {
         }
     }
 
-    fn parse_fstring_expr(&self, text: &str) -> Result<Expr, ParseError> {
+    fn parse_fstring_expr(&mut self, text: &str, token_index: usize) -> Result<Expr, ParseError> {
         let mut lexer = Lexer::new(text.to_string());
         let lexed = lexer.lex()?;
-        let tokens = attach_trivia(insert_indent_tokens(lexed.tokens));
-        let mut sub_parser = Parser::new(tokens, self.config, self.indent_width);
-        sub_parser.parse_expression()
+        let tokens = attach_trivia(insert_indent_tokens(lexed.tokens)?);
+        let mut sub_parser =
+            Parser::resume(tokens, self.config, self.indent_width, 0, self.next_id);
+        let mut expr = sub_parser.parse_expression()?;
+        self.next_id = sub_parser.next_id;
+        // Embedded expressions have no tokens of their own in this program,
+        // so their metadata points at the enclosing f-string token.
+        let span = self.tokens[token_index].span;
+        expr_metas_mut(&mut expr, &mut |meta| {
+            meta.span = span;
+            meta.token_range = TokenRange {
+                start: token_index,
+                end: token_index,
+            };
+            meta.leading_trivia.clear();
+            meta.trailing_trivia.clear();
+        });
+        Ok(expr)
     }
 
     fn expect_keyword(&mut self, keyword: Keyword) -> Result<(), ParseError> {
@@ -1628,6 +1846,58 @@ impl Parser {
         }
     }
 
+    /// Hands the comments inside a bracketed construct to its elements.
+    ///
+    /// `open` and `close` are the bracket tokens. Comments before an element,
+    /// including one right after the opening bracket, lead it; the others
+    /// trail the element they follow. Nested constructs are finished first,
+    /// so they keep the comments that fall inside them.
+    fn attach_comments(&mut self, open: usize, close: usize, mut elements: Vec<&mut NodeMeta>) {
+        elements.sort_by_key(|meta| meta.token_range.start);
+        let starts: Vec<usize> = elements.iter().map(|meta| meta.token_range.start).collect();
+        for (position, meta) in elements.into_iter().enumerate() {
+            let start = starts[position];
+            let end = starts.get(position + 1).copied().unwrap_or(close);
+            let mut leading = Vec::new();
+            if position == 0 {
+                leading.extend(self.take_comments(open, false));
+            }
+            leading.extend(self.take_comments(start, true));
+            let mut trailing = Vec::new();
+            for index in start..end {
+                if index != start {
+                    trailing.extend(self.take_comments(index, true));
+                }
+                trailing.extend(self.take_comments(index, false));
+            }
+            if end == close {
+                trailing.extend(self.take_comments(close, true));
+            }
+            meta.leading_trivia = leading;
+            meta.trailing_trivia = trailing;
+        }
+    }
+
+    /// Comments around the token at `index` that no element has claimed yet.
+    fn take_comments(&mut self, index: usize, leading: bool) -> Vec<Trivia> {
+        let Some(token) = self.tokens.get(index) else {
+            return Vec::new();
+        };
+        let trivia = if leading {
+            &token.leading_trivia
+        } else {
+            &token.trailing_trivia
+        };
+        trivia
+            .iter()
+            .filter(|trivia| {
+                matches!(trivia.kind, TriviaKind::Comment(_))
+                    && self.claimed_comments.insert(trivia.span.start.offset)
+            })
+            .cloned()
+            .collect()
+    }
+
     fn node_meta(&mut self, start: usize, end: usize) -> NodeMeta {
         let start_token = self.tokens.get(start);
         let end_token = self.tokens.get(end);
@@ -1648,6 +1918,8 @@ impl Parser {
             token_range: TokenRange { start, end },
             leading_trivia,
             trailing_trivia,
+            dirty: false,
+            comment: None,
         };
         self.next_id = self.next_id.saturating_add(1);
         meta
@@ -1690,3 +1962,7 @@ impl Parser {
         }))
     }
 }
+
+fn element_metas(elements: &mut [Expr]) -> Vec<&mut NodeMeta> {
+    elements.iter_mut().map(expr_meta_mut).collect()
+}
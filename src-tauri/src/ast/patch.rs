@@ -0,0 +1,604 @@
+use super::lexer::{keyword, Lexer};
+use super::*;
+use serde_json::Value;
+use std::collections::HashSet;
+
+impl IrProgram {
+    /// Applies `operation` and returns the operation undoing it.
+    ///
+    /// The program is left as it was if the operation addresses a node that
+    /// is missing or of the wrong kind, gives a field a value of the wrong
+    /// shape, or leaves code Python refuses, such as `break` outside a loop,
+    /// a literal as an assignment target or a literal whose source is not
+    /// one token. Code that was refused already does not count.
+    /// Changed nodes and the blocks that gained or lost statements are
+    /// marked dirty.
+    pub fn apply(&mut self, operation: IrOperation) -> Result<IrOperation, PatchError> {
+        let body = serde_json::to_value(&self.body).map_err(patch_error)?;
+        let mut ids = Ids::default();
+        ids.visit_program(self);
+        let ids = ids.0;
+        let next_id = ids.iter().max().map_or(1, |max| max + 1);
+        let mut patch = Patch {
+            body,
+            ids,
+            next_id,
+            program_dirty: false,
+        };
+        let inverse = patch.apply(operation)?;
+        let statements: Vec<IrStmt> = serde_json::from_value(patch.body).map_err(patch_error)?;
+        let before = refused_code(&self.body);
+        if let Some((_, message)) = refused_code(&statements)
+            .into_iter()
+            .find(|error| !before.contains(error))
+        {
+            return Err(PatchError {
+                message: message.to_string(),
+            });
+        }
+        self.body = statements;
+        self.dirty |= patch.program_dirty;
+        Ok(inverse)
+    }
+}
+
+/// A program body being edited in its serialized form, where nodes are the
+/// objects with a `meta` field and are addressed by JSON pointers.
+struct Patch {
+    body: Value,
+    /// Ids in use, which new nodes must not take.
+    ids: HashSet<u64>,
+    next_id: u64,
+    /// Whether statements were added to or removed from the program itself.
+    program_dirty: bool,
+}
+
+impl Patch {
+    fn apply(&mut self, operation: IrOperation) -> Result<IrOperation, PatchError> {
+        match operation {
+            IrOperation::InsertStmt { block, index, stmt } => {
+                let mut stmt = *stmt;
+                let id = self.adopt(|adopt| adopt.visit_stmt(&mut stmt))?;
+                let stmt = serde_json::to_value(&stmt).map_err(patch_error)?;
+                self.insert(block, index, stmt)?;
+                Ok(IrOperation::DeleteNode { id })
+            }
+            IrOperation::DeleteNode { id } => {
+                let (block, index, stmt) = self.remove(id)?;
+                Ok(IrOperation::InsertStmt {
+                    block,
+                    index,
+                    stmt: Box::new(serde_json::from_value(stmt).map_err(patch_error)?),
+                })
+            }
+            IrOperation::MoveNode { id, block, index } => {
+                if let Some(block) = block {
+                    let moved = self.find(id)?;
+                    let target = self.find(block)?;
+                    if target.starts_with(&format!("{}/", moved.trim_end_matches("/data"))) {
+                        return Err(PatchError {
+                            message: "a statement cannot be moved into itself".to_string(),
+                        });
+                    }
+                }
+                let (old_block, old_index, stmt) = self.remove(id)?;
+                self.insert(block, index, stmt)?;
+                Ok(IrOperation::MoveNode {
+                    id,
+                    block: old_block,
+                    index: old_index,
+                })
+            }
+            IrOperation::ReplaceExpr { id, expr } => {
+                let (pointer, old) = self.expr_pointer(id)?;
+                let mut old_ids = Ids::default();
+                old_ids.visit_expr(&old);
+                self.ids.retain(|id| !old_ids.0.contains(id));
+                let mut expr = *expr;
+                let new_id = self.adopt(|adopt| adopt.visit_expr(&mut expr))?;
+                *self.node_mut(&pointer) = serde_json::to_value(&expr).map_err(patch_error)?;
+                Ok(IrOperation::ReplaceExpr {
+                    id: new_id,
+                    expr: Box::new(old),
+                })
+            }
+            IrOperation::SetField { id, field, value } => {
+                let pointer = self.find(id)?;
+                let node = self.node_mut(&pointer);
+                let Some(old) = node.get(&field) else {
+                    return Err(PatchError {
+                        message: format!("node {} has no field {field}", id.0),
+                    });
+                };
+                if field == "meta" || holds_nodes(old) || holds_nodes(&value) {
+                    return Err(PatchError {
+                        message: format!("field {field} holds nodes; edit them instead"),
+                    });
+                }
+                check_names(&field, &value)?;
+                let old = std::mem::replace(&mut node[&field], value);
+                node["meta"]["dirty"] = Value::Bool(true);
+                Ok(IrOperation::SetField {
+                    id,
+                    field,
+                    value: old,
+                })
+            }
+        }
+    }
+
+    /// Pointer to the node with `id`.
+    fn find(&self, id: NodeId) -> Result<String, PatchError> {
+        if id.0 == 0 {
+            return Err(missing(id));
+        }
+        find(&self.body, id.0).ok_or_else(|| missing(id))
+    }
+
+    fn node(&self, pointer: &str) -> &Value {
+        self.body.pointer(pointer).unwrap_or(&Value::Null)
+    }
+
+    fn node_mut(&mut self, pointer: &str) -> &mut Value {
+        self.body
+            .pointer_mut(pointer)
+            .expect("pointers come from the body")
+    }
+
+    /// Pointer to the statement list of `block`, or of the program.
+    fn statements(&self, block: Option<NodeId>) -> Result<String, PatchError> {
+        let Some(block) = block else {
+            return Ok(String::new());
+        };
+        let pointer = self.find(block)?;
+        if self.node(&pointer).get("statements").is_none() {
+            return Err(PatchError {
+                message: format!("node {} is not a block", block.0),
+            });
+        }
+        Ok(format!("{pointer}/statements"))
+    }
+
+    fn insert(
+        &mut self,
+        block: Option<NodeId>,
+        index: usize,
+        stmt: Value,
+    ) -> Result<(), PatchError> {
+        let pointer = self.statements(block)?;
+        let Value::Array(statements) = self.node_mut(&pointer) else {
+            return Err(missing(block.unwrap_or_default()));
+        };
+        if index > statements.len() {
+            return Err(PatchError {
+                message: format!("statement index {index} is out of range"),
+            });
+        }
+        statements.insert(index, stmt);
+        self.touch(block);
+        Ok(())
+    }
+
+    /// Takes the statement `id` out of its list, returning where it was.
+    fn remove(&mut self, id: NodeId) -> Result<(Option<NodeId>, usize, Value), PatchError> {
+        let pointer = self.find(id)?;
+        let not_statement = || PatchError {
+            message: format!("node {} is not a statement", id.0),
+        };
+        let (list, index) = pointer
+            .strip_suffix("/data")
+            .and_then(|wrapper| wrapper.rsplit_once('/'))
+            .ok_or_else(not_statement)?;
+        let index = index.parse::<usize>().map_err(|_| not_statement())?;
+        let block = match list.strip_suffix("/statements") {
+            Some(block) => self.node(block)["meta"]["id"].as_u64().map(NodeId),
+            None if list.is_empty() => None,
+            None => return Err(not_statement()),
+        };
+        let Value::Array(statements) = self.node_mut(list) else {
+            return Err(not_statement());
+        };
+        let stmt = statements.remove(index);
+        self.touch(block);
+        Ok((block, index, stmt))
+    }
+
+    /// Pointer to the expression whose node is `id`, including the tag of
+    /// its variant, and the expression.
+    fn expr_pointer(&self, id: NodeId) -> Result<(String, IrExpr), PatchError> {
+        let pointer = self.find(id)?;
+        let mut wrapper = pointer.strip_suffix("/data").unwrap_or_default();
+        // Comprehensions are tagged twice.
+        if let Some(outer) = wrapper.strip_suffix("/data") {
+            if self.node(outer)["kind"] == "Comprehension" {
+                wrapper = outer;
+            }
+        }
+        let not_expr = || PatchError {
+            message: format!("node {} is not an expression", id.0),
+        };
+        if wrapper.is_empty() || wrapper.ends_with("/pattern") {
+            return Err(not_expr());
+        }
+        let expr = serde_json::from_value(self.node(wrapper).clone()).map_err(|_| not_expr())?;
+        Ok((wrapper.to_string(), expr))
+    }
+
+    /// Lets `visit` walk new nodes with an `Adopt`, returning the id of the
+    /// first.
+    fn adopt(&mut self, visit: impl FnOnce(&mut Adopt)) -> Result<NodeId, PatchError> {
+        let mut adopt = Adopt {
+            ids: &mut self.ids,
+            next_id: &mut self.next_id,
+            root: None,
+            taken: None,
+        };
+        visit(&mut adopt);
+        if let Some(id) = adopt.taken {
+            return Err(PatchError {
+                message: format!("node {id} already exists"),
+            });
+        }
+        Ok(adopt.root.unwrap_or_default())
+    }
+
+    fn touch(&mut self, block: Option<NodeId>) {
+        match block.and_then(|block| find(&self.body, block.0)) {
+            Some(pointer) => self.node_mut(&pointer)["meta"]["dirty"] = Value::Bool(true),
+            None => self.program_dirty = true,
+        }
+    }
+}
+
+fn patch_error(error: serde_json::Error) -> PatchError {
+    PatchError {
+        message: error.to_string(),
+    }
+}
+
+fn missing(id: NodeId) -> PatchError {
+    PatchError {
+        message: format!("node {} does not exist", id.0),
+    }
+}
+
+/// JSON pointer to the node with `id` in `value`.
+fn find(value: &Value, id: u64) -> Option<String> {
+    match value {
+        Value::Object(map) => {
+            if map
+                .get("meta")
+                .and_then(|meta| meta.get("id"))
+                .and_then(Value::as_u64)
+                == Some(id)
+            {
+                return Some(String::new());
+            }
+            map.iter()
+                .find_map(|(key, field)| Some(format!("/{key}{}", find(field, id)?)))
+        }
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .find_map(|(index, item)| Some(format!("/{index}{}", find(item, id)?))),
+        _ => None,
+    }
+}
+
+/// Collects the ids of the nodes it visits.
+#[derive(Default)]
+struct Ids(HashSet<u64>);
+
+impl IrVisitor for Ids {
+    fn visit_meta(&mut self, meta: &NodeMeta) {
+        self.0.insert(meta.id.0);
+    }
+}
+
+/// Gives the nodes it visits that have no id new ones, and marks the first
+/// node, the root of what is added, dirty.
+struct Adopt<'a> {
+    ids: &'a mut HashSet<u64>,
+    next_id: &'a mut u64,
+    root: Option<NodeId>,
+    /// An id the nodes brought that was in use already.
+    taken: Option<u64>,
+}
+
+impl IrVisitorMut for Adopt<'_> {
+    fn visit_meta(&mut self, meta: &mut NodeMeta) {
+        if meta.id.0 == 0 {
+            while !self.ids.insert(*self.next_id) {
+                *self.next_id += 1;
+            }
+            meta.id = NodeId(*self.next_id);
+        } else if !self.ids.insert(meta.id.0) {
+            self.taken.get_or_insert(meta.id.0);
+        }
+        if self.root.is_none() {
+            meta.dirty = true;
+            self.root = Some(meta.id);
+        }
+    }
+}
+
+/// Rejects a value for a field holding names, such as the name of a `def`
+/// or the names of an import, if one of them is not an identifier.
+fn check_names(field: &str, value: &Value) -> Result<(), PatchError> {
+    let mut names = Vec::new();
+    match (field, value) {
+        ("name" | "attr" | "target" | "alias", Value::String(name)) => names.push(name.as_str()),
+        ("module", Value::String(module)) => names.extend(module.split('.')),
+        ("names" | "params", Value::Array(items)) => {
+            for item in items {
+                match item {
+                    Value::String(name) => names.push(name.as_str()),
+                    // Imported modules are dotted.
+                    Value::Object(map) => {
+                        if let Some(Value::String(name)) = map.get("name") {
+                            names.extend(name.split('.'));
+                        }
+                        if let Some(Value::String(alias)) = map.get("alias") {
+                            names.push(alias.as_str());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        _ => {}
+    }
+    match names.into_iter().find(|name| !is_identifier(name)) {
+        Some(name) => Err(PatchError {
+            message: format!("`{name}` is not a valid name"),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Whether the lexer reads `name` as one identifier.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        && keyword(name).is_none()
+}
+
+fn holds_nodes(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => map.contains_key("meta") || map.values().any(holds_nodes),
+        Value::Array(items) => items.iter().any(holds_nodes),
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Context {
+    in_loop: bool,
+    in_function: bool,
+    /// Inside a function, maybe with class bodies in between, so that
+    /// `nonlocal` has variables to name.
+    enclosed: bool,
+}
+
+/// Code Python refuses, with the reason.
+fn refused_code(body: &[IrStmt]) -> Vec<(NodeId, &'static str)> {
+    let mut errors = Vec::new();
+    check_statements(body, Context::default(), &mut errors);
+    let mut grammar = Grammar(&mut errors);
+    for stmt in body {
+        grammar.visit_stmt(stmt);
+    }
+    errors
+}
+
+fn check_statements(
+    statements: &[IrStmt],
+    context: Context,
+    errors: &mut Vec<(NodeId, &'static str)>,
+) {
+    let loop_body = Context {
+        in_loop: true,
+        ..context
+    };
+    for stmt in statements {
+        if !context.in_function {
+            OutsideFunction(errors).visit_stmt(stmt);
+        }
+        let mut check =
+            |block: &IrBlock, context| check_statements(&block.statements, context, errors);
+        match stmt {
+            IrStmt::Break(stmt) if !context.in_loop => {
+                errors.push((stmt.meta.id, "'break' outside loop"));
+            }
+            IrStmt::Continue(stmt) if !context.in_loop => {
+                errors.push((stmt.meta.id, "'continue' not properly in loop"));
+            }
+            IrStmt::Return(stmt) if !context.in_function => {
+                errors.push((stmt.meta.id, "'return' outside function"));
+            }
+            IrStmt::Nonlocal(stmt) if !context.enclosed => {
+                errors.push((
+                    stmt.meta.id,
+                    "nonlocal declaration not allowed at module level",
+                ));
+            }
+            IrStmt::If(stmt) => {
+                check(&stmt.body, context);
+                for elif in &stmt.elifs {
+                    check(&elif.body, context);
+                }
+                if let Some(block) = &stmt.else_body {
+                    check(block, context);
+                }
+            }
+            IrStmt::While(stmt) => {
+                check(&stmt.body, loop_body);
+                if let Some(block) = &stmt.else_body {
+                    check(block, context);
+                }
+            }
+            IrStmt::For(stmt) => {
+                check(&stmt.body, loop_body);
+                if let Some(block) = &stmt.else_body {
+                    check(block, context);
+                }
+            }
+            IrStmt::Match(stmt) => {
+                for case in &stmt.cases.cases {
+                    check(&case.body, context);
+                }
+            }
+            IrStmt::FunctionDef(stmt) => check(
+                &stmt.body,
+                Context {
+                    in_loop: false,
+                    in_function: true,
+                    enclosed: true,
+                },
+            ),
+            IrStmt::ClassDef(stmt) => check(
+                &stmt.body,
+                Context {
+                    enclosed: context.enclosed,
+                    ..Context::default()
+                },
+            ),
+            IrStmt::Try(stmt) => {
+                check(&stmt.body, context);
+                for handler in &stmt.handlers {
+                    check(&handler.body, context);
+                }
+                if let Some(block) = &stmt.else_body {
+                    check(block, context);
+                }
+                if let Some(block) = &stmt.finally_body {
+                    check(block, context);
+                }
+            }
+            IrStmt::With(stmt) => check(&stmt.body, context),
+            _ => {}
+        }
+    }
+}
+
+/// Finds `yield` and `await` in the expressions of a statement outside any
+/// function, leaving its blocks to `check_statements`.
+struct OutsideFunction<'a>(&'a mut Vec<(NodeId, &'static str)>);
+
+impl IrVisitor for OutsideFunction<'_> {
+    fn visit_block(&mut self, _block: &IrBlock) {}
+
+    fn visit_expr(&mut self, expr: &IrExpr) {
+        match expr {
+            IrExpr::Yield(expr) => self.0.push((expr.meta.id, "'yield' outside function")),
+            IrExpr::YieldFrom(expr) => self.0.push((expr.meta.id, "'yield' outside function")),
+            IrExpr::Await(expr) => self.0.push((expr.meta.id, "'await' outside function")),
+            // The body of a lambda is a function of its own.
+            IrExpr::Lambda(_) => return,
+            _ => {}
+        }
+        self.walk_expr(expr);
+    }
+}
+
+/// Finds targets that cannot be assigned or deleted and literals whose
+/// source does not read back as themselves.
+struct Grammar<'a>(&'a mut Vec<(NodeId, &'static str)>);
+
+impl Grammar<'_> {
+    fn target(&mut self, stmt: &NodeMeta, target: &IrExpr, single: bool, message: &'static str) {
+        if !assignable(target, single) {
+            self.0.push((stmt.id, message));
+        }
+    }
+}
+
+impl IrVisitor for Grammar<'_> {
+    fn visit_stmt(&mut self, stmt: &IrStmt) {
+        let assign = "cannot assign to expression";
+        match stmt {
+            IrStmt::Assign(stmt) => {
+                for target in &stmt.targets {
+                    self.target(&stmt.meta, target, false, assign);
+                }
+            }
+            IrStmt::AugAssign(stmt) => self.target(
+                &stmt.meta,
+                &stmt.target,
+                true,
+                "illegal expression for augmented assignment",
+            ),
+            IrStmt::For(stmt) => self.target(&stmt.meta, &stmt.target, false, assign),
+            IrStmt::Del(stmt) => {
+                self.target(&stmt.meta, &stmt.target, false, "cannot delete expression")
+            }
+            _ => {}
+        }
+        self.walk_stmt(stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &IrExpr) {
+        if let IrExpr::Literal(literal) = expr {
+            if !reads_back(&literal.literal) {
+                self.0.push((literal.meta.id, "invalid literal"));
+            }
+        }
+        self.walk_expr(expr);
+    }
+
+    fn visit_comprehension_for(&mut self, comprehension: &IrComprehensionFor) {
+        self.target(
+            &comprehension.meta,
+            &comprehension.target,
+            false,
+            "cannot assign to expression",
+        );
+        self.walk_comprehension_for(comprehension);
+    }
+}
+
+/// Whether `target` can be assigned to: a name, attribute or subscript, or
+/// unless `single`, a tuple or list of targets.
+fn assignable(target: &IrExpr, single: bool) -> bool {
+    match target {
+        IrExpr::Identifier(_) | IrExpr::Attribute(_) | IrExpr::Subscript(_) => true,
+        IrExpr::Grouped(grouped) => assignable(&grouped.expr, single),
+        IrExpr::Tuple(IrTupleExpr { elements, .. }) | IrExpr::List(IrListExpr { elements, .. }) => {
+            !single && elements.iter().all(|element| assignable(element, false))
+        }
+        _ => false,
+    }
+}
+
+/// Whether the source of `literal` lexes as exactly one token of its kind.
+fn reads_back(literal: &Literal) -> bool {
+    let raw = match literal {
+        Literal::Number(number) => &number.raw,
+        Literal::String(string) => &string.raw,
+        Literal::Bool(_) | Literal::None => return true,
+    };
+    let Ok(lexed) = Lexer::new(raw.clone()).lex() else {
+        return false;
+    };
+    let mut tokens = lexed.tokens.into_iter().filter(|token| {
+        !token.raw.is_empty()
+            && !matches!(
+                token.kind,
+                TokenKind::Newline
+                    | TokenKind::Eof
+                    | TokenKind::Indent { .. }
+                    | TokenKind::Dedent { .. }
+            )
+    });
+    let matches = match (tokens.next(), literal) {
+        (Some(token), Literal::Number(_)) => {
+            matches!(token.kind, TokenKind::Number(_)) && token.raw == *raw
+        }
+        (Some(token), _) => matches!(token.kind, TokenKind::String(_)) && token.raw == *raw,
+        (None, _) => false,
+    };
+    matches && tokens.next().is_none()
+}
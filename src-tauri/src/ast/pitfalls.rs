@@ -0,0 +1,380 @@
+use super::lexer::Lexer;
+use super::names::BUILTINS;
+use super::*;
+use std::collections::HashSet;
+
+impl Program {
+    /// Finds the code of each `PitfallKind` that Python runs, `table` being
+    /// the program's `symbols()`. Unlike the lint rules these are hints for
+    /// learners, about code that rarely does what was meant.
+    pub fn pitfalls(&self, table: &SymbolTable) -> Vec<Pitfall> {
+        let mut bound = HashSet::new();
+        for reference in &table.references {
+            if matches!(
+                reference.kind,
+                ReferenceKind::Definition | ReferenceKind::Parameter
+            ) {
+                bound.insert(reference.symbol);
+            }
+        }
+        let mut find = Pitfalls {
+            program: self,
+            table,
+            bound: &bound,
+            generator: false,
+            found: Vec::new(),
+        };
+        find.visit_program(self);
+        let mut shadowed = HashSet::new();
+        for reference in &table.references {
+            let name = &table.symbols[reference.symbol.0].name;
+            if bound.contains(&reference.symbol)
+                && reference.kind != ReferenceKind::Use
+                && BUILTINS.contains(&name.as_str())
+                && !name.starts_with("__")
+                && shadowed.insert(reference.symbol)
+            {
+                let span = self.name_span(reference.span, None, name);
+                find.add(
+                    PitfallKind::ShadowedBuiltin,
+                    reference.node,
+                    span,
+                    [("name", name.clone())],
+                );
+            }
+        }
+        let mut pitfalls = find.found;
+        pitfalls.sort_by_key(|pitfall| pitfall.span.start.offset);
+        pitfalls
+    }
+
+    /// Source of the tokens within `span`.
+    fn text(&self, span: Span) -> String {
+        tokens_text(&self.raw_tokens, span.start.offset, span.end.offset)
+    }
+}
+
+/// Recognizes `if x = 1:` and the like in source that failed to parse with
+/// `error`, which the parser reports at the `=`.
+pub fn error_pitfall(source: &str, error: &ParseError) -> Option<Pitfall> {
+    let tokens = Lexer::new(source.to_string()).lex().ok()?.tokens;
+    let at = tokens
+        .iter()
+        .position(|token| token.span.start.offset == error.span.start.offset)?;
+    if tokens[at].kind != TokenKind::Operator(Operator::Assign) {
+        return None;
+    }
+    let line = error.span.start.line;
+    let keyword = tokens[..at]
+        .iter()
+        .rev()
+        .take_while(|token| token.span.start.line == line)
+        .filter(|token| !token.raw.is_empty() && !token.kind.is_trivia())
+        .last()?;
+    if !matches!(
+        keyword.kind,
+        TokenKind::Keyword(Keyword::If | Keyword::Elif | Keyword::While)
+    ) {
+        return None;
+    }
+    let colon = tokens[at..]
+        .iter()
+        .find(|token| token.kind == TokenKind::Colon || token.span.start.line != line)?;
+    let args = [
+        (
+            "name",
+            tokens_text(&tokens, keyword.span.end.offset, error.span.start.offset),
+        ),
+        (
+            "value",
+            tokens_text(&tokens, error.span.end.offset, colon.span.start.offset),
+        ),
+    ];
+    Some(Pitfall {
+        kind: PitfallKind::AssignmentInCondition,
+        args: args
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+        node: None,
+        span: error.span,
+    })
+}
+
+/// Source of the tokens from `start` to `end`, without the whitespace
+/// around it.
+fn tokens_text(tokens: &[LexToken], start: usize, end: usize) -> String {
+    let text: String = tokens
+        .iter()
+        .filter(|token| start <= token.span.start.offset && token.span.end.offset <= end)
+        .map(|token| token.raw.as_str())
+        .collect();
+    text.trim().to_string()
+}
+
+/// The expression inside any parentheses around `expr`.
+fn ungrouped(mut expr: &Expr) -> &Expr {
+    while let Expr::Grouped(grouped) = expr {
+        expr = &grouped.expr;
+    }
+    expr
+}
+
+struct Pitfalls<'a> {
+    program: &'a Program,
+    table: &'a SymbolTable,
+    /// Symbols the program assigns or defines.
+    bound: &'a HashSet<SymbolId>,
+    /// Whether the function around the current statement is a generator.
+    generator: bool,
+    found: Vec<Pitfall>,
+}
+
+impl Pitfalls<'_> {
+    fn add<const N: usize>(
+        &mut self,
+        kind: PitfallKind,
+        node: NodeId,
+        span: Span,
+        args: [(&str, String); N],
+    ) {
+        self.found.push(Pitfall {
+            kind,
+            args: args
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            node: Some(node),
+            span,
+        });
+    }
+
+    /// Whether `expr` is the builtin `name` rather than something the
+    /// program binds to that name.
+    fn is_builtin(&self, expr: &Expr, name: &str) -> bool {
+        let Expr::Identifier(identifier) = expr else {
+            return false;
+        };
+        identifier.name == name
+            && self
+                .table
+                .nodes
+                .get(&identifier.meta.id)
+                .is_none_or(|symbol| !self.bound.contains(symbol))
+    }
+
+    /// The argument of a call of the builtin `name` with one argument.
+    fn builtin_call<'e>(&self, expr: &'e Expr, name: &str) -> Option<&'e Expr> {
+        match ungrouped(expr) {
+            Expr::Call(call)
+                if call.args.len() == 1
+                    && call.kwargs.is_empty()
+                    && self.is_builtin(&call.callee, name) =>
+            {
+                Some(&call.args[0])
+            }
+            _ => None,
+        }
+    }
+
+    fn condition(&mut self, condition: &Expr) {
+        if let Expr::NamedExpr(named) = ungrouped(condition) {
+            if let Expr::Literal(_) = ungrouped(&named.value) {
+                let value = self.program.text(expr_meta(&named.value).span);
+                self.add(
+                    PitfallKind::AssignmentInCondition,
+                    named.meta.id,
+                    named.meta.span,
+                    [("name", named.name.clone()), ("value", value)],
+                );
+            }
+        }
+    }
+
+    fn mutable_default(&mut self, param: &FuncParam) {
+        let Some(default) = &param.default else {
+            return;
+        };
+        let mutable = match ungrouped(default) {
+            Expr::List(_) | Expr::Dict(_) | Expr::Set(_) => true,
+            Expr::Comprehension(comprehension) => {
+                !matches!(comprehension, ComprehensionExpr::Generator(_))
+            }
+            Expr::Call(call) => {
+                call.args.is_empty()
+                    && call.kwargs.is_empty()
+                    && ["list", "dict", "set"]
+                        .iter()
+                        .any(|name| self.is_builtin(&call.callee, name))
+            }
+            _ => false,
+        };
+        if mutable {
+            let meta = expr_meta(default);
+            self.add(
+                PitfallKind::MutableDefault,
+                meta.id,
+                meta.span,
+                [("name", param.name.clone())],
+            );
+        }
+    }
+}
+
+impl Visitor for Pitfalls<'_> {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::If(stmt) => {
+                self.condition(&stmt.condition);
+                for elif in &stmt.elifs {
+                    self.condition(&elif.condition);
+                }
+            }
+            Stmt::While(stmt) => {
+                self.condition(&stmt.condition);
+                let endless = matches!(
+                    ungrouped(&stmt.condition),
+                    Expr::Literal(LiteralExpr {
+                        literal: Literal::Bool(true),
+                        ..
+                    })
+                );
+                let mut exits = Exits {
+                    loops: 0,
+                    found: false,
+                };
+                exits.visit_block(&stmt.body);
+                if endless && !exits.found && !self.generator {
+                    let span = Span {
+                        start: stmt.meta.span.start,
+                        end: expr_meta(&stmt.condition).span.end,
+                    };
+                    self.add(PitfallKind::EndlessLoop, stmt.meta.id, span, []);
+                }
+            }
+            Stmt::For(stmt) => {
+                let sequence = self
+                    .builtin_call(&stmt.iterable, "range")
+                    .and_then(|argument| self.builtin_call(argument, "len"));
+                if let Some(sequence) = sequence {
+                    let meta = expr_meta(&stmt.iterable);
+                    let args = [
+                        ("index", self.program.text(expr_meta(&stmt.target).span)),
+                        ("sequence", self.program.text(expr_meta(sequence).span)),
+                    ];
+                    self.add(PitfallKind::RangeLenLoop, meta.id, meta.span, args);
+                }
+            }
+            Stmt::Expr(stmt) => {
+                if let Expr::Compare(compare) = &stmt.expr {
+                    if compare.ops == [CompareOp::Eq] {
+                        let args = [
+                            ("target", self.program.text(expr_meta(&compare.left).span)),
+                            (
+                                "value",
+                                self.program.text(expr_meta(&compare.comparators[0]).span),
+                            ),
+                        ];
+                        let meta = &stmt.meta;
+                        self.add(PitfallKind::ComparisonStatement, meta.id, meta.span, args);
+                    }
+                }
+            }
+            Stmt::FunctionDef(function) => {
+                for param in &function.params {
+                    self.mutable_default(param);
+                }
+                let mut yields = Yields(false);
+                yields.visit_block(&function.body);
+                let outer = std::mem::replace(&mut self.generator, yields.0);
+                self.walk_stmt(stmt);
+                self.generator = outer;
+                return;
+            }
+            _ => {}
+        }
+        self.walk_stmt(stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let Expr::Compare(compare) = expr {
+            let operands: Vec<&Expr> = std::iter::once(compare.left.as_ref())
+                .chain(&compare.comparators)
+                .collect();
+            for (index, op) in compare.ops.iter().enumerate() {
+                let suggestion = match op {
+                    CompareOp::Eq => "is",
+                    CompareOp::NotEq => "is not",
+                    _ => continue,
+                };
+                let none = |expr: &Expr| {
+                    matches!(
+                        ungrouped(expr),
+                        Expr::Literal(LiteralExpr {
+                            literal: Literal::None,
+                            ..
+                        })
+                    )
+                };
+                if none(operands[index]) || none(operands[index + 1]) {
+                    let op = if *op == CompareOp::Eq { "==" } else { "!=" };
+                    self.add(
+                        PitfallKind::NoneComparison,
+                        compare.meta.id,
+                        compare.meta.span,
+                        [
+                            ("op", op.to_string()),
+                            ("suggestion", suggestion.to_string()),
+                        ],
+                    );
+                }
+            }
+        }
+        self.walk_expr(expr);
+    }
+}
+
+/// Looks for a `break` of the loop whose body it visits, a `return` or a
+/// `raise`.
+struct Exits {
+    /// Loops inside the body around the current statement.
+    loops: usize,
+    found: bool,
+}
+
+impl Visitor for Exits {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Break(_) if self.loops == 0 => self.found = true,
+            Stmt::Return(_) | Stmt::Raise(_) => self.found = true,
+            Stmt::While(_) | Stmt::For(_) => {
+                self.loops += 1;
+                self.walk_stmt(stmt);
+                self.loops -= 1;
+            }
+            // A function or class in the loop returns from itself.
+            Stmt::FunctionDef(_) | Stmt::ClassDef(_) => {}
+            _ => self.walk_stmt(stmt),
+        }
+    }
+}
+
+/// Looks for a `yield` in the body of a function it visits, which makes the
+/// function a generator.
+struct Yields(bool);
+
+impl Visitor for Yields {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        if !matches!(stmt, Stmt::FunctionDef(_) | Stmt::ClassDef(_)) {
+            self.walk_stmt(stmt);
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Yield(_) | Expr::YieldFrom(_) => self.0 = true,
+            Expr::Lambda(_) => {}
+            _ => self.walk_expr(expr),
+        }
+    }
+}
@@ -1,15 +1,62 @@
+use super::layout::{self, Doc};
 use super::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
 
 impl Program {
     pub fn to_python(&self, config: RenderConfig) -> String {
+        self.to_python_with_source_map(config).source
+    }
+
+    /// Renders the program like `to_python`, along with the span of the
+    /// text rendered for each node. Line breaks are written the way the
+    /// source wrote them, as its `source_format.line_ending`.
+    pub fn to_python_with_source_map(&self, config: RenderConfig) -> RenderedPython {
         if config.mode == RenderMode::Lossless && !self.dirty && !self.raw_tokens.is_empty() {
-            return render_lossless(&self.raw_tokens);
+            let mut source_map = BTreeMap::new();
+            for stmt in &self.body {
+                stmt_metas_mut(&mut stmt.clone(), &mut |meta| {
+                    if meta.id != NodeId(0) {
+                        source_map.insert(meta.id, meta.span);
+                    }
+                });
+            }
+            return RenderedPython {
+                source: render_lossless(&self.raw_tokens),
+                source_map,
+            };
         }
         render_pretty(self, config)
     }
 }
 
-fn render_lossless(tokens: &[LexToken]) -> String {
+impl Stmt {
+    /// Renders the statement on its own at the top level, without the line
+    /// break ending it.
+    pub fn to_python(&self, indent_width: usize, config: RenderConfig) -> String {
+        let mut program = Program::empty(indent_width);
+        program.body.push(self.clone());
+        program.dirty = true;
+        let mut source = program.to_python(config);
+        if source.ends_with('\n') {
+            source.pop();
+        }
+        source
+    }
+}
+
+impl Expr {
+    /// Renders the expression on its own, as an expression statement.
+    pub fn to_python(&self, config: RenderConfig) -> String {
+        let stmt = Stmt::Expr(ExprStmt {
+            meta: NodeMeta::default(),
+            expr: self.clone(),
+        });
+        stmt.to_python(4, config)
+    }
+}
+
+pub(super) fn render_lossless(tokens: &[LexToken]) -> String {
     let mut output = String::new();
     for token in tokens {
         output.push_str(&token.raw);
@@ -17,29 +64,608 @@ fn render_lossless(tokens: &[LexToken]) -> String {
     output
 }
 
+#[derive(Clone, Copy)]
 struct PrettyContext<'a> {
-    tokens: Option<&'a [Token]>,
-    reuse_token_ranges: bool,
+    /// Raw tokens of the program, which clean nodes are copied from and
+    /// which tell where the source had trailing commas.
+    tokens: &'a [LexToken],
+    config: RenderConfig,
+    /// Whether expressions are rendered inside brackets, where they may be
+    /// broken across lines.
+    enclosed: bool,
+    placements: &'a RefCell<Placements>,
+}
+
+/// Place in the rendered lines: the index of a line and a byte offset in it.
+type LinePosition = (usize, usize);
+
+/// Where nodes were rendered, until the rendered lines are joined.
+#[derive(Default)]
+struct Placements {
+    spans: Vec<(NodeId, LinePosition, LinePosition)>,
+    copied: Vec<Copied>,
+}
+
+/// Nodes copied from the source along with the node `id` that contains
+/// them, with their source spans. They keep their place relative to the
+/// start of that node, which was at `start` in the source.
+struct Copied {
+    id: NodeId,
+    start: Position,
+    nodes: Vec<(NodeId, Span)>,
+}
+
+impl<'a> PrettyContext<'a> {
+    fn enclosed(&self) -> PrettyContext<'a> {
+        PrettyContext {
+            enclosed: true,
+            ..*self
+        }
+    }
+
+    /// Indentation of a line `level` blocks deep.
+    fn indent(&self, level: usize, indent_width: usize) -> String {
+        match self.config.indent_style {
+            IndentStyle::Spaces => " ".repeat(level * indent_width),
+            IndentStyle::Tabs => "\t".repeat(level),
+        }
+    }
+
+    /// Lays out a line starting with `prefix` and made of `parts`, and
+    /// adds it to `lines`.
+    fn push_line(&self, lines: &mut Vec<String>, prefix: &str, parts: Vec<Doc>) {
+        self.push_laid_out(lines, prefix, parts, self.config.max_line_length);
+    }
+
+    /// Adds a line starting with `prefix` and made of `parts` to `lines`
+    /// without breaking it, however long it is.
+    fn push_flat_line(&self, lines: &mut Vec<String>, prefix: &str, parts: Vec<Doc>) {
+        self.push_laid_out(lines, prefix, parts, usize::MAX);
+    }
+
+    fn push_laid_out(&self, lines: &mut Vec<String>, prefix: &str, parts: Vec<Doc>, width: usize) {
+        let (text, marks) =
+            layout::print_marked(&Doc::concat(parts), width, prefix, self.indent_unit());
+        let mut placements = self.placements.borrow_mut();
+        for (id, range) in marks {
+            placements.spans.push((
+                id,
+                (lines.len(), prefix.len() + range.start),
+                (lines.len(), prefix.len() + range.end),
+            ));
+        }
+        lines.push(format!("{prefix}{text}"));
+    }
+
+    /// Records that the node `id` was rendered from `start` to `end`.
+    fn place(&self, id: NodeId, start: LinePosition, end: LinePosition) {
+        self.placements.borrow_mut().spans.push((id, start, end));
+    }
+
+    /// Records the nodes inside `meta`'s node, which was copied from the
+    /// source along with them.
+    fn place_copied(&self, meta: &NodeMeta, mut walk: impl FnMut(&mut dyn FnMut(&mut NodeMeta))) {
+        let mut nodes = Vec::new();
+        walk(&mut |inner| {
+            if inner.id != meta.id {
+                nodes.push((inner.id, inner.span));
+            }
+        });
+        self.placements.borrow_mut().copied.push(Copied {
+            id: meta.id,
+            start: meta.span.start,
+            nodes,
+        });
+    }
+
+    /// Renders `expr` on a single line.
+    fn flat(&self, expr: &Expr) -> String {
+        layout::print(
+            &render_expr(expr, PREC_LAMBDA, self),
+            usize::MAX,
+            "",
+            self.indent_unit(),
+        )
+    }
+
+    fn indent_unit(&self) -> &'static str {
+        match self.config.indent_style {
+            IndentStyle::Spaces => layout::INDENT,
+            IndentStyle::Tabs => "\t",
+        }
+    }
+
+    /// Separator between elements that stay on one line.
+    fn comma(&self) -> &'static str {
+        if self.config.space_after_comma {
+            ", "
+        } else {
+            ","
+        }
+    }
+
+    /// `op` with the spacing binary operators get.
+    fn operator(&self, op: &str) -> Doc {
+        if self.config.spaces_around_operators {
+            format!(" {op} ").into()
+        } else {
+            op.into()
+        }
+    }
+
+    /// Renders `expr` where a statement expects an expression binding at
+    /// least at `parent_prec`, wrapping boolean chains in parentheses when
+    /// they have to be broken.
+    fn clause(&self, expr: &Expr, parent_prec: u8) -> Doc {
+        if !matches!(expr, Expr::BoolOp(_)) {
+            return render_expr(expr, parent_prec, self);
+        }
+        if let Some(original) = self.original_expr(expr) {
+            return original.into();
+        }
+        Doc::group(Doc::concat(vec![
+            Doc::IfBreak("(".to_string()),
+            Doc::indent(Doc::concat(vec![
+                Doc::SoftLine,
+                render_expr(expr, 0, &self.enclosed()),
+            ])),
+            Doc::SoftLine,
+            Doc::IfBreak(")".to_string()),
+        ]))
+    }
+
+    /// Appends the comment of the node with `meta`, if any, to the last
+    /// rendered line.
+    fn trailing_comment(&self, meta: &NodeMeta, lines: &mut [String]) {
+        if let (Some(comment), Some(last)) = (&meta.comment, lines.last_mut()) {
+            last.push_str("  #");
+            last.push_str(comment);
+        }
+    }
+
+    /// Whether the source has a comma before the bracket closing `span`,
+    /// which keeps the elements inside on lines of their own.
+    fn magic_trailing_comma(&self, span: &Span) -> bool {
+        let Some((start, end)) = self.token_bounds(span) else {
+            return false;
+        };
+        let mut tokens = self.tokens[start..end]
+            .iter()
+            .rev()
+            .filter(|token| !token.kind.is_trivia());
+        matches!(
+            tokens.next().map(|token| &token.kind),
+            Some(TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace)
+        ) && matches!(
+            tokens.next().map(|token| &token.kind),
+            Some(TokenKind::Comma)
+        )
+    }
+
+    /// Source lines of `stmt` when it can be copied instead of rendered at
+    /// `prefix`, the indentation it is rendered with.
+    ///
+    /// A statement with a nested block is only copied when its indentation
+    /// is unchanged, since the lines of the block are copied along with it.
+    fn original_stmt(&self, stmt: &Stmt, prefix: &str) -> Option<Vec<String>> {
+        if !self.config.reuse_token_ranges || stmt_is_dirty(stmt) {
+            return None;
+        }
+        let span = &stmt_meta(stmt).span;
+        let (start, end) = self.token_bounds(span)?;
+        let lead = self.line_lead(start);
+        let text = normalize_line_breaks(&render_lossless(&self.tokens[start..end]));
+        // The range of a statement with a block runs into the indentation
+        // of the line after it, which belongs to the next statement.
+        let text = match text.rfind('\n') {
+            Some(at) if text[at..].trim().is_empty() => &text[..at],
+            _ => &text,
+        };
+        if let Stmt::Empty(_) = stmt {
+            return Some(vec![format!("{}{text}", lead.trim_end())]);
+        }
+        if !lead.trim().is_empty() || (lead != prefix && has_block(stmt)) {
+            return None;
+        }
+        Some(copied_lines(text, prefix))
+    }
+
+    /// Copies the header of the clause with `meta` from the source into
+    /// `lines`, up to the colon opening `body`, when it is clean although
+    /// `body` changed. Returns whether it did.
+    ///
+    /// `decorators` and `exprs` are the expressions of the header. A header
+    /// spanning several lines is only copied when its indentation is
+    /// unchanged.
+    fn push_original_header(
+        &self,
+        lines: &mut Vec<String>,
+        prefix: &str,
+        meta: &NodeMeta,
+        body: &Block,
+        decorators: &[Expr],
+        exprs: &[&Expr],
+    ) -> bool {
+        if !self.config.reuse_token_ranges
+            || meta_is_dirty(meta)
+            || decorators
+                .iter()
+                .chain(exprs.iter().copied())
+                .any(expr_is_dirty)
+        {
+            return false;
+        }
+        let index_at = |offset: usize| {
+            self.tokens
+                .partition_point(|token| token.span.start.offset < offset)
+        };
+        let mut start = index_at(meta.span.start.offset);
+        if let Some(decorator) = decorators.first() {
+            let at = self.tokens[..index_at(expr_meta(decorator).span.start.offset)]
+                .iter()
+                .rposition(|token| !token.kind.is_trivia());
+            match at {
+                Some(at) if self.tokens[at].kind == TokenKind::Operator(Operator::At) => start = at,
+                _ => return false,
+            }
+        }
+        let end = index_at(body.meta.span.start.offset);
+        let Some(colon) = self.tokens[start.min(end)..end]
+            .iter()
+            .rposition(|token| !token.kind.is_trivia())
+        else {
+            return false;
+        };
+        let colon = start + colon;
+        if self.tokens[colon].kind != TokenKind::Colon {
+            return false;
+        }
+        let lead = self.line_lead(start);
+        let text = normalize_line_breaks(&render_lossless(&self.tokens[start..=colon]));
+        if !lead.trim().is_empty() || (lead != prefix && text.contains('\n')) {
+            return false;
+        }
+        lines.extend(copied_lines(&text, prefix));
+        let origin = NodeMeta {
+            span: Span {
+                start: self.tokens[start].span.start,
+                ..meta.span
+            },
+            ..meta.clone()
+        };
+        self.place_copied(&origin, |f| {
+            for expr in decorators.iter().chain(exprs.iter().copied()) {
+                expr_metas_mut(&mut expr.clone(), f);
+            }
+        });
+        true
+    }
+
+    /// Source text between the start of the line of the raw token at
+    /// `index` and the token.
+    fn line_lead(&self, index: usize) -> String {
+        let line = self.tokens[index].span.start.line;
+        let line_start = self.tokens[..index].partition_point(|token| token.span.start.line < line);
+        render_lossless(&self.tokens[line_start..index])
+    }
+
+    /// Source text of `expr` when it can be copied instead of rendered.
+    ///
+    /// Text spanning several lines is only copied when its line breaks are
+    /// enclosed in brackets of its own, because an enclosing bracket may
+    /// not be there any more.
+    fn original_expr(&self, expr: &Expr) -> Option<String> {
+        if !self.config.reuse_token_ranges || expr_is_dirty(expr) {
+            return None;
+        }
+        let (start, end) = self.token_bounds(&expr_meta(expr).span)?;
+        let mut depth = 0usize;
+        for token in &self.tokens[start..end] {
+            match &token.kind {
+                TokenKind::LParen | TokenKind::LBracket | TokenKind::LBrace => depth += 1,
+                TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace => {
+                    depth = depth.saturating_sub(1)
+                }
+                TokenKind::Comment(_) if depth == 0 => return None,
+                TokenKind::RawWhitespace(text) if depth == 0 && text.contains(['\n', '\r']) => {
+                    return None
+                }
+                _ => {}
+            }
+        }
+        Some(normalize_line_breaks(&render_lossless(
+            &self.tokens[start..end],
+        )))
+    }
+
+    /// Range of raw tokens exactly covering `span`, if there is one.
+    fn token_bounds(&self, span: &Span) -> Option<(usize, usize)> {
+        if span.start.offset >= span.end.offset {
+            return None;
+        }
+        let start = self
+            .tokens
+            .partition_point(|token| token.span.start.offset < span.start.offset);
+        let end = self
+            .tokens
+            .partition_point(|token| token.span.end.offset <= span.end.offset);
+        let first = self.tokens.get(start)?;
+        let last = self.tokens.get(end.checked_sub(1)?)?;
+        (start < end
+            && first.span.start.offset == span.start.offset
+            && last.span.end.offset == span.end.offset)
+            .then_some((start, end))
+    }
+}
+
+/// Lines of copied `text`, the first one moved to `prefix`.
+fn copied_lines(text: &str, prefix: &str) -> Vec<String> {
+    text.split('\n')
+        .enumerate()
+        .map(|(index, line)| {
+            if index == 0 {
+                format!("{prefix}{line}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect()
 }
 
-fn render_pretty(program: &Program, config: RenderConfig) -> String {
+fn render_pretty(program: &Program, config: RenderConfig) -> RenderedPython {
     let mut lines = Vec::new();
+    let placements = RefCell::new(Placements::default());
     let context = PrettyContext {
-        tokens: if config.reuse_token_ranges {
-            Some(&program.tokens)
-        } else {
-            None
-        },
-        reuse_token_ranges: config.reuse_token_ranges,
+        tokens: &program.raw_tokens,
+        config,
+        enclosed: false,
+        placements: &placements,
     };
-    for stmt in &program.body {
-        render_stmt(stmt, 0, program.indent_width, &mut lines, &context);
+    match config.top_level_blank_lines {
+        Some(blank_lines) => {
+            for (before, stmt) in space_definitions(&program.body, blank_lines) {
+                lines.extend((0..before).map(|_| String::new()));
+                render_stmt(&stmt, 0, program.indent_width, &mut lines, &context);
+            }
+        }
+        None => {
+            for stmt in &program.body {
+                render_stmt(stmt, 0, program.indent_width, &mut lines, &context);
+            }
+        }
     }
     let output = lines.join("\n");
-    if output.is_empty() {
+    let joined = if output.is_empty() {
         output
     } else {
         format!("{output}\n")
+    };
+    let line_ending = program.source_format.line_ending;
+    let (source, offsets) = with_line_ending(&joined, line_ending);
+    let source_map = source_map(
+        &source,
+        line_ending,
+        &lines,
+        &offsets,
+        placements.into_inner(),
+    );
+    RenderedPython { source, source_map }
+}
+
+/// `text` with every line break, those of copied text included, written as
+/// `line_ending`, and the offset in it of each byte offset of `text`.
+fn with_line_ending(text: &str, line_ending: LineEnding) -> (String, Vec<usize>) {
+    let mut output = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, ch)) = chars.next() {
+        offsets.resize(index + 1, output.len());
+        match ch {
+            '\r' if chars.peek().is_some_and(|&(_, next)| next == '\n') => {
+                chars.next();
+                output.push_str(line_ending.as_str());
+            }
+            '\r' | '\n' => output.push_str(line_ending.as_str()),
+            _ => output.push(ch),
+        }
+    }
+    offsets.resize(text.len() + 1, output.len());
+    (output, offsets)
+}
+
+/// Spans in `source` of the nodes placed in `lines`, which joined with
+/// `\n` have their offsets moved to `source` by `offsets`.
+fn source_map(
+    source: &str,
+    line_ending: LineEnding,
+    lines: &[String],
+    offsets: &[usize],
+    placements: Placements,
+) -> BTreeMap<NodeId, Span> {
+    let mut line_offsets = vec![0];
+    for line in lines {
+        line_offsets.push(line_offsets.last().unwrap() + line.len() + 1);
+    }
+    let mut line_starts = vec![0];
+    line_starts.extend(
+        source
+            .match_indices(line_ending.as_str())
+            .map(|(index, found)| index + found.len()),
+    );
+    let position = |offset: usize| {
+        let offset = offsets[offset.min(offsets.len() - 1)];
+        let line = line_starts.partition_point(|&start| start <= offset);
+        let start = line_starts[line - 1];
+        Position {
+            line,
+            column: source[start..offset].chars().count() + 1,
+            offset,
+        }
+    };
+    let mut source_map = BTreeMap::new();
+    for (id, (start_line, start), (end_line, end)) in placements.spans {
+        if id != NodeId(0) {
+            let span = Span {
+                start: position(line_offsets[start_line] + start),
+                end: position(line_offsets[end_line] + end),
+            };
+            source_map.insert(id, span);
+        }
+    }
+    // Copied text keeps its columns, except on the first line, where it
+    // is moved along with the start of the node it was copied with.
+    for Copied {
+        id,
+        start: from,
+        nodes,
+    } in placements.copied
+    {
+        let Some(to) = source_map.get(&id).map(|span| span.start) else {
+            continue;
+        };
+        let moved = |moving: Position| {
+            let line = (to.line + moving.line).saturating_sub(from.line);
+            let column = if moving.line == from.line {
+                (to.column + moving.column).saturating_sub(from.column)
+            } else {
+                moving.column
+            };
+            let start = line_starts
+                .get(line.max(1) - 1)
+                .copied()
+                .unwrap_or(source.len());
+            let offset = source[start..]
+                .char_indices()
+                .nth(column.max(1) - 1)
+                .map_or(source.len(), |(index, _)| start + index);
+            position(offset)
+        };
+        for (node, span) in nodes {
+            if node != NodeId(0) {
+                let span = Span {
+                    start: moved(span.start),
+                    end: moved(span.end),
+                };
+                source_map.insert(node, span);
+            }
+        }
+    }
+    source_map
+}
+
+/// Top-level statements other than blank lines, each with the number of
+/// blank lines to put above it: `blank_lines` around definitions and the
+/// comments right above them, and as many as the program has elsewhere.
+fn space_definitions(body: &[Stmt], blank_lines: usize) -> Vec<(usize, Stmt)> {
+    let is_comment = |stmt: &Stmt| matches!(stmt, Stmt::Comment(_));
+    let is_blank = |stmt: &Stmt| matches!(stmt, Stmt::Empty(_));
+    let is_definition = |stmt: &Stmt| matches!(stmt, Stmt::FunctionDef(_) | Stmt::ClassDef(_));
+    let mut pending: VecDeque<Stmt> = body.iter().cloned().collect();
+    let mut statements = Vec::new();
+    let mut blanks = 0;
+    while let Some(mut stmt) = pending.pop_front() {
+        if is_blank(&stmt) {
+            blanks += 1;
+            continue;
+        }
+        for hoisted in hoist_comments(&mut stmt).into_iter().rev() {
+            pending.push_front(hoisted);
+        }
+        if is_definition(&stmt) {
+            // The blank lines after a definition end up in its last block.
+            let mut last = Some(&mut stmt);
+            while let Some(block) = last.and_then(|stmt| blocks_mut(stmt).pop()) {
+                while block.statements.last().is_some_and(is_blank) {
+                    block.statements.pop();
+                }
+                last = block.statements.last_mut();
+            }
+        }
+        statements.push((blanks, stmt));
+        blanks = 0;
+    }
+    // Whether each statement is a definition or a comment attached to one.
+    let mut in_definition = vec![false; statements.len()];
+    for index in (0..statements.len()).rev() {
+        let stmt = &statements[index].1;
+        in_definition[index] = is_definition(stmt)
+            || (is_comment(stmt)
+                && statements
+                    .get(index + 1)
+                    .is_some_and(|(before, _)| *before == 0)
+                && in_definition[index + 1]);
+    }
+    for index in 1..statements.len() {
+        let previous = &statements[index - 1].1;
+        let starts_definition =
+            in_definition[index] && !(in_definition[index - 1] && is_comment(previous));
+        if starts_definition || is_definition(previous) {
+            statements[index].0 = blank_lines;
+        }
+    }
+    statements
+}
+
+/// Takes the comment lines at the end of the last block of `stmt` that
+/// are indented less than that block, along with the lines after them.
+/// They end up in the block because blank and comment lines come before
+/// the dedent, but belong after the statement.
+pub(super) fn hoist_comments(stmt: &mut Stmt) -> Vec<Stmt> {
+    let Some(block) = blocks_mut(stmt).pop() else {
+        return Vec::new();
+    };
+    let is_code = |stmt: &Stmt| !matches!(stmt, Stmt::Empty(_) | Stmt::Comment(_));
+    let Some(last) = block.statements.iter().rposition(is_code) else {
+        return Vec::new();
+    };
+    let hoisted = hoist_comments(&mut block.statements[last]);
+    block.statements.splice(last + 1..last + 1, hoisted);
+    let column = block
+        .statements
+        .iter()
+        .find(|stmt| is_code(stmt))
+        .map_or(0, |stmt| stmt_meta(stmt).span.start.column);
+    // Comments added by editors have no place in the source to go by.
+    let split = (last + 1..block.statements.len()).find(|&index| match &block.statements[index] {
+        Stmt::Comment(comment) => {
+            !meta_is_dirty(&comment.meta) && comment.meta.span.start.column < column
+        }
+        _ => false,
+    });
+    match split {
+        Some(split) => block.statements.split_off(split),
+        None => Vec::new(),
+    }
+}
+
+/// The blocks of `stmt` in source order.
+pub(super) fn blocks_mut(stmt: &mut Stmt) -> Vec<&mut Block> {
+    match stmt {
+        Stmt::If(stmt) => std::iter::once(&mut stmt.body)
+            .chain(stmt.elifs.iter_mut().map(|elif| &mut elif.body))
+            .chain(stmt.else_body.as_mut())
+            .collect(),
+        Stmt::While(stmt) => std::iter::once(&mut stmt.body)
+            .chain(stmt.else_body.as_mut())
+            .collect(),
+        Stmt::For(stmt) => std::iter::once(&mut stmt.body)
+            .chain(stmt.else_body.as_mut())
+            .collect(),
+        Stmt::Match(stmt) => stmt
+            .cases
+            .cases
+            .iter_mut()
+            .map(|case| &mut case.body)
+            .collect(),
+        Stmt::FunctionDef(stmt) => vec![&mut stmt.body],
+        Stmt::ClassDef(stmt) => vec![&mut stmt.body],
+        Stmt::Try(stmt) => std::iter::once(&mut stmt.body)
+            .chain(stmt.handlers.iter_mut().map(|handler| &mut handler.body))
+            .chain(stmt.else_body.as_mut())
+            .chain(stmt.finally_body.as_mut())
+            .collect(),
+        Stmt::With(stmt) => vec![&mut stmt.body],
+        _ => Vec::new(),
     }
 }
 
@@ -50,30 +676,54 @@ fn render_stmt(
     lines: &mut Vec<String>,
     context: &PrettyContext,
 ) {
-    if context.reuse_token_ranges {
-        if let Some(tokens) = context.tokens {
-            if let Some(rendered) = render_token_range(tokens, &stmt_meta(stmt).token_range) {
-                for line in rendered.split('\n') {
-                    lines.push(line.to_string());
-                }
-                return;
-            }
+    let prefix = context.indent(indent_level, indent_width);
+    let start = (lines.len(), prefix.len());
+    if let Some(original) = context.original_stmt(stmt, &prefix) {
+        lines.extend(original);
+        if !matches!(stmt, Stmt::Empty(_)) {
+            context.place(stmt_meta(stmt).id, start, (lines.len(), 0));
+            context.place_copied(stmt_meta(stmt), |f| stmt_metas_mut(&mut stmt.clone(), f));
         }
+        return;
     }
-    let prefix = indent_str(indent_level * indent_width);
     match stmt {
         Stmt::If(stmt) => {
-            lines.push(format!(
-                "{prefix}if {}:",
-                render_expr(&stmt.condition, 0)
-            ));
+            let header = [&stmt.condition];
+            if !context.push_original_header(lines, &prefix, &stmt.meta, &stmt.body, &[], &header) {
+                context.push_line(
+                    lines,
+                    &prefix,
+                    vec![
+                        "if ".into(),
+                        context.clause(&stmt.condition, PREC_LAMBDA),
+                        ":".into(),
+                    ],
+                );
+            }
             render_block(&stmt.body, indent_level + 1, indent_width, lines, context);
             for elif in &stmt.elifs {
-                lines.push(format!(
-                    "{prefix}elif {}:",
-                    render_expr(&elif.condition, 0)
-                ));
+                let start = (lines.len(), prefix.len());
+                let header = [&elif.condition];
+                if !context.push_original_header(
+                    lines,
+                    &prefix,
+                    &elif.meta,
+                    &elif.body,
+                    &[],
+                    &header,
+                ) {
+                    context.push_line(
+                        lines,
+                        &prefix,
+                        vec![
+                            "elif ".into(),
+                            context.clause(&elif.condition, PREC_LAMBDA),
+                            ":".into(),
+                        ],
+                    );
+                }
                 render_block(&elif.body, indent_level + 1, indent_width, lines, context);
+                context.place(elif.meta.id, start, (lines.len(), 0));
             }
             if let Some(body) = &stmt.else_body {
                 lines.push(format!("{prefix}else:"));
@@ -81,10 +731,18 @@ fn render_stmt(
             }
         }
         Stmt::While(stmt) => {
-            lines.push(format!(
-                "{prefix}while {}:",
-                render_expr(&stmt.condition, 0)
-            ));
+            let header = [&stmt.condition];
+            if !context.push_original_header(lines, &prefix, &stmt.meta, &stmt.body, &[], &header) {
+                context.push_line(
+                    lines,
+                    &prefix,
+                    vec![
+                        "while ".into(),
+                        context.clause(&stmt.condition, PREC_LAMBDA),
+                        ":".into(),
+                    ],
+                );
+            }
             render_block(&stmt.body, indent_level + 1, indent_width, lines, context);
             if let Some(body) = &stmt.else_body {
                 lines.push(format!("{prefix}else:"));
@@ -92,12 +750,21 @@ fn render_stmt(
             }
         }
         Stmt::For(stmt) => {
-            let async_prefix = if stmt.is_async { "async " } else { "" };
-            lines.push(format!(
-                "{prefix}{async_prefix}for {} in {}:",
-                render_expr(&stmt.target, 0),
-                render_expr(&stmt.iterable, 0)
-            ));
+            let header = [&stmt.target, &stmt.iterable];
+            if !context.push_original_header(lines, &prefix, &stmt.meta, &stmt.body, &[], &header) {
+                let async_prefix = if stmt.is_async { "async " } else { "" };
+                context.push_line(
+                    lines,
+                    &prefix,
+                    vec![
+                        format!("{async_prefix}for ").into(),
+                        render_expr(&stmt.target, 0, context),
+                        " in ".into(),
+                        context.clause(&stmt.iterable, PREC_TUPLE),
+                        ":".into(),
+                    ],
+                );
+            }
             render_block(&stmt.body, indent_level + 1, indent_width, lines, context);
             if let Some(body) = &stmt.else_body {
                 lines.push(format!("{prefix}else:"));
@@ -105,64 +772,127 @@ fn render_stmt(
             }
         }
         Stmt::FunctionDef(stmt) => {
-            for decorator in &stmt.decorators {
-                lines.push(format!("{prefix}@{}", render_expr(decorator, 0)));
-            }
-            let params = stmt.params.iter().map(|p| {
-                let prefix = match p.kind {
-                    ParamKind::Star => "*",
-                    ParamKind::DoubleStar => "**",
-                    ParamKind::Normal => "",
-                };
-                let name_part = format!("{}{}", prefix, p.name);
-                let with_ann = if let Some(ref ann) = p.annotation {
-                    format!("{}: {}", name_part, render_expr(ann, 0))
-                } else {
-                    name_part
-                };
-                if let Some(ref default) = p.default {
-                    format!("{} = {}", with_ann, render_expr(default, 0))
-                } else {
-                    with_ann
+            let header: Vec<&Expr> = stmt
+                .params
+                .iter()
+                .flat_map(|param| param.annotation.iter().chain(&param.default))
+                .chain(&stmt.return_type)
+                .collect();
+            if !context.push_original_header(
+                lines,
+                &prefix,
+                &stmt.meta,
+                &stmt.body,
+                &stmt.decorators,
+                &header,
+            ) {
+                for decorator in &stmt.decorators {
+                    context.push_line(
+                        lines,
+                        &prefix,
+                        vec!["@".into(), render_expr(decorator, PREC_LAMBDA, context)],
+                    );
+                    context.trailing_comment(expr_meta(decorator), lines);
                 }
-            }).collect::<Vec<_>>().join(", ");
-            let async_prefix = if stmt.is_async { "async " } else { "" };
-            if let Some(ref ret) = stmt.return_type {
-                lines.push(format!("{prefix}{async_prefix}def {}({}) -> {}:", stmt.name, params, render_expr(ret, 0)));
-            } else {
-                lines.push(format!("{prefix}{async_prefix}def {}({}):", stmt.name, params));
+                let async_prefix = if stmt.is_async { "async " } else { "" };
+                let mut parts = vec![format!("{async_prefix}def {}(", stmt.name).into()];
+                for (index, param) in stmt.params.iter().enumerate() {
+                    if index > 0 {
+                        parts.push(context.comma().into());
+                    }
+                    let star = match param.kind {
+                        ParamKind::Star => "*",
+                        ParamKind::DoubleStar => "**",
+                        ParamKind::Normal => "",
+                    };
+                    parts.push(format!("{star}{}", param.name).into());
+                    if let Some(annotation) = &param.annotation {
+                        parts.push(": ".into());
+                        parts.push(render_expr(annotation, PREC_LAMBDA, context));
+                    }
+                    if let Some(default) = &param.default {
+                        let equals = if param.annotation.is_some() {
+                            " = "
+                        } else {
+                            "="
+                        };
+                        parts.push(equals.into());
+                        parts.push(render_expr(default, PREC_LAMBDA, context));
+                    }
+                }
+                parts.push(")".into());
+                if let Some(return_type) = &stmt.return_type {
+                    parts.push(" -> ".into());
+                    parts.push(render_expr(return_type, PREC_LAMBDA, context));
+                }
+                parts.push(":".into());
+                context.push_flat_line(lines, &prefix, parts);
             }
             render_block(&stmt.body, indent_level + 1, indent_width, lines, context);
         }
         Stmt::ClassDef(stmt) => {
-            for decorator in &stmt.decorators {
-                lines.push(format!("{prefix}@{}", render_expr(decorator, 0)));
-            }
-            if stmt.bases.is_empty() {
-                lines.push(format!("{prefix}class {}:", stmt.name));
-            } else {
-                let bases = stmt.bases.iter().map(|b| render_expr(b, 0)).collect::<Vec<_>>().join(", ");
-                lines.push(format!("{prefix}class {}({}):", stmt.name, bases));
+            let header: Vec<&Expr> = stmt.bases.iter().collect();
+            if !context.push_original_header(
+                lines,
+                &prefix,
+                &stmt.meta,
+                &stmt.body,
+                &stmt.decorators,
+                &header,
+            ) {
+                for decorator in &stmt.decorators {
+                    context.push_line(
+                        lines,
+                        &prefix,
+                        vec!["@".into(), render_expr(decorator, PREC_LAMBDA, context)],
+                    );
+                    context.trailing_comment(expr_meta(decorator), lines);
+                }
+                if stmt.bases.is_empty() {
+                    lines.push(format!("{prefix}class {}:", stmt.name));
+                } else {
+                    let mut parts = vec![format!("class {}(", stmt.name).into()];
+                    for (index, base) in stmt.bases.iter().enumerate() {
+                        if index > 0 {
+                            parts.push(context.comma().into());
+                        }
+                        parts.push(render_expr(base, PREC_LAMBDA, context));
+                    }
+                    parts.push("):".into());
+                    context.push_flat_line(lines, &prefix, parts);
+                }
             }
             render_block(&stmt.body, indent_level + 1, indent_width, lines, context);
         }
         Stmt::Match(stmt) => {
-            lines.push(format!(
-                "{prefix}match {}:",
-                render_expr(&stmt.subject, 0)
-            ));
+            context.push_line(
+                lines,
+                &prefix,
+                vec![
+                    "match ".into(),
+                    render_expr(&stmt.subject, 0, context),
+                    ":".into(),
+                ],
+            );
             render_case_block(&stmt.cases, indent_level + 1, indent_width, lines, context);
         }
         Stmt::Assign(stmt) => {
-            let targets_str = stmt.targets.iter()
-                .map(|t| render_expr(t, 0))
-                .collect::<Vec<_>>()
-                .join(", ");
-            lines.push(format!(
-                "{prefix}{} = {}",
-                targets_str,
-                render_expr(&stmt.value, 0)
-            ));
+            // Several targets are the items of a tuple.
+            let target_prec = if stmt.targets.len() > 1 {
+                PREC_LAMBDA
+            } else {
+                PREC_TUPLE
+            };
+            let mut parts = Vec::new();
+            for (index, target) in stmt.targets.iter().enumerate() {
+                if index > 0 {
+                    parts.push(context.comma().into());
+                }
+                parts.push(render_expr(target, target_prec, context));
+            }
+            parts.push(" = ".into());
+            parts.push(context.clause(&stmt.value, PREC_TUPLE));
+            context.push_line(lines, &prefix, parts);
         }
         Stmt::AugAssign(stmt) => {
             let op_str = match stmt.op {
@@ -173,22 +903,29 @@ fn render_stmt(
                 Operator::PercentAssign => "%=",
                 _ => "?=",
             };
-            lines.push(format!(
-                "{prefix}{} {} {}",
-                render_expr(&stmt.target, 0),
-                op_str,
-                render_expr(&stmt.value, 0)
-            ));
+            context.push_line(
+                lines,
+                &prefix,
+                vec![
+                    render_expr(&stmt.target, 0, context),
+                    format!(" {op_str} ").into(),
+                    context.clause(&stmt.value, PREC_TUPLE),
+                ],
+            );
         }
         Stmt::Expr(stmt) => {
-            lines.push(format!("{prefix}{}", render_expr(&stmt.expr, 0)));
+            context.push_line(lines, &prefix, vec![render_expr(&stmt.expr, 0, context)]);
         }
         Stmt::Pass(_) => {
             lines.push(format!("{prefix}pass"));
         }
         Stmt::Return(stmt) => {
             if let Some(value) = &stmt.value {
-                lines.push(format!("{prefix}return {}", render_expr(value, 0)));
+                context.push_line(
+                    lines,
+                    &prefix,
+                    vec!["return ".into(), context.clause(value, PREC_TUPLE)],
+                );
             } else {
                 lines.push(format!("{prefix}return"));
             }
@@ -200,6 +937,7 @@ fn render_stmt(
             lines.push(format!("{prefix}continue"));
         }
         Stmt::Empty(_) => lines.push(String::new()),
+        Stmt::Comment(stmt) => lines.push(format!("{prefix}#{}", stmt.text)),
         Stmt::Import(stmt) => {
             if stmt.is_from {
                 let names_str: Vec<String> = stmt.names.iter().map(|n| {
@@ -209,7 +947,7 @@ fn render_stmt(
                         n.name.clone()
                     }
                 }).collect();
-                lines.push(format!("{prefix}from {} import {}", stmt.module, names_str.join(", ")));
+                lines.push(format!("{prefix}from {} import {}", stmt.module, names_str.join(context.comma())));
             } else {
                 if let Some(first) = stmt.names.first() {
                     if let Some(alias) = &first.alias {
@@ -226,25 +964,46 @@ fn render_stmt(
             lines.push(format!("{prefix}try:"));
             render_block(&stmt.body, indent_level + 1, indent_width, lines, context);
             for handler in &stmt.handlers {
-                match (&handler.exception_type, &handler.name) {
-                    (Some(exc_type), Some(name)) => {
-                        lines.push(format!(
-                            "{prefix}except {} as {}:",
-                            render_expr(exc_type, 0),
-                            name
-                        ));
-                    }
-                    (Some(exc_type), None) => {
-                        lines.push(format!(
-                            "{prefix}except {}:",
-                            render_expr(exc_type, 0)
-                        ));
-                    }
-                    _ => {
-                        lines.push(format!("{prefix}except:"));
+                let start = (lines.len(), prefix.len());
+                let header: Vec<&Expr> = handler.exception_type.iter().collect();
+                if !context.push_original_header(
+                    lines,
+                    &prefix,
+                    &handler.meta,
+                    &handler.body,
+                    &[],
+                    &header,
+                ) {
+                    match (&handler.exception_type, &handler.name) {
+                        (Some(exc_type), Some(name)) => {
+                            context.push_flat_line(
+                                lines,
+                                &prefix,
+                                vec![
+                                    "except ".into(),
+                                    render_expr(exc_type, PREC_LAMBDA, context),
+                                    format!(" as {name}:").into(),
+                                ],
+                            );
+                        }
+                        (Some(exc_type), None) => {
+                            context.push_flat_line(
+                                lines,
+                                &prefix,
+                                vec![
+                                    "except ".into(),
+                                    render_expr(exc_type, PREC_LAMBDA, context),
+                                    ":".into(),
+                                ],
+                            );
+                        }
+                        _ => {
+                            lines.push(format!("{prefix}except:"));
+                        }
                     }
                 }
                 render_block(&handler.body, indent_level + 1, indent_width, lines, context);
+                context.place(handler.meta.id, start, (lines.len(), 0));
             }
             if let Some(else_body) = &stmt.else_body {
                 lines.push(format!("{prefix}else:"));
@@ -256,64 +1015,104 @@ fn render_stmt(
             }
         }
         Stmt::With(stmt) => {
-            let items_str = stmt.items.iter().map(|item| {
-                if let Some(name) = &item.name {
-                    format!("{} as {name}", render_expr(&item.context, 0))
-                } else {
-                    render_expr(&item.context, 0)
+            let header: Vec<&Expr> = stmt.items.iter().map(|item| &item.context).collect();
+            if !context.push_original_header(lines, &prefix, &stmt.meta, &stmt.body, &[], &header) {
+                let async_prefix = if stmt.is_async { "async " } else { "" };
+                let mut parts = vec![format!("{async_prefix}with ").into()];
+                for (index, item) in stmt.items.iter().enumerate() {
+                    if index > 0 {
+                        parts.push(context.comma().into());
+                    }
+                    parts.push(render_expr(&item.context, PREC_LAMBDA, context));
+                    if let Some(name) = &item.name {
+                        parts.push(format!(" as {name}").into());
+                    }
                 }
-            }).collect::<Vec<_>>().join(", ");
-            let async_prefix = if stmt.is_async { "async " } else { "" };
-            lines.push(format!("{prefix}{async_prefix}with {items_str}:"));
+                parts.push(":".into());
+                context.push_flat_line(lines, &prefix, parts);
+            }
             render_block(&stmt.body, indent_level + 1, indent_width, lines, context);
         }
         Stmt::Assert(stmt) => {
             if let Some(message) = &stmt.message {
-                lines.push(format!(
-                    "{prefix}assert {}, {}",
-                    render_expr(&stmt.condition, 0),
-                    render_expr(message, 0)
-                ));
+                context.push_line(
+                    lines,
+                    &prefix,
+                    vec![
+                        "assert ".into(),
+                        context.clause(&stmt.condition, PREC_LAMBDA),
+                        context.comma().into(),
+                        render_expr(message, PREC_LAMBDA, context),
+                    ],
+                );
             } else {
-                lines.push(format!(
-                    "{prefix}assert {}",
-                    render_expr(&stmt.condition, 0)
-                ));
+                context.push_line(
+                    lines,
+                    &prefix,
+                    vec![
+                        "assert ".into(),
+                        context.clause(&stmt.condition, PREC_LAMBDA),
+                    ],
+                );
             }
         }
         Stmt::Raise(stmt) => {
             if let Some(exception) = &stmt.exception {
-                lines.push(format!("{prefix}raise {}", render_expr(exception, 0)));
+                context.push_line(
+                    lines,
+                    &prefix,
+                    vec![
+                        "raise ".into(),
+                        render_expr(exception, PREC_LAMBDA, context),
+                    ],
+                );
             } else {
                 lines.push(format!("{prefix}raise"));
             }
         }
         Stmt::Del(stmt) => {
-            lines.push(format!("{prefix}del {}", render_expr(&stmt.target, 0)));
+            context.push_line(
+                lines,
+                &prefix,
+                vec!["del ".into(), render_expr(&stmt.target, 0, context)],
+            );
         }
         Stmt::Global(stmt) => {
-            lines.push(format!("{prefix}global {}", stmt.names.join(", ")));
+            lines.push(format!("{prefix}global {}", stmt.names.join(context.comma())));
         }
         Stmt::Nonlocal(stmt) => {
-            lines.push(format!("{prefix}nonlocal {}", stmt.names.join(", ")));
+            lines.push(format!("{prefix}nonlocal {}", stmt.names.join(context.comma())));
         }
         Stmt::AnnAssign(stmt) => {
             if let Some(ref value) = stmt.value {
-                lines.push(format!(
-                    "{prefix}{}: {} = {}",
-                    stmt.target,
-                    render_expr(&stmt.annotation, 0),
-                    render_expr(value, 0)
-                ));
+                context.push_line(
+                    lines,
+                    &prefix,
+                    vec![
+                        format!("{}: ", stmt.target).into(),
+                        render_expr(&stmt.annotation, PREC_LAMBDA, context),
+                        " = ".into(),
+                        context.clause(value, PREC_TUPLE),
+                    ],
+                );
             } else {
-                lines.push(format!(
-                    "{prefix}{}: {}",
-                    stmt.target,
-                    render_expr(&stmt.annotation, 0)
-                ));
+                context.push_line(
+                    lines,
+                    &prefix,
+                    vec![
+                        format!("{}: ", stmt.target).into(),
+                        render_expr(&stmt.annotation, PREC_LAMBDA, context),
+                    ],
+                );
             }
         }
     }
+    if !has_block(stmt) {
+        context.trailing_comment(stmt_meta(stmt), lines);
+    }
+    if !matches!(stmt, Stmt::Empty(_)) {
+        context.place(stmt_meta(stmt).id, start, (lines.len(), 0));
+    }
 }
 
 fn render_block(
@@ -323,13 +1122,37 @@ fn render_block(
     lines: &mut Vec<String>,
     context: &PrettyContext,
 ) {
-    if block.statements.is_empty() {
-        lines.push(format!("{}pass", indent_str(indent_level * indent_width)));
-        return;
-    }
-    for stmt in &block.statements {
+    // The block starts with the line break ending the line of its header.
+    context.trailing_comment(&block.meta, lines);
+    let start = (lines.len(), 0);
+    // A block left with only comments, say by deleting its statements,
+    // gets a `pass` after them, ahead of trailing blank lines.
+    let statements = &block.statements;
+    let has_code = statements
+        .iter()
+        .any(|stmt| !matches!(stmt, Stmt::Comment(_) | Stmt::Empty(_)));
+    let pass_at = (!has_code).then(|| {
+        statements
+            .iter()
+            .rposition(|stmt| !matches!(stmt, Stmt::Empty(_)))
+            .map_or(0, |index| index + 1)
+    });
+    let pass = |lines: &mut Vec<String>| {
+        lines.push(format!(
+            "{}pass",
+            context.indent(indent_level, indent_width)
+        ));
+    };
+    for (index, stmt) in statements.iter().enumerate() {
+        if pass_at == Some(index) {
+            pass(lines);
+        }
         render_stmt(stmt, indent_level, indent_width, lines, context);
     }
+    if pass_at == Some(statements.len()) {
+        pass(lines);
+    }
+    context.place(block.meta.id, start, (lines.len(), 0));
 }
 
 fn render_case_block(
@@ -339,18 +1162,26 @@ fn render_case_block(
     lines: &mut Vec<String>,
     context: &PrettyContext,
 ) {
+    context.trailing_comment(&block.meta, lines);
+    let block_start = (lines.len(), 0);
     for case_stmt in &block.cases {
+        let start = (
+            lines.len(),
+            context.indent(indent_level, indent_width).len(),
+        );
         let pattern = render_pattern(&case_stmt.pattern);
         lines.push(format!(
             "{}case {}:",
-            indent_str(indent_level * indent_width),
+            context.indent(indent_level, indent_width),
             pattern
         ));
         render_block(&case_stmt.body, indent_level + 1, indent_width, lines, context);
+        context.place(case_stmt.meta.id, start, (lines.len(), 0));
     }
+    context.place(block.meta.id, block_start, (lines.len(), 0));
 }
 
-fn stmt_meta(stmt: &Stmt) -> &NodeMeta {
+pub(super) fn stmt_meta(stmt: &Stmt) -> &NodeMeta {
     match stmt {
         Stmt::If(stmt) => &stmt.meta,
         Stmt::While(stmt) => &stmt.meta,
@@ -366,6 +1197,7 @@ fn stmt_meta(stmt: &Stmt) -> &NodeMeta {
         Stmt::Break(stmt) => &stmt.meta,
         Stmt::Continue(stmt) => &stmt.meta,
         Stmt::Empty(stmt) => &stmt.meta,
+        Stmt::Comment(stmt) => &stmt.meta,
         Stmt::Import(stmt) => &stmt.meta,
         Stmt::Try(stmt) => &stmt.meta,
         Stmt::With(stmt) => &stmt.meta,
@@ -378,27 +1210,43 @@ fn stmt_meta(stmt: &Stmt) -> &NodeMeta {
     }
 }
 
-fn render_token_range(tokens: &[Token], range: &TokenRange) -> Option<String> {
-    if range.start > range.end || range.end >= tokens.len() {
-        return None;
-    }
-    let mut output = String::new();
-    for token in &tokens[range.start..=range.end] {
-        output.push_str(&render_token(token));
+pub(super) fn stmt_meta_mut(stmt: &mut Stmt) -> &mut NodeMeta {
+    match stmt {
+        Stmt::If(stmt) => &mut stmt.meta,
+        Stmt::While(stmt) => &mut stmt.meta,
+        Stmt::For(stmt) => &mut stmt.meta,
+        Stmt::Match(stmt) => &mut stmt.meta,
+        Stmt::FunctionDef(stmt) => &mut stmt.meta,
+        Stmt::ClassDef(stmt) => &mut stmt.meta,
+        Stmt::Assign(stmt) => &mut stmt.meta,
+        Stmt::AugAssign(stmt) => &mut stmt.meta,
+        Stmt::Expr(stmt) => &mut stmt.meta,
+        Stmt::Pass(stmt) => &mut stmt.meta,
+        Stmt::Return(stmt) => &mut stmt.meta,
+        Stmt::Break(stmt) => &mut stmt.meta,
+        Stmt::Continue(stmt) => &mut stmt.meta,
+        Stmt::Empty(stmt) => &mut stmt.meta,
+        Stmt::Comment(stmt) => &mut stmt.meta,
+        Stmt::Import(stmt) => &mut stmt.meta,
+        Stmt::Try(stmt) => &mut stmt.meta,
+        Stmt::With(stmt) => &mut stmt.meta,
+        Stmt::Assert(stmt) => &mut stmt.meta,
+        Stmt::Raise(stmt) => &mut stmt.meta,
+        Stmt::Del(stmt) => &mut stmt.meta,
+        Stmt::Global(stmt) => &mut stmt.meta,
+        Stmt::Nonlocal(stmt) => &mut stmt.meta,
+        Stmt::AnnAssign(stmt) => &mut stmt.meta,
     }
-    Some(output)
 }
 
-fn render_token(token: &Token) -> String {
-    let mut output = String::new();
-    for trivia in &token.leading_trivia {
-        output.push_str(&render_trivia(trivia));
-    }
-    output.push_str(&token.raw);
-    for trivia in &token.trailing_trivia {
-        output.push_str(&render_trivia(trivia));
+/// Line of the last character of `span`. Statement spans end after the line
+/// break that ends them.
+pub(super) fn last_line(span: &Span) -> usize {
+    if span.end.column == 1 && span.end.line > span.start.line {
+        span.end.line - 1
+    } else {
+        span.end.line
     }
-    output
 }
 
 fn render_trivia(trivia: &Trivia) -> String {
@@ -417,145 +1265,281 @@ fn render_pattern(pattern: &Pattern) -> String {
     }
 }
 
-fn render_expr(expr: &Expr, parent_prec: u8) -> String {
+fn render_expr(expr: &Expr, parent_prec: u8, context: &PrettyContext) -> Doc {
+    let meta = expr_meta(expr);
+    if let Some(original) = context.original_expr(expr) {
+        context.place_copied(meta, |f| expr_metas_mut(&mut expr.clone(), f));
+        let original = Doc::mark(meta.id, original.into());
+        return wrap_if_needed(original, expr_precedence(expr), parent_prec);
+    }
+    Doc::mark(meta.id, render_expr_node(expr, parent_prec, context))
+}
+
+fn render_expr_node(expr: &Expr, parent_prec: u8, context: &PrettyContext) -> Doc {
     match expr {
-        Expr::Identifier(expr) => expr.name.clone(),
-        Expr::Literal(expr) => render_literal(&expr.literal),
+        Expr::Identifier(expr) => expr.name.clone().into(),
+        Expr::Literal(expr) => match (&expr.literal, context.config.quote_style) {
+            (Literal::String(value), Some(quote)) => requote(&value.raw, quote).into(),
+            (literal, _) => render_literal(literal).into(),
+        },
+        Expr::Grouped(expr)
+            if context.config.remove_redundant_parens
+                && parens_are_redundant(&expr.expr, parent_prec)
+                && !has_comments(std::iter::once(expr_meta(&expr.expr))) =>
+        {
+            render_expr(&expr.expr, parent_prec, context)
+        }
         Expr::Grouped(expr) => match &*expr.expr {
-            Expr::Comprehension(ComprehensionExpr::Generator(_)) => render_expr(&expr.expr, 0),
-            _ => format!("({})", render_expr(&expr.expr, 0)),
+            // These bring their own parentheses.
+            Expr::Comprehension(ComprehensionExpr::Generator(_)) | Expr::NamedExpr(_) => {
+                render_expr(&expr.expr, PREC_TUPLE, context)
+            }
+            _ => render_elements(
+                "(",
+                ")",
+                vec![(
+                    render_expr(&expr.expr, PREC_TUPLE, &context.enclosed()),
+                    expr_meta(&expr.expr),
+                )],
+                false,
+                false,
+                context,
+            ),
         },
+        Expr::Tuple(expr) if has_comments(expr.elements.iter().map(expr_meta)) => {
+            let elements = expr
+                .elements
+                .iter()
+                .map(|item| element(item, context))
+                .collect();
+            render_elements("(", ")", elements, true, false, context)
+        }
+        Expr::Tuple(expr) if expr.elements.is_empty() => "()".into(),
         Expr::Tuple(expr) => {
-            if expr.elements.len() == 1 {
-                format!("{},", render_expr(&expr.elements[0], 0))
+            let rendered = if expr.elements.len() == 1 {
+                Doc::concat(vec![
+                    render_expr(&expr.elements[0], PREC_LAMBDA, context),
+                    ",".into(),
+                ])
             } else {
-                expr.elements
-                    .iter()
-                    .map(|item| render_expr(item, 0))
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            }
+                let mut parts = Vec::new();
+                for (index, item) in expr.elements.iter().enumerate() {
+                    if index > 0 {
+                        parts.push(context.comma().into());
+                    }
+                    parts.push(render_expr(item, PREC_LAMBDA, context));
+                }
+                Doc::concat(parts)
+            };
+            wrap_if_needed(rendered, PREC_TUPLE, parent_prec)
         }
         Expr::List(expr) => {
-            let inner = expr
+            let elements = expr
                 .elements
                 .iter()
-                .map(|item| render_expr(item, 0))
-                .collect::<Vec<_>>()
-                .join(", ");
-            format!("[{inner}]")
+                .map(|item| element(item, context))
+                .collect();
+            render_elements(
+                "[",
+                "]",
+                elements,
+                true,
+                context.magic_trailing_comma(&expr.meta.span),
+                context,
+            )
         }
         Expr::Dict(expr) => {
-            let inner = expr
+            let inner = context.enclosed();
+            let entries = expr
                 .entries
                 .iter()
-                .map(|entry| format!("{}: {}", render_expr(&entry.key, 0), render_expr(&entry.value, 0)))
-                .collect::<Vec<_>>()
-                .join(", ");
-            format!("{{{inner}}}")
+                .map(|entry| {
+                    let doc = Doc::concat(vec![
+                        render_expr(&entry.key, PREC_LAMBDA, &inner),
+                        ": ".into(),
+                        render_expr(&entry.value, PREC_LAMBDA, &inner),
+                    ]);
+                    (doc, &entry.meta)
+                })
+                .collect();
+            render_elements(
+                "{",
+                "}",
+                entries,
+                true,
+                context.magic_trailing_comma(&expr.meta.span),
+                context,
+            )
         }
         Expr::Set(expr) => {
-            let inner = expr
+            let elements = expr
                 .elements
                 .iter()
-                .map(|item| render_expr(item, 0))
-                .collect::<Vec<_>>()
-                .join(", ");
-            format!("{{{inner}}}")
+                .map(|item| element(item, context))
+                .collect();
+            render_elements(
+                "{",
+                "}",
+                elements,
+                true,
+                context.magic_trailing_comma(&expr.meta.span),
+                context,
+            )
         }
         Expr::Comprehension(expr) => {
-            let render_suffix = |fors: &[ComprehensionFor]| {
-                let mut suffix = String::new();
+            let inner = context.enclosed();
+            let render_clauses = |head: Doc, fors: &[ComprehensionFor]| {
+                let mut parts = vec![Doc::SoftLine, head];
                 for comp in fors {
-                    suffix.push_str(&format!(
-                        " for {} in {}",
-                        render_expr(&comp.target, 0),
-                        render_expr(&comp.iter, 0)
-                    ));
+                    parts.push(Doc::Line);
+                    parts.push("for ".into());
+                    parts.push(render_expr(&comp.target, PREC_TUPLE, &inner));
+                    parts.push(" in ".into());
+                    parts.push(render_expr(&comp.iter, PREC_OR, &inner));
                     for condition in &comp.ifs {
-                        suffix.push_str(&format!(" if {}", render_expr(condition, 0)));
+                        parts.push(Doc::Line);
+                        parts.push("if ".into());
+                        parts.push(render_expr(condition, PREC_OR, &inner));
                     }
                 }
-                suffix
+                Doc::indent(Doc::concat(parts))
             };
-            match expr {
-                ComprehensionExpr::List(expr) => {
-                    let suffix = render_suffix(&expr.fors);
-                    format!("[{}{suffix}]", render_expr(&expr.element, 0))
-                }
-                ComprehensionExpr::Set(expr) => {
-                    let suffix = render_suffix(&expr.fors);
-                    format!("{{{}{suffix}}}", render_expr(&expr.element, 0))
-                }
-                ComprehensionExpr::Generator(expr) => {
-                    let suffix = render_suffix(&expr.fors);
-                    format!("({}{suffix})", render_expr(&expr.element, 0))
-                }
+            let (open, clauses, close) = match expr {
+                ComprehensionExpr::List(expr) => (
+                    "[",
+                    render_clauses(render_expr(&expr.element, PREC_LAMBDA, &inner), &expr.fors),
+                    "]",
+                ),
+                ComprehensionExpr::Set(expr) => (
+                    "{",
+                    render_clauses(render_expr(&expr.element, PREC_LAMBDA, &inner), &expr.fors),
+                    "}",
+                ),
+                ComprehensionExpr::Generator(expr) => (
+                    "(",
+                    render_clauses(render_expr(&expr.element, PREC_LAMBDA, &inner), &expr.fors),
+                    ")",
+                ),
                 ComprehensionExpr::Dict(expr) => {
-                    let suffix = render_suffix(&expr.fors);
-                    format!(
-                        "{{{}: {}{suffix}}}",
-                        render_expr(&expr.key, 0),
-                        render_expr(&expr.value, 0)
-                    )
+                    let entry = Doc::concat(vec![
+                        render_expr(&expr.key, PREC_LAMBDA, &inner),
+                        ": ".into(),
+                        render_expr(&expr.value, PREC_LAMBDA, &inner),
+                    ]);
+                    ("{", render_clauses(entry, &expr.fors), "}")
                 }
-            }
+            };
+            Doc::group(Doc::concat(vec![
+                open.into(),
+                clauses,
+                Doc::SoftLine,
+                close.into(),
+            ]))
         }
         Expr::Attribute(expr) => {
-            let prec = 13;
+            let prec = PREC_PRIMARY;
+            let value = render_expr(&expr.value, prec, context);
+            // The dot would be read as part of a number.
+            let value = match &*expr.value {
+                Expr::Literal(LiteralExpr {
+                    literal: Literal::Number(_),
+                    ..
+                }) => Doc::concat(vec!["(".into(), value, ")".into()]),
+                _ => value,
+            };
             wrap_if_needed(
-                format!("{}.{}", render_expr(&expr.value, prec), expr.attr),
+                Doc::concat(vec![value, format!(".{}", expr.attr).into()]),
                 prec,
                 parent_prec,
             )
         }
         Expr::Subscript(expr) => {
-            let prec = 13;
+            let prec = PREC_PRIMARY;
             wrap_if_needed(
-                format!(
-                    "{}[{}]",
-                    render_expr(&expr.value, prec),
-                    render_expr(&expr.index, 0)
-                ),
+                Doc::concat(vec![
+                    render_expr(&expr.value, prec, context),
+                    render_elements(
+                        "[",
+                        "]",
+                        vec![element(&expr.index, context)],
+                        false,
+                        false,
+                        context,
+                    ),
+                ]),
                 prec,
                 parent_prec,
             )
         }
         Expr::Slice(expr) => {
-            let lower = expr.lower.as_ref().map_or(String::new(), |e| render_expr(e, 0));
-            let upper = expr.upper.as_ref().map_or(String::new(), |e| render_expr(e, 0));
+            let bound = |bound: &Option<Box<Expr>>| match bound {
+                Some(bound) => render_expr(bound, PREC_LAMBDA, context),
+                None => Doc::concat(Vec::new()),
+            };
+            // Colons between complex bounds are spaced like operators,
+            // except next to a missing bound.
+            let spaced = context.config.spaces_around_operators
+                && [&expr.lower, &expr.upper, &expr.step]
+                    .into_iter()
+                    .flatten()
+                    .any(|bound| !is_simple_slice_bound(bound));
+            let colon = |before: bool, after: bool| match (spaced && before, spaced && after) {
+                (true, true) => " : ",
+                (true, false) => " :",
+                (false, true) => ": ",
+                (false, false) => ":",
+            };
+            let mut parts = vec![
+                bound(&expr.lower),
+                colon(expr.lower.is_some(), expr.upper.is_some()).into(),
+                bound(&expr.upper),
+            ];
             if let Some(step) = &expr.step {
-                format!("{}:{}:{}", lower, upper, render_expr(step, 0))
-            } else {
-                format!("{}:{}", lower, upper)
+                parts.push(colon(expr.upper.is_some(), true).into());
+                parts.push(render_expr(step, PREC_LAMBDA, context));
             }
+            Doc::concat(parts)
         }
         Expr::Call(expr) => {
-            let prec = 13;
-            let callee = render_expr(&expr.callee, prec);
-            let args_parts: Vec<String> = expr
+            let prec = PREC_PRIMARY;
+            let callee = render_expr(&expr.callee, prec, context);
+            // A generator that is the only argument shares the call's
+            // parentheses: `f(a for a in b)`.
+            if let ([arg @ Expr::Comprehension(ComprehensionExpr::Generator(_))], []) =
+                (expr.args.as_slice(), expr.kwargs.as_slice())
+            {
+                let rendered = Doc::concat(vec![callee, element(arg, context).0]);
+                return wrap_if_needed(rendered, prec, parent_prec);
+            }
+            let inner = context.enclosed();
+            let args = expr
                 .args
                 .iter()
-                .map(|arg| render_expr(arg, 0))
+                .map(|arg| element(arg, context))
+                .chain(expr.kwargs.iter().map(|kw| {
+                    let doc = Doc::concat(vec![
+                        format!("{}=", kw.name).into(),
+                        render_expr(&kw.value, PREC_LAMBDA, &inner),
+                    ]);
+                    (doc, expr_meta(&kw.value))
+                }))
                 .collect();
-            let kwargs_parts: Vec<String> = expr
-                .kwargs
-                .iter()
-                .map(|kw| format!("{}={}", kw.name, render_expr(&kw.value, 0)))
-                .collect();
-            let all_parts: Vec<&str> = args_parts.iter().chain(kwargs_parts.iter()).map(|s| s.as_str()).collect();
-            let rendered = format!("{callee}({})", all_parts.join(", "));
+            let magic_trailing_comma = context.magic_trailing_comma(&expr.meta.span);
+            let rendered = Doc::concat(vec![
+                callee,
+                render_elements("(", ")", args, true, magic_trailing_comma, context),
+            ]);
             wrap_if_needed(rendered, prec, parent_prec)
         }
         Expr::Unary(expr) => {
-            let prec = 12;
-            let value = render_expr(&expr.expr, prec);
+            let prec = unary_precedence(&expr.op);
+            let value = render_expr(&expr.expr, prec, context);
             let op = match expr.op {
                 UnaryOp::Neg => "-",
                 UnaryOp::Not => "not ",
                 UnaryOp::BitNot => "~",
             };
-            wrap_if_needed(format!("{op}{value}"), prec, parent_prec)
+            wrap_if_needed(Doc::concat(vec![op.into(), value]), prec, parent_prec)
         }
         Expr::BoolOp(expr) => {
             let prec = boolop_precedence(&expr.op);
@@ -563,17 +1547,30 @@ fn render_expr(expr: &Expr, parent_prec: u8) -> String {
                 BoolOp::And => "and",
                 BoolOp::Or => "or",
             };
-            let rendered = expr
-                .values
-                .iter()
-                .map(|value| render_expr(value, prec + 1))
-                .collect::<Vec<_>>()
-                .join(&format!(" {op} "));
+            // Operands go on lines of their own, operator first, but only
+            // where brackets allow the line breaks.
+            let mut parts = Vec::new();
+            for (index, value) in expr.values.iter().enumerate() {
+                if index > 0 {
+                    parts.push(if context.enclosed {
+                        Doc::Line
+                    } else {
+                        " ".into()
+                    });
+                    parts.push(format!("{op} ").into());
+                }
+                parts.push(render_expr(value, prec + 1, context));
+            }
+            let rendered = if context.enclosed {
+                Doc::group(Doc::concat(parts))
+            } else {
+                Doc::concat(parts)
+            };
             wrap_if_needed(rendered, prec, parent_prec)
         }
         Expr::Compare(expr) => {
-            let prec = 4;
-            let mut rendered = render_expr(&expr.left, prec + 1);
+            let prec = PREC_COMPARE;
+            let mut parts = vec![render_expr(&expr.left, prec + 1, context)];
             for (op, comparator) in expr.ops.iter().zip(expr.comparators.iter()) {
                 let op_str = match op {
                     CompareOp::Eq => "==",
@@ -587,39 +1584,58 @@ fn render_expr(expr: &Expr, parent_prec: u8) -> String {
                     CompareOp::Is => "is",
                     CompareOp::IsNot => "is not",
                 };
-                rendered.push_str(&format!(
-                    " {op_str} {}",
-                    render_expr(comparator, prec + 1)
-                ));
+                parts.push(match op {
+                    CompareOp::In | CompareOp::NotIn | CompareOp::Is | CompareOp::IsNot => {
+                        format!(" {op_str} ").into()
+                    }
+                    _ => context.operator(op_str),
+                });
+                parts.push(render_expr(comparator, prec + 1, context));
             }
-            wrap_if_needed(rendered, prec, parent_prec)
+            wrap_if_needed(Doc::concat(parts), prec, parent_prec)
         }
         Expr::IfExpr(expr) => {
-            let prec = 1;
-            let body = render_expr(&expr.body, prec + 1);
-            let condition = render_expr(&expr.condition, prec + 1);
-            let else_body = render_expr(&expr.else_body, prec);
+            let prec = PREC_IF_EXPR;
+            let body = render_expr(&expr.body, prec + 1, context);
+            let condition = render_expr(&expr.condition, prec + 1, context);
+            let else_body = render_expr(&expr.else_body, prec, context);
             wrap_if_needed(
-                format!("{body} if {condition} else {else_body}"),
+                Doc::concat(vec![
+                    body,
+                    " if ".into(),
+                    condition,
+                    " else ".into(),
+                    else_body,
+                ]),
                 prec,
                 parent_prec,
             )
         }
         Expr::Lambda(expr) => {
-            let prec = 0;
-            let params = expr.params.join(", ");
-            let body = render_expr(&expr.body, 0);
-            let rendered = if params.is_empty() {
-                format!("lambda: {body}")
+            let prec = PREC_LAMBDA;
+            let params = expr.params.join(context.comma());
+            let body = render_expr(&expr.body, prec, context);
+            let head = if params.is_empty() {
+                "lambda: ".to_string()
             } else {
-                format!("lambda {params}: {body}")
+                format!("lambda {params}: ")
             };
-            wrap_if_needed(rendered, prec, parent_prec)
+            wrap_if_needed(Doc::concat(vec![head.into(), body]), prec, parent_prec)
         }
         Expr::Binary(expr) => {
             let prec = binary_precedence(&expr.op);
-            let left = render_expr(&expr.left, prec);
-            let right = render_expr(&expr.right, prec + 1);
+            // `**` groups to the right and takes unary operators on its
+            // right but not on its left.
+            let (left_prec, right_prec) = match expr.op {
+                BinaryOp::Power => (PREC_AWAIT, PREC_UNARY),
+                _ => (prec, prec + 1),
+            };
+            let hugged = expr.op == BinaryOp::Power
+                && context.config.hug_power_operands
+                && is_simple_power_operand(&expr.left)
+                && is_simple_power_operand(&expr.right);
+            let left = render_expr(&expr.left, left_prec, context);
+            let right = render_expr(&expr.right, right_prec, context);
             let op = match expr.op {
                 BinaryOp::Add => "+",
                 BinaryOp::Sub => "-",
@@ -634,43 +1650,256 @@ fn render_expr(expr: &Expr, parent_prec: u8) -> String {
                 BinaryOp::LeftShift => "<<",
                 BinaryOp::RightShift => ">>",
             };
-            wrap_if_needed(format!("{left} {op} {right}"), prec, parent_prec)
+            let op = if hugged {
+                op.into()
+            } else {
+                context.operator(op)
+            };
+            wrap_if_needed(Doc::concat(vec![left, op, right]), prec, parent_prec)
         }
         Expr::FString(expr) => {
             let q = match expr.quote {
                 QuoteStyle::Double => '"',
                 QuoteStyle::Single => '\'',
             };
-            let mut out = format!("f{q}");
+            // Strings in replacement fields keep their quotes, and the quotes
+            // of the f-string only change if no field has quotes.
+            let fields = PrettyContext {
+                config: RenderConfig {
+                    quote_style: None,
+                    ..context.config
+                },
+                ..*context
+            };
+            let mut out = String::from(q);
+            let mut requotable = true;
             for part in &expr.parts {
                 match part {
                     FStringPart::Literal(s) => out.push_str(s),
                     FStringPart::Expr(e) => {
+                        let field = fields.flat(e);
+                        requotable &= !field.contains(['"', '\'', '\\']);
                         out.push('{');
-                        out.push_str(&render_expr(e, 0));
+                        out.push_str(&field);
                         out.push('}');
                     }
                 }
             }
             out.push(q);
-            out
-        }
-        Expr::NamedExpr(expr) => {
-            format!("({} := {})", expr.name, render_expr(&expr.value, 0))
+            match context.config.quote_style {
+                Some(quote) if requotable => format!("f{}", requote(&out, quote)).into(),
+                _ => format!("f{out}").into(),
+            }
         }
+        // Unparenthesized walruses are only allowed in a few places, so
+        // they always get parentheses.
+        Expr::NamedExpr(expr) => Doc::concat(vec![
+            format!("({} := ", expr.name).into(),
+            render_expr(&expr.value, PREC_LAMBDA, context),
+            ")".into(),
+        ]),
         Expr::Yield(expr) => {
-            match &expr.value {
-                Some(v) => format!("yield {}", render_expr(v, 0)),
-                None => "yield".to_string(),
-            }
+            let rendered = match &expr.value {
+                Some(value) => Doc::concat(vec![
+                    "yield ".into(),
+                    render_expr(value, PREC_TUPLE, context),
+                ]),
+                None => "yield".into(),
+            };
+            wrap_if_needed(rendered, PREC_TUPLE, parent_prec)
+        }
+        Expr::YieldFrom(expr) => wrap_if_needed(
+            Doc::concat(vec![
+                "yield from ".into(),
+                render_expr(&expr.value, PREC_LAMBDA, context),
+            ]),
+            PREC_TUPLE,
+            parent_prec,
+        ),
+        Expr::Await(expr) => wrap_if_needed(
+            Doc::concat(vec![
+                "await ".into(),
+                render_expr(&expr.value, PREC_PRIMARY, context),
+            ]),
+            PREC_AWAIT,
+            parent_prec,
+        ),
+    }
+}
+
+// Binding strengths of expressions, from the loosest to the tightest as in
+// the Python grammar. An expression is put in parentheses where its parent
+// needs a tighter one. The binary operators come between `PREC_COMPARE`
+// and `PREC_AWAIT`, see `BinaryOp::precedence`.
+
+/// Tuples and yields, which only go without parentheses where a statement
+/// takes a whole list of expressions.
+const PREC_TUPLE: u8 = 0;
+/// Lambdas, and the least any single expression binds, as in call arguments.
+const PREC_LAMBDA: u8 = 1;
+const PREC_IF_EXPR: u8 = 2;
+/// `or`; `and` is one more, see `boolop_precedence`.
+const PREC_OR: u8 = 3;
+const PREC_NOT: u8 = 5;
+const PREC_COMPARE: u8 = 6;
+/// `-` and `~`. `**` binds tighter, but takes them on its right.
+const PREC_UNARY: u8 = 13;
+const PREC_AWAIT: u8 = 15;
+/// Attribute references, subscripts and calls.
+const PREC_PRIMARY: u8 = 16;
+/// Expressions that never need parentheses.
+const PREC_ATOM: u8 = u8::MAX;
+
+/// Binding strength `render_expr` gives `expr` when deciding on parentheses.
+fn expr_precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Tuple(expr) if tuple_is_parenthesized(expr) => PREC_ATOM,
+        Expr::Tuple(_) | Expr::Yield(_) | Expr::YieldFrom(_) => PREC_TUPLE,
+        Expr::Lambda(_) => PREC_LAMBDA,
+        Expr::IfExpr(_) => PREC_IF_EXPR,
+        Expr::BoolOp(expr) => boolop_precedence(&expr.op),
+        Expr::Unary(expr) => unary_precedence(&expr.op),
+        Expr::Compare(_) => PREC_COMPARE,
+        Expr::Binary(expr) => binary_precedence(&expr.op),
+        Expr::Await(_) => PREC_AWAIT,
+        Expr::Attribute(_) | Expr::Subscript(_) | Expr::Call(_) => PREC_PRIMARY,
+        Expr::Identifier(_)
+        | Expr::Literal(_)
+        | Expr::FString(_)
+        | Expr::Grouped(_)
+        | Expr::List(_)
+        | Expr::Dict(_)
+        | Expr::Set(_)
+        | Expr::Comprehension(_)
+        | Expr::Slice(_)
+        | Expr::NamedExpr(_) => PREC_ATOM,
+    }
+}
+
+fn unary_precedence(op: &UnaryOp) -> u8 {
+    match op {
+        UnaryOp::Not => PREC_NOT,
+        UnaryOp::Neg | UnaryOp::BitNot => PREC_UNARY,
+    }
+}
+
+/// Whether the source of the parsed `tuple` has parentheses around it,
+/// which the parser keeps in its span rather than in a `Grouped` node.
+fn tuple_is_parenthesized(tuple: &TupleExpr) -> bool {
+    match tuple.elements.first() {
+        Some(first) => expr_meta(first).span.start.offset > tuple.meta.span.start.offset,
+        None => true,
+    }
+}
+
+/// Renders an element of a bracketed list, which may use line breaks.
+fn element<'a>(expr: &'a Expr, context: &PrettyContext) -> (Doc, &'a NodeMeta) {
+    (
+        render_expr(expr, PREC_LAMBDA, &context.enclosed()),
+        expr_meta(expr),
+    )
+}
+
+fn comments(trivia: &[Trivia]) -> impl Iterator<Item = &Trivia> {
+    trivia
+        .iter()
+        .filter(|trivia| matches!(trivia.kind, TriviaKind::Comment(_)))
+}
+
+fn has_comments<'a>(mut metas: impl Iterator<Item = &'a NodeMeta>) -> bool {
+    metas.any(|meta| {
+        comments(&meta.leading_trivia)
+            .chain(comments(&meta.trailing_trivia))
+            .next()
+            .is_some()
+    })
+}
+
+/// Renders comma-separated elements between `open` and `close`.
+///
+/// Elements that do not fit on the line go on an indented line of their
+/// own, and if they do not fit there either, each element gets a line,
+/// followed by a comma when another element follows or `trailing_comma` is
+/// set. Comments force the latter and are kept next to their element, and
+/// so does `magic_trailing_comma`, set when the source ends the elements
+/// with a comma. A comment right after `open` stays on its line, which is
+/// the end of the whole line when the elements fit on it.
+fn render_elements(
+    open: &str,
+    close: &str,
+    elements: Vec<(Doc, &NodeMeta)>,
+    trailing_comma: bool,
+    magic_trailing_comma: bool,
+    context: &PrettyContext,
+) -> Doc {
+    if elements.is_empty() {
+        return format!("{open}{close}").into();
+    }
+    let count = elements.len();
+    // A lone element only breaks inside itself unless comments break it.
+    let trailing_comma = trailing_comma
+        && (count > 1
+            || magic_trailing_comma
+            || has_comments(elements.iter().map(|(_, meta)| *meta)));
+    let mut parts = Vec::new();
+    if magic_trailing_comma {
+        parts.push(Doc::BreakParent);
+    }
+    let opening_comments = elements[0]
+        .1
+        .leading_trivia
+        .iter()
+        .take_while(|trivia| match &trivia.kind {
+            TriviaKind::RawWhitespace(text) => !text.contains(['\n', '\r']),
+            TriviaKind::Comment(_) => true,
+            TriviaKind::Blank(_) => false,
+        })
+        .filter(|trivia| matches!(trivia.kind, TriviaKind::Comment(_)))
+        .count();
+    let mut suffix = String::new();
+    for trivia in comments(&elements[0].1.leading_trivia).take(opening_comments) {
+        suffix.push_str("  ");
+        suffix.push_str(&render_trivia(trivia));
+    }
+    for (position, (doc, meta)) in elements.into_iter().enumerate() {
+        if position > 0 {
+            parts.push(if context.config.space_after_comma {
+                Doc::Line
+            } else {
+                Doc::SoftLine
+            });
         }
-        Expr::YieldFrom(expr) => {
-            format!("yield from {}", render_expr(&expr.value, 0))
+        let skipped = if position == 0 { opening_comments } else { 0 };
+        for trivia in comments(&meta.leading_trivia).skip(skipped) {
+            parts.push(render_trivia(trivia).into());
+            parts.push(Doc::HardLine);
         }
-        Expr::Await(expr) => {
-            format!("await {}", render_expr(&expr.value, 0))
+        parts.push(doc);
+        if position + 1 < count {
+            parts.push(",".into());
+        } else if trailing_comma {
+            parts.push(Doc::IfBreak(",".to_string()));
+        }
+        for (index, trivia) in comments(&meta.trailing_trivia).enumerate() {
+            if index == 0 && trivia.span.start.line <= meta.span.end.line {
+                parts.push("  ".into());
+            } else {
+                parts.push(Doc::HardLine);
+            }
+            parts.push(render_trivia(trivia).into());
+            parts.push(Doc::BreakParent);
         }
     }
+    Doc::group(Doc::concat(vec![
+        open.into(),
+        Doc::LineSuffix(suffix),
+        Doc::indent(Doc::concat(vec![
+            Doc::SoftLine,
+            Doc::group(Doc::concat(parts)),
+        ])),
+        Doc::SoftLine,
+        close.into(),
+    ]))
 }
 
 fn render_literal(literal: &Literal) -> String {
@@ -688,25 +1917,140 @@ fn render_literal(literal: &Literal) -> String {
     }
 }
 
+/// Puts the quoted string `raw` in `preferred` quotes unless that takes
+/// more escapes, and drops escapes of the quote it is not in.
+pub(super) fn requote(raw: &str, preferred: QuoteStyle) -> String {
+    let preferred = match preferred {
+        QuoteStyle::Double => '"',
+        QuoteStyle::Single => '\'',
+    };
+    let quote = match raw.chars().next() {
+        Some(quote @ ('"' | '\'')) if raw.len() >= 2 && raw.ends_with(quote) => quote,
+        _ => return raw.to_string(),
+    };
+    let other = if quote == '"' { '\'' } else { '"' };
+    let body = unescape_quote(&raw[1..raw.len() - 1], other);
+    let swapped = escape_quote(&unescape_quote(&body, quote), other);
+    let escapes = |text: &str| text.matches('\\').count();
+    let (escapes, swapped_escapes) = (escapes(&body), escapes(&swapped));
+    if swapped_escapes < escapes || (swapped_escapes == escapes && other == preferred) {
+        format!("{other}{swapped}{other}")
+    } else {
+        format!("{quote}{body}{quote}")
+    }
+}
+
+fn unescape_quote(body: &str, quote: char) -> String {
+    let mut output = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            output.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some(next) if next == quote => output.push(next),
+            Some(next) => {
+                output.push(ch);
+                output.push(next);
+            }
+            None => output.push(ch),
+        }
+    }
+    output
+}
+
+fn escape_quote(body: &str, quote: char) -> String {
+    let mut output = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            output.push(ch);
+            output.extend(chars.next());
+        } else {
+            if ch == quote {
+                output.push('\\');
+            }
+            output.push(ch);
+        }
+    }
+    output
+}
+
+/// Whether parentheses around `expr` can go where its parent binds at
+/// `parent_prec`. `render_expr` puts back the ones the precedence of `expr`
+/// needs, so only those around numbers followed by a dot stay, and those
+/// around tuples, yields and walruses, which could change the meaning where
+/// a statement takes a list of expressions.
+fn parens_are_redundant(expr: &Expr, parent_prec: u8) -> bool {
+    match expr {
+        Expr::Literal(expr) if matches!(expr.literal, Literal::Number(_)) => {
+            parent_prec < PREC_PRIMARY
+        }
+        Expr::Tuple(_) | Expr::Yield(_) | Expr::YieldFrom(_) | Expr::NamedExpr(_) => false,
+        _ => true,
+    }
+}
+
+/// Whether `expr` is a name, a number or an attribute of a name, which
+/// may be negated.
+fn is_simple_power_operand(expr: &Expr) -> bool {
+    match expr {
+        Expr::Identifier(_) => true,
+        Expr::Literal(expr) => matches!(expr.literal, Literal::Number(_)),
+        Expr::Attribute(expr) => {
+            matches!(&*expr.value, Expr::Identifier(_) | Expr::Attribute(_))
+                && is_simple_power_operand(&expr.value)
+        }
+        Expr::Unary(expr) => expr.op == UnaryOp::Neg && is_simple_power_operand(&expr.expr),
+        _ => false,
+    }
+}
+
+/// Whether `expr` is a name or a literal, which may have a unary operator,
+/// so that the colons around it in a slice need no spaces.
+fn is_simple_slice_bound(expr: &Expr) -> bool {
+    match expr {
+        Expr::Identifier(_) | Expr::Literal(_) => true,
+        Expr::Unary(expr) => expr.op != UnaryOp::Not && is_simple_slice_bound(&expr.expr),
+        _ => false,
+    }
+}
+
 fn binary_precedence(op: &BinaryOp) -> u8 {
     op.precedence()
 }
 
 fn boolop_precedence(op: &BoolOp) -> u8 {
     match op {
-        BoolOp::Or => 2,
-        BoolOp::And => 3,
+        BoolOp::Or => PREC_OR,
+        BoolOp::And => PREC_OR + 1,
     }
 }
 
-fn wrap_if_needed(rendered: String, prec: u8, parent_prec: u8) -> String {
+fn wrap_if_needed(rendered: Doc, prec: u8, parent_prec: u8) -> Doc {
     if prec < parent_prec {
-        format!("({rendered})")
+        Doc::concat(vec!["(".into(), rendered, ")".into()])
     } else {
         rendered
     }
 }
 
-fn indent_str(count: usize) -> String {
-    " ".repeat(count)
+pub(super) fn has_block(stmt: &Stmt) -> bool {
+    matches!(
+        stmt,
+        Stmt::If(_)
+            | Stmt::While(_)
+            | Stmt::For(_)
+            | Stmt::Match(_)
+            | Stmt::FunctionDef(_)
+            | Stmt::ClassDef(_)
+            | Stmt::Try(_)
+            | Stmt::With(_)
+    )
+}
+
+fn normalize_line_breaks(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
 }
+
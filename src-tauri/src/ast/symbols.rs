@@ -0,0 +1,426 @@
+use super::render::stmt_meta;
+use super::*;
+use std::collections::{HashMap, HashSet};
+
+impl Program {
+    /// Resolves every name of the program to its symbol, following Python's
+    /// scoping rules: names bound in a function are local to it unless
+    /// declared `global` or `nonlocal`, and class bodies are not visible
+    /// from the functions and comprehensions nested in them.
+    pub fn symbols(&self) -> SymbolTable {
+        let mut collect = Collect {
+            scopes: vec![Scope {
+                kind: ScopeKind::Module,
+                node: self.meta.id,
+                parent: None,
+            }],
+            current: ScopeId(0),
+            binding: false,
+            occurrences: Vec::new(),
+        };
+        collect.visit_program(self);
+        resolve(collect.scopes, collect.occurrences)
+    }
+
+    /// Span of the first token reading `name` within `span`, after the
+    /// first token reading `after` if given, or `span` itself when there is
+    /// none.
+    pub(super) fn name_span(&self, span: Span, after: Option<&str>, name: &str) -> Span {
+        let tokens = &self.raw_tokens;
+        let first = tokens.partition_point(|token| token.span.start.offset < span.start.offset);
+        let mut started = after.is_none();
+        for token in &tokens[first..] {
+            if token.span.end.offset > span.end.offset {
+                break;
+            }
+            if started && token.raw == name {
+                return token.span;
+            }
+            started |= after == Some(token.raw.as_str());
+        }
+        span
+    }
+}
+
+/// Names an `import` statement binds: the alias, or for a plain `import` of
+/// a dotted module, its first component.
+pub(super) fn imported_names(stmt: &ImportStmt) -> Vec<&str> {
+    if !stmt.is_from && stmt.names.is_empty() {
+        return stmt.module.split('.').take(1).collect();
+    }
+    stmt.names
+        .iter()
+        .map(|name| name.alias.as_deref().unwrap_or(&name.name))
+        .collect()
+}
+
+/// A name as it occurs in the source, before it is resolved.
+struct Occurrence {
+    name: String,
+    node: NodeId,
+    span: Span,
+    kind: ReferenceKind,
+    scope: ScopeId,
+}
+
+/// Opens the scopes of a program and collects the names occurring in each.
+struct Collect {
+    scopes: Vec<Scope>,
+    current: ScopeId,
+    /// Set while visiting an assignment target, whose names are bound.
+    binding: bool,
+    occurrences: Vec<Occurrence>,
+}
+
+impl Collect {
+    fn add(&mut self, name: &str, meta: &NodeMeta, kind: ReferenceKind) {
+        self.add_in(self.current, name, meta, kind);
+    }
+
+    fn add_in(&mut self, scope: ScopeId, name: &str, meta: &NodeMeta, kind: ReferenceKind) {
+        self.occurrences.push(Occurrence {
+            name: name.to_string(),
+            node: meta.id,
+            span: meta.span,
+            kind,
+            scope,
+        });
+    }
+
+    /// Runs `visit` in a new scope opened by the node `meta`.
+    fn scoped(&mut self, kind: ScopeKind, meta: &NodeMeta, visit: impl FnOnce(&mut Self)) {
+        let parent = self.current;
+        self.scopes.push(Scope {
+            kind,
+            node: meta.id,
+            parent: Some(parent),
+        });
+        self.current = ScopeId(self.scopes.len() - 1);
+        let binding = std::mem::take(&mut self.binding);
+        visit(self);
+        self.binding = binding;
+        self.current = parent;
+    }
+
+    fn bind(&mut self, target: &Expr) {
+        let binding = std::mem::replace(&mut self.binding, true);
+        self.visit_expr(target);
+        self.binding = binding;
+    }
+
+    /// Collects the names `del target` unbinds; the objects of attributes
+    /// and subscripts are only read.
+    fn delete(&mut self, target: &Expr) {
+        match target {
+            Expr::Identifier(identifier) => {
+                self.add(&identifier.name, &identifier.meta, ReferenceKind::Deletion);
+            }
+            Expr::Tuple(TupleExpr { elements, .. }) | Expr::List(ListExpr { elements, .. }) => {
+                for element in elements {
+                    self.delete(element);
+                }
+            }
+            Expr::Grouped(grouped) => self.delete(&grouped.expr),
+            _ => self.visit_expr(target),
+        }
+    }
+
+    fn visit_comprehension(
+        &mut self,
+        meta: &NodeMeta,
+        fors: &[ComprehensionFor],
+        elements: &[&Expr],
+    ) {
+        // The first iterable is evaluated where the comprehension is.
+        if let Some(first) = fors.first() {
+            self.visit_expr(&first.iter);
+        }
+        self.scoped(ScopeKind::Comprehension, meta, |collect| {
+            for (index, comprehension) in fors.iter().enumerate() {
+                if index > 0 {
+                    collect.visit_expr(&comprehension.iter);
+                }
+                collect.bind(&comprehension.target);
+                for condition in &comprehension.ifs {
+                    collect.visit_expr(condition);
+                }
+            }
+            for element in elements {
+                collect.visit_expr(element);
+            }
+        });
+    }
+}
+
+impl Visitor for Collect {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        let meta = stmt_meta(stmt);
+        match stmt {
+            Stmt::FunctionDef(function) => {
+                for decorator in &function.decorators {
+                    self.visit_expr(decorator);
+                }
+                for param in &function.params {
+                    self.walk_param(param);
+                }
+                if let Some(return_type) = &function.return_type {
+                    self.visit_expr(return_type);
+                }
+                self.add(&function.name, meta, ReferenceKind::Definition);
+                self.scoped(ScopeKind::Function, meta, |collect| {
+                    for param in &function.params {
+                        collect.add(&param.name, meta, ReferenceKind::Parameter);
+                    }
+                    collect.visit_block(&function.body);
+                });
+            }
+            Stmt::ClassDef(class) => {
+                for decorator in &class.decorators {
+                    self.visit_expr(decorator);
+                }
+                for base in &class.bases {
+                    self.visit_expr(base);
+                }
+                self.add(&class.name, meta, ReferenceKind::Definition);
+                self.scoped(ScopeKind::Class, meta, |collect| {
+                    collect.visit_block(&class.body);
+                });
+            }
+            Stmt::Assign(assign) => {
+                self.visit_expr(&assign.value);
+                for target in &assign.targets {
+                    self.bind(target);
+                }
+            }
+            Stmt::AugAssign(assign) => {
+                self.visit_expr(&assign.target);
+                self.visit_expr(&assign.value);
+                self.bind(&assign.target);
+            }
+            Stmt::AnnAssign(assign) => {
+                self.visit_expr(&assign.annotation);
+                let kind = match &assign.value {
+                    Some(value) => {
+                        self.visit_expr(value);
+                        ReferenceKind::Definition
+                    }
+                    None => ReferenceKind::Declaration,
+                };
+                self.add(&assign.target, meta, kind);
+            }
+            Stmt::Del(stmt) => self.delete(&stmt.target),
+            Stmt::For(stmt) => {
+                self.visit_expr(&stmt.iterable);
+                self.bind(&stmt.target);
+                self.visit_block(&stmt.body);
+                if let Some(body) = &stmt.else_body {
+                    self.visit_block(body);
+                }
+            }
+            Stmt::Import(import) => {
+                for name in imported_names(import) {
+                    self.add(name, meta, ReferenceKind::Definition);
+                }
+            }
+            Stmt::Global(global) => {
+                for name in &global.names {
+                    self.add(name, meta, ReferenceKind::Global);
+                }
+            }
+            Stmt::Nonlocal(nonlocal) => {
+                for name in &nonlocal.names {
+                    self.add(name, meta, ReferenceKind::Nonlocal);
+                }
+            }
+            Stmt::With(with) => {
+                for item in &with.items {
+                    self.visit_expr(&item.context);
+                    if let Some(name) = &item.name {
+                        self.add(name, meta, ReferenceKind::Definition);
+                    }
+                }
+                self.visit_block(&with.body);
+            }
+            Stmt::Try(stmt) => {
+                self.visit_block(&stmt.body);
+                for handler in &stmt.handlers {
+                    if let Some(exception_type) = &handler.exception_type {
+                        self.visit_expr(exception_type);
+                    }
+                    if let Some(name) = &handler.name {
+                        self.add(name, &handler.meta, ReferenceKind::Definition);
+                    }
+                    self.visit_block(&handler.body);
+                }
+                for body in [&stmt.else_body, &stmt.finally_body].into_iter().flatten() {
+                    self.visit_block(body);
+                }
+            }
+            _ => self.walk_stmt(stmt),
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Identifier(identifier) => {
+                let kind = if self.binding {
+                    ReferenceKind::Definition
+                } else {
+                    ReferenceKind::Use
+                };
+                self.add(&identifier.name, &identifier.meta, kind);
+            }
+            // Unpacking binds the names inside.
+            Expr::Tuple(_) | Expr::List(_) | Expr::Grouped(_) => self.walk_expr(expr),
+            _ if self.binding => {
+                // The object of `a.b = ...` or `a[i] = ...` is only read.
+                self.binding = false;
+                self.visit_expr(expr);
+                self.binding = true;
+            }
+            Expr::Lambda(lambda) => {
+                self.scoped(ScopeKind::Lambda, &lambda.meta, |collect| {
+                    for param in &lambda.params {
+                        collect.add(param, &lambda.meta, ReferenceKind::Parameter);
+                    }
+                    collect.visit_expr(&lambda.body);
+                });
+            }
+            Expr::Comprehension(comprehension) => match comprehension {
+                ComprehensionExpr::List(list) => {
+                    self.visit_comprehension(&list.meta, &list.fors, &[&list.element])
+                }
+                ComprehensionExpr::Set(set) => {
+                    self.visit_comprehension(&set.meta, &set.fors, &[&set.element])
+                }
+                ComprehensionExpr::Generator(generator) => self.visit_comprehension(
+                    &generator.meta,
+                    &generator.fors,
+                    &[&generator.element],
+                ),
+                ComprehensionExpr::Dict(dict) => {
+                    self.visit_comprehension(&dict.meta, &dict.fors, &[&dict.key, &dict.value])
+                }
+            },
+            Expr::NamedExpr(named) => {
+                self.visit_expr(&named.value);
+                // `:=` in a comprehension binds in the scope around it.
+                let mut scope = self.current;
+                while self.scopes[scope.0].kind == ScopeKind::Comprehension {
+                    match self.scopes[scope.0].parent {
+                        Some(parent) => scope = parent,
+                        None => break,
+                    }
+                }
+                self.add_in(scope, &named.name, &named.meta, ReferenceKind::Definition);
+            }
+            _ => self.walk_expr(expr),
+        }
+    }
+
+    fn visit_pattern(&mut self, pattern: &Pattern) {
+        if let Pattern::Identifier(identifier) = pattern {
+            self.add(
+                &identifier.name,
+                &identifier.meta,
+                ReferenceKind::Definition,
+            );
+        }
+    }
+}
+
+/// Names declared in one scope.
+#[derive(Default)]
+struct Declarations<'a> {
+    bound: HashSet<&'a str>,
+    global: HashSet<&'a str>,
+    nonlocal: HashSet<&'a str>,
+}
+
+fn resolve(scopes: Vec<Scope>, occurrences: Vec<Occurrence>) -> SymbolTable {
+    let mut declarations: Vec<Declarations> =
+        scopes.iter().map(|_| Declarations::default()).collect();
+    for occurrence in &occurrences {
+        let declared = &mut declarations[occurrence.scope.0];
+        let name = occurrence.name.as_str();
+        match occurrence.kind {
+            ReferenceKind::Definition
+            | ReferenceKind::Parameter
+            | ReferenceKind::Declaration
+            | ReferenceKind::Deletion => {
+                declared.bound.insert(name);
+            }
+            ReferenceKind::Global => {
+                declared.global.insert(name);
+            }
+            ReferenceKind::Nonlocal => {
+                declared.nonlocal.insert(name);
+            }
+            ReferenceKind::Use => {}
+        }
+    }
+    let mut table = SymbolTable::default();
+    let mut symbols: HashMap<(ScopeId, &str), SymbolId> = HashMap::new();
+    for occurrence in &occurrences {
+        let name = occurrence.name.as_str();
+        let scope = owner(&scopes, &declarations, occurrence.scope, name);
+        let symbol = *symbols.entry((scope, name)).or_insert_with(|| {
+            table.symbols.push(Symbol {
+                name: name.to_string(),
+                scope,
+            });
+            SymbolId(table.symbols.len() - 1)
+        });
+        table.nodes.entry(occurrence.node).or_insert(symbol);
+        table.references.push(Reference {
+            node: occurrence.node,
+            span: occurrence.span,
+            kind: occurrence.kind,
+            symbol,
+            scope: occurrence.scope,
+        });
+    }
+    table.scopes = scopes;
+    table
+}
+
+/// Scope whose variable `name` is, as seen from `scope`.
+fn owner(scopes: &[Scope], declarations: &[Declarations], scope: ScopeId, name: &str) -> ScopeId {
+    let module = ScopeId(0);
+    let declared = &declarations[scope.0];
+    if declared.global.contains(name) {
+        return module;
+    }
+    if declared.nonlocal.contains(name) {
+        let mut enclosing = scopes[scope.0].parent;
+        while let Some(parent) = enclosing {
+            if parent != module
+                && scopes[parent.0].kind != ScopeKind::Class
+                && (declarations[parent.0].bound.contains(name)
+                    || declarations[parent.0].nonlocal.contains(name))
+            {
+                return owner(scopes, declarations, parent, name);
+            }
+            enclosing = scopes[parent.0].parent;
+        }
+        // A `nonlocal` without a binding around it is an error in Python;
+        // the name stays in the scope declaring it.
+        return scope;
+    }
+    if declared.bound.contains(name) {
+        return scope;
+    }
+    let mut enclosing = scopes[scope.0].parent;
+    while let Some(parent) = enclosing {
+        let declared = &declarations[parent.0];
+        let visible = scopes[parent.0].kind != ScopeKind::Class
+            && (declared.bound.contains(name)
+                || declared.global.contains(name)
+                || declared.nonlocal.contains(name));
+        if visible || parent == module {
+            return owner(scopes, declarations, parent, name);
+        }
+        enclosing = scopes[parent.0].parent;
+    }
+    module
+}
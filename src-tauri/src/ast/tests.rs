@@ -14,6 +14,7 @@ fn pretty_roundtrip(source: &str) -> Result<Program, ParseError> {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     parse_with(PythonVersion::Py310, &rendered)
 }
@@ -25,6 +26,7 @@ fn lossless_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Lossless,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -36,6 +38,7 @@ fn lossless_roundtrip_with_comments() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Lossless,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -99,6 +102,7 @@ fn ann_assign_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -110,6 +114,7 @@ fn ann_assign_with_value_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -121,6 +126,7 @@ fn function_def_with_annotations_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -132,6 +138,7 @@ fn function_def_partial_annotations_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -143,6 +150,7 @@ fn function_def_return_only_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -154,6 +162,7 @@ fn ann_assign_complex_type_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -185,7 +194,12 @@ fn expr_eq(left: &Expr, right: &Expr) -> bool {
                 && expr_eq(&left.else_body, &right.else_body)
         }
         (Expr::Call(left), Expr::Call(right)) => {
-            expr_eq(&left.callee, &right.callee) && expr_vec_eq(&left.args, &right.args)
+            expr_eq(&left.callee, &right.callee)
+                && expr_vec_eq(&left.args, &right.args)
+                && left.kwargs.len() == right.kwargs.len()
+                && left.kwargs.iter().zip(&right.kwargs).all(|(left, right)| {
+                    left.name == right.name && expr_eq(&left.value, &right.value)
+                })
         }
         (Expr::Tuple(left), Expr::Tuple(right)) => expr_vec_eq(&left.elements, &right.elements),
         (Expr::List(left), Expr::List(right)) => expr_vec_eq(&left.elements, &right.elements),
@@ -198,10 +212,40 @@ fn expr_eq(left: &Expr, right: &Expr) -> bool {
             expr_eq(&left.value, &right.value) && expr_eq(&left.index, &right.index)
         }
         (Expr::Comprehension(left), Expr::Comprehension(right)) => comp_eq(left, right),
+        (Expr::Await(left), Expr::Await(right)) => expr_eq(&left.value, &right.value),
+        (Expr::NamedExpr(left), Expr::NamedExpr(right)) => {
+            left.name == right.name && expr_eq(&left.value, &right.value)
+        }
+        (Expr::Yield(left), Expr::Yield(right)) => option_eq(&left.value, &right.value),
+        (Expr::YieldFrom(left), Expr::YieldFrom(right)) => expr_eq(&left.value, &right.value),
+        (Expr::Slice(left), Expr::Slice(right)) => {
+            option_eq(&left.lower, &right.lower)
+                && option_eq(&left.upper, &right.upper)
+                && option_eq(&left.step, &right.step)
+        }
+        (Expr::FString(left), Expr::FString(right)) => {
+            left.parts.len() == right.parts.len()
+                && left
+                    .parts
+                    .iter()
+                    .zip(&right.parts)
+                    .all(|parts| match parts {
+                        (FStringPart::Literal(left), FStringPart::Literal(right)) => left == right,
+                        (FStringPart::Expr(left), FStringPart::Expr(right)) => expr_eq(left, right),
+                        _ => false,
+                    })
+        }
         _ => false,
     }
 }
 
+fn option_eq(left: &Option<Box<Expr>>, right: &Option<Box<Expr>>) -> bool {
+    match (left, right) {
+        (Some(left), Some(right)) => expr_eq(left, right),
+        (left, right) => left.is_none() && right.is_none(),
+    }
+}
+
 fn expr_vec_eq(left: &[Expr], right: &[Expr]) -> bool {
     left.len() == right.len() && left.iter().zip(right.iter()).all(|(a, b)| expr_eq(a, b))
 }
@@ -253,13 +297,30 @@ fn comp_fors_eq(left: &[ComprehensionFor], right: &[ComprehensionFor]) -> bool {
 
 #[test]
 fn property_expr_roundtrip() {
-    for seed in 1..20 {
-        let expr = generate_expr(seed, 3);
+    for seed in 1..500 {
+        let scope =
+            [Scope::MODULE, Scope::function(false), Scope::function(true)][(seed % 3) as usize];
+        let expr = generate_expr(seed, 4, scope);
         let expected = expr.clone();
-        let stmt = Stmt::Expr(ExprStmt {
+        let mut stmt = Stmt::Expr(ExprStmt {
             meta: NodeMeta::default(),
             expr,
         });
+        if scope.yields {
+            stmt = Stmt::FunctionDef(FunctionDefStmt {
+                meta: NodeMeta::default(),
+                name: "f".to_string(),
+                params: Vec::new(),
+                decorators: Vec::new(),
+                body: Block {
+                    meta: NodeMeta::default(),
+                    indent_level: 1,
+                    statements: vec![stmt],
+                },
+                return_type: None,
+                is_async: scope.awaits,
+            });
+        }
         let program = Program {
             meta: NodeMeta::default(),
             indent_width: 4,
@@ -267,20 +328,53 @@ fn property_expr_roundtrip() {
             tokens: Vec::new(),
             raw_tokens: Vec::new(),
             dirty: true,
+            source_format: SourceFormat::default(),
         };
         let rendered = program.to_python(RenderConfig {
             mode: RenderMode::Pretty,
             reuse_token_ranges: false,
+            ..RenderConfig::default()
         });
-        let parsed = parse_with(PythonVersion::Py310, &rendered).unwrap();
-        let parsed_expr = match &parsed.body[0] {
+        let parsed = parse_with(PythonVersion::Py310, &rendered)
+            .unwrap_or_else(|error| panic!("{rendered:?}: {error:?}"));
+        let parsed_stmt = match &parsed.body[0] {
+            Stmt::FunctionDef(def) => &def.body.statements[0],
+            stmt => stmt,
+        };
+        let parsed_expr = match parsed_stmt {
             Stmt::Expr(stmt) => &stmt.expr,
             _ => panic!("expected expression statement"),
         };
-        assert!(expr_eq(&expected, parsed_expr));
+        assert!(expr_eq(&expected, parsed_expr), "{rendered:?}");
     }
 }
 
+#[test]
+fn lambda_body_leaves_comprehension_clauses() {
+    let source = "x = {k: lambda: 1 for k in keys}\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let Stmt::Assign(AssignStmt { value, .. }) = &program.body[0] else {
+        panic!("expected assignment");
+    };
+    assert!(matches!(
+        value,
+        Expr::Comprehension(ComprehensionExpr::Dict(_))
+    ));
+}
+
+#[test]
+fn parentheses_follow_precedence() {
+    let bare = RenderConfig {
+        remove_redundant_parens: true,
+        ..RenderConfig::default()
+    };
+    let source = "a = (-x) ** (-y)\nb = -(x ** y)\nc = (a ** b) ** (c ** d)\nd = (not x) == y\ne = not (x == y)\nf = (await x) ** 2\ng = (a - b) - (c - d)\nh = (lambda: x) if (a if b else c) else (lambda: y)\ni = [(yield), (x := 1), (a, b)]\n";
+    assert_eq!(
+        render_styled(source, bare),
+        "a = (-x) ** -y\nb = -x ** y\nc = (a ** b) ** c ** d\nd = (not x) == y\ne = not x == y\nf = await x ** 2\ng = a - b - (c - d)\nh = (lambda: x) if (a if b else c) else (lambda: y)\ni = [(yield), (x := 1), (a, b)]\n"
+    );
+}
+
 #[test]
 fn fstring_roundtrip() {
     let source = "x = f\"hello {name}\"\n";
@@ -288,6 +382,7 @@ fn fstring_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -299,6 +394,7 @@ fn fstring_lossless_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Lossless,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -310,53 +406,296 @@ fn fstring_multiple_exprs() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
 
-fn generate_expr(seed: u64, depth: u8) -> Expr {
+/// What Python allows where a generated expression sits.
+#[derive(Clone, Copy)]
+struct Scope {
+    yields: bool,
+    awaits: bool,
+    walrus: bool,
+    /// In an f-string field, where a `{` or a lambda's `:` would end the field.
+    field: bool,
+}
+
+impl Scope {
+    const MODULE: Scope = Scope {
+        yields: false,
+        awaits: false,
+        walrus: true,
+        field: false,
+    };
+
+    fn function(is_async: bool) -> Scope {
+        Scope {
+            yields: true,
+            awaits: is_async,
+            ..Scope::MODULE
+        }
+    }
+}
+
+fn generate_expr(seed: u64, depth: u8, scope: Scope) -> Expr {
     let mut value = seed;
     let mut next = || {
-        value = value.wrapping_mul(1664525).wrapping_add(1013904223);
-        value
+        value = value
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        value >> 33
+    };
+    let meta = NodeMeta::default;
+    let identifier = |index: u64| {
+        Expr::Identifier(IdentifierExpr {
+            meta: meta(),
+            name: format!("v{}", index % 5),
+        })
     };
     if depth == 0 {
-        return Expr::Literal(LiteralExpr {
-            meta: NodeMeta::default(),
-            literal: Literal::Number(NumberLiteral {
-                raw: (next() % 10).to_string(),
-            }),
-        });
+        return if next() % 2 == 0 {
+            Expr::Literal(LiteralExpr {
+                meta: meta(),
+                literal: Literal::Number(NumberLiteral {
+                    raw: (next() % 10).to_string(),
+                }),
+            })
+        } else {
+            identifier(next())
+        };
     }
-    match next() % 4 {
-        0 => Expr::Identifier(IdentifierExpr {
-            meta: NodeMeta::default(),
-            name: format!("v{}", next() % 5),
+    let (choice, pick) = (next() % 23, next());
+    let (a, b, c) = (next(), next(), next());
+    let sub_in = |seed: u64, scope: Scope| Box::new(generate_expr(seed, depth - 1, scope));
+    let sub = |seed: u64| sub_in(seed, scope);
+    // Comprehensions and lambdas are functions of their own, and Python
+    // refuses walruses in the iterables of comprehensions.
+    let nested = Scope {
+        yields: false,
+        ..scope
+    };
+    let iterable = Scope {
+        walrus: false,
+        ..nested
+    };
+    let fors = || {
+        (0..pick % 2 + 1)
+            .map(|index| ComprehensionFor {
+                meta: meta(),
+                target: Expr::Identifier(IdentifierExpr {
+                    meta: meta(),
+                    name: format!("i{index}"),
+                }),
+                iter: *sub_in(b + index, iterable),
+                ifs: (c % 2 == 0)
+                    .then(|| *sub_in(c + index, nested))
+                    .into_iter()
+                    .collect(),
+            })
+            .collect()
+    };
+    match choice {
+        0 => Expr::Unary(UnaryExpr {
+            meta: meta(),
+            op: [UnaryOp::Neg, UnaryOp::Not, UnaryOp::BitNot][(pick % 3) as usize].clone(),
+            expr: sub(a),
         }),
-        1 => Expr::Unary(UnaryExpr {
-            meta: NodeMeta::default(),
-            op: UnaryOp::Neg,
-            expr: Box::new(generate_expr(next(), depth - 1)),
+        1 | 2 => Expr::Binary(BinaryExpr {
+            meta: meta(),
+            left: sub(a),
+            op: [
+                BinaryOp::Add,
+                BinaryOp::Sub,
+                BinaryOp::Mul,
+                BinaryOp::Div,
+                BinaryOp::Mod,
+                BinaryOp::FloorDiv,
+                BinaryOp::Power,
+                BinaryOp::BitAnd,
+                BinaryOp::BitOr,
+                BinaryOp::BitXor,
+                BinaryOp::LeftShift,
+                BinaryOp::RightShift,
+            ][(pick % 12) as usize]
+                .clone(),
+            right: sub(b),
         }),
-        2 => Expr::Binary(BinaryExpr {
-            meta: NodeMeta::default(),
-            left: Box::new(generate_expr(next(), depth - 1)),
-            op: if next() % 2 == 0 {
-                BinaryOp::Add
+        3 => {
+            let ops = [
+                CompareOp::Eq,
+                CompareOp::NotEq,
+                CompareOp::Lt,
+                CompareOp::LtEq,
+                CompareOp::Gt,
+                CompareOp::GtEq,
+                CompareOp::In,
+                CompareOp::NotIn,
+                CompareOp::Is,
+                CompareOp::IsNot,
+            ];
+            Expr::Compare(CompareExpr {
+                meta: meta(),
+                left: sub(a),
+                ops: vec![
+                    ops[(pick % 10) as usize].clone(),
+                    ops[(c % 10) as usize].clone(),
+                ],
+                comparators: vec![*sub(b), *sub(c)],
+            })
+        }
+        4 => Expr::BoolOp(BoolOpExpr {
+            meta: meta(),
+            op: if pick % 2 == 0 {
+                BoolOp::And
             } else {
-                BinaryOp::Mul
+                BoolOp::Or
             },
-            right: Box::new(generate_expr(next(), depth - 1)),
+            values: vec![*sub(a), *sub(b), *sub(c)],
         }),
-        _ => Expr::Call(CallExpr {
-            meta: NodeMeta::default(),
-            callee: Box::new(Expr::Identifier(IdentifierExpr {
-                meta: NodeMeta::default(),
-                name: "fn".to_string(),
+        5 => Expr::IfExpr(IfExpr {
+            meta: meta(),
+            body: sub(a),
+            condition: sub(b),
+            else_body: sub(c),
+        }),
+        6 if !scope.field => Expr::Lambda(LambdaExpr {
+            meta: meta(),
+            params: (0..pick % 3).map(|index| format!("p{index}")).collect(),
+            body: sub_in(
+                a,
+                Scope {
+                    awaits: false,
+                    ..nested
+                },
+            ),
+        }),
+        7 if scope.awaits => Expr::Await(AwaitExprData {
+            meta: meta(),
+            value: sub(a),
+        }),
+        8 => Expr::Tuple(TupleExpr {
+            meta: meta(),
+            elements: [a, b, c][..(pick % 3) as usize + 1]
+                .iter()
+                .map(|&seed| *sub(seed))
+                .collect(),
+        }),
+        9 => Expr::List(ListExpr {
+            meta: meta(),
+            elements: vec![*sub(a), *sub(b)],
+        }),
+        10 => Expr::Attribute(AttributeExpr {
+            meta: meta(),
+            value: sub(a),
+            attr: "real".to_string(),
+        }),
+        11 => Expr::Subscript(SubscriptExpr {
+            meta: meta(),
+            value: sub(a),
+            index: sub(b),
+        }),
+        12 => Expr::Call(CallExpr {
+            meta: meta(),
+            callee: sub(a),
+            args: vec![*sub(b)],
+            kwargs: (pick % 2 == 0)
+                .then(|| KeywordArg {
+                    name: "key".to_string(),
+                    value: *sub(c),
+                })
+                .into_iter()
+                .collect(),
+        }),
+        13 if scope.walrus => Expr::NamedExpr(NamedExprData {
+            meta: meta(),
+            name: format!("v{}", pick % 5),
+            value: sub(a),
+        }),
+        14 if scope.yields => Expr::Yield(YieldExprData {
+            meta: meta(),
+            value: (pick % 2 == 0).then(|| sub(a)),
+        }),
+        15 if scope.yields && !scope.awaits => Expr::YieldFrom(YieldFromExprData {
+            meta: meta(),
+            value: sub(a),
+        }),
+        16 => Expr::Subscript(SubscriptExpr {
+            meta: meta(),
+            value: sub(a),
+            index: Box::new(Expr::Slice(SliceExpr {
+                meta: meta(),
+                lower: (pick % 2 == 0).then(|| sub(b)),
+                upper: (pick % 3 != 0).then(|| sub(c)),
+                step: (pick % 5 == 0).then(|| sub(a + 1)),
             })),
-            args: vec![generate_expr(next(), depth - 1)],
-            kwargs: vec![],
         }),
+        17 => Expr::Grouped(GroupedExpr {
+            meta: meta(),
+            expr: sub(a),
+        }),
+        18 if !scope.field => Expr::Dict(DictExpr {
+            meta: meta(),
+            entries: [a, b][..(pick % 2) as usize + 1]
+                .iter()
+                .map(|&seed| DictEntry {
+                    meta: meta(),
+                    key: *sub(seed),
+                    value: *sub(seed + 1),
+                })
+                .collect(),
+        }),
+        19 if !scope.field => Expr::Set(SetExpr {
+            meta: meta(),
+            elements: vec![*sub(a), *sub(b)],
+        }),
+        20 => Expr::Comprehension(match pick % 4 {
+            0 => ComprehensionExpr::List(ComprehensionListExpr {
+                meta: meta(),
+                element: sub_in(a, nested),
+                fors: fors(),
+            }),
+            1 => ComprehensionExpr::Generator(ComprehensionGeneratorExpr {
+                meta: meta(),
+                element: sub_in(a, nested),
+                fors: fors(),
+            }),
+            2 if !scope.field => ComprehensionExpr::Set(ComprehensionSetExpr {
+                meta: meta(),
+                element: sub_in(a, nested),
+                fors: fors(),
+            }),
+            _ if !scope.field => ComprehensionExpr::Dict(ComprehensionDictExpr {
+                meta: meta(),
+                key: sub_in(a, nested),
+                value: sub_in(a + 1, nested),
+                fors: fors(),
+            }),
+            _ => ComprehensionExpr::List(ComprehensionListExpr {
+                meta: meta(),
+                element: sub_in(a, nested),
+                fors: fors(),
+            }),
+        }),
+        21 if !scope.field => Expr::FString(FStringExpr {
+            meta: meta(),
+            parts: vec![
+                FStringPart::Literal("n ".to_string()),
+                FStringPart::Expr(*sub_in(
+                    a,
+                    Scope {
+                        field: true,
+                        ..nested
+                    },
+                )),
+            ],
+            quote: if pick % 2 == 0 {
+                QuoteStyle::Double
+            } else {
+                QuoteStyle::Single
+            },
+        }),
+        _ => identifier(pick),
     }
 }
 
@@ -367,6 +706,7 @@ fn tuple_assign_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -378,6 +718,7 @@ fn tuple_assign_three_targets() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -389,6 +730,7 @@ fn single_assign_still_works() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     assert_eq!(rendered, source);
 }
@@ -403,6 +745,7 @@ fn for_range_roundtrip() {
     let rendered = program.to_python(RenderConfig {
         mode: RenderMode::Pretty,
         reuse_token_ranges: false,
+        ..RenderConfig::default()
     });
     eprintln!("Rendered: {:?}", rendered);
     assert_eq!(rendered.trim(), source.trim());
@@ -415,6 +758,7 @@ fn for_range_roundtrip() {
             let rendered2 = p.to_python(RenderConfig {
                 mode: RenderMode::Pretty,
                 reuse_token_ranges: false,
+                ..RenderConfig::default()
             });
             eprintln!("IR Rendered: {:?}", rendered2);
             assert_eq!(rendered2.trim(), source.trim());
@@ -425,3 +769,1603 @@ fn for_range_roundtrip() {
         }
     }
 }
+
+fn without_ids(mut program: Program) -> Program {
+    program.meta.id = NodeId(0);
+    for stmt in &mut program.body {
+        stmt_metas_mut(stmt, &mut |meta| meta.id = NodeId(0));
+    }
+    program
+}
+
+fn assert_reparse(source: &str, start: usize, end: usize, text: &str) -> (Program, Program) {
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let edit = TextEdit {
+        start,
+        end,
+        text: text.to_string(),
+    };
+    let config = ParserConfig {
+        features: FeatureSet::from_version(PythonVersion::Py310),
+    };
+    let reparsed = program.reparse(&edit, config).unwrap();
+    let expected = parse_with(PythonVersion::Py310, &edit.apply(source).unwrap()).unwrap();
+    assert_eq!(
+        format!("{:?}", without_ids(reparsed.clone())),
+        format!("{:?}", without_ids(expected))
+    );
+    (program, reparsed)
+}
+
+#[test]
+fn reparse_keeps_untouched_statements() {
+    let source = "a = 1\nb = 2\n# note\nc = f'{a}'\n\ndef f(x):\n    return x\n";
+    let start = source.find('2').unwrap();
+    let (program, reparsed) = assert_reparse(source, start, start + 1, "(3 +\n 4)");
+    assert_eq!(
+        render::stmt_meta(program.body.last().unwrap()).id,
+        render::stmt_meta(reparsed.body.last().unwrap()).id
+    );
+}
+
+#[test]
+fn reparse_comments_and_blocks() {
+    let source = "if a:\n    pass\n# note\nb = 2\nc = 3\n";
+    let comment = source.find("note").unwrap();
+    assert_reparse(source, comment, comment + 4, "changed");
+    let line = source.find("b = 2").unwrap();
+    assert_reparse(source, line, line, "    ");
+    assert_reparse(source, source.len(), source.len(), "d = [\n    4,\n]\n");
+}
+
+#[test]
+fn reparse_refreshes_trivia_after_the_region() {
+    // Joining the comment with the next line hands the comment to `def`.
+    let source = "a = 1\nb = 2\n# note\nc = f'{a}'\n\ndef f(x):\n    return x\n";
+    assert_reparse(source, 18, 19, "(");
+}
+
+#[test]
+fn reparse_multiline_replacement() {
+    let source = "a = 1\nb = 2\nc = 3\n";
+    let start = source.find('2').unwrap();
+    let (_, reparsed) = assert_reparse(source, start, start + 1, "(2,\n3)");
+    assert_eq!(reparsed.body.len(), 3);
+}
+
+fn stmt_ids(program: &Program) -> Vec<u64> {
+    program
+        .body
+        .iter()
+        .map(|stmt| render::stmt_meta(stmt).id.0)
+        .collect()
+}
+
+#[test]
+fn reconcile_ids_survive_edits() {
+    let old = parse_with(PythonVersion::Py310, "a = 1\nif a:\n    b = 2\nc = 3\n").unwrap();
+    let source = "z = 0\na = 1\nif a > 0:\n    b = 2\nc = 4\n";
+    let mut new = parse_with(PythonVersion::Py310, source).unwrap();
+    new.reconcile_ids(&old);
+    let old_ids = stmt_ids(&old);
+    let new_ids = stmt_ids(&new);
+    assert!(!old_ids.contains(&new_ids[0]));
+    assert_eq!(&new_ids[1..], &old_ids[..]);
+    assert_eq!(new.meta.id, old.meta.id);
+    let (Stmt::If(old_if), Stmt::If(new_if)) = (&old.body[1], &new.body[2]) else {
+        panic!("expected if statements");
+    };
+    assert_eq!(
+        render::stmt_meta(&old_if.body.statements[0]).id,
+        render::stmt_meta(&new_if.body.statements[0]).id
+    );
+    let mut ids = Vec::new();
+    for stmt in &mut new.body {
+        stmt_metas_mut(stmt, &mut |meta| ids.push(meta.id.0));
+    }
+    let count = ids.len();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), count);
+}
+
+#[test]
+fn reconcile_ids_follow_moved_statements() {
+    let old = parse_with(PythonVersion::Py310, "def f():\n    return 1\n\nx = f()\n").unwrap();
+    let source = "x = f()\n\ndef f():\n    return 1\n";
+    let mut new = parse_with(PythonVersion::Py310, source).unwrap();
+    new.reconcile_ids(&old);
+    let old_ids = stmt_ids(&old);
+    let new_ids = stmt_ids(&new);
+    assert_eq!(new_ids[0], old_ids[old_ids.len() - 1]);
+    assert_eq!(new_ids[new_ids.len() - 1], old_ids[0]);
+}
+
+#[test]
+fn tab_indentation_roundtrip() {
+    let source = "if a:\n\tif b:\n\t\tpass\n\tc = 1\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let rendered = program.to_python(RenderConfig {
+        mode: RenderMode::Lossless,
+        reuse_token_ranges: false,
+        ..RenderConfig::default()
+    });
+    assert_eq!(rendered, source);
+    let Stmt::If(outer) = &program.body[0] else {
+        panic!("expected if statement");
+    };
+    let Stmt::If(inner) = &outer.body.statements[0] else {
+        panic!("expected if statement");
+    };
+    assert_eq!(outer.body.indent_level, 1);
+    assert_eq!(inner.body.indent_level, 2);
+}
+
+#[test]
+fn indentation_widths_follow_python_rules() {
+    let source = "if a:\n        if b:\n\t    pass\n";
+    assert!(parse_with(PythonVersion::Py310, source).is_err());
+    let source = "if a:\n    if b:\n      pass\n    c = 1\n";
+    assert!(parse_with(PythonVersion::Py310, source).is_ok());
+}
+
+#[test]
+fn mixed_indentation_errors_name_line() {
+    let source = "if a:\n\tb = 1\n        c = 2\n";
+    let error = parse_with(PythonVersion::Py310, source).unwrap_err();
+    assert_eq!(error.span.start.line, 3);
+    assert!(error.to_string().contains("inconsistent use of tabs"));
+
+    let source = "if a:\n    b = 1\n  c = 2\n";
+    let error = parse_with(PythonVersion::Py310, source).unwrap_err();
+    assert_eq!(error.span.start.line, 3);
+    assert!(error.message.contains("unindent"));
+}
+
+#[test]
+fn crlf_and_bom_roundtrip() {
+    let source = "\u{feff}# -*- coding: utf-8 -*-\r\nif a:\r\n    b = (1,\r\n 2)\r\n\r\nc = 3\r\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let rendered = program.to_python(RenderConfig {
+        mode: RenderMode::Lossless,
+        reuse_token_ranges: false,
+        ..RenderConfig::default()
+    });
+    assert_eq!(rendered, source);
+    assert_eq!(program.body.len(), 3);
+    assert_eq!(program.source_format.line_ending, LineEnding::CrLf);
+    assert!(program.source_format.bom);
+    assert_eq!(program.source_format.coding.as_deref(), Some("utf-8"));
+    let Stmt::Assign(assign) = &program.body[2] else {
+        panic!("expected assignment");
+    };
+    assert_eq!(assign.meta.span.start.line, 6);
+    assert_eq!(assign.meta.span.start.column, 1);
+}
+
+#[test]
+fn surgical_rendering_keeps_crlf_line_endings() {
+    let source = "x  =  1\r\nif x :\r\n    y = [1,\r\n         2]\r\n    z = x\r\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let Stmt::If(stmt) = &program.body[1] else {
+        panic!("expected if");
+    };
+    let Stmt::Assign(assign) = &stmt.body.statements[1] else {
+        panic!("expected assignment");
+    };
+    let (copied, id, value) = (
+        render::stmt_meta(&stmt.body.statements[0]).id,
+        assign.meta.id,
+        expr_meta(&assign.value).id,
+    );
+    let mut ir = python_to_ir(&program);
+    ir.apply(IrOperation::ReplaceExpr {
+        id: value,
+        expr: Box::new(parse_expr_to_ir("x + 1", ParserConfig::default()).unwrap()),
+    })
+    .unwrap();
+    let features = FeatureSet::from_version(PythonVersion::Py310);
+    let rendered = ir_to_python(&ir, &features)
+        .unwrap()
+        .to_python_with_source_map(RenderConfig {
+            mode: RenderMode::Lossless,
+            reuse_token_ranges: true,
+            ..RenderConfig::default()
+        });
+    assert_eq!(
+        rendered.source,
+        "x  =  1\r\nif x :\r\n    y = [1,\r\n         2]\r\n    z = x + 1\r\n"
+    );
+    assert_eq!(mapped(&rendered, copied), "y = [1,\r\n         2]\r\n");
+    assert_eq!(mapped(&rendered, id), "z = x + 1\r\n");
+}
+
+#[test]
+fn cr_line_endings_and_cookie_placement() {
+    let source = "a = 1\rb = 2\r";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    assert_eq!(program.body.len(), 2);
+    assert_eq!(program.source_format.line_ending, LineEnding::Cr);
+    assert_eq!(render::stmt_meta(&program.body[1]).span.start.line, 2);
+
+    let source = "#!/usr/bin/env python\n# vim: set fileencoding=latin-1 :\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    assert_eq!(program.source_format.coding.as_deref(), Some("latin-1"));
+    let source = "a = 1\n# coding: latin-1\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    assert_eq!(program.source_format.coding, None);
+}
+
+#[test]
+fn reparse_crlf_source() {
+    let source = "a = 1\r\nb = 2\r\nc = 3\r\n";
+    let start = source.find('2').unwrap();
+    assert_reparse(source, start, start + 1, "(4,\r\n5)");
+}
+
+#[test]
+fn bracket_comments_survive_pretty_rendering() {
+    let source = "values = [  # numbers\n    1,  # one\n    # before two\n    2,\n]\nif values:\n    total = add(values,  # all\n        start=0)\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let pretty = RenderConfig {
+        mode: RenderMode::Pretty,
+        reuse_token_ranges: false,
+        ..RenderConfig::default()
+    };
+    let rendered = program.to_python(pretty);
+    assert_eq!(
+        rendered,
+        "values = [  # numbers\n    1,  # one\n    # before two\n    2,\n]\nif values:\n    total = add(\n        values,  # all\n        start=0,\n    )\n"
+    );
+    let reparsed = parse_with(PythonVersion::Py310, &rendered).unwrap();
+    assert_eq!(reparsed.to_python(pretty), rendered);
+}
+
+#[test]
+fn bracket_comments_survive_ir_roundtrip() {
+    let source = "point = (x,  # horizontal\n    y)\nvalue = table[key  # lookup\n]\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let ir = python_to_ir(&program);
+    let features = FeatureSet::from_version(PythonVersion::Py310);
+    let restored = ir_to_python(&ir, &features).unwrap();
+    let rendered = restored.to_python(RenderConfig {
+        mode: RenderMode::Pretty,
+        reuse_token_ranges: false,
+        ..RenderConfig::default()
+    });
+    assert_eq!(
+        rendered,
+        "point = (\n    x,  # horizontal\n    y,\n)\nvalue = table[\n    key  # lookup\n]\n"
+    );
+}
+
+#[test]
+fn list_literal_with_several_elements() {
+    let program = parse_with(PythonVersion::Py310, "values = [1, 2, 3]\n").unwrap();
+    let Stmt::Assign(assign) = &program.body[0] else {
+        panic!("expected assignment");
+    };
+    let Expr::List(list) = &assign.value else {
+        panic!("expected list");
+    };
+    assert_eq!(list.elements.len(), 3);
+}
+
+fn render_surgically(ir: &IrProgram) -> String {
+    let features = FeatureSet::from_version(PythonVersion::Py310);
+    ir_to_python(ir, &features)
+        .unwrap()
+        .to_python(RenderConfig {
+            mode: RenderMode::Lossless,
+            reuse_token_ranges: true,
+            ..RenderConfig::default()
+        })
+}
+
+fn dirty_number(raw: &str) -> IrExpr {
+    IrExpr::Literal(IrLiteralExpr {
+        meta: NodeMeta {
+            dirty: true,
+            ..NodeMeta::default()
+        },
+        literal: Literal::Number(NumberLiteral {
+            raw: raw.to_string(),
+        }),
+    })
+}
+
+#[test]
+fn surgical_rendering_rewrites_only_dirty_nodes() {
+    let source = "x  =  [1,2]   # keep\n\n# note\nif x :\n    print( x )\n    y = f(1,\n          2)\nz = 3\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let mut ir = python_to_ir(&program);
+    ir.dirty = true;
+    assert_eq!(render_surgically(&ir), source);
+
+    let IrStmt::If(stmt) = &mut ir.body[3] else {
+        panic!("expected if");
+    };
+    let IrStmt::Assign(assign) = &mut stmt.body.statements[1] else {
+        panic!("expected assignment");
+    };
+    let IrExpr::Call(call) = &mut assign.value else {
+        panic!("expected call");
+    };
+    call.args[1] = dirty_number("20");
+    ir.dirty = false;
+    assert_eq!(
+        render_surgically(&ir),
+        "x  =  [1,2]   # keep\n\n# note\nif x :\n    print( x )\n    y = f(1, 20)\nz = 3\n"
+    );
+}
+
+#[test]
+fn surgical_rendering_keeps_clean_lines_and_headers() {
+    let source = "def f(x,  y):\n    if x:\n        return 1\n    while x:\n        x -= 1\n    return x\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let Stmt::FunctionDef(function) = &program.body[0] else {
+        panic!("expected function");
+    };
+    let id = render::stmt_meta(&function.body.statements[2]).id;
+    let mut ir = python_to_ir(&program);
+    ir.apply(IrOperation::DeleteNode { id }).unwrap();
+    assert_eq!(
+        render_surgically(&ir),
+        "def f(x,  y):\n    if x:\n        return 1\n    while x:\n        x -= 1\n"
+    );
+}
+
+#[test]
+fn surgical_rendering_copies_operators_and_postfixes_whole() {
+    let source = "y  =  f( 1 ).a[ 2 ] + g( 3 )\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let mut ir = python_to_ir(&program);
+    let IrStmt::Assign(assign) = &mut ir.body[0] else {
+        panic!("expected assignment");
+    };
+    assign.meta.dirty = true;
+    assert_eq!(render_surgically(&ir), "y = f( 1 ).a[ 2 ] + g( 3 )\n");
+}
+
+#[test]
+fn surgical_rendering_reindents_moved_statements() {
+    let source = "if ready :\n    go( 1 )\nstop(  2 )\nf(a +\n  b)\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let mut ir = python_to_ir(&program);
+    let moved = ir.body.remove(1);
+    let IrStmt::If(stmt) = &mut ir.body[0] else {
+        panic!("expected if");
+    };
+    stmt.body.meta.dirty = true;
+    stmt.body.statements.push(moved);
+    let IrStmt::Expr(call_stmt) = &ir.body[1] else {
+        panic!("expected expression statement");
+    };
+    let IrExpr::Call(call) = &call_stmt.expr else {
+        panic!("expected call");
+    };
+    let argument = call.args[0].clone();
+    ir.body.push(IrStmt::Expr(IrExprStmt {
+        meta: NodeMeta::default(),
+        expr: argument,
+    }));
+    assert_eq!(
+        render_surgically(&ir),
+        "if ready :\n    go( 1 )\n    stop(  2 )\nf(a +\n  b)\na + b\n"
+    );
+}
+
+#[test]
+fn comments_belong_to_statements() {
+    let source =
+        "# setup\nx = 1  # one\n\nif x:  # check\n    y = 2  # two\n    # inside\nz = 3\n# end\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let mut ir = python_to_ir(&program);
+    let IrStmt::Comment(comment) = &ir.body[0] else {
+        panic!("expected comment");
+    };
+    assert_eq!(comment.text, " setup");
+    let IrStmt::If(stmt) = &ir.body[3] else {
+        panic!("expected if");
+    };
+    assert_eq!(stmt.body.meta.comment.as_deref(), Some(" check"));
+    assert!(
+        matches!(&stmt.body.statements[1], IrStmt::Comment(comment) if comment.text == " inside")
+    );
+    assert!(matches!(ir.body.last(), Some(IrStmt::Comment(comment)) if comment.text == " end"));
+
+    // A moved statement takes its comment along, and a rewritten one keeps it.
+    let moved = ir.body.remove(1);
+    let IrStmt::Assign(assign) = &moved else {
+        panic!("expected assignment");
+    };
+    assert_eq!(assign.meta.comment.as_deref(), Some(" one"));
+    ir.body.push(moved);
+    ir.dirty = true;
+    let IrStmt::If(stmt) = &mut ir.body[2] else {
+        panic!("expected if");
+    };
+    let IrStmt::Assign(assign) = &mut stmt.body.statements[0] else {
+        panic!("expected assignment");
+    };
+    assign.value = dirty_number("20");
+    let expected =
+        "# setup\n\nif x:  # check\n    y = 20  # two\n    # inside\nz = 3\n# end\nx = 1  # one\n";
+    assert_eq!(render_surgically(&ir), expected);
+    let features = FeatureSet::from_version(PythonVersion::Py310);
+    let pretty = ir_to_python(&ir, &features)
+        .unwrap()
+        .to_python(RenderConfig {
+            mode: RenderMode::Pretty,
+            reuse_token_ranges: false,
+            ..RenderConfig::default()
+        });
+    assert_eq!(pretty, expected);
+}
+
+fn render_with_width(source: &str, max_line_length: usize) -> String {
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let rendered = program.to_python(RenderConfig {
+        mode: RenderMode::Pretty,
+        max_line_length,
+        ..RenderConfig::default()
+    });
+    parse_with(PythonVersion::Py310, &rendered).unwrap();
+    rendered
+}
+
+#[test]
+fn long_calls_and_collections_wrap() {
+    let source =
+        "result = some_function(argument_one, argument_two, argument_three, argument_four)\n";
+    assert_eq!(
+        render_with_width(source, 79),
+        "result = some_function(\n    argument_one, argument_two, argument_three, argument_four\n)\n"
+    );
+    assert_eq!(
+        render_with_width(source, 40),
+        "result = some_function(\n    argument_one,\n    argument_two,\n    argument_three,\n    argument_four,\n)\n"
+    );
+    let source =
+        "if True:\n    values = {'alpha': [1, 2, 3], 'beta': compute(first, second, third)}\n";
+    assert_eq!(
+        render_with_width(source, 40),
+        "if True:\n    values = {\n        'alpha': [1, 2, 3],\n        'beta': compute(\n            first, second, third\n        ),\n    }\n"
+    );
+}
+
+#[test]
+fn long_comprehensions_and_boolean_chains_wrap() {
+    let source = "squares = [value * value for value in range(100) if value % 3 == 0]\n";
+    assert_eq!(render_with_width(source, 79), source);
+    assert_eq!(
+        render_with_width(source, 40),
+        "squares = [\n    value * value\n    for value in range(100)\n    if value % 3 == 0\n]\n"
+    );
+    let source = "if first_condition and second_condition or third_condition:\n    pass\n";
+    assert_eq!(
+        render_with_width(source, 40),
+        "if (\n    first_condition and second_condition\n    or third_condition\n):\n    pass\n"
+    );
+    let source = "print(first_condition and second_condition and third_condition)\n";
+    assert_eq!(
+        render_with_width(source, 40),
+        "print(\n    first_condition\n    and second_condition\n    and third_condition\n)\n"
+    );
+}
+
+fn render_styled(source: &str, config: RenderConfig) -> String {
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let rendered = program.to_python(RenderConfig {
+        mode: RenderMode::Pretty,
+        ..config
+    });
+    parse_with(PythonVersion::Py310, &rendered).unwrap();
+    rendered
+}
+
+#[test]
+fn render_style_options() {
+    let tabs = RenderConfig {
+        indent_style: IndentStyle::Tabs,
+        max_line_length: 24,
+        ..RenderConfig::default()
+    };
+    assert_eq!(
+        render_styled("if x:\n    y = call(first, second)\n", tabs),
+        "if x:\n\ty = call(\n\t\tfirst, second\n\t)\n"
+    );
+
+    let single = RenderConfig {
+        quote_style: Some(QuoteStyle::Single),
+        ..RenderConfig::default()
+    };
+    assert_eq!(
+        render_styled("a = \"text\"\nb = \"it's\"\nc = f\"{a}!\"\n", single),
+        "a = 'text'\nb = \"it's\"\nc = f'{a}!'\n"
+    );
+
+    let spaced = RenderConfig {
+        top_level_blank_lines: Some(1),
+        ..RenderConfig::default()
+    };
+    assert_eq!(
+        render_styled(
+            "import os\ndef f():\n    pass\n\n\n# about g\ndef g():\n\n    pass\nx = 1\ny = 2\n",
+            spaced
+        ),
+        "import os\n\ndef f():\n    pass\n\n# about g\ndef g():\n\n    pass\n\nx = 1\ny = 2\n"
+    );
+
+    let compact = RenderConfig {
+        spaces_around_operators: false,
+        space_after_comma: false,
+        ..RenderConfig::default()
+    };
+    assert_eq!(
+        render_styled(
+            "x = a + b * c\nif a == b and c not in d:\n    f(a, b)\n",
+            compact
+        ),
+        "x = a+b*c\nif a==b and c not in d:\n    f(a,b)\n"
+    );
+
+    let bare = RenderConfig {
+        remove_redundant_parens: true,
+        ..RenderConfig::default()
+    };
+    assert_eq!(
+        render_styled(
+            "x = (a + b)\nif (ready):\n    y = (a + b) * c\n    z = (1).real\n    f((a.b), (c), (await g))\n",
+            bare
+        ),
+        "x = a + b\nif ready:\n    y = (a + b) * c\n    z = (1).real\n    f(a.b, c, await g)\n"
+    );
+}
+
+/// Sources and the output Black gives for them.
+const FORMAT_CORPUS: &[(&str, &str)] = &[
+    // Quotes.
+    (
+        "a = 'text'\nb = 'it\\'s'\nc = 'say \"hi\"'\nd = \"say \\\"hi\\\"\"\ne = f'{name}!'\n",
+        "a = \"text\"\nb = \"it's\"\nc = 'say \"hi\"'\nd = 'say \"hi\"'\ne = f\"{name}!\"\n",
+    ),
+    (
+        "f'{data[\"key\"]}'\nescaped = 'a\\\\'\n",
+        "f'{data[\"key\"]}'\nescaped = \"a\\\\\"\n",
+    ),
+    // Blank lines.
+    (
+        "import os\nx = 1\ndef f(a = 1, b: int=2):\n    return a\nclass A:\n\n\n    x = 1\n    def g(self):\n        pass\n    y = 2\n\n\n\n\nz = 3\n",
+        "import os\n\nx = 1\n\n\ndef f(a=1, b: int = 2):\n    return a\n\n\nclass A:\n    x = 1\n\n    def g(self):\n        pass\n\n    y = 2\n\n\nz = 3\n",
+    ),
+    (
+        "if ready:\n    def f():\n        pass\nelse:\n    pass\ntry:\n    go()\n\n\nexcept Error:\n    pass\n",
+        "if ready:\n    def f():\n        pass\n\nelse:\n    pass\ntry:\n    go()\n\n\nexcept Error:\n    pass\n",
+    ),
+    (
+        "\n\nif sys:\n    import os\n    x = 1\n",
+        "if sys:\n    import os\n\n    x = 1\n",
+    ),
+    // Comments.
+    (
+        "x = 1  #count\n\n\n\n# about f\ndef f():  # header\n    # inside\n    pass\n# after\nvalues = [1, 2]\n",
+        "x = 1  # count\n\n\n# about f\ndef f():  # header\n    # inside\n    pass\n\n\n# after\nvalues = [1, 2]\n",
+    ),
+    (
+        "def f():\n\tif x:\n\t\treturn 1\n\t# after if\n# module\nx = 2\n",
+        "def f():\n    if x:\n        return 1\n    # after if\n\n\n# module\nx = 2\n",
+    ),
+    (
+        "class A:\n    # first\n    def f(self):\n        pass\n    # g\n    @property  # cached\n    def g(self):\n        pass\n\n\n\n# end\n",
+        "class A:\n    # first\n    def f(self):\n        pass\n\n    # g\n    @property  # cached\n    def g(self):\n        pass\n\n\n# end\n",
+    ),
+    // Magic trailing comma.
+    (
+        "values = [1, 2,]\nconfig = {'a': 1,}\ncall(a,)\nshort = [1, 2]\n",
+        "values = [\n    1,\n    2,\n]\nconfig = {\n    \"a\": 1,\n}\ncall(\n    a,\n)\nshort = [1, 2]\n",
+    ),
+    // Line length.
+    (
+        "result = some_function(argument_one, argument_two, argument_three, argument_four, five)\nresult = some_function(argument_one, argument_two, argument_three, argument_four, fifth_)\n",
+        "result = some_function(argument_one, argument_two, argument_three, argument_four, five)\nresult = some_function(\n    argument_one, argument_two, argument_three, argument_four, fifth_\n)\n",
+    ),    // Operators.
+    (
+        "squares = [i ** 2 for i in range(10)]\nz = a.b ** -1 + f(x) ** 2\n",
+        "squares = [i**2 for i in range(10)]\nz = a.b**-1 + f(x) ** 2\n",
+    ),
+    (
+        "tail = x[a+1:]\nhead = x[1:2]\nstep = x[:f(x):2]\n",
+        "tail = x[a + 1 :]\nhead = x[1:2]\nstep = x[: f(x) : 2]\n",
+    ),
+    // Comments after opening brackets.
+    (
+        "x = [  # c\n    1,\n    2,\n]\ncall(  # c\n    a\n)\n",
+        "x = [  # c\n    1,\n    2,\n]\ncall(a)  # c\n",
+    ),
+    // Redundant parentheses.
+    (
+        "if (a and\n b):\n    pass\nwhile (x := f()):\n    pass\n",
+        "if a and b:\n    pass\nwhile (x := f()):\n    pass\n",
+    ),
+    (
+        "total = sum(x for x in xs)\npairs = zip((a for a in b), c)\n",
+        "total = sum(x for x in xs)\npairs = zip((a for a in b), c)\n",
+    ),
+];
+
+#[test]
+fn format_python_matches_black() {
+    for (source, expected) in FORMAT_CORPUS {
+        let formatted = format_python(source.to_string(), ParserConfig::default()).unwrap();
+        assert_eq!(&formatted, expected, "formatting {source:?}");
+        let again = format_python(formatted.clone(), ParserConfig::default()).unwrap();
+        assert_eq!(again, formatted, "formatting {source:?} twice");
+    }
+}
+
+#[test]
+fn format_python_keeps_line_endings_and_reports_errors() {
+    let formatted = format_python(
+        "x = 'a'\r\nif x:\r\n  y = 1\r\n".to_string(),
+        ParserConfig::default(),
+    );
+    assert_eq!(formatted.unwrap(), "x = \"a\"\r\nif x:\r\n    y = 1\r\n");
+    assert!(format_python("x = (\n".to_string(), ParserConfig::default()).is_err());
+}
+
+/// Text of the node `id` in `rendered`, checking that its span agrees with
+/// itself.
+fn mapped(rendered: &RenderedPython, id: NodeId) -> &str {
+    let span = rendered.source_map[&id];
+    for position in [span.start, span.end] {
+        let before = &rendered.source[..position.offset];
+        assert_eq!(position.line, before.matches('\n').count() + 1);
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        assert_eq!(position.column, before[line_start..].chars().count() + 1);
+    }
+    &rendered.source[span.start.offset..span.end.offset]
+}
+
+#[test]
+fn source_map_locates_rendered_nodes() {
+    let source =
+        "if  ready :\n    total = price * (count + 1)\nitems = [first,\n         second]\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let rendered = program.to_python_with_source_map(RenderConfig {
+        mode: RenderMode::Pretty,
+        ..RenderConfig::default()
+    });
+    let Stmt::If(stmt) = &program.body[0] else {
+        panic!("expected if");
+    };
+    let Stmt::Assign(assign) = &stmt.body.statements[0] else {
+        panic!("expected assignment");
+    };
+    assert_eq!(
+        mapped(&rendered, stmt.meta.id),
+        "if ready:\n    total = price * (count + 1)\n"
+    );
+    assert_eq!(mapped(&rendered, expr_meta(&stmt.condition).id), "ready");
+    assert_eq!(
+        mapped(&rendered, expr_meta(&assign.value).id),
+        "price * (count + 1)"
+    );
+    assert_eq!(rendered.source_map[&assign.meta.id].start.column, 5);
+
+    // Nodes copied from the source keep their place relative to the
+    // statement they are copied with.
+    let mut ir = python_to_ir(&program);
+    let IrStmt::If(stmt) = &mut ir.body[0] else {
+        panic!("expected if");
+    };
+    let IrExpr::Identifier(condition) = &mut stmt.condition else {
+        panic!("expected identifier");
+    };
+    condition.meta.dirty = true;
+    let features = FeatureSet::from_version(PythonVersion::Py310);
+    let program = ir_to_python(&ir, &features).unwrap();
+    let rendered = program.to_python_with_source_map(RenderConfig {
+        mode: RenderMode::Lossless,
+        reuse_token_ranges: true,
+        ..RenderConfig::default()
+    });
+    assert_eq!(
+        rendered.source,
+        "if ready:\n    total = price * (count + 1)\nitems = [first,\n         second]\n"
+    );
+    let Stmt::Assign(assign) = &program.body[1] else {
+        panic!("expected assignment");
+    };
+    let Expr::List(list) = &assign.value else {
+        panic!("expected list");
+    };
+    let second = rendered.source_map[&expr_meta(&list.elements[1]).id];
+    assert_eq!((second.start.line, second.start.column), (4, 10));
+    assert_eq!(mapped(&rendered, expr_meta(&list.elements[1]).id), "second");
+    assert_eq!(
+        mapped(&rendered, assign.meta.id),
+        "items = [first,\n         second]\n"
+    );
+}
+
+#[test]
+fn snippets_convert_to_and_from_ir() {
+    let config = ParserConfig::default();
+    let features = FeatureSet::from_version(PythonVersion::Py310);
+    let expr = parse_expr_to_ir("x*2 +  1", config).unwrap();
+    let IrExpr::Binary(binary) = &expr else {
+        panic!("expected binary expression");
+    };
+    assert!(binary.meta.dirty);
+    assert_eq!(binary.meta.id, NodeId(0));
+    assert_eq!(
+        ir_expr_to_python(&expr).to_python(RenderConfig::default()),
+        "x * 2 + 1"
+    );
+
+    let stmt = parse_stmt_to_ir("\nif ready :\n    go( 1 )\n", config).unwrap();
+    assert_eq!(
+        ir_stmt_to_python(&stmt, &features)
+            .unwrap()
+            .to_python(4, RenderConfig::default()),
+        "if ready:\n    go(1)"
+    );
+
+    // Comment lines around a statement are left out; its own comment stays.
+    let stmt = parse_stmt_to_ir("# note\nx = 1  # one\n# after\n", config).unwrap();
+    assert_eq!(
+        ir_stmt_to_python(&stmt, &features)
+            .unwrap()
+            .to_python(4, RenderConfig::default()),
+        "x = 1  # one"
+    );
+
+    // Inserted into a program, a parsed snippet is rendered rather than
+    // copied from the program's source.
+    let program = parse_with(PythonVersion::Py310, "a = 1\nb = 2\n").unwrap();
+    let mut ir = python_to_ir(&program);
+    let IrStmt::Assign(assign) = &mut ir.body[1] else {
+        panic!("expected assignment");
+    };
+    assign.value = expr;
+    assert_eq!(render_surgically(&ir), "a = 1\nb = x * 2 + 1\n");
+
+    assert!(parse_expr_to_ir("x = 1", config).is_err());
+    assert!(parse_stmt_to_ir("a\nb", config).is_err());
+    assert!(parse_stmt_to_ir("\n\n", config).is_err());
+    assert!(parse_expr_to_ir("(", config).is_err());
+    // An expression has nowhere to keep a comment outside its brackets.
+    assert!(parse_expr_to_ir("x  # c", config).is_err());
+    assert!(parse_expr_to_ir("# c\nx", config).is_err());
+    assert!(parse_expr_to_ir("[x,  # c\n 1]", config).is_ok());
+}
+
+fn render_decoded(envelope: IrEnvelope, stores: &TokenStores) -> String {
+    let features = FeatureSet::from_version(PythonVersion::Py310);
+    let ir = decode_ir(envelope, stores).unwrap();
+    ir_to_python(&ir, &features)
+        .unwrap()
+        .to_python(RenderConfig::default())
+}
+
+#[test]
+fn ir_envelopes_detach_tokens_and_migrate() {
+    let source = "# setup\nvalues = [1,  2]  # pair\nif values :\n    print( values )\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let mut stores = TokenStores::new();
+    let json = encode_ir(
+        python_to_ir(&program),
+        IrEncodeOptions::default(),
+        &mut stores,
+    );
+    assert!(json.token_handle.is_none());
+    assert_eq!(
+        json.program,
+        serde_json::to_value(python_to_ir(&program)).unwrap()
+    );
+
+    let options = IrEncodeOptions {
+        encoding: IrEncoding::Compact,
+        detach_tokens: true,
+    };
+    let compact = encode_ir(python_to_ir(&program), options, &mut stores);
+    assert_eq!(compact.version, IR_VERSION);
+    assert!(compact.token_handle.is_some());
+    assert!(compact.program["token_store"].is_null());
+    assert!(compact.program.to_string().len() * 4 < json.program.to_string().len());
+    assert_eq!(render_decoded(compact.clone(), &stores), source);
+    let inline = IrEncodeOptions {
+        detach_tokens: false,
+        ..options
+    };
+    let inline = encode_ir(python_to_ir(&program), inline, &mut stores);
+    assert_eq!(render_decoded(inline, &TokenStores::new()), source);
+    assert!(decode_ir(compact.clone(), &TokenStores::new()).is_err());
+    assert!(decode_ir(
+        IrEnvelope {
+            version: IR_VERSION + 1,
+            ..compact
+        },
+        &stores
+    )
+    .is_err());
+
+    // Version 1 had blank statements for comment lines and no owned comments.
+    let mut old = json.program.clone();
+    old["body"][0] = serde_json::json!({
+        "kind": "Empty",
+        "data": { "meta": old["body"][0]["data"]["meta"], "source": "Source" },
+    });
+    old["body"][1]["data"]["meta"]["comment"] = serde_json::Value::Null;
+    let old = IrEnvelope {
+        version: 1,
+        encoding: IrEncoding::Json,
+        token_handle: None,
+        program: old,
+    };
+    let migrated = decode_ir(old, &stores).unwrap();
+    assert!(matches!(&migrated.body[0], IrStmt::Comment(comment) if comment.text == " setup"));
+    let IrStmt::Assign(assign) = &migrated.body[1] else {
+        panic!("expected assignment");
+    };
+    assert_eq!(assign.meta.comment.as_deref(), Some(" pair"));
+}
+
+#[test]
+fn ir_operations_apply_and_invert() {
+    let source = "while x :\n    a = 1\nb = f( 2 )\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let mut ir = python_to_ir(&program);
+    let IrStmt::While(stmt) = &ir.body[0] else {
+        panic!("expected while");
+    };
+    let IrExpr::Identifier(condition) = &stmt.condition else {
+        panic!("expected identifier");
+    };
+    let (loop_body, condition) = (stmt.body.meta.id, condition.meta.id);
+    let IrStmt::Assign(assign) = &ir.body[1] else {
+        panic!("expected assignment");
+    };
+    let IrExpr::Call(call) = &assign.value else {
+        panic!("expected call");
+    };
+    let IrExpr::Literal(argument) = &call.args[0] else {
+        panic!("expected literal");
+    };
+    let (assign, argument) = (assign.meta.id, argument.meta.id);
+    let config = ParserConfig::default();
+    let statement = |source| parse_stmt_to_ir(source, ParserConfig::default()).unwrap();
+
+    let mut undo = Vec::new();
+    let operations = [
+        IrOperation::InsertStmt {
+            block: Some(loop_body),
+            index: 1,
+            stmt: Box::new(statement("break")),
+        },
+        IrOperation::ReplaceExpr {
+            id: argument,
+            expr: Box::new(parse_expr_to_ir("y + 1", config).unwrap()),
+        },
+        IrOperation::SetField {
+            id: condition,
+            field: "name".to_string(),
+            value: serde_json::Value::from("running"),
+        },
+        IrOperation::MoveNode {
+            id: assign,
+            block: Some(loop_body),
+            index: 0,
+        },
+    ];
+    for operation in operations {
+        undo.push(ir.apply(operation).unwrap());
+    }
+    let edited = "while running:\n    b = f(y + 1)\n    a = 1\n    break\n";
+    assert_eq!(render_surgically(&ir), edited);
+
+    // Refused operations leave the program alone.
+    let IrOperation::DeleteNode { id: inserted } = undo[0] else {
+        panic!("expected the insertion to be undone by a deletion");
+    };
+    let refused = [
+        IrOperation::MoveNode {
+            id: inserted,
+            block: None,
+            index: 0,
+        },
+        IrOperation::InsertStmt {
+            block: None,
+            index: 1,
+            stmt: Box::new(statement("continue")),
+        },
+        IrOperation::InsertStmt {
+            block: Some(loop_body),
+            index: 9,
+            stmt: Box::new(statement("pass")),
+        },
+        IrOperation::ReplaceExpr {
+            id: loop_body,
+            expr: Box::new(parse_expr_to_ir("1", config).unwrap()),
+        },
+        IrOperation::SetField {
+            id: assign,
+            field: "value".to_string(),
+            value: serde_json::Value::Null,
+        },
+        IrOperation::DeleteNode { id: NodeId(999) },
+    ];
+    for operation in refused {
+        assert!(ir.apply(operation).is_err());
+    }
+    assert_eq!(render_surgically(&ir), edited);
+
+    for operation in undo.into_iter().rev() {
+        ir.apply(operation).unwrap();
+    }
+    assert_eq!(render_surgically(&ir), "while x:\n    a = 1\nb = f(2)\n");
+}
+
+#[test]
+fn ir_operations_keep_to_the_grammar() {
+    let source = "def f():\n    global g\n    while y:\n        x = 1\nimport os.path as p\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let Stmt::FunctionDef(function) = &program.body[0] else {
+        panic!("expected function");
+    };
+    let Stmt::While(stmt) = &function.body.statements[1] else {
+        panic!("expected while");
+    };
+    let id = |stmt| render::stmt_meta(stmt).id;
+    let (function, global) = (function.meta.id, id(&function.body.statements[0]));
+    let (loop_body, assign) = (stmt.body.meta.id, id(&stmt.body.statements[0]));
+    let Stmt::Assign(AssignStmt { targets, value, .. }) = &stmt.body.statements[0] else {
+        panic!("expected assignment");
+    };
+    let (target, one) = (expr_meta(&targets[0]).id, expr_meta(value).id);
+    let import = id(&program.body[1]);
+    let mut ir = python_to_ir(&program);
+    let statement = |source| parse_stmt_to_ir(source, ParserConfig::default()).unwrap();
+    let set = |id, field: &str, value: serde_json::Value| IrOperation::SetField {
+        id,
+        field: field.to_string(),
+        value,
+    };
+
+    let refused = [
+        set(function, "name", "for".into()),
+        set(function, "name", "2f".into()),
+        set(global, "names", serde_json::json!(["g", "not"])),
+        set(
+            import,
+            "names",
+            serde_json::json!([{ "name": "os.if", "alias": null }]),
+        ),
+        set(import, "module", "os.".into()),
+        IrOperation::ReplaceExpr {
+            id: target,
+            expr: Box::new(parse_expr_to_ir("5", ParserConfig::default()).unwrap()),
+        },
+        set(
+            one,
+            "literal",
+            serde_json::json!({ "kind": "Number", "data": { "raw": "1; import os" } }),
+        ),
+        set(
+            one,
+            "literal",
+            serde_json::json!({ "kind": "Number", "data": { "raw": "'1'" } }),
+        ),
+        IrOperation::InsertStmt {
+            block: None,
+            index: 0,
+            stmt: Box::new(statement("nonlocal g")),
+        },
+        IrOperation::InsertStmt {
+            block: None,
+            index: 0,
+            stmt: Box::new(statement("x = yield 1")),
+        },
+        IrOperation::InsertStmt {
+            block: None,
+            index: 0,
+            stmt: Box::new(statement("class C:\n    y = await g()\n")),
+        },
+    ];
+    for operation in refused {
+        assert!(ir.apply(operation).is_err());
+    }
+    ir.apply(set(function, "name", "for_each".into())).unwrap();
+    ir.apply(set(
+        one,
+        "literal",
+        serde_json::json!({ "kind": "Number", "data": { "raw": "2.5" } }),
+    ))
+    .unwrap();
+    let inserted = ir.apply(IrOperation::InsertStmt {
+        block: Some(loop_body),
+        index: 0,
+        stmt: Box::new(statement("nonlocal_count = yield x")),
+    });
+
+    // Emptying a block leaves `pass` in either mode.
+    ir.apply(IrOperation::DeleteNode { id: assign }).unwrap();
+    ir.apply(inserted.unwrap()).unwrap();
+    let features = FeatureSet::from_version(PythonVersion::Py310);
+    let program = ir_to_python(&ir, &features).unwrap();
+    for mode in [RenderMode::Lossless, RenderMode::Pretty] {
+        let rendered = program.to_python(RenderConfig {
+            mode,
+            reuse_token_ranges: mode == RenderMode::Lossless,
+            ..RenderConfig::default()
+        });
+        assert_eq!(
+            rendered,
+            "def for_each():\n    global g\n    while y:\n        pass\nimport os.path as p\n"
+        );
+    }
+}
+
+#[test]
+fn visitors_walk_and_rewrite_trees() {
+    struct Names(Vec<String>);
+
+    impl Visitor for Names {
+        fn visit_expr(&mut self, expr: &Expr) {
+            if let Expr::Identifier(identifier) = expr {
+                self.0.push(identifier.name.clone());
+            }
+            self.walk_expr(expr);
+        }
+
+        fn visit_param(&mut self, param: &FuncParam) {
+            self.0.push(param.name.clone());
+            self.walk_param(param);
+        }
+    }
+
+    struct Rename;
+
+    impl IrVisitorMut for Rename {
+        fn visit_expr(&mut self, expr: &mut IrExpr) {
+            match expr {
+                IrExpr::Identifier(identifier) if identifier.name == "total" => {
+                    identifier.name = "sum_".to_string();
+                    identifier.meta.dirty = true;
+                }
+                expr => self.walk_expr(expr),
+            }
+        }
+    }
+
+    let source = "def add(total, step=unit):\n    while total < limit:\n        total += [step for step in steps if step][0]\n    return total\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let mut names = Names(Vec::new());
+    names.visit_program(&program);
+    assert_eq!(
+        names.0,
+        [
+            "total", "step", "unit", "total", "limit", "total", "step", "step", "steps", "step",
+            "total"
+        ]
+    );
+
+    let mut ir = python_to_ir(&program);
+    for stmt in &mut ir.body {
+        Rename.visit_stmt(stmt);
+    }
+    assert_eq!(
+        render_surgically(&ir),
+        "def add(total, step=unit):\n    while sum_ < limit:\n        sum_ += [step for step in steps if step][0]\n    return sum_\n"
+    );
+}
+
+/// First syntax node, outermost first, whose source is `text`.
+fn syntax_node<'a>(node: &'a SyntaxNode, text: &str) -> Option<&'a SyntaxNode> {
+    if node.text() == text {
+        return Some(node);
+    }
+    node.children.iter().find_map(|child| match child {
+        SyntaxElement::Node(node) => syntax_node(node, text),
+        SyntaxElement::Token(_) => None,
+    })
+}
+
+#[test]
+fn syntax_tree_owns_every_token_once() {
+    let source = "@decorate\ndef f(a,  # first\n      b=[x for x in y if x]):\n    if a:  # check\n        return a  # early\n\n    # later\n    match b:\n        case 1:\n            pass\n    return f\"{a}\" + b\n\n\nz = f( 1 ).a[ 2 ]  # tail";
+    let mut tree = SyntaxTree::new(parse_with(PythonVersion::Py310, source).unwrap());
+    assert_eq!(tree.text(), source);
+    assert_eq!(tree.root.tokens().len(), tree.program.raw_tokens.len());
+
+    let early = syntax_node(&tree.root, "        return a  # early\n").unwrap();
+    assert_eq!(early.kind, SyntaxKind::Stmt);
+    let header = "    match b:\n        case 1:\n            pass\n";
+    assert_eq!(
+        syntax_node(&tree.root, header).unwrap().kind,
+        SyntaxKind::Stmt
+    );
+    for (text, kind) in [
+        ("f( 1 ).a[ 2 ]", SyntaxKind::Expr),
+        ("for x in y if x", SyntaxKind::ComprehensionFor),
+        ("1", SyntaxKind::Pattern),
+        ("f\"{a}\"", SyntaxKind::Expr),
+    ] {
+        assert_eq!(syntax_node(&tree.root, text).unwrap().kind, kind, "{text}");
+    }
+
+    let z = stmt_ids(&tree.program).last().copied().unwrap();
+    let id = syntax_node(early, "a").unwrap().id;
+    tree.replace(id, "a  +  1", ParserConfig::default())
+        .unwrap();
+    assert_eq!(tree.text(), source.replace("return a ", "return a  +  1 "));
+    assert_eq!(stmt_ids(&tree.program).last().copied(), Some(z));
+
+    let tail = syntax_node(&tree.root, "z = f( 1 ).a[ 2 ]  # tail")
+        .unwrap()
+        .id;
+    assert!(tree
+        .replace(tail, "z = (", ParserConfig::default())
+        .is_err());
+    tree.replace(tail, "z=0", ParserConfig::default()).unwrap();
+    assert!(tree.text().ends_with("+ b\n\n\nz=0  # tail"));
+}
+
+/// How each occurrence of `name` is resolved: its kind, and the kind of the
+/// scope of its symbol.
+fn resolved(table: &SymbolTable, name: &str) -> Vec<(ReferenceKind, ScopeKind)> {
+    table
+        .references
+        .iter()
+        .filter(|reference| table.symbols[reference.symbol.0].name == name)
+        .map(|reference| {
+            let scope = table.symbols[reference.symbol.0].scope;
+            (reference.kind, table.scopes[scope.0].kind)
+        })
+        .collect()
+}
+
+#[test]
+fn symbols_follow_python_scopes() {
+    let source = "import os\ncount = 0\n\ndef bump(step, *rest):\n    global count\n    count += step\n    total = 0\n    def inner():\n        nonlocal total\n        total = len(rest)\n    return [count * x for x in rest if (y := x)]\n\nclass Box:\n    size = 1\n    def grow(self):\n        return size\nprint(bump, y, os)\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let table = program.symbols();
+    use ReferenceKind::*;
+    use ScopeKind::*;
+    assert_eq!(
+        resolved(&table, "count"),
+        [
+            (Definition, Module),
+            (Global, Module),
+            (Use, Module),
+            (Definition, Module),
+            (Use, Module)
+        ]
+    );
+    assert_eq!(
+        resolved(&table, "total"),
+        [
+            (Definition, Function),
+            (Nonlocal, Function),
+            (Definition, Function)
+        ]
+    );
+    assert_eq!(
+        resolved(&table, "rest"),
+        [(Parameter, Function), (Use, Function), (Use, Function)]
+    );
+    assert_eq!(
+        resolved(&table, "x"),
+        [
+            (Definition, Comprehension),
+            (Use, Comprehension),
+            (Use, Comprehension)
+        ]
+    );
+    // `:=` binds in the function, so the `y` printed is another, unbound one.
+    assert_eq!(
+        resolved(&table, "y"),
+        [(Definition, Function), (Use, Module)]
+    );
+    let y: Vec<_> = table
+        .references
+        .iter()
+        .filter(|reference| table.symbols[reference.symbol.0].name == "y")
+        .map(|reference| reference.symbol)
+        .collect();
+    assert_ne!(y[0], y[1]);
+    // Methods do not see the names of their class.
+    assert_eq!(
+        resolved(&table, "size"),
+        [(Definition, Class), (Use, Module)]
+    );
+    assert_eq!(
+        resolved(&table, "os"),
+        [(Definition, Module), (Use, Module)]
+    );
+    assert_eq!(resolved(&table, "len"), [(Use, Module)]);
+
+    let Stmt::FunctionDef(bump) = &program.body[3] else {
+        panic!("expected def");
+    };
+    let symbol = table.nodes[&bump.meta.id];
+    assert_eq!(table.symbols[symbol.0].name, "bump");
+    assert_eq!(table.scopes.len(), 6);
+}
+
+#[test]
+fn name_checks_find_unbound_reads() {
+    let source = "def total(items):\n    for item in items:\n        sum_ = sum_ + item\n        count += 1\n    if items:\n        last = items[-1]\n    else:\n        print(lsat)\n    return last\n\nprint(totl([1]), late)\nlate = 1\n\ndef loop(items):\n    while True:\n        if done:\n            break\n        done = True\n    for item in items:\n        if item:\n            print(previous)\n        previous = item\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let found: Vec<(NameDiagnosticKind, &str, usize)> = program
+        .check_names(&program.symbols())
+        .iter()
+        .map(|diagnostic| {
+            let name = &source[diagnostic.span.start.offset..diagnostic.span.end.offset];
+            assert_eq!(name, diagnostic.name);
+            (diagnostic.kind, name, diagnostic.span.start.line)
+        })
+        .collect();
+    use NameDiagnosticKind::*;
+    assert_eq!(
+        found,
+        [
+            (UsedBeforeAssignment, "sum_", 3),
+            (UsedBeforeAssignment, "count", 4),
+            (Undefined, "lsat", 8),
+            (Undefined, "totl", 11),
+            (UsedBeforeAssignment, "late", 11),
+            (UsedBeforeAssignment, "done", 16),
+        ]
+    );
+}
+
+#[test]
+fn name_checks_follow_bare_annotations_and_del() {
+    let source = "x: int\nprint(x)\n\ndef f(items):\n    count: int\n    for item in items:\n        count = item\n    del items\n    print(items, count)\n    del (gone, count)\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let table = program.symbols();
+    use ReferenceKind::*;
+    assert_eq!(
+        resolved(&table, "x"),
+        [(Declaration, ScopeKind::Module), (Use, ScopeKind::Module)]
+    );
+    assert_eq!(resolved(&table, "gone"), [(Deletion, ScopeKind::Function)]);
+    let found: Vec<(NameDiagnosticKind, &str, usize)> = program
+        .check_names(&table)
+        .iter()
+        .map(|diagnostic| {
+            let name = &source[diagnostic.span.start.offset..diagnostic.span.end.offset];
+            (diagnostic.kind, name, diagnostic.span.start.line)
+        })
+        .collect();
+    use NameDiagnosticKind::*;
+    assert_eq!(
+        found,
+        [
+            (UsedBeforeAssignment, "x", 2),
+            (UsedBeforeAssignment, "items", 9),
+            (Undefined, "gone", 10),
+        ]
+    );
+}
+
+#[test]
+fn warnings_find_unused_names_and_dead_code() {
+    let source = "import os\nfrom math import pi, tau\n\nclass Shape:\n    def area(self, scale):\n        with open(\"log\") as log:\n            size = 2\n        return pi\n        print(size)\n\ndef loop(items, _skip):\n    for item in items:\n        break\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let warnings = program.warnings(&program.symbols());
+    let found: Vec<(WarningKind, &str, usize)> = warnings
+        .iter()
+        .map(|warning| {
+            let text = &source[warning.span.start.offset..warning.span.end.offset];
+            (warning.kind, text, warning.span.start.line)
+        })
+        .collect();
+    use WarningKind::*;
+    assert_eq!(
+        found,
+        [
+            (UnusedImport, "os", 1),
+            (UnusedImport, "tau", 2),
+            (UnusedParameter, "scale", 5),
+            (UnusedVariable, "log", 6),
+            (UnreachableCode, "print(size)\n", 9),
+            (UnusedVariable, "item", 12),
+        ]
+    );
+    assert!(warnings
+        .iter()
+        .all(|warning| warning.severity == Severity::Warning));
+
+    // Each fix applies to the IR on its own.
+    let fixed: Vec<String> = warnings
+        .iter()
+        .map(|warning| {
+            let mut ir = python_to_ir(&program);
+            for operation in &warning.fix.operations {
+                ir.apply(operation.clone()).unwrap();
+            }
+            render_surgically(&ir)
+        })
+        .collect();
+    assert!(fixed[0].starts_with("from math import pi, tau\n"));
+    assert!(fixed[1].starts_with("import os\nfrom math import pi\n"));
+    assert!(fixed[2].contains("    def area(self, _scale):\n"));
+    assert!(fixed[3].contains("        with open(\"log\") as _log:\n            size = 2\n"));
+    assert!(fixed[4].contains("        return pi\n\ndef loop"));
+    assert!(fixed[5].contains("    for _item in items:\n"));
+}
+
+#[test]
+fn warnings_skip_dead_code_and_check_static_methods() {
+    let source = "class Tools:\n    @staticmethod\n    def scale(value, factor):\n        return factor\n\n    def reset(self):\n        return None\n        result = 0\n        import os\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let found: Vec<(WarningKind, &str, usize)> = program
+        .warnings(&program.symbols())
+        .iter()
+        .map(|warning| {
+            let text = &source[warning.span.start.offset..warning.span.end.offset];
+            (warning.kind, text, warning.span.start.line)
+        })
+        .collect();
+    use WarningKind::*;
+    assert_eq!(
+        found,
+        [
+            (UnusedParameter, "value", 3),
+            (UnreachableCode, "result = 0\n        import os\n", 8),
+        ]
+    );
+}
+
+#[test]
+fn linter_applies_config_and_noqa() {
+    let source = "import os  # noqa\nimport sys  # noqa: undefined-name\ncount = 0\n\ndef bump(step):\n    global count\n    count += 1\n    print(totl)  # NOQA: undefined-name\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let found = |linter: &Linter, config: &LintConfig| -> Vec<(String, Severity, usize)> {
+        linter
+            .lint(&program, config)
+            .into_iter()
+            .map(|diagnostic| {
+                (
+                    diagnostic.rule,
+                    diagnostic.severity,
+                    diagnostic.span.start.line,
+                )
+            })
+            .collect()
+    };
+    let linter = Linter::default();
+    let defaults = [
+        ("unused-import".to_string(), Severity::Warning, 2),
+        ("unused-parameter".to_string(), Severity::Warning, 5),
+    ];
+    assert_eq!(found(&linter, &LintConfig::default()), defaults);
+
+    let mut config = LintConfig::default();
+    config.rules.insert(
+        "global-statement".to_string(),
+        RuleConfig {
+            enabled: Some(true),
+            severity: Some(Severity::Error),
+        },
+    );
+    config.rules.insert(
+        "unused-parameter".to_string(),
+        RuleConfig {
+            enabled: Some(false),
+            severity: None,
+        },
+    );
+    assert_eq!(
+        found(&linter, &config),
+        [
+            ("unused-import".to_string(), Severity::Warning, 2),
+            ("global-statement".to_string(), Severity::Error, 6),
+        ]
+    );
+    let rules = linter.rules(&config);
+    assert!(rules
+        .iter()
+        .any(|rule| rule.id == "global-statement" && rule.enabled));
+
+    // Rules from outside the engine see the program and its symbols.
+    struct PrintCalls;
+    impl LintRule for PrintCalls {
+        fn id(&self) -> &'static str {
+            "print-call"
+        }
+        fn description(&self) -> &'static str {
+            "Calls of print"
+        }
+        fn check(&self, context: &LintContext) -> Vec<Finding> {
+            let symbols = context.symbols;
+            symbols
+                .references
+                .iter()
+                .filter(|reference| symbols.symbols[reference.symbol.0].name == "print")
+                .map(|reference| Finding {
+                    message: "print call".to_string(),
+                    node: Some(reference.node),
+                    span: reference.span,
+                    fix: None,
+                })
+                .collect()
+        }
+    }
+    let mut linter = Linter::new();
+    linter.add_rule(Box::new(PrintCalls));
+    assert_eq!(
+        found(&linter, &LintConfig::default()),
+        [("print-call".to_string(), Severity::Warning, 8)]
+    );
+}
+
+#[test]
+fn noqa_comments_open_with_the_word() {
+    let source = "import os  # see noqa docs\nimport sys  # noqa : unused-import\nimport re  # noqa : undefined-name\nimport json  # noqaish\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let lines: Vec<usize> = Linter::default()
+        .lint(&program, &LintConfig::default())
+        .iter()
+        .map(|diagnostic| diagnostic.span.start.line)
+        .collect();
+    assert_eq!(lines, [1, 3, 4]);
+}
+
+#[test]
+fn pitfalls_find_beginner_mistakes() {
+    let source = "list = [3, 1]\ntotal = 0\ntotal == 5\nfor i in range(len(list)):\n    if (found := 1):\n        pass\nif total == None:\n    pass\n\ndef add(item, items=[]):\n    while True:\n        for other in items:\n            break\n    return items\n\nwhile (True):\n    if total != None:\n        return_value = total\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let pitfalls = program.pitfalls(&program.symbols());
+    let found: Vec<(PitfallKind, &str, usize)> = pitfalls
+        .iter()
+        .map(|pitfall| {
+            let text = &source[pitfall.span.start.offset..pitfall.span.end.offset];
+            (pitfall.kind, text.trim_end(), pitfall.span.start.line)
+        })
+        .collect();
+    use PitfallKind::*;
+    assert_eq!(
+        found,
+        [
+            (ShadowedBuiltin, "list", 1),
+            (ComparisonStatement, "total == 5", 3),
+            (RangeLenLoop, "range(len(list))", 4),
+            (AssignmentInCondition, "found := 1", 5),
+            (NoneComparison, "total == None", 7),
+            (MutableDefault, "[]", 10),
+            (EndlessLoop, "while True", 11),
+            (EndlessLoop, "while (True)", 16),
+            (NoneComparison, "total != None", 17),
+        ]
+    );
+    let args = |index: usize| -> Vec<(&str, &str)> {
+        pitfalls[index]
+            .args
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect()
+    };
+    assert_eq!(args(2), [("index", "i"), ("sequence", "list")]);
+    assert_eq!(args(3), [("name", "found"), ("value", "1")]);
+    assert_eq!(args(8), [("op", "!="), ("suggestion", "is not")]);
+
+    // `if x = 1:` does not parse, but the error still gets its hint.
+    let source = "x = 0\nif x = 1:\n    pass\n";
+    let error = parse_with(PythonVersion::Py310, source).unwrap_err();
+    let pitfall = error_pitfall(source, &error).unwrap();
+    assert_eq!(pitfall.kind, AssignmentInCondition);
+    assert_eq!(pitfall.span.start.line, 2);
+    assert_eq!(pitfall.args["name"], "x");
+    assert_eq!(pitfall.args["value"], "1");
+}
+
+#[test]
+fn endless_loop_hints_allow_raise_and_generators() {
+    let source = "def ticks():\n    while True:\n        yield 1\n\ndef wait():\n    while True:\n        raise TimeoutError\n\ndef spin():\n    while True:\n        pass\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let found: Vec<(PitfallKind, usize)> = program
+        .pitfalls(&program.symbols())
+        .iter()
+        .map(|pitfall| (pitfall.kind, pitfall.span.start.line))
+        .collect();
+    assert_eq!(found, [(PitfallKind::EndlessLoop, 10)]);
+}
+
+#[test]
+fn types_are_inferred_from_values_and_annotations() {
+    struct Spans(BTreeMap<NodeId, Span>);
+
+    impl Visitor for Spans {
+        fn visit_meta(&mut self, meta: &NodeMeta) {
+            self.0.insert(meta.id, meta.span);
+        }
+    }
+
+    let source = "count = 0\nname = input(\"Name: \")\nfor i in range(3):\n    count += i\nratio = count / 2\nwords = name.split()\nlengths = [len(w) for w in words]\npairs = {w: len(w) for w in words}\nfirst, second = 1, \"a\"\n\ndef double(n: int) -> int:\n    return n * 2\n\nlabel = \"total: \" + count\nresult = double(4)\nif name < 3:\n    pass\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let types = program.infer_types(&program.symbols());
+    let mut spans = Spans(BTreeMap::new());
+    spans.visit_program(&program);
+    let found: BTreeMap<&str, String> = types
+        .types
+        .iter()
+        .map(|(node, found)| {
+            let span = spans.0[node];
+            (
+                &source[span.start.offset..span.end.offset],
+                found.to_string(),
+            )
+        })
+        .collect();
+    for (expr, expected) in [
+        ("count", "int"),
+        ("name", "str"),
+        ("input(\"Name: \")", "str"),
+        ("i", "int"),
+        ("count / 2", "float"),
+        ("ratio", "float"),
+        ("words", "list[str]"),
+        ("lengths", "list[int]"),
+        ("pairs", "dict[str, int]"),
+        ("1, \"a\"", "tuple[int, str]"),
+        ("second", "str"),
+        ("n * 2", "int"),
+        ("result", "int"),
+        ("name < 3", "bool"),
+    ] {
+        assert_eq!(
+            found.get(expr).map(String::as_str),
+            Some(expected),
+            "{expr}"
+        );
+    }
+    assert!(!found.contains_key("label"));
+    let errors: Vec<(String, usize)> = types
+        .errors
+        .iter()
+        .map(|error| (error.message(), error.span.start.line))
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            (
+                "unsupported operand type(s) for +: 'str' and 'int'".to_string(),
+                14
+            ),
+            (
+                "'<' not supported between instances of 'str' and 'int'".to_string(),
+                16
+            ),
+        ]
+    );
+}
+
+#[test]
+fn type_inference_checks_augmented_assignments() {
+    let source =
+        "x = 1\nx += 'a'\ntotal = 0\ntotal += input()\nwords = 'a'\nwords *= 3\nprint(x, total)\n";
+    let program = parse_with(PythonVersion::Py310, source).unwrap();
+    let types = program.infer_types(&program.symbols());
+    let errors: Vec<(String, usize)> = types
+        .errors
+        .iter()
+        .map(|error| (error.message(), error.span.start.line))
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            (
+                "unsupported operand type(s) for +=: 'int' and 'str'".to_string(),
+                2
+            ),
+            (
+                "unsupported operand type(s) for +=: 'int' and 'str'".to_string(),
+                4
+            ),
+        ]
+    );
+}
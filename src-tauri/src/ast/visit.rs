@@ -0,0 +1,455 @@
+use super::*;
+
+/// Defines a visitor trait over one tree, the AST or the IR, by shared or by
+/// mutable reference. The two trees have the same shape, so one definition
+/// serves all four traits.
+macro_rules! visitor {
+    (
+        $(#[$attr:meta])*
+        $Visitor:ident [$($m:tt)?],
+        $Program:ident,
+        $Block:ident,
+        $Stmt:ident,
+        $Expr:ident,
+        $Pattern:ident,
+        $FuncParam:ident,
+        $ComprehensionFor:ident,
+        $ComprehensionExpr:ident,
+        $FStringPart:ident $(,)?
+    ) => {
+        $(#[$attr])*
+        pub trait $Visitor {
+            fn visit_program(&mut self, program: &$($m)? $Program) {
+                self.walk_program(program);
+            }
+
+            fn visit_block(&mut self, block: &$($m)? $Block) {
+                self.walk_block(block);
+            }
+
+            fn visit_stmt(&mut self, stmt: &$($m)? $Stmt) {
+                self.walk_stmt(stmt);
+            }
+
+            fn visit_expr(&mut self, expr: &$($m)? $Expr) {
+                self.walk_expr(expr);
+            }
+
+            fn visit_pattern(&mut self, pattern: &$($m)? $Pattern) {
+                self.walk_pattern(pattern);
+            }
+
+            fn visit_param(&mut self, param: &$($m)? $FuncParam) {
+                self.walk_param(param);
+            }
+
+            fn visit_comprehension_for(&mut self, comprehension: &$($m)? $ComprehensionFor) {
+                self.walk_comprehension_for(comprehension);
+            }
+
+            /// Called with the metadata of every node before its children
+            /// are visited, including nodes that have no method of their
+            /// own, such as `elif` clauses and dictionary entries.
+            fn visit_meta(&mut self, _meta: &$($m)? NodeMeta) {}
+
+            fn walk_program(&mut self, program: &$($m)? $Program) {
+                self.visit_meta(&$($m)? program.meta);
+                for stmt in &$($m)? program.body {
+                    self.visit_stmt(stmt);
+                }
+            }
+
+            fn walk_block(&mut self, block: &$($m)? $Block) {
+                self.visit_meta(&$($m)? block.meta);
+                for stmt in &$($m)? block.statements {
+                    self.visit_stmt(stmt);
+                }
+            }
+
+            fn walk_stmt(&mut self, stmt: &$($m)? $Stmt) {
+                match stmt {
+                    $Stmt::If(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_expr(&$($m)? stmt.condition);
+                        self.visit_block(&$($m)? stmt.body);
+                        for elif in &$($m)? stmt.elifs {
+                            self.visit_meta(&$($m)? elif.meta);
+                            self.visit_expr(&$($m)? elif.condition);
+                            self.visit_block(&$($m)? elif.body);
+                        }
+                        if let Some(body) = &$($m)? stmt.else_body {
+                            self.visit_block(body);
+                        }
+                    }
+                    $Stmt::While(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_expr(&$($m)? stmt.condition);
+                        self.visit_block(&$($m)? stmt.body);
+                        if let Some(body) = &$($m)? stmt.else_body {
+                            self.visit_block(body);
+                        }
+                    }
+                    $Stmt::For(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_expr(&$($m)? stmt.target);
+                        self.visit_expr(&$($m)? stmt.iterable);
+                        self.visit_block(&$($m)? stmt.body);
+                        if let Some(body) = &$($m)? stmt.else_body {
+                            self.visit_block(body);
+                        }
+                    }
+                    $Stmt::Match(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_expr(&$($m)? stmt.subject);
+                        self.visit_meta(&$($m)? stmt.cases.meta);
+                        for case in &$($m)? stmt.cases.cases {
+                            self.visit_meta(&$($m)? case.meta);
+                            self.visit_pattern(&$($m)? case.pattern);
+                            self.visit_block(&$($m)? case.body);
+                        }
+                    }
+                    $Stmt::FunctionDef(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        for decorator in &$($m)? stmt.decorators {
+                            self.visit_expr(decorator);
+                        }
+                        for param in &$($m)? stmt.params {
+                            self.visit_param(param);
+                        }
+                        if let Some(return_type) = &$($m)? stmt.return_type {
+                            self.visit_expr(return_type);
+                        }
+                        self.visit_block(&$($m)? stmt.body);
+                    }
+                    $Stmt::ClassDef(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        for decorator in &$($m)? stmt.decorators {
+                            self.visit_expr(decorator);
+                        }
+                        for base in &$($m)? stmt.bases {
+                            self.visit_expr(base);
+                        }
+                        self.visit_block(&$($m)? stmt.body);
+                    }
+                    $Stmt::Assign(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        for target in &$($m)? stmt.targets {
+                            self.visit_expr(target);
+                        }
+                        self.visit_expr(&$($m)? stmt.value);
+                    }
+                    $Stmt::AugAssign(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_expr(&$($m)? stmt.target);
+                        self.visit_expr(&$($m)? stmt.value);
+                    }
+                    $Stmt::Expr(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_expr(&$($m)? stmt.expr);
+                    }
+                    $Stmt::Pass(stmt) => self.visit_meta(&$($m)? stmt.meta),
+                    $Stmt::Return(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        if let Some(value) = &$($m)? stmt.value {
+                            self.visit_expr(value);
+                        }
+                    }
+                    $Stmt::Break(stmt) => self.visit_meta(&$($m)? stmt.meta),
+                    $Stmt::Continue(stmt) => self.visit_meta(&$($m)? stmt.meta),
+                    $Stmt::Empty(stmt) => self.visit_meta(&$($m)? stmt.meta),
+                    $Stmt::Comment(stmt) => self.visit_meta(&$($m)? stmt.meta),
+                    $Stmt::Import(stmt) => self.visit_meta(&$($m)? stmt.meta),
+                    $Stmt::Try(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_block(&$($m)? stmt.body);
+                        for handler in &$($m)? stmt.handlers {
+                            self.visit_meta(&$($m)? handler.meta);
+                            if let Some(exception_type) = &$($m)? handler.exception_type {
+                                self.visit_expr(exception_type);
+                            }
+                            self.visit_block(&$($m)? handler.body);
+                        }
+                        if let Some(body) = &$($m)? stmt.else_body {
+                            self.visit_block(body);
+                        }
+                        if let Some(body) = &$($m)? stmt.finally_body {
+                            self.visit_block(body);
+                        }
+                    }
+                    $Stmt::With(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        for item in &$($m)? stmt.items {
+                            self.visit_expr(&$($m)? item.context);
+                        }
+                        self.visit_block(&$($m)? stmt.body);
+                    }
+                    $Stmt::Assert(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_expr(&$($m)? stmt.condition);
+                        if let Some(message) = &$($m)? stmt.message {
+                            self.visit_expr(message);
+                        }
+                    }
+                    $Stmt::Raise(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        if let Some(exception) = &$($m)? stmt.exception {
+                            self.visit_expr(exception);
+                        }
+                    }
+                    $Stmt::Del(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_expr(&$($m)? stmt.target);
+                    }
+                    $Stmt::Global(stmt) => self.visit_meta(&$($m)? stmt.meta),
+                    $Stmt::Nonlocal(stmt) => self.visit_meta(&$($m)? stmt.meta),
+                    $Stmt::AnnAssign(stmt) => {
+                        self.visit_meta(&$($m)? stmt.meta);
+                        self.visit_expr(&$($m)? stmt.annotation);
+                        if let Some(value) = &$($m)? stmt.value {
+                            self.visit_expr(value);
+                        }
+                    }
+                }
+            }
+
+            fn walk_expr(&mut self, expr: &$($m)? $Expr) {
+                match expr {
+                    $Expr::Identifier(expr) => self.visit_meta(&$($m)? expr.meta),
+                    $Expr::Literal(expr) => self.visit_meta(&$($m)? expr.meta),
+                    $Expr::Binary(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.left);
+                        self.visit_expr(&$($m)? expr.right);
+                    }
+                    $Expr::Unary(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.expr);
+                    }
+                    $Expr::BoolOp(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        for value in &$($m)? expr.values {
+                            self.visit_expr(value);
+                        }
+                    }
+                    $Expr::Compare(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.left);
+                        for comparator in &$($m)? expr.comparators {
+                            self.visit_expr(comparator);
+                        }
+                    }
+                    $Expr::Lambda(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.body);
+                    }
+                    $Expr::IfExpr(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.body);
+                        self.visit_expr(&$($m)? expr.condition);
+                        self.visit_expr(&$($m)? expr.else_body);
+                    }
+                    $Expr::Call(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.callee);
+                        for arg in &$($m)? expr.args {
+                            self.visit_expr(arg);
+                        }
+                        for kwarg in &$($m)? expr.kwargs {
+                            self.visit_expr(&$($m)? kwarg.value);
+                        }
+                    }
+                    $Expr::Tuple(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        for element in &$($m)? expr.elements {
+                            self.visit_expr(element);
+                        }
+                    }
+                    $Expr::Attribute(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.value);
+                    }
+                    $Expr::Subscript(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.value);
+                        self.visit_expr(&$($m)? expr.index);
+                    }
+                    $Expr::Slice(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        for part in [&$($m)? expr.lower, &$($m)? expr.upper, &$($m)? expr.step]
+                            .into_iter()
+                            .flatten()
+                        {
+                            self.visit_expr(part);
+                        }
+                    }
+                    $Expr::Grouped(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.expr);
+                    }
+                    $Expr::List(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        for element in &$($m)? expr.elements {
+                            self.visit_expr(element);
+                        }
+                    }
+                    $Expr::Dict(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        for entry in &$($m)? expr.entries {
+                            self.visit_meta(&$($m)? entry.meta);
+                            self.visit_expr(&$($m)? entry.key);
+                            self.visit_expr(&$($m)? entry.value);
+                        }
+                    }
+                    $Expr::Set(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        for element in &$($m)? expr.elements {
+                            self.visit_expr(element);
+                        }
+                    }
+                    $Expr::Comprehension(expr) => {
+                        let fors = match expr {
+                            $ComprehensionExpr::List(expr) => {
+                                self.visit_meta(&$($m)? expr.meta);
+                                self.visit_expr(&$($m)? expr.element);
+                                &$($m)? expr.fors
+                            }
+                            $ComprehensionExpr::Set(expr) => {
+                                self.visit_meta(&$($m)? expr.meta);
+                                self.visit_expr(&$($m)? expr.element);
+                                &$($m)? expr.fors
+                            }
+                            $ComprehensionExpr::Generator(expr) => {
+                                self.visit_meta(&$($m)? expr.meta);
+                                self.visit_expr(&$($m)? expr.element);
+                                &$($m)? expr.fors
+                            }
+                            $ComprehensionExpr::Dict(expr) => {
+                                self.visit_meta(&$($m)? expr.meta);
+                                self.visit_expr(&$($m)? expr.key);
+                                self.visit_expr(&$($m)? expr.value);
+                                &$($m)? expr.fors
+                            }
+                        };
+                        for comprehension in fors {
+                            self.visit_comprehension_for(comprehension);
+                        }
+                    }
+                    $Expr::FString(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        for part in &$($m)? expr.parts {
+                            if let $FStringPart::Expr(value) = part {
+                                self.visit_expr(value);
+                            }
+                        }
+                    }
+                    $Expr::NamedExpr(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.value);
+                    }
+                    $Expr::Yield(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        if let Some(value) = &$($m)? expr.value {
+                            self.visit_expr(value);
+                        }
+                    }
+                    $Expr::YieldFrom(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.value);
+                    }
+                    $Expr::Await(expr) => {
+                        self.visit_meta(&$($m)? expr.meta);
+                        self.visit_expr(&$($m)? expr.value);
+                    }
+                }
+            }
+
+            fn walk_pattern(&mut self, pattern: &$($m)? $Pattern) {
+                match pattern {
+                    $Pattern::Wildcard(meta) => self.visit_meta(meta),
+                    $Pattern::Identifier(pattern) => self.visit_meta(&$($m)? pattern.meta),
+                    $Pattern::Literal(pattern) => self.visit_meta(&$($m)? pattern.meta),
+                }
+            }
+
+            fn walk_param(&mut self, param: &$($m)? $FuncParam) {
+                if let Some(annotation) = &$($m)? param.annotation {
+                    self.visit_expr(annotation);
+                }
+                if let Some(default) = &$($m)? param.default {
+                    self.visit_expr(default);
+                }
+            }
+
+            fn walk_comprehension_for(&mut self, comprehension: &$($m)? $ComprehensionFor) {
+                self.visit_meta(&$($m)? comprehension.meta);
+                self.visit_expr(&$($m)? comprehension.target);
+                self.visit_expr(&$($m)? comprehension.iter);
+                for condition in &$($m)? comprehension.ifs {
+                    self.visit_expr(condition);
+                }
+            }
+        }
+    };
+}
+
+visitor!(
+    /// Walks the AST. Each `visit_` method defaults to the matching `walk_`
+    /// method, which visits the node's children in source order; override
+    /// a `visit_` method to act on a kind of node, and call `walk_` from it
+    /// to go on into its children.
+    Visitor [],
+    Program,
+    Block,
+    Stmt,
+    Expr,
+    Pattern,
+    FuncParam,
+    ComprehensionFor,
+    ComprehensionExpr,
+    FStringPart,
+);
+
+visitor!(
+    /// `Visitor` for rewriting the AST in place.
+    VisitorMut [mut],
+    Program,
+    Block,
+    Stmt,
+    Expr,
+    Pattern,
+    FuncParam,
+    ComprehensionFor,
+    ComprehensionExpr,
+    FStringPart,
+);
+
+visitor!(
+    /// `Visitor` over the IR.
+    IrVisitor [],
+    IrProgram,
+    IrBlock,
+    IrStmt,
+    IrExpr,
+    IrPattern,
+    IrFuncParam,
+    IrComprehensionFor,
+    IrComprehensionExpr,
+    IrFStringPart,
+);
+
+visitor!(
+    /// `Visitor` for rewriting the IR in place. Nodes it changes should be
+    /// marked dirty so that rendering picks them up.
+    // Nothing rewrites a whole IR program yet.
+    #[allow(dead_code)]
+    IrVisitorMut [mut],
+    IrProgram,
+    IrBlock,
+    IrStmt,
+    IrExpr,
+    IrPattern,
+    IrFuncParam,
+    IrComprehensionFor,
+    IrComprehensionExpr,
+    IrFStringPart,
+);
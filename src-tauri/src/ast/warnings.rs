@@ -0,0 +1,402 @@
+use super::render::stmt_meta;
+use super::symbols::imported_names;
+use super::*;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+impl Program {
+    /// Finds imports, variables of functions and parameters that nothing
+    /// reads, and statements that can never run. Names starting with `_`,
+    /// the first parameter of a method that is not a `@staticmethod`, names
+    /// declared `global` or `nonlocal` and names bound in code that never
+    /// runs are left alone. Each warning comes with a fix for the IR
+    /// of the program: unused imports and unreachable statements are
+    /// removed, and unused variables and parameters get a leading `_`.
+    /// `table` is the program's `symbols()`.
+    pub fn warnings(&self, table: &SymbolTable) -> Vec<Warning> {
+        let mut sites = Sites::default();
+        sites.visit_program(self);
+        let mut read = HashSet::new();
+        let mut declared = HashSet::new();
+        for reference in &table.references {
+            match reference.kind {
+                // Deleting a variable uses it up.
+                ReferenceKind::Use | ReferenceKind::Deletion => {
+                    read.insert(reference.symbol);
+                }
+                ReferenceKind::Global | ReferenceKind::Nonlocal => {
+                    declared.insert(reference.symbol);
+                }
+                _ => {}
+            }
+        }
+        let mut lint = Lint {
+            program: self,
+            table,
+            sites: &sites,
+            read,
+            declared,
+            warnings: Vec::new(),
+        };
+        lint.imports();
+        lint.variables();
+        lint.parameters();
+        let mut warnings = lint.warnings;
+        warnings.extend(sites.unreachable);
+        warnings.sort_by_key(|warning| warning.span.start.offset);
+        warnings
+    }
+}
+
+/// How the name a node binds is renamed.
+#[derive(Clone, Copy)]
+enum Rename {
+    /// By setting a field of the node.
+    Field(&'static str),
+    /// By replacing the `with` statement, whose names are in its items.
+    WithItem,
+}
+
+/// What the warnings need to know about the nodes of a program.
+#[derive(Default)]
+struct Sites {
+    /// The block of each statement, or `None` for the program, and its
+    /// index there.
+    places: HashMap<NodeId, (Option<NodeId>, usize)>,
+    renames: HashMap<NodeId, Rename>,
+    imports: Vec<ImportStmt>,
+    /// Functions defined right in a class body that get the instance or the
+    /// class as their first argument.
+    methods: HashSet<NodeId>,
+    unreachable: Vec<Warning>,
+}
+
+impl Sites {
+    fn statements(&mut self, block: Option<NodeId>, statements: &[Stmt]) {
+        for (index, stmt) in statements.iter().enumerate() {
+            self.places.insert(stmt_meta(stmt).id, (block, index));
+        }
+        let Some(jump) = statements.iter().position(|stmt| {
+            matches!(
+                stmt,
+                Stmt::Return(_) | Stmt::Break(_) | Stmt::Continue(_) | Stmt::Raise(_)
+            )
+        }) else {
+            return;
+        };
+        let dead: Vec<&NodeMeta> = statements[jump + 1..]
+            .iter()
+            .filter(|stmt| !matches!(stmt, Stmt::Comment(_) | Stmt::Empty(_)))
+            .map(stmt_meta)
+            .collect();
+        if let (Some(first), Some(last)) = (dead.first(), dead.last()) {
+            self.unreachable.push(Warning {
+                kind: WarningKind::UnreachableCode,
+                severity: Severity::Warning,
+                name: None,
+                node: first.id,
+                span: Span {
+                    start: first.span.start,
+                    end: last.span.end,
+                },
+                fix: QuickFix {
+                    title: "Remove the unreachable code".to_string(),
+                    operations: dead
+                        .iter()
+                        .map(|meta| IrOperation::DeleteNode { id: meta.id })
+                        .collect(),
+                },
+            });
+        }
+    }
+}
+
+impl Visitor for Sites {
+    fn visit_program(&mut self, program: &Program) {
+        self.statements(None, &program.body);
+        self.walk_program(program);
+    }
+
+    fn visit_block(&mut self, block: &Block) {
+        self.statements(Some(block.meta.id), &block.statements);
+        self.walk_block(block);
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        let id = stmt_meta(stmt).id;
+        match stmt {
+            Stmt::AnnAssign(_) => {
+                self.renames.insert(id, Rename::Field("target"));
+            }
+            Stmt::With(_) => {
+                self.renames.insert(id, Rename::WithItem);
+            }
+            Stmt::Try(stmt) => {
+                for handler in &stmt.handlers {
+                    self.renames.insert(handler.meta.id, Rename::Field("name"));
+                }
+            }
+            Stmt::Import(import) => self.imports.push(import.clone()),
+            Stmt::ClassDef(class) => {
+                for stmt in &class.body.statements {
+                    if let Stmt::FunctionDef(function) = stmt {
+                        let is_static = function.decorators.iter().any(|decorator| {
+                            matches!(decorator, Expr::Identifier(name) if name.name == "staticmethod")
+                        });
+                        if !is_static {
+                            self.methods.insert(function.meta.id);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.walk_stmt(stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Identifier(identifier) => {
+                self.renames
+                    .insert(identifier.meta.id, Rename::Field("name"));
+            }
+            Expr::NamedExpr(named) => {
+                self.renames.insert(named.meta.id, Rename::Field("name"));
+            }
+            _ => {}
+        }
+        self.walk_expr(expr);
+    }
+
+    fn visit_pattern(&mut self, pattern: &Pattern) {
+        if let Pattern::Identifier(identifier) = pattern {
+            self.renames
+                .insert(identifier.meta.id, Rename::Field("name"));
+        }
+        self.walk_pattern(pattern);
+    }
+}
+
+/// Converts the statement `id` to the IR.
+struct FindStmt {
+    id: NodeId,
+    found: Option<IrStmt>,
+}
+
+impl Visitor for FindStmt {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        if stmt_meta(stmt).id == self.id {
+            self.found = Some(stmt_to_ir(stmt));
+        } else if self.found.is_none() {
+            self.walk_stmt(stmt);
+        }
+    }
+}
+
+struct Lint<'a> {
+    program: &'a Program,
+    table: &'a SymbolTable,
+    sites: &'a Sites,
+    /// Symbols read somewhere.
+    read: HashSet<SymbolId>,
+    /// Symbols declared `global` or `nonlocal` somewhere.
+    declared: HashSet<SymbolId>,
+    warnings: Vec<Warning>,
+}
+
+impl Lint<'_> {
+    fn warn(&mut self, kind: WarningKind, reference: &Reference, span: Span, fix: QuickFix) {
+        self.warnings.push(Warning {
+            kind,
+            severity: Severity::Warning,
+            name: Some(self.table.symbols[reference.symbol.0].name.clone()),
+            node: reference.node,
+            span,
+            fix,
+        });
+    }
+
+    /// Whether `span` lies in code reported as unreachable.
+    fn unreachable(&self, span: Span) -> bool {
+        self.sites.unreachable.iter().any(|warning| {
+            warning.span.start.offset <= span.start.offset
+                && span.end.offset <= warning.span.end.offset
+        })
+    }
+
+    fn imports(&mut self) {
+        let table = self.table;
+        for import in &self.sites.imports {
+            if import.module == "__future__" || self.unreachable(import.meta.span) {
+                continue;
+            }
+            let bound = table.references.iter().filter(|reference| {
+                reference.node == import.meta.id && reference.kind == ReferenceKind::Definition
+            });
+            for (index, (name, reference)) in
+                imported_names(import).into_iter().zip(bound).enumerate()
+            {
+                if self.read.contains(&reference.symbol) {
+                    continue;
+                }
+                let operation = if import.names.len() > 1 {
+                    let mut kept = import.names.clone();
+                    kept.remove(index);
+                    IrOperation::SetField {
+                        id: import.meta.id,
+                        field: "names".to_string(),
+                        value: serde_json::to_value(kept).unwrap_or_default(),
+                    }
+                } else {
+                    IrOperation::DeleteNode { id: import.meta.id }
+                };
+                let fix = QuickFix {
+                    title: format!("Remove the import of '{name}'"),
+                    operations: vec![operation],
+                };
+                let span = self
+                    .program
+                    .name_span(import.meta.span, Some("import"), name);
+                self.warn(WarningKind::UnusedImport, reference, span, fix);
+            }
+        }
+    }
+
+    fn variables(&mut self) {
+        let table = self.table;
+        let mut definitions: BTreeMap<SymbolId, Vec<&Reference>> = BTreeMap::new();
+        let mut parameters = HashSet::new();
+        for reference in &table.references {
+            match reference.kind {
+                ReferenceKind::Definition if !self.unreachable(reference.span) => definitions
+                    .entry(reference.symbol)
+                    .or_default()
+                    .push(reference),
+                ReferenceKind::Parameter => {
+                    parameters.insert(reference.symbol);
+                }
+                _ => {}
+            }
+        }
+        for (symbol, references) in definitions {
+            let name = &table.symbols[symbol.0].name;
+            if table.scopes[table.symbols[symbol.0].scope.0].kind != ScopeKind::Function
+                || name.starts_with('_')
+                || self.read.contains(&symbol)
+                || self.declared.contains(&symbol)
+                || parameters.contains(&symbol)
+            {
+                continue;
+            }
+            // Imports, functions and classes bound to a name no one reads
+            // are not variables.
+            let Some(renames) = references
+                .iter()
+                .map(|reference| Some((reference.node, *self.sites.renames.get(&reference.node)?)))
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+            let fix = QuickFix {
+                title: format!("Rename to '_{name}'"),
+                operations: self.rename(&renames, name),
+            };
+            let span = self.program.name_span(references[0].span, None, name);
+            self.warn(WarningKind::UnusedVariable, references[0], span, fix);
+        }
+    }
+
+    fn parameters(&mut self) {
+        let table = self.table;
+        for (index, scope) in table.scopes.iter().enumerate() {
+            if scope.kind != ScopeKind::Function {
+                continue;
+            }
+            let method = self.sites.methods.contains(&scope.node);
+            let parameters = table.references.iter().filter(|reference| {
+                reference.scope == ScopeId(index) && reference.kind == ReferenceKind::Parameter
+            });
+            for (position, reference) in parameters.enumerate() {
+                let name = &table.symbols[reference.symbol.0].name;
+                if (method && position == 0)
+                    || name.starts_with('_')
+                    || self.read.contains(&reference.symbol)
+                    || self.declared.contains(&reference.symbol)
+                {
+                    continue;
+                }
+                let renamed = format!("_{name}");
+                let operations = self.replace(reference.node, |stmt| {
+                    if let IrStmt::FunctionDef(function) = stmt {
+                        for param in &mut function.params {
+                            if param.name == *name {
+                                param.name = renamed.clone();
+                            }
+                        }
+                    }
+                });
+                let fix = QuickFix {
+                    title: format!("Rename to '{renamed}'"),
+                    operations,
+                };
+                let span = self.program.name_span(reference.span, Some("("), name);
+                self.warn(WarningKind::UnusedParameter, reference, span, fix);
+            }
+        }
+    }
+
+    /// Operations giving the name `name` bound at `bindings` a leading `_`.
+    /// Statements are replaced before fields are set, so that a replaced
+    /// statement does not bring back an old name inside it.
+    fn rename(&self, bindings: &[(NodeId, Rename)], name: &str) -> Vec<IrOperation> {
+        let renamed = format!("_{name}");
+        let mut operations = Vec::new();
+        let mut fields = Vec::new();
+        let mut seen = HashSet::new();
+        for &(node, rename) in bindings {
+            if !seen.insert(node) {
+                continue;
+            }
+            match rename {
+                Rename::Field(field) => fields.push(IrOperation::SetField {
+                    id: node,
+                    field: field.to_string(),
+                    value: Value::from(renamed.as_str()),
+                }),
+                Rename::WithItem => operations.extend(self.replace(node, |stmt| {
+                    if let IrStmt::With(with) = stmt {
+                        for item in &mut with.items {
+                            if item.name.as_deref() == Some(name) {
+                                item.name = Some(renamed.clone());
+                            }
+                        }
+                    }
+                })),
+            }
+        }
+        operations.extend(fields);
+        operations
+    }
+
+    /// Operations putting the statement `id`, changed by `edit`, in place of
+    /// itself. Its nodes keep their ids.
+    fn replace(&self, id: NodeId, edit: impl FnOnce(&mut IrStmt)) -> Vec<IrOperation> {
+        let Some(&(block, index)) = self.sites.places.get(&id) else {
+            return Vec::new();
+        };
+        let mut find = FindStmt { id, found: None };
+        find.visit_program(self.program);
+        let Some(mut stmt) = find.found else {
+            return Vec::new();
+        };
+        edit(&mut stmt);
+        vec![
+            IrOperation::DeleteNode { id },
+            IrOperation::InsertStmt {
+                block,
+                index,
+                stmt: Box::new(stmt),
+            },
+        ]
+    }
+}
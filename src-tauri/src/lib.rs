@@ -1,11 +1,12 @@
 mod ast;
+mod session;
 use serde::Serialize;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use std::process::Command;
 use std::sync::Mutex;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use tauri::{AppHandle, Emitter};
+use tauri::{AppHandle, Emitter, State};
 
 #[tauri::command]
 fn get_empty_ir() -> ast::IrProgram {
@@ -15,29 +16,250 @@ fn get_empty_ir() -> ast::IrProgram {
         body: Vec::new(),
         token_store: None,
         dirty: true,
+        source_format: ast::SourceFormat::default(),
     }
 }
 
+/// The editor's source for the commands that predate document sessions.
+static EDITOR_SOURCE: Mutex<Option<session::Document>> = Mutex::new(None);
+
+#[tauri::command]
+fn parse_python_to_ir(
+    source: String,
+    options: Option<ast::IrEncodeOptions>,
+) -> Result<ast::IrEnvelope, String> {
+    let mut editor = EDITOR_SOURCE.lock().unwrap();
+    let document = editor.get_or_insert_with(session::Document::default);
+    document.set_source(source);
+    document
+        .encode_ir(options.unwrap_or_default())
+        .map_err(|error| error.to_string())
+}
+
+#[tauri::command]
+fn apply_python_edit(
+    edit: ast::TextEdit,
+    options: Option<ast::IrEncodeOptions>,
+) -> Result<ast::IrEnvelope, String> {
+    let mut editor = EDITOR_SOURCE.lock().unwrap();
+    let document = editor
+        .as_mut()
+        .ok_or_else(|| "no source has been parsed yet".to_string())?;
+    document
+        .apply_text_edit(&edit)
+        .map_err(|error| error.to_string())?;
+    document
+        .encode_ir(options.unwrap_or_default())
+        .map_err(|error| error.to_string())
+}
+
+#[tauri::command]
+fn open_document(
+    documents: State<'_, session::Documents>,
+    source: String,
+) -> session::OpenedDocument {
+    documents.open(source)
+}
+
+#[tauri::command]
+fn close_document(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+) -> Result<(), String> {
+    documents.close(id)
+}
+
+#[tauri::command]
+fn apply_document_edit(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+    edit: ast::TextEdit,
+) -> Result<session::DocumentChange, String> {
+    documents.apply_text_edit(id, &edit)
+}
+
+#[tauri::command]
+fn apply_document_ir_splice(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+    splice: session::IrSplice,
+) -> Result<session::DocumentChange, String> {
+    documents.apply_ir_splice(id, splice)
+}
+
+#[tauri::command]
+fn apply_document_operations(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+    operations: Vec<ast::IrOperation>,
+) -> Result<session::AppliedOperations, String> {
+    documents.apply_ir_operations(id, operations)
+}
+
+#[tauri::command]
+fn replace_document_syntax_node(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+    node: ast::NodeId,
+    text: String,
+) -> Result<session::DocumentChange, String> {
+    documents.replace_syntax_node(id, node, &text)
+}
+
+#[tauri::command]
+fn undo_document_edit(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+    version: u64,
+) -> Result<session::DocumentChange, String> {
+    documents.undo(id, version)
+}
+
+#[tauri::command]
+fn redo_document_edit(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+    version: u64,
+) -> Result<session::DocumentChange, String> {
+    documents.redo(id, version)
+}
+
+#[tauri::command]
+fn get_document_history(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+) -> Result<session::HistoryState, String> {
+    documents.history(id)
+}
+
+#[tauri::command]
+fn get_document_text(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+) -> Result<String, String> {
+    documents.text(id)
+}
+
+#[tauri::command]
+fn get_document_diagnostics(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+) -> Result<Vec<session::Diagnostic>, String> {
+    documents.diagnostics(id)
+}
+
+#[tauri::command]
+fn get_document_ir(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+    options: Option<ast::IrEncodeOptions>,
+) -> Result<ast::IrEnvelope, String> {
+    documents.encoded_ir(id, options.unwrap_or_default())
+}
+
+#[tauri::command]
+fn get_document_syntax_tree(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+) -> Result<ast::SyntaxNode, String> {
+    documents.syntax_tree(id)
+}
+
+#[tauri::command]
+fn get_document_symbols(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+) -> Result<ast::SymbolTable, String> {
+    documents.symbols(id)
+}
+
 #[tauri::command]
-fn parse_python_to_ir(source: String) -> Result<ast::IrProgram, String> {
-    let config = ast::ParserConfig::default();
-    let program = ast::Program::parse(source, config).map_err(|error| error.to_string())?;
-    Ok(ast::python_to_ir(&program))
+fn get_document_pitfalls(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+) -> Result<Vec<ast::Pitfall>, String> {
+    documents.pitfalls(id)
+}
+
+#[tauri::command]
+fn get_document_types(
+    documents: State<'_, session::Documents>,
+    id: session::DocumentId,
+) -> Result<ast::TypeTable, String> {
+    documents.types(id)
 }
 
 #[tauri::command]
 fn generate_python_from_ir(
-    ir: ast::IrProgram,
+    documents: State<'_, session::Documents>,
+    ir: ast::IrEnvelope,
     render_mode: ast::RenderMode,
-) -> Result<String, String> {
+    document: Option<session::DocumentId>,
+) -> Result<ast::RenderedPython, String> {
+    // The envelope's token store, if it left one, is with the document that
+    // encoded it: an open document, or else the editor's source.
+    let ir = match document {
+        Some(id) => documents.decode_ir(id, ir)?,
+        None => match &*EDITOR_SOURCE.lock().unwrap() {
+            Some(editor) => editor.decode_ir(ir),
+            None => ast::decode_ir(ir, &ast::TokenStores::new()),
+        }
+        .map_err(|error| error.to_string())?,
+    };
     let features = ast::FeatureSet::from_version(ast::PythonVersion::Py310);
     let program = ast::ir_to_python(&ir, &features).map_err(|error| error.to_string())?;
-    Ok(program.to_python(ast::RenderConfig {
+    Ok(program.to_python_with_source_map(ast::RenderConfig {
         mode: render_mode,
-        reuse_token_ranges: false,
+        reuse_token_ranges: render_mode == ast::RenderMode::Lossless,
+        ..ast::RenderConfig::default()
     }))
 }
 
+#[tauri::command]
+fn parse_stmt_to_ir(source: String) -> Result<ast::IrStmt, String> {
+    ast::parse_stmt_to_ir(&source, ast::ParserConfig::default()).map_err(|error| error.to_string())
+}
+
+#[tauri::command]
+fn parse_expr_to_ir(source: String) -> Result<ast::IrExpr, String> {
+    ast::parse_expr_to_ir(&source, ast::ParserConfig::default()).map_err(|error| error.to_string())
+}
+
+#[tauri::command]
+fn generate_python_from_ir_stmt(stmt: ast::IrStmt) -> Result<String, String> {
+    let features = ast::FeatureSet::from_version(ast::PythonVersion::Py310);
+    let stmt = ast::ir_stmt_to_python(&stmt, &features).map_err(|error| error.to_string())?;
+    Ok(stmt.to_python(4, ast::RenderConfig::default()))
+}
+
+#[tauri::command]
+fn generate_python_from_ir_expr(expr: ast::IrExpr) -> String {
+    ast::ir_expr_to_python(&expr).to_python(ast::RenderConfig::default())
+}
+
+#[tauri::command]
+fn format_python(source: String) -> Result<String, String> {
+    ast::format_python(source, ast::ParserConfig::default()).map_err(|error| error.to_string())
+}
+
+#[tauri::command]
+fn lint_python(
+    source: String,
+    config: Option<ast::LintConfig>,
+) -> Result<Vec<ast::LintDiagnostic>, String> {
+    ast::lint_python(
+        source,
+        &config.unwrap_or_default(),
+        ast::ParserConfig::default(),
+    )
+    .map_err(|error| error.to_string())
+}
+
+#[tauri::command]
+fn get_lint_rules(config: Option<ast::LintConfig>) -> Vec<ast::LintRuleInfo> {
+    ast::Linter::default().rules(&config.unwrap_or_default())
+}
+
 #[derive(Serialize, Clone)]
 struct RunResult {
     stdout: String,
@@ -297,10 +519,35 @@ pub fn run() {
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_fs::init())
+        .manage(session::Documents::default())
         .invoke_handler(tauri::generate_handler![
             get_empty_ir,
             parse_python_to_ir,
+            apply_python_edit,
+            open_document,
+            close_document,
+            apply_document_edit,
+            apply_document_ir_splice,
+            apply_document_operations,
+            replace_document_syntax_node,
+            undo_document_edit,
+            redo_document_edit,
+            get_document_history,
+            get_document_text,
+            get_document_diagnostics,
+            get_document_ir,
+            get_document_syntax_tree,
+            get_document_symbols,
+            get_document_pitfalls,
+            get_document_types,
             generate_python_from_ir,
+            parse_stmt_to_ir,
+            parse_expr_to_ir,
+            generate_python_from_ir_stmt,
+            generate_python_from_ir_expr,
+            format_python,
+            lint_python,
+            get_lint_rules,
             run_python,
             stop_python
         ])
@@ -0,0 +1,781 @@
+use crate::ast;
+use history::{History, Revert, Stack};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+mod history;
+#[cfg(test)]
+mod tests;
+
+/// Documents open in the editor. Each keeps its parsed program between
+/// commands, so that edits send and return only what changed.
+#[derive(Default)]
+pub struct Documents {
+    table: Mutex<DocumentTable>,
+}
+
+#[derive(Default)]
+struct DocumentTable {
+    next_id: u64,
+    documents: HashMap<DocumentId, Document>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct DocumentId(pub u64);
+
+/// Source of a document and its last successful parse, kept so that text
+/// edits only re-parse the statements they touch and so that node ids stay
+/// the same from one parse to the next.
+#[derive(Default)]
+pub struct Document {
+    source: String,
+    program: Option<ast::Program>,
+    /// Why `source` does not parse; `program` is then from an older text.
+    error: Option<ast::ParseError>,
+    /// Counts the changes to `source`.
+    version: u64,
+    history: History,
+    /// What the last change did to `program`, taken to tell the webview.
+    replaced: Option<Replaced>,
+    /// Lint diagnostics of a version, kept from when they were asked for.
+    lints: Option<(u64, Vec<Diagnostic>)>,
+    /// Token stores of the IR sent without them, kept while `source` is.
+    tokens: ast::TokenStores,
+}
+
+/// The program a change replaced, if the document had one.
+struct Replaced {
+    program: Option<ast::Program>,
+    /// Top-level statements an edit re-parsed; the others only moved.
+    range: Option<ast::ReparsedRange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: ast::Span,
+    /// Node the diagnostic is about, when the source parsed.
+    pub node: Option<ast::NodeId>,
+    pub severity: ast::Severity,
+    /// Id of the lint rule reporting it.
+    pub rule: Option<String>,
+    /// Operations on the document's IR resolving a warning.
+    pub fix: Option<ast::QuickFix>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenedDocument {
+    pub id: DocumentId,
+    pub version: u64,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Replaces `deleted` top-level statements from `start` on with `inserted`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IrSplice {
+    pub start: usize,
+    pub deleted: usize,
+    pub inserted: Vec<ast::IrStmt>,
+}
+
+/// How far the statements after a splice moved.
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+pub struct PositionShift {
+    pub lines: isize,
+    pub offset: isize,
+    pub tokens: isize,
+}
+
+/// Changes to the program a document last parsed to: `splice` turns the old
+/// body into the new one once the statements after it are moved by `shift`.
+#[derive(Debug, Clone, Serialize)]
+pub struct IrDelta {
+    #[serde(flatten)]
+    pub splice: IrSplice,
+    pub shift: PositionShift,
+}
+
+/// How far a document can go back and forward from its current version.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HistoryState {
+    pub version: u64,
+    pub undo: usize,
+    pub redo: usize,
+}
+
+/// A change made by IR operations, with the operations undoing it in the
+/// order to apply them.
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedOperations {
+    pub change: DocumentChange,
+    pub inverse: Vec<ast::IrOperation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentChange {
+    pub version: u64,
+    /// The edit to the source, unless the change was one.
+    pub text: Option<ast::TextEdit>,
+    /// `None` when the program did not change, such as when the new source
+    /// does not parse.
+    pub ir: Option<IrDelta>,
+    /// Why the new source does not parse, if it does not. Lint warnings
+    /// take longer to find and come from `Documents::diagnostics`.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Documents {
+    pub fn open(&self, source: String) -> OpenedDocument {
+        let mut document = Document::default();
+        document.set_source(source);
+        let mut table = self.table.lock().unwrap();
+        table.next_id += 1;
+        let id = DocumentId(table.next_id);
+        let opened = OpenedDocument {
+            id,
+            version: document.version,
+            diagnostics: document.diagnostics(),
+        };
+        table.documents.insert(id, document);
+        opened
+    }
+
+    pub fn close(&self, id: DocumentId) -> Result<(), String> {
+        let mut table = self.table.lock().unwrap();
+        table
+            .documents
+            .remove(&id)
+            .map(|_| ())
+            .ok_or_else(|| unknown_document(id))
+    }
+
+    pub fn apply_text_edit(
+        &self,
+        id: DocumentId,
+        edit: &ast::TextEdit,
+    ) -> Result<DocumentChange, String> {
+        self.change(id, |document| {
+            document
+                .apply_text_edit(edit)
+                .map_err(|error| error.to_string())?;
+            Ok(None)
+        })
+    }
+
+    pub fn apply_ir_splice(
+        &self,
+        id: DocumentId,
+        splice: IrSplice,
+    ) -> Result<DocumentChange, String> {
+        self.change(id, |document| document.apply_ir_splice(splice).map(Some))
+    }
+
+    pub fn apply_ir_operations(
+        &self,
+        id: DocumentId,
+        operations: Vec<ast::IrOperation>,
+    ) -> Result<AppliedOperations, String> {
+        let mut inverse = Vec::new();
+        let change = self.change(id, |document| {
+            let before = document.source.clone();
+            inverse = document.apply_ir_operations(operations)?;
+            Ok(Some(text_delta(&before, &document.source)))
+        })?;
+        Ok(AppliedOperations { change, inverse })
+    }
+
+    /// Replaces the source of the syntax node `node`, keeping the formatting
+    /// around it.
+    pub fn replace_syntax_node(
+        &self,
+        id: DocumentId,
+        node: ast::NodeId,
+        text: &str,
+    ) -> Result<DocumentChange, String> {
+        self.change(id, |document| {
+            document.replace_syntax_node(node, text).map(Some)
+        })
+    }
+
+    /// Takes back the last change to the document, whether made to its text
+    /// or to its blocks. `version` is the version the caller last saw; undo
+    /// is refused if the document has changed since.
+    pub fn undo(&self, id: DocumentId, version: u64) -> Result<DocumentChange, String> {
+        self.travel(id, version, Stack::Undo)
+    }
+
+    /// Makes again the last change `undo` took back.
+    pub fn redo(&self, id: DocumentId, version: u64) -> Result<DocumentChange, String> {
+        self.travel(id, version, Stack::Redo)
+    }
+
+    pub fn history(&self, id: DocumentId) -> Result<HistoryState, String> {
+        self.read(id, |document| HistoryState {
+            version: document.version,
+            undo: document.history.len(Stack::Undo, document.version),
+            redo: document.history.len(Stack::Redo, document.version),
+        })
+    }
+
+    pub fn text(&self, id: DocumentId) -> Result<String, String> {
+        self.read(id, |document| document.source.clone())
+    }
+
+    /// The parse error of the document's source, or else what the
+    /// built-in lint rules find in it. Kept until the source changes, so
+    /// that the editor can ask whenever typing pauses.
+    pub fn diagnostics(&self, id: DocumentId) -> Result<Vec<Diagnostic>, String> {
+        let mut table = self.table.lock().unwrap();
+        let document = table
+            .documents
+            .get_mut(&id)
+            .ok_or_else(|| unknown_document(id))?;
+        Ok(document.diagnostics())
+    }
+
+    /// The program the document last parsed to.
+    pub fn ir(&self, id: DocumentId) -> Result<ast::IrProgram, String> {
+        self.read(id, |document| match &document.program {
+            Some(program) => Ok(ast::python_to_ir(program)),
+            None => Err(document.current_ir().unwrap_err().to_string()),
+        })?
+    }
+
+    /// `ir` in an envelope. A token store left out of it stays with the
+    /// document until the source changes or the document closes.
+    pub fn encoded_ir(
+        &self,
+        id: DocumentId,
+        options: ast::IrEncodeOptions,
+    ) -> Result<ast::IrEnvelope, String> {
+        let mut table = self.table.lock().unwrap();
+        let document = table
+            .documents
+            .get_mut(&id)
+            .ok_or_else(|| unknown_document(id))?;
+        let ir = match &document.program {
+            Some(program) => ast::python_to_ir(program),
+            None => return Err(document.current_ir().unwrap_err().to_string()),
+        };
+        Ok(ast::encode_ir(ir, options, &mut document.tokens))
+    }
+
+    /// Reads the program out of an envelope from `encoded_ir`.
+    pub fn decode_ir(
+        &self,
+        id: DocumentId,
+        envelope: ast::IrEnvelope,
+    ) -> Result<ast::IrProgram, String> {
+        self.read(id, |document| document.decode_ir(envelope))?
+            .map_err(|error| error.to_string())
+    }
+
+    /// Concrete syntax tree of the program the document last parsed to.
+    pub fn syntax_tree(&self, id: DocumentId) -> Result<ast::SyntaxNode, String> {
+        self.read(id, |document| match &document.program {
+            Some(program) => Ok(ast::SyntaxTree::new(program.clone()).root),
+            None => Err(document.current_ir().unwrap_err().to_string()),
+        })?
+    }
+
+    /// Hints about mistakes learners often make in the document's source.
+    pub fn pitfalls(&self, id: DocumentId) -> Result<Vec<ast::Pitfall>, String> {
+        self.read(id, |document| match (&document.error, &document.program) {
+            (Some(error), _) => ast::error_pitfall(&document.source, error)
+                .into_iter()
+                .collect(),
+            (None, Some(program)) => program.pitfalls(&program.symbols()),
+            (None, None) => Vec::new(),
+        })
+    }
+
+    /// Types inferred for the program the document last parsed to, for
+    /// hover text and for blocks shaped by the type of their value.
+    pub fn types(&self, id: DocumentId) -> Result<ast::TypeTable, String> {
+        self.read(id, |document| match &document.program {
+            Some(program) => Ok(program.infer_types(&program.symbols())),
+            None => Err(document.current_ir().unwrap_err().to_string()),
+        })?
+    }
+
+    /// Names of the program the document last parsed to, resolved to their
+    /// scopes.
+    pub fn symbols(&self, id: DocumentId) -> Result<ast::SymbolTable, String> {
+        self.read(id, |document| match &document.program {
+            Some(program) => Ok(program.symbols()),
+            None => Err(document.current_ir().unwrap_err().to_string()),
+        })?
+    }
+
+    fn read<T>(&self, id: DocumentId, read: impl FnOnce(&Document) -> T) -> Result<T, String> {
+        let table = self.table.lock().unwrap();
+        let document = table
+            .documents
+            .get(&id)
+            .ok_or_else(|| unknown_document(id))?;
+        Ok(read(document))
+    }
+
+    fn travel(&self, id: DocumentId, version: u64, stack: Stack) -> Result<DocumentChange, String> {
+        self.change(id, |document| {
+            if version != document.version {
+                return Err(format!(
+                    "document {} is at version {}, not {version}",
+                    id.0, document.version
+                ));
+            }
+            let before = document.source.clone();
+            let Some(revert) = document.history.pop(stack, version) else {
+                return Err(match stack {
+                    Stack::Undo => "there is nothing to undo".to_string(),
+                    Stack::Redo => "there is nothing to redo".to_string(),
+                });
+            };
+            match document.revert(revert.clone()) {
+                Ok(back) => document
+                    .history
+                    .record_travel(stack, document.version, back),
+                Err(error) => {
+                    document.history.restore(stack, revert);
+                    return Err(error);
+                }
+            }
+            Ok(Some(text_delta(&before, &document.source)))
+        })
+    }
+
+    fn change(
+        &self,
+        id: DocumentId,
+        apply: impl FnOnce(&mut Document) -> Result<Option<ast::TextEdit>, String>,
+    ) -> Result<DocumentChange, String> {
+        let mut table = self.table.lock().unwrap();
+        let document = table
+            .documents
+            .get_mut(&id)
+            .ok_or_else(|| unknown_document(id))?;
+        let old_end = document.program.as_ref().map(program_end);
+        document.replaced = None;
+        let text = apply(document)?;
+        let replaced = document.replaced.take();
+        let ir = match (replaced, &document.program, old_end) {
+            _ if document.error.is_some() => None,
+            (
+                Some(Replaced {
+                    program: Some(before),
+                    range,
+                }),
+                Some(program),
+                Some(old_end),
+            ) => {
+                let range = range.unwrap_or(ast::ReparsedRange {
+                    start: 0,
+                    old_end: before.body.len(),
+                    new_end: program.body.len(),
+                });
+                let mut delta = ir_delta(
+                    &body_to_ir(&before.body[range.start..range.old_end]),
+                    &body_to_ir(&program.body[range.start..range.new_end]),
+                    position_shift(old_end, program),
+                );
+                delta.splice.start += range.start;
+                Some(delta)
+            }
+            (Some(Replaced { program: None, .. }), Some(program), _) => Some(IrDelta {
+                splice: IrSplice {
+                    start: 0,
+                    deleted: 0,
+                    inserted: body_to_ir(&program.body),
+                },
+                shift: PositionShift::default(),
+            }),
+            _ => None,
+        };
+        Ok(DocumentChange {
+            version: document.version,
+            text,
+            ir,
+            diagnostics: document.parse_error().into_iter().collect(),
+        })
+    }
+}
+
+impl Document {
+    /// The program parsed from the current source.
+    pub fn current_ir(&self) -> Result<ast::IrProgram, ast::ParseError> {
+        match (&self.error, &self.program) {
+            (Some(error), _) => Err(error.clone()),
+            (None, Some(program)) => Ok(ast::python_to_ir(program)),
+            (None, None) => Err(ast::ParseError {
+                message: "no source has been parsed yet".to_string(),
+                span: ast::Span::default(),
+            }),
+        }
+    }
+
+    /// `current_ir` in an envelope. A token store left out of it stays with
+    /// the document until the source changes.
+    pub fn encode_ir(
+        &mut self,
+        options: ast::IrEncodeOptions,
+    ) -> Result<ast::IrEnvelope, ast::ParseError> {
+        let ir = self.current_ir()?;
+        Ok(ast::encode_ir(ir, options, &mut self.tokens))
+    }
+
+    /// Reads the program out of an envelope the document encoded, failing
+    /// if the token store it refers to is gone.
+    pub fn decode_ir(&self, envelope: ast::IrEnvelope) -> Result<ast::IrProgram, ast::DecodeError> {
+        ast::decode_ir(envelope, &self.tokens)
+    }
+
+    pub fn set_source(&mut self, source: String) {
+        let result = ast::Program::parse(source.clone(), ast::ParserConfig::default());
+        self.update(source, result);
+    }
+
+    /// Applies `edit`, failing without a change if it is out of range.
+    pub fn apply_text_edit(&mut self, edit: &ast::TextEdit) -> Result<(), ast::ParseError> {
+        let inverse = self.edit_text(edit)?;
+        self.history.record_edit(self.version, edit, inverse);
+        Ok(())
+    }
+
+    /// Splices the body of the current program and renders it back, copying
+    /// the statements left alone from the source. Returns the edit this made
+    /// to the source.
+    pub fn apply_ir_splice(&mut self, splice: IrSplice) -> Result<ast::TextEdit, String> {
+        let mut ir = self.current_ir().map_err(|error| error.to_string())?;
+        let end = splice.start.saturating_add(splice.deleted);
+        if end > ir.body.len() {
+            return Err(format!(
+                "statements {}..{end} are out of range",
+                splice.start
+            ));
+        }
+        ir.body.splice(splice.start..end, splice.inserted);
+        ir.dirty = true;
+        let before = self.source.clone();
+        self.set_ir(&ir)?;
+        let edit = text_delta(&before, &self.source);
+        let inverse = inverse_edit(&edit, &before);
+        self.history
+            .record(self.version, Revert::Text(vec![inverse]));
+        Ok(edit)
+    }
+
+    /// Applies `operations` to the current program, all of them or none, and
+    /// returns the operations undoing them.
+    pub fn apply_ir_operations(
+        &mut self,
+        operations: Vec<ast::IrOperation>,
+    ) -> Result<Vec<ast::IrOperation>, String> {
+        let inverse = self.operate(operations)?;
+        self.history
+            .record(self.version, Revert::Operations(inverse.clone()));
+        Ok(inverse)
+    }
+
+    /// Replaces the source of the syntax node `node` of the current program
+    /// with `text`, failing without a change if the result does not parse.
+    /// Returns the edit this made to the source.
+    pub fn replace_syntax_node(
+        &mut self,
+        node: ast::NodeId,
+        text: &str,
+    ) -> Result<ast::TextEdit, String> {
+        let program = match (&self.error, &self.program) {
+            (None, Some(program)) => program.clone(),
+            _ => return Err(self.current_ir().unwrap_err().to_string()),
+        };
+        let mut tree = ast::SyntaxTree::new(program);
+        let edit = tree
+            .replace(node, text, ast::ParserConfig::default())
+            .map_err(|error| error.to_string())?;
+        let inverse = inverse_edit(&edit, &self.source);
+        self.update(tree.text(), Ok(tree.program));
+        self.history
+            .record(self.version, Revert::Text(vec![inverse]));
+        Ok(edit)
+    }
+
+    /// Applies `edit` without recording it, returning the edit undoing it.
+    fn edit_text(&mut self, edit: &ast::TextEdit) -> Result<ast::TextEdit, ast::ParseError> {
+        let config = ast::ParserConfig::default();
+        let source = edit.apply(&self.source)?;
+        let inverse = inverse_edit(edit, &self.source);
+        let (result, range) = match &self.program {
+            Some(program) if self.error.is_none() => {
+                match program.reparse_with_range(edit, config) {
+                    Ok((program, range)) => (Ok(program), range),
+                    Err(error) => (Err(error), None),
+                }
+            }
+            _ => (ast::Program::parse(source.clone(), config), None),
+        };
+        self.update(source, result);
+        if let Some(replaced) = &mut self.replaced {
+            replaced.range = range;
+        }
+        Ok(inverse)
+    }
+
+    /// Applies `operations` without recording them.
+    fn operate(
+        &mut self,
+        operations: Vec<ast::IrOperation>,
+    ) -> Result<Vec<ast::IrOperation>, String> {
+        let mut ir = self.current_ir().map_err(|error| error.to_string())?;
+        let mut inverse = operations
+            .into_iter()
+            .map(|operation| ir.apply(operation))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| error.to_string())?;
+        inverse.reverse();
+        self.set_ir(&ir)?;
+        Ok(inverse)
+    }
+
+    /// Makes the change `revert` describes, returning what takes it back.
+    fn revert(&mut self, revert: Revert) -> Result<Revert, String> {
+        match revert {
+            Revert::Text(edits) => {
+                let mut source = self.source.clone();
+                let mut inverse = Vec::with_capacity(edits.len());
+                for edit in &edits {
+                    inverse.push(inverse_edit(edit, &source));
+                    source = edit.apply(&source).map_err(|error| error.to_string())?;
+                }
+                inverse.reverse();
+                self.set_source(source);
+                Ok(Revert::Text(inverse))
+            }
+            Revert::Operations(operations) => self.operate(operations).map(Revert::Operations),
+        }
+    }
+
+    /// Renders `ir` into the source and parses it back, keeping the ids of
+    /// its nodes. Fails without a change if the rendered source does not
+    /// parse.
+    fn set_ir(&mut self, ir: &ast::IrProgram) -> Result<(), String> {
+        let features = ast::FeatureSet::from_version(ast::PythonVersion::Py310);
+        let rendered = ast::ir_to_python(ir, &features).map_err(|error| error.to_string())?;
+        let source = rendered.to_python(ast::RenderConfig {
+            mode: ast::RenderMode::Lossless,
+            reuse_token_ranges: true,
+            ..ast::RenderConfig::default()
+        });
+        let mut program = ast::Program::parse(source.clone(), ast::ParserConfig::default())
+            .map_err(|error| error.to_string())?;
+        program.reconcile_ids(&rendered);
+        self.source = source;
+        self.version += 1;
+        self.tokens.clear();
+        self.replaced = Some(Replaced {
+            program: self.program.replace(program),
+            range: None,
+        });
+        self.error = None;
+        Ok(())
+    }
+
+    fn update(&mut self, source: String, result: Result<ast::Program, ast::ParseError>) {
+        self.source = source;
+        self.version += 1;
+        self.tokens.clear();
+        match result {
+            Ok(mut program) => {
+                if let Some(previous) = &self.program {
+                    program.reconcile_ids(previous);
+                }
+                self.replaced = Some(Replaced {
+                    program: self.program.replace(program),
+                    range: None,
+                });
+                self.error = None;
+            }
+            Err(error) => self.error = Some(error),
+        }
+    }
+
+    /// Why the source does not parse, if it does not.
+    fn parse_error(&self) -> Option<Diagnostic> {
+        self.error.as_ref().map(|error| Diagnostic {
+            message: error.message.clone(),
+            span: error.span,
+            node: None,
+            severity: ast::Severity::Error,
+            rule: None,
+            fix: None,
+        })
+    }
+
+    /// The parse error of the source, or else what the built-in lint rules
+    /// find in it, linting each version once.
+    fn diagnostics(&mut self) -> Vec<Diagnostic> {
+        if let Some(error) = self.parse_error() {
+            return vec![error];
+        }
+        match &self.lints {
+            Some((version, lints)) if *version == self.version => lints.clone(),
+            _ => {
+                let lints = self.lint();
+                self.lints = Some((self.version, lints.clone()));
+                lints
+            }
+        }
+    }
+
+    fn lint(&self) -> Vec<Diagnostic> {
+        let Some(program) = &self.program else {
+            return Vec::new();
+        };
+        ast::Linter::default()
+            .lint(program, &ast::LintConfig::default())
+            .into_iter()
+            .map(|diagnostic| Diagnostic {
+                message: diagnostic.message,
+                span: diagnostic.span,
+                node: diagnostic.node,
+                severity: diagnostic.severity,
+                rule: Some(diagnostic.rule),
+                fix: diagnostic.fix,
+            })
+            .collect()
+    }
+}
+
+fn unknown_document(id: DocumentId) -> String {
+    format!("document {} is not open", id.0)
+}
+
+/// How far the statements after a change moved, given where the source
+/// ended and how many tokens it had before.
+fn position_shift(
+    (old_end, old_tokens): (ast::Position, usize),
+    program: &ast::Program,
+) -> PositionShift {
+    let (new_end, new_tokens) = program_end(program);
+    PositionShift {
+        lines: new_end.line as isize - old_end.line as isize,
+        offset: new_end.offset as isize - old_end.offset as isize,
+        tokens: new_tokens as isize - old_tokens as isize,
+    }
+}
+
+fn body_to_ir(body: &[ast::Stmt]) -> Vec<ast::IrStmt> {
+    body.iter().map(ast::python_stmt_to_ir).collect()
+}
+
+/// Where the source of `program` ends, and how many tokens it has.
+fn program_end(program: &ast::Program) -> (ast::Position, usize) {
+    let end = program
+        .raw_tokens
+        .last()
+        .map(|token| token.span.end)
+        .unwrap_or_default();
+    (end, program.tokens.len())
+}
+
+/// The edit undoing `edit` made to `source`.
+fn inverse_edit(edit: &ast::TextEdit, source: &str) -> ast::TextEdit {
+    ast::TextEdit {
+        start: edit.start,
+        end: edit.start + edit.text.len(),
+        text: source
+            .get(edit.start..edit.end)
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+/// The smallest edit turning `before` into `after`.
+fn text_delta(before: &str, after: &str) -> ast::TextEdit {
+    let mut prefix = before
+        .bytes()
+        .zip(after.bytes())
+        .take_while(|(old, new)| old == new)
+        .count();
+    while !before.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let mut suffix = before[prefix..]
+        .bytes()
+        .rev()
+        .zip(after[prefix..].bytes().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+    while !before.is_char_boundary(before.len() - suffix) {
+        suffix -= 1;
+    }
+    ast::TextEdit {
+        start: prefix,
+        end: before.len() - suffix,
+        text: after[prefix..after.len() - suffix].to_string(),
+    }
+}
+
+fn ir_delta(before: &[ast::IrStmt], after: &[ast::IrStmt], shift: PositionShift) -> IrDelta {
+    let to_value = |stmt: &ast::IrStmt| serde_json::to_value(stmt).unwrap_or(Value::Null);
+    let old = before.iter().map(to_value).collect::<Vec<_>>();
+    let new = after.iter().map(to_value).collect::<Vec<_>>();
+    let prefix = old
+        .iter()
+        .zip(&new)
+        .take_while(|(old, new)| old == new)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| {
+            let mut moved = (*old).clone();
+            shift_positions(&mut moved, shift);
+            &moved == *new
+        })
+        .count();
+    IrDelta {
+        splice: IrSplice {
+            start: prefix,
+            deleted: old.len() - prefix - suffix,
+            inserted: after[prefix..after.len() - suffix].to_vec(),
+        },
+        shift,
+    }
+}
+
+/// Moves the spans and token ranges in a serialized node by `shift`.
+fn shift_positions(value: &mut Value, shift: PositionShift) {
+    let add = |value: &mut Value, delta: isize| {
+        if let Some(number) = value.as_i64() {
+            *value = Value::from(number + delta as i64);
+        }
+    };
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                shift_positions(item, shift);
+            }
+        }
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                match key.as_str() {
+                    "span" => {
+                        for end in ["start", "end"] {
+                            add(&mut field[end]["line"], shift.lines);
+                            add(&mut field[end]["offset"], shift.offset);
+                        }
+                    }
+                    "token_range" => {
+                        add(&mut field["start"], shift.tokens);
+                        add(&mut field["end"], shift.tokens);
+                    }
+                    _ => shift_positions(field, shift),
+                }
+            }
+        }
+        _ => {}
+    }
+}
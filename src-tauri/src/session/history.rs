@@ -0,0 +1,158 @@
+use crate::ast;
+use std::time::{Duration, Instant};
+
+/// Keys typed closer together than this are undone together.
+const TYPING_PAUSE: Duration = Duration::from_secs(1);
+
+/// How many changes a document can undo.
+const KEPT_ENTRIES: usize = 500;
+
+/// Undo and redo stacks of a document, shared by its text and block edits.
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Entry>,
+    redo: Vec<Entry>,
+    /// Version of the document the stacks lead back from. A change that
+    /// goes around the history leaves them behind, and they are dropped.
+    version: u64,
+}
+
+/// What takes back a change.
+#[derive(Debug, Clone)]
+pub enum Revert {
+    /// Text edits, applied in order.
+    Text(Vec<ast::TextEdit>),
+    /// IR operations, applied in order. They address nodes by id, so they
+    /// still apply after text edits elsewhere in the source.
+    Operations(Vec<ast::IrOperation>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Stack {
+    Undo,
+    Redo,
+}
+
+struct Entry {
+    revert: Revert,
+    /// Set when the change was a typed key, so that the next key can join it.
+    typing: Option<Typing>,
+}
+
+#[derive(Clone, Copy)]
+struct Typing {
+    /// Where the typed text ends.
+    caret: usize,
+    at: Instant,
+}
+
+impl History {
+    pub fn len(&self, stack: Stack, version: u64) -> usize {
+        match stack {
+            _ if version != self.version => 0,
+            Stack::Undo => self.undo.len(),
+            Stack::Redo => self.redo.len(),
+        }
+    }
+
+    /// Records a change that took the document to `version`, dropping what
+    /// could be redone.
+    pub fn record(&mut self, version: u64, revert: Revert) {
+        self.push_new(
+            version,
+            Entry {
+                revert,
+                typing: None,
+            },
+        );
+    }
+
+    /// Records `edit`, which `inverse` takes back. A key typed right after
+    /// the last one, where it left the caret, joins its entry.
+    pub fn record_edit(&mut self, version: u64, edit: &ast::TextEdit, inverse: ast::TextEdit) {
+        let now = Instant::now();
+        let typing = is_keystroke(edit, &inverse).then_some(Typing {
+            caret: edit.start + edit.text.len(),
+            at: now,
+        });
+        let synced = self.version + 1 == version && self.redo.is_empty();
+        if let (true, Some(typing), Some(last)) = (synced, typing, self.undo.last_mut()) {
+            if let (Some(previous), Revert::Text(edits)) = (&mut last.typing, &mut last.revert) {
+                let continues =
+                    edit.end == previous.caret && (edit.start == edit.end || edit.text.is_empty());
+                if continues && now.duration_since(previous.at) < TYPING_PAUSE {
+                    edits.insert(0, inverse);
+                    *previous = typing;
+                    self.version = version;
+                    return;
+                }
+            }
+        }
+        self.push_new(
+            version,
+            Entry {
+                revert: Revert::Text(vec![inverse]),
+                typing,
+            },
+        );
+    }
+
+    /// Takes the change to undo or redo at `version`, if there is one.
+    pub fn pop(&mut self, stack: Stack, version: u64) -> Option<Revert> {
+        if version != self.version {
+            return None;
+        }
+        let entry = match stack {
+            Stack::Undo => self.undo.pop(),
+            Stack::Redo => self.redo.pop(),
+        };
+        entry.map(|entry| entry.revert)
+    }
+
+    /// Puts back a change `pop` took when it could not be made.
+    pub fn restore(&mut self, stack: Stack, revert: Revert) {
+        self.stack(stack).push(Entry {
+            revert,
+            typing: None,
+        });
+    }
+
+    /// Records the undo or redo that took the document to `version`, with
+    /// what takes it back onto the other stack.
+    pub fn record_travel(&mut self, stack: Stack, version: u64, revert: Revert) {
+        let other = match stack {
+            Stack::Undo => Stack::Redo,
+            Stack::Redo => Stack::Undo,
+        };
+        self.stack(other).push(Entry {
+            revert,
+            typing: None,
+        });
+        self.version = version;
+    }
+
+    fn stack(&mut self, stack: Stack) -> &mut Vec<Entry> {
+        match stack {
+            Stack::Undo => &mut self.undo,
+            Stack::Redo => &mut self.redo,
+        }
+    }
+
+    fn push_new(&mut self, version: u64, entry: Entry) {
+        if self.version + 1 != version {
+            self.undo.clear();
+        }
+        self.redo.clear();
+        if self.undo.len() == KEPT_ENTRIES {
+            self.undo.remove(0);
+        }
+        self.undo.push(entry);
+        self.version = version;
+    }
+}
+
+/// Whether `edit` types or erases a single character on one line.
+fn is_keystroke(edit: &ast::TextEdit, inverse: &ast::TextEdit) -> bool {
+    let single = |text: &str| text.chars().count() <= 1 && !text.contains(['\n', '\r']);
+    single(&edit.text) && single(&inverse.text) && edit.text.is_empty() != inverse.text.is_empty()
+}
@@ -0,0 +1,327 @@
+use super::*;
+
+fn body_values(ir: &ast::IrProgram) -> Vec<Value> {
+    ir.body
+        .iter()
+        .map(|stmt| serde_json::to_value(stmt).unwrap())
+        .collect()
+}
+
+/// Applies `delta` to the serialized body the webview would hold.
+fn apply_delta(body: &mut Vec<Value>, delta: &IrDelta) {
+    let end = delta.splice.start + delta.splice.deleted;
+    for stmt in &mut body[end..] {
+        shift_positions(stmt, delta.shift);
+    }
+    let inserted = delta
+        .splice
+        .inserted
+        .iter()
+        .map(|stmt| serde_json::to_value(stmt).unwrap());
+    body.splice(delta.splice.start..end, inserted);
+}
+
+#[test]
+fn document_edits_return_deltas() {
+    let documents = Documents::default();
+    let source = "a = 1\nb = 2\nif a:\n    c = 3\nd = 4\n";
+    let opened = documents.open(source.to_string());
+    assert!(opened.diagnostics.is_empty());
+    let id = opened.id;
+    let mut body = body_values(&documents.ir(id).unwrap());
+
+    let start = source.find('2').unwrap();
+    let edit = ast::TextEdit {
+        start,
+        end: start + 1,
+        text: "20\nx = 5".to_string(),
+    };
+    let change = documents.apply_text_edit(id, &edit).unwrap();
+    assert_eq!(change.version, opened.version + 1);
+    let delta = change.ir.unwrap();
+    assert_eq!((delta.splice.start, delta.splice.deleted), (1, 1));
+    assert_eq!(delta.splice.inserted.len(), 2);
+    assert_eq!((delta.shift.lines, delta.shift.offset), (1, 7));
+    apply_delta(&mut body, &delta);
+    assert_eq!(body, body_values(&documents.ir(id).unwrap()));
+
+    // Removing the `if` from the blocks sends back the text that went away.
+    let change = documents
+        .apply_ir_splice(
+            id,
+            IrSplice {
+                start: 3,
+                deleted: 1,
+                inserted: Vec::new(),
+            },
+        )
+        .unwrap();
+    assert_eq!(documents.text(id).unwrap(), "a = 1\nb = 20\nx = 5\nd = 4\n");
+    let text = change.text.unwrap();
+    assert_eq!((text.start, text.end, text.text.as_str()), (19, 35, ""));
+    apply_delta(&mut body, &change.ir.unwrap());
+    assert_eq!(body, body_values(&documents.ir(id).unwrap()));
+
+    // A source that does not parse keeps the last program.
+    let broken = ast::TextEdit {
+        start: 0,
+        end: 0,
+        text: "(".to_string(),
+    };
+    let change = documents.apply_text_edit(id, &broken).unwrap();
+    assert!(change.ir.is_none());
+    assert_eq!(change.diagnostics.len(), 1);
+    assert!(documents.ir(id).is_ok());
+    assert!(documents
+        .apply_ir_splice(
+            id,
+            IrSplice {
+                start: 0,
+                deleted: 1,
+                inserted: Vec::new(),
+            },
+        )
+        .is_err());
+
+    // Once it parses again, reads of unbound names are reported.
+    let fixed = ast::TextEdit {
+        start: 0,
+        end: 1,
+        text: "print(y)\n".to_string(),
+    };
+    let change = documents.apply_text_edit(id, &fixed).unwrap();
+    assert!(change.diagnostics.is_empty());
+    let diagnostics = documents.diagnostics(id).unwrap();
+    let messages: Vec<&str> = diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.message.as_str())
+        .collect();
+    assert_eq!(messages, ["name 'y' is not defined"]);
+
+    documents.close(id).unwrap();
+    assert!(documents.text(id).is_err());
+}
+
+#[test]
+fn text_edits_send_only_the_reparsed_statements() {
+    let documents = Documents::default();
+    let source = "a = 1\ndef f(x):\n    return x\nb = 2\n# end\n";
+    let id = documents.open(source.to_string()).id;
+    let mut body = body_values(&documents.ir(id).unwrap());
+
+    let mut edit = |start: usize, end: usize, text: &str| {
+        let edit = ast::TextEdit {
+            start,
+            end,
+            text: text.to_string(),
+        };
+        let delta = documents.apply_text_edit(id, &edit).unwrap().ir.unwrap();
+        apply_delta(&mut body, &delta);
+        assert_eq!(body, body_values(&documents.ir(id).unwrap()));
+        (
+            delta.splice.start,
+            delta.splice.deleted,
+            delta.splice.inserted.len(),
+        )
+    };
+    let start = source.find("return x").unwrap() + "return ".len();
+    assert_eq!(edit(start, start + 1, "x + 1\n    y = 2"), (1, 1, 1));
+    assert_eq!(edit(0, 0, "import os\n"), (0, 0, 1));
+    let end = documents.text(id).unwrap().len();
+    assert_eq!(edit(end, end, "c = 3\n"), (4, 1, 2));
+    assert_eq!(
+        documents.text(id).unwrap(),
+        "import os\na = 1\ndef f(x):\n    return x + 1\n    y = 2\nb = 2\n# end\nc = 3\n"
+    );
+}
+
+#[test]
+fn ir_operations_keep_node_ids() {
+    let documents = Documents::default();
+    let id = documents.open("if ready:\n    go()\n".to_string()).id;
+    let ir = documents.ir(id).unwrap();
+    let ast::IrStmt::If(stmt) = &ir.body[0] else {
+        panic!("expected if");
+    };
+    let block = stmt.body.meta.id;
+    let stmt = ast::parse_stmt_to_ir("stop()", ast::ParserConfig::default()).unwrap();
+    let applied = documents
+        .apply_ir_operations(
+            id,
+            vec![ast::IrOperation::InsertStmt {
+                block: Some(block),
+                index: 1,
+                stmt: Box::new(stmt),
+            }],
+        )
+        .unwrap();
+    assert_eq!(
+        documents.text(id).unwrap(),
+        "if ready:\n    go()\n    stop()\n"
+    );
+    let text = applied.change.text.unwrap();
+    assert_eq!(text.text, "    stop()\n");
+
+    // The inverse still finds the statement after the source is parsed again.
+    let version = applied.change.version;
+    let undone = documents.apply_ir_operations(id, applied.inverse).unwrap();
+    assert_eq!(undone.change.version, version + 1);
+    assert_eq!(documents.text(id).unwrap(), "if ready:\n    go()\n");
+
+    let misplaced = ast::parse_stmt_to_ir("break", ast::ParserConfig::default()).unwrap();
+    assert!(documents
+        .apply_ir_operations(
+            id,
+            vec![ast::IrOperation::InsertStmt {
+                block: None,
+                index: 0,
+                stmt: Box::new(misplaced),
+            }],
+        )
+        .is_err());
+    assert_eq!(documents.text(id).unwrap(), "if ready:\n    go()\n");
+}
+
+#[test]
+fn history_undoes_text_and_block_edits_together() {
+    let documents = Documents::default();
+    let opened = documents.open("x = 1\n".to_string());
+    let id = opened.id;
+    let typed = |start: usize, text: &str| ast::TextEdit {
+        start,
+        end: start,
+        text: text.to_string(),
+    };
+
+    // Keys typed in a row undo together; a new line starts another entry.
+    for (offset, key) in "23".char_indices() {
+        documents
+            .apply_text_edit(id, &typed(5 + offset, &key.to_string()))
+            .unwrap();
+    }
+    let erased = ast::TextEdit {
+        start: 6,
+        end: 7,
+        text: String::new(),
+    };
+    documents.apply_text_edit(id, &erased).unwrap();
+    documents.apply_text_edit(id, &typed(7, "y = 2\n")).unwrap();
+    assert_eq!(documents.text(id).unwrap(), "x = 12\ny = 2\n");
+
+    let ir = documents.ir(id).unwrap();
+    let ast::IrStmt::Assign(assign) = &ir.body[1] else {
+        panic!("expected assignment");
+    };
+    let stmt = ast::parse_stmt_to_ir("z = 3", ast::ParserConfig::default()).unwrap();
+    let moved = ast::IrOperation::MoveNode {
+        id: assign.meta.id,
+        block: None,
+        index: 0,
+    };
+    documents
+        .apply_ir_operations(
+            id,
+            vec![
+                moved,
+                ast::IrOperation::InsertStmt {
+                    block: None,
+                    index: 2,
+                    stmt: Box::new(stmt),
+                },
+            ],
+        )
+        .unwrap();
+    let state = documents.history(id).unwrap();
+    assert_eq!((state.undo, state.redo), (3, 0));
+    assert_eq!(documents.text(id).unwrap(), "y = 2\nx = 12\nz = 3\n");
+
+    // Undo is keyed on the version the caller saw.
+    assert!(documents.undo(id, state.version - 1).is_err());
+    let before = documents.text(id).unwrap();
+    let change = documents.undo(id, state.version).unwrap();
+    assert_eq!(documents.text(id).unwrap(), "x = 12\ny = 2\n");
+    assert_eq!(
+        change.text.unwrap().apply(&before).unwrap(),
+        documents.text(id).unwrap()
+    );
+    let change = documents.undo(id, change.version).unwrap();
+    assert_eq!(documents.text(id).unwrap(), "x = 12\n");
+    let change = documents.undo(id, change.version).unwrap();
+    assert_eq!(documents.text(id).unwrap(), "x = 1\n");
+    assert!(documents.undo(id, change.version).is_err());
+
+    let change = documents.redo(id, change.version).unwrap();
+    let change = documents.redo(id, change.version).unwrap();
+    let change = documents.redo(id, change.version).unwrap();
+    assert_eq!(documents.text(id).unwrap(), "y = 2\nx = 12\nz = 3\n");
+    assert!(documents.redo(id, change.version).is_err());
+
+    // A new edit drops what could be redone.
+    documents.undo(id, change.version).unwrap();
+    documents.apply_text_edit(id, &typed(0, "#")).unwrap();
+    let state = documents.history(id).unwrap();
+    assert_eq!((state.undo, state.redo), (3, 0));
+}
+
+#[test]
+fn syntax_node_replacement_keeps_formatting() {
+    fn find(node: &ast::SyntaxNode, text: &str) -> Option<ast::NodeId> {
+        if node.text() == text {
+            return Some(node.id);
+        }
+        node.children.iter().find_map(|child| match child {
+            ast::SyntaxElement::Node(node) => find(node, text),
+            ast::SyntaxElement::Token(_) => None,
+        })
+    }
+
+    let documents = Documents::default();
+    let source = "total = f( 1 )  # sum\nprint( total )\n";
+    let id = documents.open(source.to_string()).id;
+    let tree = documents.syntax_tree(id).unwrap();
+    assert_eq!(tree.text(), source);
+
+    let node = find(&tree, "f( 1 )").unwrap();
+    assert!(documents.replace_syntax_node(id, node, "f(").is_err());
+    let change = documents.replace_syntax_node(id, node, "g(  2 )").unwrap();
+    assert_eq!(
+        documents.text(id).unwrap(),
+        "total = g(  2 )  # sum\nprint( total )\n"
+    );
+    assert_eq!(change.ir.unwrap().splice.inserted.len(), 1);
+
+    documents.undo(id, change.version).unwrap();
+    assert_eq!(documents.text(id).unwrap(), source);
+}
+
+#[test]
+fn token_stores_stay_with_their_document() {
+    let documents = Documents::default();
+    let source = "x  =  1  # one\n";
+    let id = documents.open(source.to_string()).id;
+    let other = documents.open(source.to_string()).id;
+    let options = ast::IrEncodeOptions {
+        encoding: ast::IrEncoding::Compact,
+        detach_tokens: true,
+    };
+    let envelope = documents.encoded_ir(id, options).unwrap();
+    assert!(envelope.token_handle.is_some());
+    for _ in 0..10 {
+        documents.encoded_ir(other, options).unwrap();
+    }
+    let ir = documents.decode_ir(id, envelope.clone()).unwrap();
+    assert!(ir.token_store.is_some());
+    assert!(documents.decode_ir(other, envelope.clone()).is_err());
+
+    // The store goes with the source it was taken from.
+    let edit = ast::TextEdit {
+        start: 0,
+        end: 1,
+        text: "y".to_string(),
+    };
+    documents.apply_text_edit(id, &edit).unwrap();
+    assert!(documents.decode_ir(id, envelope).is_err());
+    let envelope = documents.encoded_ir(id, options).unwrap();
+    documents.close(id).unwrap();
+    assert!(documents.decode_ir(id, envelope).is_err());
+}
@@ -11,7 +11,15 @@ import { syntaxHighlighting, defaultHighlightStyle, bracketMatching, HighlightSt
 import { tags } from "@lezer/highlight";
 import { oneDark } from "@codemirror/theme-one-dark";
 import { autocompletion, type CompletionContext, type CompletionResult } from "@codemirror/autocomplete";
-import type { ir_program, run_result, run_output, theme_mode } from "./types";
+import type {
+  ir_envelope,
+  ir_program,
+  rendered_python,
+  run_result,
+  run_output,
+  theme_mode,
+} from "./types";
+import { make_ir_envelope, open_ir_envelope } from "./ir_envelope";
 import { t, set_language, get_language, get_easy_mode, set_easy_mode, type Language } from "./i18n";
 import {
   blockly_theme_dark,
@@ -78,6 +86,8 @@ let blocks_sync_timer: ReturnType<typeof setTimeout> | null = null;
 let source_save_timer: ReturnType<typeof setTimeout> | null = null;
 let resize_frame: number | null = null;
 let pending_code_sync: string | null = null;
+/** Handle of the token store the backend kept for the last parsed source. */
+let source_token_handle: number | null = null;
 let pending_source_save: string | null = null;
 let pending_blocks_sync = false;
 let current_file_path: string | null = null;
@@ -268,11 +278,16 @@ const parse_error_parts = (error_string: string): { line: number; col: number; m
 };
 
 const parse_python_to_ir = async (source: string) =>
-  invoke<ir_program>("parse_python_to_ir", { source });
+  open_ir_envelope(
+    await invoke<ir_envelope>("parse_python_to_ir", {
+      source,
+      options: { encoding: "Compact", detach_tokens: true },
+    }),
+  );
 
 const generate_python_from_ir = async (ir: ir_program) =>
-  invoke<string>("generate_python_from_ir", {
-    ir,
+  invoke<rendered_python>("generate_python_from_ir", {
+    ir: make_ir_envelope(ir, source_token_handle),
     renderMode: "Lossless",
   });
 
@@ -442,7 +457,8 @@ const sync_code_to_blocks = async (source: string) => {
   }
   is_syncing = true;
   try {
-    const ir = await parse_python_to_ir(source);
+    const { program: ir, token_handle } = await parse_python_to_ir(source);
+    source_token_handle = token_handle;
     update_node_counter(ir);
     blocks_from_ir(ir);
     set_error_line(null);
@@ -471,7 +487,7 @@ const sync_blocks_to_code = async () => {
   is_syncing = true;
   try {
     const ir = ir_from_blocks();
-    const source = await generate_python_from_ir(ir);
+    const { source } = await generate_python_from_ir(ir);
     const current = get_editor_value();
     if (current !== source) {
       set_editor_value(source);
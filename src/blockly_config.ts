@@ -36,6 +36,7 @@ export const block_type_function_def = "stmt_function_def";
 export const block_type_assign = "stmt_assign";
 export const block_type_expr = "stmt_expr";
 export const block_type_pass = "stmt_pass";
+export const block_type_comment = "stmt_comment";
 export const block_type_sync_error = "stmt_sync_error";
 export const block_type_identifier = "expr_identifier";
 export const block_type_number = "expr_number";
@@ -395,6 +396,7 @@ export const get_toolbox = () => ({
       contents: [
         { kind: "block", type: block_type_expr },
         { kind: "block", type: block_type_pass },
+        { kind: "block", type: block_type_comment },
       ],
     },
   ],
@@ -598,6 +600,17 @@ export const register_blocks = () => {
     },
   };
 
+  Blockly.Blocks[block_type_comment] = {
+    init() {
+      this.appendDummyInput()
+        .appendField(b("block_comment", "#"))
+        .appendField(new Blockly.FieldTextInput("comment"), "TEXT");
+      this.setPreviousStatement(true);
+      this.setNextStatement(true);
+      this.setColour(180);
+    },
+  };
+
   Blockly.Blocks[block_type_sync_error] = {
     init() {
       this.appendDummyInput().appendField(t("msg_sync_error"));
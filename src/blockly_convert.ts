@@ -48,6 +48,7 @@ import {
   block_type_boolop,
   block_type_call,
   block_type_case,
+  block_type_comment,
   block_type_compare,
   block_type_comprehension,
   block_type_dict,
@@ -285,6 +286,7 @@ const collect_max_id_stmt = (stmt: ir_stmt): number => {
       return Math.max(max_id, collect_max_id_expr(stmt.data.expr));
     case "Pass":
     case "Empty":
+    case "Comment":
       return max_id;
     case "Return":
       if (stmt.data.value) {
@@ -640,10 +642,36 @@ const create_statement_blocks = (statement: ir_stmt) => {
   const result = create_statement_blocks_inner(statement);
   if (result) {
     set_block_span(result.first, statement.data.meta);
+    const comment = statement_comment(statement).comment;
+    if (comment) {
+      result.first.setCommentText(comment_to_block(comment));
+    }
   }
   return result;
 };
 
+/** The meta owning the comment that ends the first line of `statement`; a header gives it to the block it opens. */
+const statement_comment = (statement: ir_stmt): node_meta => {
+  switch (statement.kind) {
+    case "If":
+    case "While":
+    case "For":
+    case "FunctionDef":
+    case "Try":
+    case "ClassDef":
+      return statement.data.body.meta;
+    case "Match":
+      return statement.data.cases.meta;
+    default:
+      return statement.data.meta;
+  }
+};
+
+// Comments are shown without the space that usually follows "#".
+const comment_to_block = (text: string) => (text.startsWith(" ") ? text.slice(1) : text);
+
+const comment_from_block = (text: string | null) => (text ? ` ${text}` : null);
+
 const create_statement_blocks_inner = (statement: ir_stmt) => {
   if (!workspace) {
     return null;
@@ -816,6 +844,12 @@ const create_statement_blocks_inner = (statement: ir_stmt) => {
     init_block(block);
     return { first: block, last: block };
   }
+  if (statement.kind === "Comment") {
+    const block = workspace.newBlock(block_type_comment);
+    block.setFieldValue(comment_to_block(statement.data.text), "TEXT");
+    init_block(block);
+    return { first: block, last: block };
+  }
   if (statement.kind === "Return") {
     const block = workspace.newBlock(block_type_return);
     if (statement.data.value) {
@@ -1503,8 +1537,10 @@ const statements_from_chain = (start_block: Blockly.Block | null): ir_stmt[] =>
   const statements: ir_stmt[] = [];
   let current_block = start_block;
   while (current_block) {
+    const comment = comment_from_block(current_block.getCommentText());
     if (current_block.type === block_type_if) {
       const result = consume_if_chain(current_block);
+      statement_comment(result.statement).comment = comment;
       statements.push(result.statement);
       current_block = result.next;
       continue;
@@ -1518,7 +1554,9 @@ const statements_from_chain = (start_block: Blockly.Block | null): ir_stmt[] =>
     if (current_block.type === block_type_case) {
       throw new Error("caseはmatchの中に配置");
     }
-    statements.push(statement_from_block(current_block));
+    const statement = statement_from_block(current_block);
+    statement_comment(statement).comment = comment;
+    statements.push(statement);
     current_block = current_block.getNextBlock();
   }
   return statements;
@@ -1753,6 +1791,14 @@ const statement_from_block = (block: Blockly.Block): ir_stmt => {
         kind: "Pass",
         data: { meta: make_meta() },
       };
+    case block_type_comment:
+      return {
+        kind: "Comment",
+        data: {
+          meta: make_meta(),
+          text: comment_from_block(block.getFieldValue("TEXT")) ?? "",
+        },
+      };
     case block_type_return: {
       const value_block = block.getInputTargetBlock("VALUE");
       return {
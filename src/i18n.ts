@@ -82,6 +82,20 @@ const translations: Record<Language, Record<string, string>> = {
     help_shortcut_2: "Ctrl+Y: やり直し",
     help_shortcut_3: "Ctrl+S: 保存",
     help_shortcut_4: "Ctrl+O: 開く",
+
+    // Pitfall Hints
+    hint_assignment_in_condition:
+      "条件の中で {name} に {value} を代入しています。くらべるときは {name} == {value} と書きます",
+    hint_comparison_statement:
+      "{target} == {value} はくらべるだけで、何も変わりません。代入するときは {target} = {value} と書きます",
+    hint_range_len_loop:
+      "for 文で {sequence} をそのままくり返せます。番号 {index} も使うときは enumerate({sequence}) が便利です",
+    hint_mutable_default:
+      "引数 {name} の初期値は、呼び出しのたびに同じものが使い回されます。None を初期値にして、関数の中で作りましょう",
+    hint_none_comparison: "None とくらべるときは {op} ではなく {suggestion} を使います",
+    hint_endless_loop: "break も return も raise もないので、このくり返しは終わりません",
+    hint_shadowed_builtin:
+      "{name} は Python にもともとある名前です。別の名前にしないと、もとの {name} が使えなくなります",
   },
   en: {
     // UI Labels
@@ -164,6 +178,20 @@ const translations: Record<Language, Record<string, string>> = {
     help_shortcut_2: "Ctrl+Y: Redo",
     help_shortcut_3: "Ctrl+S: Save",
     help_shortcut_4: "Ctrl+O: Open",
+
+    // Pitfall Hints
+    hint_assignment_in_condition:
+      "This assigns {value} to {name} inside the condition. To compare them, write {name} == {value}",
+    hint_comparison_statement:
+      "{target} == {value} only compares and changes nothing. To assign, write {target} = {value}",
+    hint_range_len_loop:
+      "A for loop can go over {sequence} directly. If you need the index {index} as well, use enumerate({sequence})",
+    hint_mutable_default:
+      "The default value of {name} is shared by every call. Use None as the default and create the value inside the function",
+    hint_none_comparison: "Compare with None using {suggestion} instead of {op}",
+    hint_endless_loop: "This loop never ends: there is no break, return or raise in it",
+    hint_shadowed_builtin:
+      "{name} is a name Python already has. Pick another name, or the original {name} stops working",
   },
 };
 
@@ -182,6 +210,13 @@ export const t = (key: string): string => {
   return translations[current_language]?.[key] ?? translations["ja"]?.[key] ?? key;
 };
 
+/** `t(key)` with each `{name}` placeholder replaced by `args[name]`. */
+export const t_args = (key: string, args: Record<string, string>): string =>
+  Object.entries(args).reduce(
+    (text, [name, value]) => text.split(`{${name}}`).join(value),
+    t(key),
+  );
+
 export const set_language = (lang: Language) => {
   current_language = lang;
   localStorage.setItem("lebl_language", lang);
@@ -233,6 +268,7 @@ const easy_labels: Record<string, string> = {
 
   // Flow
   block_pass: "なにもしない",
+  block_comment: "メモ",
   block_break: "くりかえし をやめる",
   block_continue: "つぎへすすむ",
 
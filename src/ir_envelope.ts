@@ -0,0 +1,79 @@
+import type { ir_envelope, ir_program } from "./types";
+
+/** IR version the backend writes; keep in step with `IR_VERSION`. */
+export const ir_version = 2;
+
+type json = null | boolean | number | string | json[] | { [key: string]: json };
+
+const span_fields = [
+  ["start", "line"],
+  ["start", "column"],
+  ["start", "offset"],
+  ["end", "line"],
+  ["end", "column"],
+  ["end", "offset"],
+];
+
+const token_range_fields = [["start"], ["end"]];
+
+const nest = (numbers: json[], fields: string[][]) => {
+  const nested: { [key: string]: json } = {};
+  fields.forEach((path, index) => {
+    let target = nested;
+    path.slice(0, -1).forEach((key) => {
+      target[key] ??= {};
+      target = target[key] as { [key: string]: json };
+    });
+    target[path[path.length - 1]] = numbers[index];
+  });
+  return nested;
+};
+
+/** Undo the compact encoding: enums keyed by variant, spans and token ranges as arrays, meta without defaults. */
+const expand = (value: json): json => {
+  if (Array.isArray(value)) {
+    return value.map(expand);
+  }
+  if (value === null || typeof value !== "object") {
+    return value;
+  }
+  const object: { [key: string]: json } = {};
+  Object.entries(value).forEach(([key, field]) => {
+    const expanded = expand(field);
+    const fields = key === "span" ? span_fields : key === "token_range" ? token_range_fields : null;
+    object[key] =
+      fields && Array.isArray(expanded) && expanded.length === fields.length ? nest(expanded, fields) : expanded;
+  });
+  if ("id" in object && "token_range" in object) {
+    object.leading_trivia ??= [];
+    object.trailing_trivia ??= [];
+  }
+  const keys = Object.keys(object);
+  if (keys.length === 1 && /^[A-Z]/.test(keys[0])) {
+    const data = object[keys[0]];
+    return data === null ? { kind: keys[0] } : { kind: keys[0], data };
+  }
+  return object;
+};
+
+/** A program read out of an envelope, with the handle of the token store the backend kept for it. */
+export type opened_ir = {
+  program: ir_program;
+  token_handle: number | null;
+};
+
+export const open_ir_envelope = (envelope: ir_envelope): opened_ir => {
+  if (envelope.version !== ir_version) {
+    throw new Error(`unsupported IR version ${envelope.version}`);
+  }
+  const program = envelope.encoding === "Compact" ? expand(envelope.program as json) : envelope.program;
+  return { program: program as ir_program, token_handle: envelope.token_handle };
+};
+
+/** Wrap `program`, pointing the backend at the token store of `token_handle` if it has one. */
+export const make_ir_envelope = (program: ir_program, token_handle: number | null = null): ir_envelope => ({
+  version: ir_version,
+  encoding: "Json",
+  token_handle,
+  program,
+});
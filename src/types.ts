@@ -30,6 +30,8 @@ export type node_meta = {
   token_range: token_range;
   leading_trivia: trivia[];
   trailing_trivia: trivia[];
+  dirty?: boolean;
+  comment?: string | null;
 };
 
 export type number_literal = {
@@ -329,6 +331,7 @@ export type ir_stmt =
   | { kind: "Expr"; data: ir_expr_stmt }
   | { kind: "Pass"; data: ir_pass_stmt }
   | { kind: "Empty"; data: ir_empty_stmt }
+  | { kind: "Comment"; data: ir_comment_stmt }
   | { kind: "Return"; data: ir_return_stmt }
   | { kind: "Break"; data: ir_break_stmt }
   | { kind: "Continue"; data: ir_continue_stmt }
@@ -417,6 +420,11 @@ export type ir_empty_stmt = {
   source: "Source" | "Generated";
 };
 
+export type ir_comment_stmt = {
+  meta: node_meta;
+  text: string;
+};
+
 export type ir_return_stmt = {
   meta: node_meta;
   value: expr | null;
@@ -523,6 +531,201 @@ export type ir_program = {
   body: ir_stmt[];
   token_store: unknown | null;
   dirty: boolean;
+  source_format?: source_format;
+};
+
+export type source_format = {
+  line_ending: "Lf" | "CrLf" | "Cr";
+  bom: boolean;
+  coding: string | null;
+};
+
+export type ir_encoding = "Json" | "Compact";
+
+export type ir_encode_options = {
+  encoding: ir_encoding;
+  detach_tokens: boolean;
+};
+
+export type ir_envelope = {
+  version: number;
+  encoding: ir_encoding;
+  token_handle: number | null;
+  program: unknown;
+};
+
+export type severity = "Error" | "Warning" | "Info";
+
+export type quick_fix = {
+  title: string;
+  operations: ir_operation[];
+};
+
+export type diagnostic = {
+  message: string;
+  span: span;
+  node: number | null;
+  severity: severity;
+  rule: string | null;
+  fix: quick_fix | null;
+};
+
+export type pitfall_kind =
+  | "assignment_in_condition"
+  | "comparison_statement"
+  | "range_len_loop"
+  | "mutable_default"
+  | "none_comparison"
+  | "endless_loop"
+  | "shadowed_builtin";
+
+export type pitfall = {
+  kind: pitfall_kind;
+  args: Record<string, string>;
+  node: number | null;
+  span: span;
+};
+
+export type inferred_type =
+  | { kind: "int" }
+  | { kind: "float" }
+  | { kind: "bool" }
+  | { kind: "str" }
+  | { kind: "none" }
+  | { kind: "list"; data: inferred_type }
+  | { kind: "tuple"; data: inferred_type[] }
+  | { kind: "dict"; data: [inferred_type, inferred_type] }
+  | { kind: "set"; data: inferred_type }
+  | { kind: "range" }
+  | { kind: "unknown" };
+
+export type type_diagnostic = {
+  kind: "Operands" | "Comparison";
+  op: string;
+  left: inferred_type;
+  right: inferred_type;
+  node: number;
+  span: span;
+};
+
+export type type_table = {
+  types: Record<string, inferred_type>;
+  errors: type_diagnostic[];
+};
+
+export type rule_config = {
+  enabled?: boolean | null;
+  severity?: severity | null;
+};
+
+export type lint_config = {
+  rules?: Record<string, rule_config>;
+};
+
+export type lint_diagnostic = {
+  rule: string;
+  severity: severity;
+  message: string;
+  node: number | null;
+  span: span;
+  fix: quick_fix | null;
+};
+
+export type lint_rule_info = {
+  id: string;
+  description: string;
+  severity: severity;
+  enabled: boolean;
+};
+
+export type opened_document = {
+  id: number;
+  version: number;
+  diagnostics: diagnostic[];
+};
+
+export type ir_splice = {
+  start: number;
+  deleted: number;
+  inserted: ir_stmt[];
+};
+
+export type ir_delta = ir_splice & {
+  shift: { lines: number; offset: number; tokens: number };
+};
+
+export type text_edit = {
+  start: number;
+  end: number;
+  text: string;
+};
+
+export type document_change = {
+  version: number;
+  text: text_edit | null;
+  ir: ir_delta | null;
+  diagnostics: diagnostic[];
+};
+
+export type history_state = {
+  version: number;
+  undo: number;
+  redo: number;
+};
+
+export type ir_operation =
+  | { kind: "InsertStmt"; data: { block: number | null; index: number; stmt: ir_stmt } }
+  | { kind: "DeleteNode"; data: { id: number } }
+  | { kind: "MoveNode"; data: { id: number; block: number | null; index: number } }
+  | { kind: "ReplaceExpr"; data: { id: number; expr: expr } }
+  | { kind: "SetField"; data: { id: number; field: string; value: unknown } };
+
+export type applied_operations = {
+  change: document_change;
+  inverse: ir_operation[];
+};
+
+export type lex_token = {
+  kind: { kind: string; data?: unknown };
+  span: span;
+  raw: string;
+};
+
+export type syntax_kind = "Program" | "Block" | "Stmt" | "Expr" | "Pattern" | "ComprehensionFor";
+
+export type syntax_node = {
+  id: number;
+  kind: syntax_kind;
+  children: ({ kind: "Node"; data: syntax_node } | { kind: "Token"; data: lex_token })[];
+};
+
+export type scope_kind = "Module" | "Function" | "Class" | "Comprehension" | "Lambda";
+
+export type reference_kind =
+  | "Definition"
+  | "Use"
+  | "Global"
+  | "Nonlocal"
+  | "Parameter"
+  | "Declaration"
+  | "Deletion";
+
+export type symbol_table = {
+  scopes: { kind: scope_kind; node: number; parent: number | null }[];
+  symbols: { name: string; scope: number }[];
+  references: {
+    node: number;
+    span: span;
+    kind: reference_kind;
+    symbol: number;
+    scope: number;
+  }[];
+  nodes: Record<string, number>;
+};
+
+export type rendered_python = {
+  source: string;
+  source_map: Record<string, span>;
 };
 
 export type run_result = {